@@ -0,0 +1,241 @@
+use std::{sync::Arc, time::Duration};
+
+use sysinfo::{ProcessRefreshKind, ProcessesToUpdate, System};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// How often `--adaptive-jobs` looks at the load of the machine.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The bounds of `--adaptive-jobs`.
+#[derive(Clone, Copy)]
+pub struct JobRange {
+    pub min: usize,
+    pub max: usize,
+}
+
+/// Parses `min..max`, e.g. `2..12`.
+pub fn parse_range(text: &str) -> Result<JobRange, String> {
+    let (min, max) = text
+        .split_once("..")
+        .ok_or_else(|| format!("`{}` is not a range such as 2..12", text))?;
+    let parse = |bound: &str| {
+        bound
+            .trim()
+            .parse::<usize>()
+            .map_err(|_| format!("`{}` is not a range such as 2..12", text))
+    };
+    let range = JobRange {
+        min: parse(min)?,
+        max: parse(max)?,
+    };
+    if range.min == 0 || range.min > range.max {
+        return Err(format!(
+            "`{}` needs at least one job and the smaller bound first",
+            text
+        ));
+    }
+    Ok(range)
+}
+
+/// Tells how many cores other programs keep busy.
+pub trait Load: Send {
+    /// The cores other programs used since the last call, which may be a
+    /// fraction.
+    fn busy(&mut self) -> f32;
+}
+
+/// The load of the machine, less the conversions of this run.
+pub struct SystemLoad {
+    system: System,
+    cpus: usize,
+}
+
+impl SystemLoad {
+    pub fn new(cpus: usize) -> Self {
+        // sysinfo otherwise keeps a file of every process open, and the run
+        // needs its descriptors for the files it works on.
+        sysinfo::set_open_files_limit(0);
+        let mut system = System::new();
+        system.refresh_cpu_usage();
+        SystemLoad { system, cpus }
+    }
+}
+
+impl Load for SystemLoad {
+    fn busy(&mut self) -> f32 {
+        self.system.refresh_cpu_usage();
+        self.system.refresh_processes_specifics(
+            ProcessesToUpdate::All,
+            true,
+            ProcessRefreshKind::nothing().with_cpu(),
+        );
+        let total = self.system.global_cpu_usage() / 100.0 * self.cpus as f32;
+        // Our own conversions are the encoders we started, in cores.
+        let own = sysinfo::get_current_pid().map_or(0.0, |pid| {
+            self.system
+                .processes()
+                .iter()
+                .filter(|(id, process)| **id == pid || process.parent() == Some(pid))
+                .map(|(_, process)| process.cpu_usage() / 100.0)
+                .sum()
+        });
+        total - own
+    }
+}
+
+/// Runs between `range.min` and `range.max` conversions, as many as there
+/// are cores left idle by other programs. The conversion slots are made for
+/// `range.max`, and the ones not to be used are parked here.
+pub struct Adaptive {
+    range: JobRange,
+    load: Box<dyn Load>,
+    cpus: usize,
+    /// Slots taken out of use.
+    parked: Vec<OwnedSemaphorePermit>,
+    /// How many conversions to run. Parking can lag behind it while more
+    /// than that are still running.
+    jobs: usize,
+}
+
+impl Adaptive {
+    /// Starts at `range.min` until the first sample shows how busy the
+    /// machine is.
+    pub fn new(range: JobRange) -> Self {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Adaptive::with_load(range, cpus, Box::new(SystemLoad::new(cpus)))
+    }
+
+    /// Adapts to `load` on a machine with `cpus` cores.
+    pub fn with_load(range: JobRange, cpus: usize, load: Box<dyn Load>) -> Self {
+        Adaptive {
+            range,
+            load,
+            cpus,
+            parked: Vec::new(),
+            jobs: range.min,
+        }
+    }
+
+    pub fn jobs(&self) -> usize {
+        self.jobs
+    }
+
+    /// How many slots are parked, so not in use by a conversion.
+    pub fn parked(&self) -> usize {
+        self.parked.len()
+    }
+
+    /// Measures the CPU use of other programs since the last sample and sets
+    /// the number of conversions from the cores they leave idle. Returns the
+    /// new number if it changed.
+    pub fn sample(&mut self) -> Option<usize> {
+        let jobs = jobs_for(self.cpus, self.load.busy(), self.range);
+        (jobs != self.jobs).then(|| {
+            self.jobs = jobs;
+            jobs
+        })
+    }
+
+    /// Parks or hands back slots of `slots` until `jobs` of them are in
+    /// use. Slots held by running conversions can only be parked once they
+    /// finish, so this is called again every time round the run loop.
+    pub fn settle(&mut self, slots: &Arc<Semaphore>) {
+        let wanted = self.range.max - self.jobs;
+        self.parked.truncate(wanted);
+        while self.parked.len() < wanted {
+            match slots.clone().try_acquire_owned() {
+                Ok(slot) => self.parked.push(slot),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+/// The number of conversions for `cpus` cores of which other programs keep
+/// `busy` busy, within `range`.
+fn jobs_for(cpus: usize, busy: f32, range: JobRange) -> usize {
+    let idle = (cpus as f32 - busy.max(0.0)).floor().max(0.0) as usize;
+    idle.clamp(range.min, range.max)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Busy cores as a test sets them.
+    struct Scripted(Arc<Mutex<f32>>);
+
+    impl Load for Scripted {
+        fn busy(&mut self) -> f32 {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn adaptive(min: usize, max: usize, cpus: usize) -> (Adaptive, Arc<Mutex<f32>>) {
+        let busy = Arc::new(Mutex::new(0.0));
+        let load = Box::new(Scripted(busy.clone()));
+        (Adaptive::with_load(JobRange { min, max }, cpus, load), busy)
+    }
+
+    #[test]
+    fn ranges_need_a_job_and_their_smaller_bound_first() {
+        let range = parse_range("2..12").unwrap();
+        assert_eq!((range.min, range.max), (2, 12));
+        assert_eq!(parse_range(" 3 .. 3 ").unwrap().max, 3);
+        for text in ["12", "0..4", "5..2", "a..b", "2..", "..2"] {
+            assert!(parse_range(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn jobs_follow_the_idle_cores_within_the_range() {
+        let (mut adaptive, busy) = adaptive(2, 12, 16);
+        assert_eq!(adaptive.jobs(), 2);
+
+        // An idle machine at night.
+        assert_eq!(adaptive.sample(), Some(12));
+        assert_eq!(adaptive.sample(), None);
+        // Someone starts using it.
+        *busy.lock().unwrap() = 9.5;
+        assert_eq!(adaptive.sample(), Some(6));
+        *busy.lock().unwrap() = 15.9;
+        assert_eq!(adaptive.sample(), Some(2));
+        // More than the machine has, as sampling can overshoot.
+        *busy.lock().unwrap() = 40.0;
+        assert_eq!(adaptive.sample(), None);
+        *busy.lock().unwrap() = -1.0;
+        assert_eq!(adaptive.sample(), Some(12));
+    }
+
+    #[test]
+    fn unused_slots_are_parked_once_conversions_let_go_of_them() {
+        let (mut adaptive, busy) = adaptive(2, 8, 8);
+        let slots = Arc::new(Semaphore::new(8));
+        adaptive.settle(&slots);
+        assert_eq!((adaptive.parked(), slots.available_permits()), (6, 2));
+
+        adaptive.sample();
+        adaptive.settle(&slots);
+        assert_eq!((adaptive.parked(), slots.available_permits()), (0, 8));
+
+        // Seven conversions running when the machine gets busy.
+        let running: Vec<_> = (0..7)
+            .map(|_| slots.clone().try_acquire_owned().unwrap())
+            .collect();
+        *busy.lock().unwrap() = 5.0;
+        assert_eq!(adaptive.sample(), Some(3));
+        adaptive.settle(&slots);
+        assert_eq!(adaptive.parked(), 1);
+        // Parking catches up as they finish, and no new one starts.
+        drop(running);
+        adaptive.settle(&slots);
+        assert_eq!((adaptive.parked(), slots.available_permits()), (5, 3));
+
+        *busy.lock().unwrap() = 3.0;
+        assert_eq!(adaptive.sample(), Some(5));
+        adaptive.settle(&slots);
+        assert_eq!((adaptive.parked(), slots.available_permits()), (3, 5));
+    }
+}
@@ -0,0 +1,45 @@
+use std::{io::Read, path::Path};
+
+/// Animation control data of an animated PNG.
+pub struct Animation {
+    pub frames: u32,
+    /// How often the animation plays, 0 means forever.
+    pub plays: u32,
+}
+
+/// Looks for an `acTL` chunk in a PNG file, which marks it as an APNG. Only the
+/// chunks before the image data are read, the spec requires `acTL` to come first.
+pub fn animation(path: &Path) -> anyhow::Result<Option<Animation>> {
+    let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+
+    let mut signature = [0u8; 8];
+    file.read_exact(&mut signature)?;
+    if &signature != b"\x89PNG\r\n\x1a\n" {
+        return Err(anyhow::anyhow!("Not a PNG file"));
+    }
+
+    loop {
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header)?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+
+        match &header[4..8] {
+            b"acTL" => {
+                let mut data = [0u8; 8];
+                file.read_exact(&mut data)?;
+                return Ok(Some(Animation {
+                    frames: u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+                    plays: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+                }));
+            }
+            b"IDAT" | b"IEND" => return Ok(None),
+            _ => {
+                // Skip the chunk data and its CRC.
+                std::io::copy(
+                    &mut (&mut file).take(length as u64 + 4),
+                    &mut std::io::sink(),
+                )?;
+            }
+        }
+    }
+}
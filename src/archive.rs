@@ -0,0 +1,189 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use zip::{CompressionMethod, ZipArchive, ZipWriter, write::SimpleFileOptions};
+
+/// One entry of a zip archive, in archive order.
+pub struct Member {
+    pub index: usize,
+    pub name: String,
+    /// Where the member was extracted to, relative to the extraction directory.
+    pub path: PathBuf,
+    pub is_dir: bool,
+}
+
+/// Extracts every member of a zip archive below `dir`. Fails on member names
+/// that would end up outside of it.
+pub fn extract_all(archive_path: &Path, dir: &Path) -> anyhow::Result<Vec<Member>> {
+    let mut archive = ZipArchive::new(File::open(archive_path)?)?;
+    let mut members = Vec::with_capacity(archive.len());
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        let name = entry.name()?.into_owned();
+        let path = entry
+            .enclosed_name()
+            .ok_or_else(|| anyhow::anyhow!("Unsafe member name {}", name))?;
+
+        let target = dir.join(&path);
+        if entry.is_dir() {
+            std::fs::create_dir_all(&target)?;
+        } else {
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::io::copy(&mut entry, &mut File::create(&target)?)?;
+        }
+        members.push(Member {
+            index,
+            name,
+            path,
+            is_dir: entry.is_dir(),
+        });
+    }
+    Ok(members)
+}
+
+/// The names the members at `converted` get in the archive once they are
+/// JPEG XL, by index: their own with a `.jxl` extension in place of theirs,
+/// unless that's the name of another member or of another conversion, as
+/// with `01.png` next to `01.jpg` or `01.jxl`. Those keep their extension,
+/// `01.png.jxl`, so no page takes the place of another.
+pub fn converted_names(
+    members: &[Member],
+    converted: &[usize],
+) -> anyhow::Result<HashMap<usize, String>> {
+    let name_of = |member: &Member| member.name.replace('\\', "/");
+    let jxl_name_of = |member: &Member| {
+        Path::new(&member.name)
+            .with_extension("jxl")
+            .to_string_lossy()
+            .replace('\\', "/")
+    };
+    let converted: Vec<&Member> = members
+        .iter()
+        .filter(|member| converted.contains(&member.index))
+        .collect();
+    let mut taken: HashSet<String> = members
+        .iter()
+        .filter(|member| !converted.iter().any(|c| c.index == member.index))
+        .map(name_of)
+        .collect();
+    let mut wanted = HashMap::<String, usize>::new();
+    for member in &converted {
+        *wanted.entry(jxl_name_of(member)).or_default() += 1;
+    }
+
+    let mut names = HashMap::new();
+    for member in converted {
+        let plain = jxl_name_of(member);
+        let name = if wanted[&plain] > 1 || taken.contains(&plain) {
+            format!("{}.jxl", name_of(member))
+        } else {
+            plain
+        };
+        if !taken.insert(name.clone()) {
+            return Err(anyhow::anyhow!(
+                "{} can't be converted without replacing {}",
+                member.name,
+                name
+            ));
+        }
+        names.insert(member.index, name);
+    }
+    Ok(names)
+}
+
+/// Writes a copy of `archive_path` to `destination` in which the members in
+/// `replaced` are swapped for the given files under a new name. All other
+/// members are copied without recompressing them, in their original order.
+pub fn repack(
+    archive_path: &Path,
+    destination: &Path,
+    replaced: &HashMap<usize, (String, PathBuf)>,
+) -> anyhow::Result<()> {
+    let mut archive = ZipArchive::new(File::open(archive_path)?)?;
+    let mut writer = ZipWriter::new(File::create(destination)?);
+    for index in 0..archive.len() {
+        let entry = archive.by_index(index)?;
+        match replaced.get(&index) {
+            Some((name, path)) => {
+                // JPEG XL data doesn't get any smaller by deflating it.
+                let mut options =
+                    SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+                if let Some(modified) = entry.last_modified() {
+                    options = options.last_modified_time(modified);
+                }
+                writer.start_file(name, options)?;
+                writer.write_all(&std::fs::read(path)?)?;
+            }
+            None => writer.raw_copy_file(entry)?,
+        }
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A zip holding an empty member for each of `names`, in that order.
+    fn write_zip(path: &Path, names: &[&str]) {
+        let mut writer = ZipWriter::new(File::create(path).unwrap());
+        for name in names {
+            writer
+                .start_file(*name, SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(name.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn pages_that_differ_in_extension_keep_apart() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("book.cbz");
+        write_zip(
+            &source,
+            &[
+                "01.png",
+                "01.jpg",
+                "02.png",
+                "02.jxl",
+                "03.png",
+                "notes.txt",
+            ],
+        );
+        let members = extract_all(&source, &temp.path().join("members")).unwrap();
+
+        let names = converted_names(&members, &[0, 1, 2, 4]).unwrap();
+        assert_eq!(names[&0], "01.png.jxl");
+        assert_eq!(names[&1], "01.jpg.jxl");
+        assert_eq!(names[&2], "02.png.jxl");
+        assert_eq!(names[&4], "03.jxl");
+
+        let replaced = names
+            .into_iter()
+            .map(|(index, name)| (index, (name, temp.path().join("members/notes.txt"))))
+            .collect();
+        let repacked = temp.path().join("repacked.cbz");
+        repack(&source, &repacked, &replaced).unwrap();
+        let archive = ZipArchive::new(File::open(&repacked).unwrap()).unwrap();
+        let repacked: Vec<_> = archive.file_names().map(|name| name.unwrap()).collect();
+        assert_eq!(
+            repacked,
+            [
+                "01.png.jxl",
+                "01.jpg.jxl",
+                "02.png.jxl",
+                "02.jxl",
+                "03.jxl",
+                "notes.txt"
+            ]
+        );
+    }
+}
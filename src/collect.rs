@@ -0,0 +1,933 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use tokio::task::JoinSet;
+
+use crate::{
+    ACCEPTED_EXTENSIONS, CollisionMode, Context, ESTIMATED_OUTPUT_RATIO, FileKind, MAX_PATH,
+    MultipageMode, PlannedFile, ProcessResult, Pruned, RunPaths, Scan, Seen, SourceFile, collision,
+    conversion_jobs, convert_planned, events, extension_of, filelist, filter_dimensions, gitignore,
+    is_candidate, is_empty_output, is_long_output, pilot, plan, planned_action, prevalidate,
+    print_collision, print_plan, probe_sources, scan_files, space, split_unstable, thread_division,
+};
+
+/// Walks the input, prints the overview and asks for confirmation. Returns
+/// the planned files, or `None` if the user backed out.
+pub(crate) async fn collect(
+    context: &Context,
+    paths: &RunPaths,
+) -> anyhow::Result<Option<(Vec<PlannedFile>, Scan)>> {
+    let args = &context.args;
+    let mut scan = Scan::default();
+    let mut phase_start = Instant::now();
+
+    let entries = walk(context, paths, &mut scan)?;
+    scan.profile.lap("Collection", &mut phase_start);
+    let (sources, size) = check(entries, context, paths, &mut scan, &mut phase_start).await;
+    let mut outputs = place(sources, size, context, paths, &mut scan)?;
+    scan.profile.lap("Planning", &mut phase_start);
+
+    let estimated_size = (size as f64 * ESTIMATED_OUTPUT_RATIO) as u64;
+    let free_space = space::available(&paths.output).unwrap_or_else(|e| {
+        say!(
+            context.messages,
+            "   Warning: could not query free space on {}: {}",
+            paths.output.display(),
+            e
+        );
+        None
+    });
+    print_overview(context, paths, &scan, &outputs, free_space, estimated_size);
+    if let Some(free_space) = free_space
+        && free_space < estimated_size
+    {
+        let message = format!(
+            "The output filesystem has {} free, but the outputs may need about {}",
+            human_bytes::human_bytes(free_space as f64),
+            human_bytes::human_bytes(estimated_size as f64)
+        );
+        if args.require_free_space {
+            return Err(anyhow::anyhow!(message));
+        }
+        say!(context.messages, "Warning: {}", message);
+    }
+
+    if args.print_plan.is_some() || args.dry_run {
+        // Counts the pages of TIFFs and looks for outputs, off the runtime.
+        let planning = context.clone();
+        let actions;
+        (outputs, actions) = tokio::task::spawn_blocking(move || {
+            let replaced = outputs.replaced();
+            let actions = outputs
+                .planned
+                .iter()
+                .map(|planned| planned_action(planned, &replaced, &planning))
+                .collect();
+            (outputs, actions)
+        })
+        .await?;
+        scan.plan = plan::Plan { actions };
+        if let Some(format) = args.print_plan {
+            print_plan(&scan.plan, format, &context.messages)?;
+        }
+    }
+    if args.dry_run {
+        return Ok(Some((outputs.planned, scan)));
+    }
+
+    if !args.yes && !confirm(&outputs, context, paths, &mut scan).await? {
+        return Ok(None);
+    }
+
+    for output in &outputs.empty {
+        scan.replace_empty_output(output, &context.messages);
+    }
+    for (output, reason) in outputs.invalid {
+        scan.replace_invalid_output(&output, reason, &context.messages);
+    }
+    Ok(Some((outputs.planned, scan)))
+}
+
+/// The collected files with where their outputs go, and what is in the way
+/// of those outputs.
+struct Outputs {
+    planned: Vec<PlannedFile>,
+    /// The size of the sources.
+    size: u64,
+    collisions: Vec<collision::Collision>,
+    /// Empty outputs of earlier runs, converted again.
+    empty: Vec<PathBuf>,
+    /// Outputs that failed --revalidate-existing, with the reason.
+    invalid: Vec<(PathBuf, String)>,
+}
+
+impl Outputs {
+    /// The outputs removed before the run, which don't count as existing.
+    fn replaced(&self) -> HashSet<&PathBuf> {
+        self.empty
+            .iter()
+            .chain(self.invalid.iter().map(|(output, _)| output))
+            .collect()
+    }
+}
+
+/// The entries of the files to look at, from the walk of the input or from
+/// --files-from, leaving out what the filters prune.
+fn walk(
+    context: &Context,
+    paths: &RunPaths,
+    scan: &mut Scan,
+) -> anyhow::Result<Vec<walkdir::DirEntry>> {
+    let args = &context.args;
+    // Sorted, so duplicates resolve to the same path on every run.
+    let mut walkdir = walkdir::WalkDir::new(&paths.input)
+        .follow_links(args.follow_symlinks)
+        .sort_by_file_name();
+    if !args.tree.recursive {
+        walkdir = walkdir.max_depth(1);
+    }
+
+    let pb = context.progress_bar(ProgressBar::new_spinner());
+    pb.set_style(
+        ProgressStyle::default_spinner()
+            .template("{spinner:.green} [{elapsed_precise}] {msg}")
+            .unwrap(),
+    );
+    pb.set_message("Collecting files...");
+
+    let mut pruned = Pruned::default(); // Track entries left out by name, attribute or .gitignore
+    let mut gitignore = args
+        .respect_gitignore
+        .then(|| gitignore::Stack::new(&context.messages));
+    let mut list_error = None;
+    let entries: Box<dyn Iterator<Item = walkdir::Result<walkdir::DirEntry>>> =
+        match &args.files_from {
+            Some(list) => Box::new(
+                filelist::FileList::open(list, args.null)?
+                    .map_while(|listed| listed.map_err(|e| list_error = Some(e)).ok())
+                    .filter_map(|listed| listed_entry(&listed, &paths.input, context, &pb)),
+            ),
+            None => Box::new(walkdir.into_iter().filter_entry(|e| {
+                !pruned.prunes(e, context, gitignore.as_mut()) && paths.is_walked(e)
+            })),
+        };
+    let entries = entries
+        // Loops and unreadable directories shouldn't go unnoticed.
+        .filter_map(|e| match e {
+            Ok(e) if !is_candidate(&e, context) => None,
+            Ok(e) if e.path_is_symlink() && !pb.suspend(|| scan.symlink(&e, context)) => None,
+            Ok(e) => Some(e),
+            Err(e) => {
+                pb.suspend(|| say!(context.messages, "   Warning: {}", e));
+                scan.walk_warnings += 1;
+                None
+            }
+        })
+        .inspect(|_| pb.inc(1))
+        .collect::<Vec<_>>();
+
+    pb.finish_with_message(format!("Collected {} files.", entries.len()));
+    if let Some(e) = list_error {
+        return Err(e);
+    }
+    scan.pruned = pruned;
+    Ok(entries)
+}
+
+/// The entry of a path from --files-from, as the walk would have found it
+/// under `input`. Paths outside the input, which would have no place in the
+/// output, are left out with a warning.
+fn listed_entry(
+    listed: &Path,
+    input: &Path,
+    context: &Context,
+    pb: &ProgressBar,
+) -> Option<walkdir::Result<walkdir::DirEntry>> {
+    let args = &context.args;
+    let relative = listed
+        .strip_prefix(input)
+        .ok()
+        .map(Path::to_owned)
+        .or_else(|| {
+            // Relative to the current directory, like `find` prints them.
+            let absolute = std::path::absolute(listed).ok()?;
+            let input = std::path::absolute(input).ok()?;
+            absolute.strip_prefix(&input).ok().map(Path::to_owned)
+        })
+        .or_else(|| {
+            // Through a symlink to the input or above it; not the file
+            // itself, which may be a symlink to keep.
+            let parent = std::fs::canonicalize(listed.parent()?).ok()?;
+            let input = std::fs::canonicalize(input).ok()?;
+            Some(parent.strip_prefix(&input).ok()?.join(listed.file_name()?))
+        })
+        .filter(|relative| {
+            relative
+                .components()
+                .all(|component| matches!(component, std::path::Component::Normal(_)))
+        });
+    let Some(relative) = relative else {
+        pb.suspend(|| {
+            say!(
+                context.messages,
+                "   Warning: {} is not inside the input, leaving it out",
+                listed.display()
+            )
+        });
+        return None;
+    };
+    walkdir::WalkDir::new(input.join(relative))
+        .follow_links(args.follow_symlinks)
+        .max_depth(0)
+        .into_iter()
+        .next()
+}
+
+/// Reads what the walk found and leaves out the files that shouldn't be
+/// converted: duplicates, unreadable and unstable files, and those failing
+/// --prevalidate or the dimension limits. Returns the rest with their total
+/// size.
+async fn check(
+    entries: Vec<walkdir::DirEntry>,
+    context: &Context,
+    paths: &RunPaths,
+    scan: &mut Scan,
+    phase_start: &mut Instant,
+) -> (Vec<SourceFile>, u64) {
+    let args = &context.args;
+    let checked = scan_files(entries, context).await;
+
+    // The same physical file can show up under several paths through
+    // symlinks or hard links, convert it only once.
+    let mut seen = Seen::new(paths);
+    let checked = checked
+        .into_iter()
+        .filter(|(source, _)| !scan.is_duplicate(source, &mut seen, context))
+        .collect::<Vec<_>>();
+
+    // Leave out files that can't be read instead of failing the whole run,
+    // and total up the size of the rest for the overview.
+    let mut size = 0;
+    let files = checked
+        .into_iter()
+        .filter_map(|(source, readable)| {
+            size += scan.readable(&source, readable, context)?;
+            Some(source)
+        })
+        .collect::<Vec<_>>();
+    scan.profile.lap("Size calculation", phase_start);
+
+    // Files a copy or import is still writing would convert to truncated
+    // images, leave them for a later run.
+    let files = match args.stable_seconds {
+        Some(seconds) => {
+            let (stable, changing) = split_unstable(files, seconds).await;
+            for source in changing {
+                say!(
+                    context.messages,
+                    "   Unstable, skipped: {}",
+                    source.path.display()
+                );
+                scan.left_out(&source);
+                scan.unstable.push(source.path);
+            }
+            scan.profile.lap("Stability check", phase_start);
+            stable
+        }
+        None => files,
+    };
+
+    let files = if args.prevalidate {
+        let (valid, failed) = prevalidate(files, context).await;
+        for (source, reason) in failed {
+            say!(
+                context.messages,
+                "   Failed validation: {}: {}",
+                source.path.display(),
+                reason
+            );
+            scan.left_out(&source);
+            scan.failed_validation.push((source.path, reason));
+        }
+        scan.profile.lap("Validation", phase_start);
+        valid
+    } else {
+        files
+    };
+
+    if args.probe {
+        probe_sources(&files, context, scan).await;
+        scan.profile.lap("Probe", phase_start);
+    }
+
+    let files = if args.dimension_limits().is_set() {
+        let kept = filter_dimensions(files, context, scan).await;
+        for (file, reason) in &scan.outside_dimensions {
+            detail!(
+                context.messages,
+                "   Outside the dimensions: {}: {}",
+                file.display(),
+                reason
+            );
+        }
+        scan.profile.lap("Dimensions", phase_start);
+        kept
+    } else {
+        files
+    };
+    (files, size)
+}
+
+/// Decides up front where every output goes, so outputs that would land on
+/// the same file are found before anything is converted, and which outputs
+/// of earlier runs are converted again.
+fn place(
+    files: Vec<SourceFile>,
+    size: u64,
+    context: &Context,
+    paths: &RunPaths,
+    scan: &mut Scan,
+) -> anyhow::Result<Outputs> {
+    let args = &context.args;
+    let mut planned = files
+        .into_iter()
+        .map(|source| {
+            let kind = FileKind::of(&source, context);
+            let relative = source.path.strip_prefix(&paths.input)?;
+            let output = kind.output_path(relative, &paths.output, args);
+            Ok((source, kind, output))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // Hard links to planned files come last, so they give way to those.
+    let mut links = scan.take_links(context, paths);
+    let mut existing_outputs = Vec::new();
+    let output_dirs = planned
+        .iter()
+        .filter_map(|(_, _, output)| output.as_deref())
+        .chain(links.iter().map(|(_, output)| output.as_path()))
+        .filter_map(Path::parent)
+        .collect::<BTreeSet<_>>();
+    for dir in output_dirs {
+        if let Ok(entries) = std::fs::read_dir(dir) {
+            existing_outputs.extend(entries.filter_map(|e| e.ok()).map(|e| e.path()));
+        }
+    }
+    let collisions = collision::resolve(
+        planned
+            .iter_mut()
+            .filter_map(|(source, _, output)| Some((source.path.as_path(), output.as_mut()?)))
+            .chain(
+                links
+                    .iter_mut()
+                    .map(|(link, output)| (link.as_path(), output)),
+            ),
+        &existing_outputs,
+        args.on_collision == CollisionMode::Suffix,
+    );
+    scan.link_outputs.extend(links);
+    for collision in &collisions {
+        print_collision(collision, &context.messages);
+    }
+    let empty = planned
+        .iter()
+        .filter_map(|(source, _, output)| Some((source, output.as_ref()?)))
+        .filter(|(source, output)| is_empty_output(source, output))
+        .map(|(_, output)| output.clone())
+        .collect::<Vec<_>>();
+    let invalid = planned
+        .iter()
+        .filter_map(|(source, _, output)| {
+            let output = output.as_ref()?;
+            Some((output.clone(), scan.revalidate(source, output, args)?))
+        })
+        .collect::<Vec<_>>();
+
+    let long_outputs = planned
+        .iter()
+        .filter_map(|(_, _, output)| output.as_ref())
+        .filter(|output| is_long_output(output))
+        .collect::<Vec<_>>();
+    if !long_outputs.is_empty() {
+        say!(
+            context.messages,
+            "Warning: {} output paths are {} characters or longer:",
+            long_outputs.len(),
+            MAX_PATH
+        );
+        for output in &long_outputs {
+            say!(context.messages, "   {}", output.display());
+        }
+    }
+
+    if !collisions.is_empty() && args.on_collision == CollisionMode::Error {
+        return Err(anyhow::anyhow!(
+            "{} outputs collide on case- or normalization-insensitive filesystems, rename the sources or rerun with --on-collision suffix",
+            collisions.len()
+        ));
+    }
+    Ok(Outputs {
+        planned,
+        size,
+        collisions,
+        empty,
+        invalid,
+    })
+}
+
+/// The labels of the overview, for the width of its first column.
+const OVERVIEW_LABELS: &[&str] = &[
+    "Input",
+    "Output",
+    "Recursive",
+    "Jobs",
+    "Copy jobs",
+    "Threads",
+    "Copy All",
+    "Extensions",
+    "Multi-page",
+    "Rate limit",
+    "Policy",
+    "Excluded",
+    "Files to process",
+    "Collisions",
+    "Empty sources",
+    "Empty outputs",
+    "Existing outputs",
+    "Unstable",
+    "Free space",
+    "Duplicates",
+    "Walk warnings",
+    "Pruned dirs",
+    "Hidden",
+    "Gitignored",
+    "Symlinks",
+    "Dangling symlinks",
+    "Inaccessible",
+    "Failed validation",
+    "Dimensions",
+    "Probed",
+];
+
+/// Prints what the run is going to do, before it is confirmed.
+fn print_overview(
+    context: &Context,
+    paths: &RunPaths,
+    scan: &Scan,
+    outputs: &Outputs,
+    free_space: Option<u64>,
+    estimated_size: u64,
+) {
+    let (args, messages) = (&context.args, &context.messages);
+    let width = OVERVIEW_LABELS.iter().map(|s| s.len()).max().unwrap_or(0);
+    // Print aligned key-value pairs
+    let row = |label: &str, value: String| say!(messages, "{:<width$} : {}", label, value);
+
+    say!(messages, "{}", "-".repeat(60)); // Simple separator
+    row("Input", paths.input_display(args));
+    row("Output", args.tree.output.clone());
+    row(
+        "Recursive",
+        (if args.tree.recursive { "Yes" } else { "No" }).to_string(),
+    );
+    match args.adaptive_jobs {
+        Some(range) => row(
+            "Jobs",
+            format!("{} to {}, following the load", range.min, range.max),
+        ),
+        None => row("Jobs", args.parallel.jobs.to_string()),
+    }
+    row("Copy jobs", args.copy_jobs.to_string());
+    row(
+        "Threads",
+        thread_division(args.total_threads.unwrap_or(1), conversion_jobs(args)),
+    );
+    row(
+        "Copy All",
+        (if args.copy_all { "Yes" } else { "No" }).to_string(),
+    );
+    row(
+        "Extensions",
+        format!(
+            "{} image extensions{}",
+            context.accepted.len(),
+            extension_changes(context)
+        ),
+    );
+    row(
+        "Multi-page",
+        match args.multipage {
+            MultipageMode::First => "First page only",
+            MultipageMode::All => "All pages",
+            MultipageMode::Skip => "Skip",
+        }
+        .to_string(),
+    );
+    if let Some(rate) = args.rate_limit {
+        row(
+            "Rate limit",
+            format!(
+                "{}/s for copies and what conversions read",
+                human_bytes::human_bytes(rate as f64)
+            ),
+        );
+    }
+    if let Some(policy) = &args.policy {
+        row(
+            "Policy",
+            format!(
+                "{} entries (--distance and --effort on the command line win)",
+                policy.entries().count()
+            ),
+        );
+        for (key, entry) in policy.entries() {
+            say!(messages, "{:<width$}   [{}] {}", "", key, entry);
+        }
+    }
+    if let Some(excluded) = &paths.excluded_output {
+        row(
+            "Excluded",
+            format!(
+                "{} (the output directory is inside the input)",
+                excluded.display()
+            ),
+        );
+    }
+    row(
+        "Files to process",
+        format!(
+            "{} (Total size: {})",
+            outputs.planned.len(),
+            human_bytes::human_bytes(outputs.size as f64)
+        ),
+    );
+
+    if !outputs.collisions.is_empty() {
+        row(
+            "Collisions",
+            format!("{} renamed with a ~N suffix", outputs.collisions.len()),
+        );
+    }
+    if !scan.empty_sources.is_empty() {
+        row(
+            "Empty sources",
+            format!("{} (left out as invalid input)", scan.empty_sources.len()),
+        );
+    }
+    if args.stable_seconds.is_some() {
+        row(
+            "Unstable",
+            format!("{} (still being written, skipped)", scan.unstable.len()),
+        );
+    }
+    if !outputs.empty.is_empty() {
+        row(
+            "Empty outputs",
+            format!("{} (converted again)", outputs.empty.len()),
+        );
+    }
+    if args.revalidate_existing.is_some() {
+        row(
+            "Existing outputs",
+            format!(
+                "{} ok, {} invalid (converted again)",
+                scan.revalidated,
+                outputs.invalid.len()
+            ),
+        );
+    }
+    if let Some(free_space) = free_space {
+        row(
+            "Free space",
+            format!(
+                "{} (outputs estimated at {})",
+                human_bytes::human_bytes(free_space as f64),
+                human_bytes::human_bytes(estimated_size as f64)
+            ),
+        );
+    }
+    if !scan.duplicates.is_empty() {
+        row("Duplicates", scan.duplicates.len().to_string());
+    }
+    if scan.pruned.dirs > 0 {
+        let mut names: Vec<_> = context
+            .excluded_dirs
+            .iter()
+            .map(|name| name.to_string_lossy())
+            .collect();
+        names.sort_unstable();
+        row(
+            "Pruned dirs",
+            format!("{} named {}", scan.pruned.dirs, names.join(", ")),
+        );
+    }
+    if args.filters.skip_hidden {
+        row(
+            "Hidden",
+            format!(
+                "{} files and {} directories skipped",
+                scan.pruned.hidden_files, scan.pruned.hidden_dirs
+            ),
+        );
+    }
+    if args.respect_gitignore {
+        row(
+            "Gitignored",
+            format!(
+                "{} files and {} directories ignored",
+                scan.pruned.ignored_files, scan.pruned.ignored_dirs
+            ),
+        );
+    }
+    if scan.walk_warnings > 0 {
+        row("Walk warnings", scan.walk_warnings.to_string());
+    }
+    if scan.symlinks > 0 {
+        row(
+            "Symlinks",
+            if !scan.skipped_symlinks.is_empty() {
+                format!(
+                    "{} ({} skipped)",
+                    scan.symlinks,
+                    scan.skipped_symlinks.len()
+                )
+            } else {
+                scan.symlinks.to_string()
+            },
+        );
+    }
+    if !scan.dangling.is_empty() {
+        row("Dangling symlinks", scan.dangling.len().to_string());
+    }
+    if !scan.inaccessible.is_empty() {
+        row("Inaccessible", scan.inaccessible.len().to_string());
+    }
+
+    if args.prevalidate {
+        row(
+            "Failed validation",
+            scan.failed_validation.len().to_string(),
+        );
+    }
+    if args.probe {
+        let probed = || {
+            outputs
+                .planned
+                .iter()
+                .filter_map(|(source, _, _)| scan.probed.get(&source.path))
+        };
+        row(
+            "Probed",
+            format!(
+                "{} stills, {} animations, {} CMYK, {} high bit depth ({} failed)",
+                probed().filter(|info| info.frames <= 1).count(),
+                probed().filter(|info| info.frames > 1).count(),
+                probed().filter(|info| info.cmyk).count(),
+                probed().filter(|info| info.is_high_bit_depth()).count(),
+                scan.probe_failed
+            ),
+        );
+    }
+    if args.dimension_limits().is_set() {
+        row(
+            "Dimensions",
+            format!(
+                "{} left out, {} unknown and kept",
+                scan.outside_dimensions.len(),
+                scan.unknown_dimensions
+            ),
+        );
+    }
+
+    say!(messages, "{}", "-".repeat(60)); // Simple separator
+    say!(messages); // Add a blank line for spacing
+}
+
+/// How the image extensions of the run differ from the built-in list, for
+/// the overview.
+fn extension_changes(context: &Context) -> String {
+    let mut changes = Vec::new();
+    if context.args.extensions.is_some() {
+        // Listing what the built-in list lost would be most of it.
+        let mut accepted: Vec<_> = context.accepted.iter().map(String::as_str).collect();
+        accepted.sort_unstable();
+        changes.push(format!(
+            "instead of the built-in list: {}",
+            accepted.join(", ")
+        ));
+    } else {
+        let mut added: Vec<_> = context
+            .accepted
+            .iter()
+            .filter(|extension| !ACCEPTED_EXTENSIONS.contains(&extension.as_str()))
+            .map(String::as_str)
+            .collect();
+        added.sort_unstable();
+        let removed: Vec<_> = ACCEPTED_EXTENSIONS
+            .iter()
+            .filter(|extension| !context.accepted.contains(**extension))
+            .copied()
+            .collect();
+        if !added.is_empty() {
+            changes.push(format!("added {}", added.join(", ")));
+        }
+        if !removed.is_empty() {
+            changes.push(format!("removed {}", removed.join(", ")));
+        }
+    }
+    if changes.is_empty() {
+        String::new()
+    } else {
+        format!(" ({})", changes.join("; "))
+    }
+}
+
+/// Asks the user whether to go on, after the --pilot samples if there are
+/// any, and goes on when there is no one to ask. Backing out offers to
+/// remove what the pilot wrote.
+async fn confirm(
+    outputs: &Outputs,
+    context: &Context,
+    paths: &RunPaths,
+    scan: &mut Scan,
+) -> anyhow::Result<bool> {
+    let Some(ask) = &context.prompt else {
+        return Ok(true);
+    };
+    let mut question = "Are you sure to proceed?".to_string();
+    if context.args.pilot > 0 {
+        let replaced = outputs.replaced();
+        if let Some(estimate) =
+            run_pilot(&outputs.planned, &replaced, &paths.output, context, scan).await
+        {
+            question = format!(
+                "Estimated output: ~{} (from {}), estimated time: ~{} at current settings. Proceed?",
+                human_bytes::human_bytes(estimate.output_size as f64),
+                human_bytes::human_bytes(outputs.size as f64),
+                events::format_duration(estimate.time)
+            );
+        }
+        if !scan.pilot_outputs.is_empty() {
+            question = format!(
+                "{} The {} sample outputs are already written.",
+                question,
+                scan.pilot_outputs.len()
+            );
+        }
+    }
+    if ask(&question, false)? {
+        return Ok(true);
+    }
+
+    if !scan.pilot_outputs.is_empty()
+        && ask(
+            &format!(
+                "Remove the {} sample outputs the pilot wrote?",
+                scan.pilot_outputs.len()
+            ),
+            true,
+        )?
+    {
+        let mut removed = 0;
+        for output in &scan.pilot_outputs {
+            match std::fs::remove_file(output) {
+                Ok(()) => removed += 1,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    say!(
+                        context.messages,
+                        "   Warning: could not remove {}: {}",
+                        output.display(),
+                        e
+                    )
+                }
+            }
+        }
+        say!(context.messages, "Removed {} sample outputs.", removed);
+    }
+    say!(context.messages, "Aborting...");
+    Ok(false)
+}
+
+/// Converts a sample of the planned images for --pilot, spread over their
+/// extensions and sizes, to their final outputs, and projects the size and
+/// time of the whole run from them. The results go to `scan`, so the run
+/// doesn't convert the samples again.
+async fn run_pilot(
+    planned: &[PlannedFile],
+    replaced: &HashSet<&PathBuf>,
+    output_dir: &Path,
+    context: &Context,
+    scan: &mut Scan,
+) -> Option<pilot::Estimate> {
+    let args = &context.args;
+    // Outputs that are replaced only once the run is confirmed would be
+    // skipped as existing.
+    let candidates = planned
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, kind, output))| {
+            matches!(kind, FileKind::Image(_))
+                && output.as_ref().is_some_and(|o| !replaced.contains(o))
+        })
+        .map(|(index, (source, _, _))| (index, (extension_of(&source.path), source.size())))
+        .collect::<Vec<_>>();
+    let sizes = candidates
+        .iter()
+        .map(|(_, candidate)| candidate.clone())
+        .collect::<Vec<_>>();
+    let picked = pilot::pick(&sizes, args.pilot)
+        .into_iter()
+        .map(|pick| candidates[pick].0)
+        .collect::<HashSet<_>>();
+    if picked.is_empty() {
+        return None;
+    }
+
+    say!(
+        context.messages,
+        "Pilot: converting {} sample files...",
+        picked.len()
+    );
+    let started = std::time::Instant::now();
+    let mut files = picked.iter().map(|&index| &planned[index]);
+    let mut set = JoinSet::new();
+    let mut samples = Vec::new();
+    let mut failed = 0; // Track samples that didn't convert
+    loop {
+        while set.len() < args.parallel.jobs.max(1)
+            && let Some((source, kind, output)) = files.next()
+        {
+            let (file, kind, output, output_dir, context) = (
+                source.path.clone(),
+                *kind,
+                output.clone(),
+                output_dir.to_owned(),
+                context.clone(),
+            );
+            set.spawn(async move {
+                let started = std::time::Instant::now();
+                let converted =
+                    convert_planned(&file, kind, output.as_deref(), &output_dir, &context).await;
+                (file, output, converted, started.elapsed())
+            });
+        }
+        let Some(task_result) = set.join_next().await else {
+            break;
+        };
+        let Ok((file, output, converted, duration)) = task_result else {
+            failed += 1;
+            continue;
+        };
+        match &converted.0 {
+            Ok(ProcessResult::Converted {
+                original_size,
+                converted_size,
+                parts,
+                ..
+            }) => {
+                samples.push(pilot::Sample {
+                    extension: extension_of(&file),
+                    original_size: *original_size,
+                    converted_size: *converted_size,
+                    duration,
+                });
+                match &converted.1 {
+                    Some(addressed) if !addressed.deduplicated => {
+                        scan.pilot_outputs.push(addressed.output.clone())
+                    }
+                    _ => {}
+                }
+                if parts.is_empty() {
+                    scan.pilot_outputs.extend(output);
+                } else {
+                    scan.pilot_outputs.extend(parts.iter().cloned());
+                }
+            }
+            _ => failed += 1,
+        }
+        scan.piloted.insert(file, converted);
+    }
+    let original_size = samples
+        .iter()
+        .map(|sample| sample.original_size)
+        .sum::<u64>();
+    let converted_size = samples
+        .iter()
+        .map(|sample| sample.converted_size)
+        .sum::<u64>();
+    say!(
+        context.messages,
+        "Pilot: {} converted in {}, {} to {}{}",
+        samples.len(),
+        events::format_duration(started.elapsed()),
+        human_bytes::human_bytes(original_size as f64),
+        human_bytes::human_bytes(converted_size as f64),
+        if failed > 0 {
+            format!(" ({} not converted)", failed)
+        } else {
+            String::new()
+        }
+    );
+
+    // Copies come out the size they are.
+    let copied = planned
+        .iter()
+        .filter(|(_, kind, output)| output.is_some() && kind.is_copy(args))
+        .map(|(source, _, _)| source.size())
+        .sum::<u64>();
+    let remaining = planned
+        .iter()
+        .enumerate()
+        .filter(|(index, (_, kind, output))| {
+            output.is_some() && !kind.is_copy(args) && !picked.contains(index)
+        })
+        .map(|(_, (source, _, _))| (extension_of(&source.path), source.size()))
+        .collect::<Vec<_>>();
+    let mut estimate = pilot::estimate(&samples, &remaining, args.parallel.jobs)?;
+    estimate.output_size += copied;
+    Some(estimate)
+}
@@ -0,0 +1,237 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use unicode_normalization::UnicodeNormalization;
+
+/// The Unicode normalization output names are written in. macOS writes names
+/// decomposed (NFD), most other systems composed (NFC), so the same name
+/// typed on each can differ in its bytes.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Normalization {
+    /// Composed, as Linux and Windows usually write names
+    Nfc,
+    /// Decomposed, as macOS writes names
+    Nfd,
+    /// Keep names as the source has them
+    None,
+}
+
+/// Writes `path` in the normalization `form`; bytes that aren't valid UTF-8
+/// are kept unchanged.
+pub fn normalize(path: &Path, form: Normalization) -> PathBuf {
+    if form == Normalization::None {
+        return path.to_owned();
+    }
+    let mut normalized = Vec::new();
+    for chunk in path.as_os_str().as_encoded_bytes().utf8_chunks() {
+        let valid = chunk.valid();
+        let valid = match form {
+            Normalization::Nfd => valid.nfd().collect::<String>(),
+            _ => valid.nfc().collect::<String>(),
+        };
+        normalized.extend_from_slice(valid.as_bytes());
+        normalized.extend_from_slice(chunk.invalid());
+    }
+    // SAFETY: valid UTF-8 and the unchanged bytes of the original path.
+    PathBuf::from(unsafe { OsString::from_encoded_bytes_unchecked(normalized) })
+}
+
+/// A planned output that another source, or a different file already in the
+/// output tree, claims under the same case-insensitive and
+/// normalization-insensitive name.
+pub struct Collision {
+    pub source: PathBuf,
+    pub output: PathBuf,
+    /// The source or existing file that holds the name.
+    pub holder: PathBuf,
+    /// Where the output goes instead, when collisions are resolved by suffix.
+    pub renamed: Option<PathBuf>,
+}
+
+/// Case-folds `path` the way case-insensitive filesystems compare names.
+/// Upper- then lowercasing gives the full Unicode folding, so `ß` and `SS`
+/// fold alike, and composing the result makes `é` and `e` with a combining
+/// accent alike too; bytes that aren't valid UTF-8 are compared unchanged.
+pub fn fold(path: &Path) -> Vec<u8> {
+    let mut folded = Vec::new();
+    for chunk in path.as_os_str().as_encoded_bytes().utf8_chunks() {
+        let valid = chunk.valid().to_uppercase().to_lowercase();
+        folded.extend_from_slice(valid.nfc().collect::<String>().as_bytes());
+        folded.extend_from_slice(chunk.invalid());
+    }
+    folded
+}
+
+/// Goes through the planned `(source, output)` pairs in order and finds the
+/// outputs that fold to the same path as an earlier one, or as a different
+/// file in `existing`. The first claim keeps the name; with `suffix` every
+/// later one is moved to the first free `name~N.ext` in place.
+pub fn resolve<'a>(
+    planned: impl IntoIterator<Item = (&'a Path, &'a mut PathBuf)>,
+    existing: &[PathBuf],
+    suffix: bool,
+) -> Vec<Collision> {
+    let planned = planned.into_iter().collect::<Vec<_>>();
+    // An existing file under exactly a planned name is the output of an
+    // earlier run, not a collision.
+    let outputs = planned
+        .iter()
+        .map(|(_, output)| output.to_path_buf())
+        .collect::<HashSet<_>>();
+    let existing = existing
+        .iter()
+        .filter(|path| !outputs.contains(*path))
+        .map(|path| (fold(path), path))
+        .collect::<HashMap<_, _>>();
+    let mut claimed = HashMap::new();
+    let holder = |claimed: &HashMap<Vec<u8>, PathBuf>, output: &Path| {
+        let key = fold(output);
+        claimed.get(&key).cloned().or_else(|| {
+            existing
+                .get(&key)
+                .filter(|path| path.as_path() != output)
+                .map(|path| path.to_path_buf())
+        })
+    };
+
+    let mut collisions = Vec::new();
+    for (source, output) in planned {
+        let Some(holder_path) = holder(&claimed, output) else {
+            claimed.insert(fold(output), source.to_owned());
+            continue;
+        };
+        let mut collision = Collision {
+            source: source.to_owned(),
+            output: output.clone(),
+            holder: holder_path,
+            renamed: None,
+        };
+        if suffix {
+            let renamed = (2..)
+                .map(|n| with_suffix(output, n))
+                .find(|candidate| holder(&claimed, candidate).is_none())
+                .expect("some suffix is free");
+            claimed.insert(fold(&renamed), source.to_owned());
+            *output = renamed.clone();
+            collision.renamed = Some(renamed);
+        }
+        collisions.push(collision);
+    }
+    collisions
+}
+
+fn with_suffix(path: &Path, n: usize) -> PathBuf {
+    let mut name = OsString::from(path.file_stem().unwrap_or_default());
+    name.push(format!("~{}", n));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Resolves the outputs of `planned` source names, each `name.jxl` in
+    /// `out`, and returns where they end up and the collisions found.
+    fn resolve_names(
+        planned: &[&str],
+        existing: &[&str],
+        suffix: bool,
+    ) -> (Vec<PathBuf>, Vec<Collision>) {
+        let sources: Vec<PathBuf> = planned
+            .iter()
+            .map(|name| Path::new("in").join(name))
+            .collect();
+        let mut outputs: Vec<PathBuf> = sources
+            .iter()
+            .map(|source| {
+                Path::new("out")
+                    .join(source.file_name().unwrap())
+                    .with_extension("jxl")
+            })
+            .collect();
+        let existing: Vec<PathBuf> = existing
+            .iter()
+            .map(|name| Path::new("out").join(name))
+            .collect();
+        let collisions = resolve(
+            sources.iter().map(PathBuf::as_path).zip(outputs.iter_mut()),
+            &existing,
+            suffix,
+        );
+        (outputs, collisions)
+    }
+
+    #[test]
+    fn names_fold_like_a_case_insensitive_filesystem() {
+        assert_eq!(fold(Path::new("Cover.png")), fold(Path::new("cover.PNG")));
+        assert_eq!(
+            fold(Path::new("Straße.jxl")),
+            fold(Path::new("STRASSE.jxl"))
+        );
+        // Composed and decomposed é.
+        assert_eq!(fold(Path::new("caf\u{e9}")), fold(Path::new("cafe\u{301}")));
+        assert_ne!(fold(Path::new("cover.png")), fold(Path::new("cover.jpg")));
+    }
+
+    #[test]
+    fn the_first_claim_keeps_the_name() {
+        let (outputs, collisions) =
+            resolve_names(&["Cover.png", "cover.png", "back.png"], &[], false);
+
+        assert_eq!(outputs[0], Path::new("out/Cover.jxl"));
+        assert_eq!(collisions.len(), 1);
+        assert_eq!(collisions[0].source, Path::new("in/cover.png"));
+        assert_eq!(collisions[0].holder, Path::new("in/Cover.png"));
+        assert_eq!(collisions[0].renamed, None);
+        // Without suffixing the output is left for the caller to skip.
+        assert_eq!(outputs[1], Path::new("out/cover.jxl"));
+    }
+
+    #[test]
+    fn later_claims_get_the_first_free_suffix() {
+        let (outputs, collisions) = resolve_names(
+            &["Straße.png", "STRASSE.png", "strasse.png"],
+            // What an earlier run renamed `strasse.png` to.
+            &["strasse~2.jxl"],
+            true,
+        );
+
+        // `STRASSE~2.jxl` would only fold like the existing file, `strasse.png`
+        // gets its earlier output back.
+        assert_eq!(
+            outputs,
+            [
+                Path::new("out/Straße.jxl"),
+                Path::new("out/STRASSE~3.jxl"),
+                Path::new("out/strasse~2.jxl"),
+            ]
+        );
+        assert_eq!(collisions.len(), 2);
+        assert_eq!(collisions[1].holder, Path::new("in/Straße.png"));
+        assert_eq!(
+            collisions[1].renamed.as_deref(),
+            Some(Path::new("out/strasse~2.jxl"))
+        );
+    }
+
+    #[test]
+    fn existing_files_hold_their_name_unless_they_are_the_output() {
+        // The output of an earlier run under the same name.
+        let (_, collisions) = resolve_names(&["cover.png"], &["cover.jxl"], false);
+        assert!(collisions.is_empty());
+
+        // A different file that only folds alike.
+        let (outputs, collisions) = resolve_names(&["cover.png"], &["COVER.jxl"], true);
+        assert_eq!(collisions.len(), 1);
+        assert_eq!(collisions[0].holder, Path::new("out/COVER.jxl"));
+        assert_eq!(outputs[0], Path::new("out/cover~2.jxl"));
+    }
+}
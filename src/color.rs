@@ -0,0 +1,79 @@
+use clap::ValueEnum;
+
+/// Colour primaries a source can be declared to use.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorSpace {
+    Srgb,
+    DisplayP3,
+    Rec2020,
+    /// sRGB primaries with linear light values
+    Linear,
+}
+
+/// Transfer functions a source can be declared to use.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transfer {
+    Srgb,
+    Linear,
+    Pq,
+    Hlg,
+}
+
+impl ColorSpace {
+    /// The ffmpeg `-color_primaries` value.
+    pub fn primaries(self) -> &'static str {
+        match self {
+            ColorSpace::Srgb | ColorSpace::Linear => "bt709",
+            ColorSpace::DisplayP3 => "smpte432",
+            ColorSpace::Rec2020 => "bt2020",
+        }
+    }
+
+    /// The ffmpeg `-colorspace` (matrix) value that goes with the primaries.
+    pub fn matrix(self) -> &'static str {
+        match self {
+            ColorSpace::Srgb | ColorSpace::Linear | ColorSpace::DisplayP3 => "bt709",
+            ColorSpace::Rec2020 => "bt2020nc",
+        }
+    }
+
+    /// The transfer function implied when no `--assume-transfer` is given.
+    pub fn default_transfer(self) -> Transfer {
+        match self {
+            ColorSpace::Linear => Transfer::Linear,
+            _ => Transfer::Srgb,
+        }
+    }
+}
+
+impl Transfer {
+    /// The ffmpeg `-color_trc` value.
+    pub fn trc(self) -> &'static str {
+        match self {
+            Transfer::Srgb => "iec61966-2-1",
+            Transfer::Linear => "linear",
+            Transfer::Pq => "smpte2084",
+            Transfer::Hlg => "arib-std-b67",
+        }
+    }
+}
+
+/// Colour metadata written to the output without touching the pixels. Fields
+/// left `None` keep whatever ffmpeg takes over from the source.
+#[derive(Clone, Copy, Default)]
+pub struct ColorTags {
+    pub primaries: Option<&'static str>,
+    pub matrix: Option<&'static str>,
+    pub trc: Option<&'static str>,
+}
+
+impl ColorTags {
+    pub fn is_empty(self) -> bool {
+        self.primaries.is_none() && self.matrix.is_none() && self.trc.is_none()
+    }
+}
+
+/// Whether ffprobe reported a colour property as missing.
+pub fn is_untagged(value: &str) -> bool {
+    matches!(value, "" | "unknown" | "unspecified" | "reserved")
+}
@@ -0,0 +1,36 @@
+use crate::events::Messages;
+
+pub use crate::{
+    decode::DecodeArgs, diff::DiffArgs, history::StatsArgs, prune::PruneArgs, restore::RestoreArgs,
+    verify::VerifyArgs,
+};
+
+/// Rebuilds the JPEGs that were transcoded losslessly.
+pub async fn restore_jpeg(args: RestoreArgs) -> anyhow::Result<()> {
+    crate::restore::run(args, &Messages::print()).await
+}
+
+/// Compares the reports of two runs.
+pub fn report_diff(args: DiffArgs) -> anyhow::Result<()> {
+    crate::diff::run(args, &Messages::print())
+}
+
+/// Prints the totals of the runs so far.
+pub fn stats(args: StatsArgs) -> anyhow::Result<()> {
+    crate::history::run(args, &Messages::print())
+}
+
+/// Checks that the outputs of a run decode and match their sources.
+pub async fn verify(args: VerifyArgs) -> anyhow::Result<()> {
+    crate::verify::run(args, &Messages::print()).await
+}
+
+/// Removes outputs whose source is gone, and abandoned partial files.
+pub fn prune(args: PruneArgs) -> anyhow::Result<()> {
+    crate::prune::run(args, &Messages::print())
+}
+
+/// Decodes JPEG XL files back to PNG, or to the JPEG they were made from.
+pub async fn decode(args: DecodeArgs) -> anyhow::Result<()> {
+    crate::decode::run(args, &Messages::print()).await
+}
@@ -0,0 +1,125 @@
+use std::path::Path;
+
+const CODESTREAM_SIGNATURE: &[u8] = b"\xFF\x0A";
+const SIGNATURE_BOX: &[u8] = b"\0\0\0\x0CJXL \r\n\x87\n";
+const FTYP_BOX: &[u8] = b"\0\0\0\x14ftypjxl \0\0\0\0jxl ";
+
+/// Whether a JPEG XL file is wrapped in the ISOBMFF container.
+pub fn is_container(data: &[u8]) -> bool {
+    data.starts_with(SIGNATURE_BOX)
+}
+
+/// Describes how a JPEG XL file on disk is stored, `container` or `codestream`.
+pub fn layout(path: &Path) -> std::io::Result<&'static str> {
+    let mut header = [0u8; 12];
+    let len = std::io::Read::read(&mut std::fs::File::open(path)?, &mut header)?;
+    Ok(if is_container(&header[..len]) {
+        "container"
+    } else {
+        "codestream"
+    })
+}
+
+/// Wraps a bare codestream file in a minimal container, in place.
+pub fn wrap(path: &Path) -> anyhow::Result<()> {
+    let codestream = std::fs::read(path)?;
+    if is_container(&codestream) {
+        return Ok(());
+    }
+    if !codestream.starts_with(CODESTREAM_SIGNATURE) {
+        return Err(anyhow::anyhow!("Not a JPEG XL codestream"));
+    }
+
+    let mut data = Vec::with_capacity(codestream.len() + 48);
+    data.extend_from_slice(SIGNATURE_BOX);
+    data.extend_from_slice(FTYP_BOX);
+    match u32::try_from(codestream.len() + 8) {
+        Ok(size) => data.extend_from_slice(&size.to_be_bytes()),
+        // A size of zero means the box runs to the end of the file.
+        Err(_) => data.extend_from_slice(&0u32.to_be_bytes()),
+    }
+    data.extend_from_slice(b"jxlc");
+    data.extend_from_slice(&codestream);
+    std::fs::write(path, data)?;
+    Ok(())
+}
+
+/// Replaces a container file by its bare codestream, in place. Returns the
+/// types of the boxes that were dropped with the container, e.g. `Exif`.
+pub fn unwrap(path: &Path) -> anyhow::Result<Vec<String>> {
+    let data = std::fs::read(path)?;
+    if !is_container(&data) {
+        return Ok(Vec::new());
+    }
+
+    let mut codestream = Vec::with_capacity(data.len());
+    let mut dropped = Vec::new();
+    for (box_type, content) in boxes(&data)? {
+        match box_type {
+            b"jxlc" => codestream.extend_from_slice(content),
+            // Partial codestream boxes start with a 4-byte sequence index.
+            b"jxlp" => codestream.extend_from_slice(content.get(4..).unwrap_or_default()),
+            b"JXL " | b"ftyp" | b"jxll" => {}
+            other => dropped.push(String::from_utf8_lossy(other).trim().to_string()),
+        }
+    }
+
+    if !codestream.starts_with(CODESTREAM_SIGNATURE) {
+        return Err(anyhow::anyhow!("No codestream found in container"));
+    }
+    std::fs::write(path, codestream)?;
+    Ok(dropped)
+}
+
+/// Whether a JPEG XL file carries the `jbrd` box needed to restore the JPEG it
+/// was transcoded from.
+pub fn has_jpeg_reconstruction(path: &Path) -> anyhow::Result<bool> {
+    let data = std::fs::read(path)?;
+    if !is_container(&data) {
+        return Ok(false);
+    }
+    Ok(boxes(&data)?
+        .iter()
+        .any(|(box_type, _)| *box_type == b"jbrd"))
+}
+
+/// Types of the boxes in a JPEG XL file, empty for a bare codestream.
+pub fn box_types(path: &Path) -> anyhow::Result<Vec<String>> {
+    let data = std::fs::read(path)?;
+    if !is_container(&data) {
+        return Ok(Vec::new());
+    }
+    Ok(boxes(&data)?
+        .iter()
+        .map(|(box_type, _)| String::from_utf8_lossy(box_type).into_owned())
+        .collect())
+}
+
+/// Splits a container file into the types and contents of its boxes.
+fn boxes(data: &[u8]) -> anyhow::Result<Vec<(&[u8], &[u8])>> {
+    let mut boxes = Vec::new();
+    let mut position = 0;
+    while position < data.len() {
+        let header = data
+            .get(position..position + 8)
+            .ok_or_else(|| anyhow::anyhow!("Truncated box header"))?;
+        let box_type = &header[4..8];
+        let (header_len, box_len) =
+            match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+                0 => (8, data.len() - position),
+                1 => {
+                    let large = data
+                        .get(position + 8..position + 16)
+                        .ok_or_else(|| anyhow::anyhow!("Truncated box header"))?;
+                    (16, u64::from_be_bytes(large.try_into().unwrap()) as usize)
+                }
+                size => (8, size as usize),
+            };
+        let content = data
+            .get(position + header_len..position.saturating_add(box_len))
+            .ok_or_else(|| anyhow::anyhow!("Truncated box"))?;
+        boxes.push((box_type, content));
+        position += box_len;
+    }
+    Ok(boxes)
+}
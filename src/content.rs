@@ -0,0 +1,128 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// Where `--content-addressed output` converts to before the output's hash
+/// is known, inside the output directory.
+pub const STAGING_DIR: &str = ".bulk-jxl-staging";
+
+/// The index of `--content-addressed` in the output directory.
+pub const INDEX_FILE: &str = "index.jsonl";
+
+/// Where a content-addressed output went.
+#[derive(Debug)]
+pub struct Addressed {
+    /// SHA-256 of the source or of the output, in hex.
+    pub hash: String,
+    pub output: PathBuf,
+    /// Whether an output with the same hash was there already.
+    pub deduplicated: bool,
+}
+
+/// The SHA-256 of the contents of `path`, in hex.
+pub fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 256 << 10];
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => hasher.update(&buffer[..read]),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// The output for `hash` under `output_dir`, fanned out over directories
+/// named after its first two characters: `ab/cdef….jxl`.
+pub fn path_for(output_dir: &Path, hash: &str, extension: &std::ffi::OsStr) -> PathBuf {
+    let (fan_out, rest) = hash.split_at(2);
+    output_dir
+        .join(fan_out)
+        .join(rest)
+        .with_extension(extension)
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    /// The source, relative to the input directory, escaped like the paths of
+    /// the report so names that aren't UTF-8 keep their bytes.
+    source: &'a str,
+    hash: &'a str,
+    /// The output, relative to the output directory.
+    output: &'a str,
+}
+
+/// The JSON lines index from source paths to their hashes, appended to by
+/// every run.
+pub struct Index {
+    file: File,
+    output_dir: PathBuf,
+}
+
+impl Index {
+    pub fn open(output_dir: &Path) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(output_dir.join(INDEX_FILE))?;
+        Ok(Index {
+            file,
+            output_dir: output_dir.to_owned(),
+        })
+    }
+
+    /// Adds the line for `source`. Written with a single append, so a line is
+    /// never torn by another writer or half written by a crash mid-line.
+    pub fn append(&mut self, source: &Path, addressed: &Addressed) -> std::io::Result<()> {
+        let output = addressed
+            .output
+            .strip_prefix(&self.output_dir)
+            .unwrap_or(&addressed.output);
+        let mut line = serde_json::to_string(&Entry {
+            source: &crate::report::escape_path(source),
+            hash: &addressed.hash,
+            output: &crate::report::escape_path(output),
+        })?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::ffi::OsStrExt;
+
+    use super::*;
+
+    #[test]
+    fn index_lines_keep_the_bytes_of_names() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut index = Index::open(temp.path()).unwrap();
+        let source = Path::new(std::ffi::OsStr::from_bytes(b"Stra\xdfe/\xff.png"));
+        let addressed = Addressed {
+            hash: "abcdef".to_string(),
+            output: temp.path().join("ab/cdef.jxl"),
+            deduplicated: false,
+        };
+
+        index.append(source, &addressed).unwrap();
+
+        let text = std::fs::read_to_string(temp.path().join(INDEX_FILE)).unwrap();
+        let entry: serde_json::Value = serde_json::from_str(&text).unwrap();
+        let field = entry["source"].as_str().unwrap();
+        assert_eq!(crate::report::unescape_path(field), source);
+        assert_eq!(entry["output"], "ab/cdef.jxl");
+    }
+}
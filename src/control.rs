@@ -0,0 +1,151 @@
+use tokio::sync::watch;
+
+use crate::shutdown::Signal;
+
+/// Stops, pauses and resumes the runs of a [`crate::Converter`] from
+/// elsewhere, as Ctrl-C, SIGUSR1 and SIGUSR2 do on the command line. Reaches
+/// the runs going on when it is used; a stop asked for before a run starts
+/// doesn't carry over to it, a pause does.
+#[derive(Clone)]
+pub struct Handle {
+    requests: watch::Sender<Requests>,
+}
+
+/// What was asked of the runs so far.
+#[derive(Clone, Copy, Default)]
+struct Requests {
+    stops: usize,
+    /// What asked for the last stop.
+    signal: Option<Signal>,
+    paused: bool,
+}
+
+impl Default for Handle {
+    fn default() -> Self {
+        Handle {
+            requests: watch::Sender::new(Requests::default()),
+        }
+    }
+}
+
+impl Handle {
+    /// Starts no new files and lets the running ones finish, after which
+    /// the run ends with [`crate::Error::Stopped`]. Asked again, the running
+    /// files are aborted.
+    pub fn stop(&self) {
+        self.signal(Signal::Interrupt);
+    }
+
+    /// Starts no new files until [`Handle::resume`].
+    pub fn pause(&self) {
+        self.requests.send_modify(|requests| requests.paused = true);
+    }
+
+    pub fn resume(&self) {
+        self.requests
+            .send_modify(|requests| requests.paused = false);
+    }
+
+    /// Passes the stop and pause signals the process gets on to the runs of
+    /// this handle, as the command line does. A stop before a run listens
+    /// ends the process right away, as it would without a handler.
+    pub fn forward_signals(&self) -> std::io::Result<()> {
+        let mut stop_requests = crate::shutdown::Listener::new()?;
+        let mut pause_requests = crate::pause::Listener::new()?;
+        let control = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    signal = stop_requests.recv() => {
+                        if !control.is_listened() {
+                            std::process::exit(signal.exit_code());
+                        }
+                        control.signal(signal);
+                    }
+                    request = pause_requests.recv() => match request {
+                        crate::pause::Request::Pause => control.pause(),
+                        crate::pause::Request::Resume => control.resume(),
+                    },
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// A stop asked for by `signal`, which sets how the run ends.
+    pub(crate) fn signal(&self, signal: Signal) {
+        self.requests.send_modify(|requests| {
+            requests.stops += 1;
+            requests.signal = Some(signal);
+        });
+    }
+
+    /// Whether a run is listening, so a stop has something to stop.
+    pub(crate) fn is_listened(&self) -> bool {
+        self.requests.receiver_count() > 0
+    }
+
+    /// Listens for what is asked of a run from now on.
+    pub(crate) fn listen(&self) -> Listener {
+        let requests = self.requests.subscribe();
+        let seen = Requests {
+            paused: false,
+            ..*requests.borrow()
+        };
+        Listener { requests, seen }
+    }
+}
+
+/// A request to a run.
+#[derive(Clone, Copy)]
+pub(crate) enum Request {
+    Stop(Signal),
+    Pause,
+    Resume,
+}
+
+/// The requests of a [`Handle`] as a run takes them, one at a time.
+pub(crate) struct Listener {
+    requests: watch::Receiver<Requests>,
+    /// What the run has taken so far.
+    seen: Requests,
+}
+
+impl Listener {
+    pub async fn recv(&mut self) -> Request {
+        loop {
+            if let Some(request) = self.next() {
+                return request;
+            }
+            // With every handle gone nothing more can be asked.
+            if self.requests.changed().await.is_err() {
+                return std::future::pending().await;
+            }
+        }
+    }
+
+    /// A stop asked for since the last `recv`, without waiting for one.
+    pub fn pending(&mut self) -> Option<Signal> {
+        std::iter::from_fn(|| self.next()).find_map(|request| match request {
+            Request::Stop(signal) => Some(signal),
+            _ => None,
+        })
+    }
+
+    fn next(&mut self) -> Option<Request> {
+        let requests = *self.requests.borrow_and_update();
+        if requests.stops > self.seen.stops {
+            self.seen.stops += 1;
+            return Some(Request::Stop(requests.signal.unwrap_or(Signal::Interrupt)));
+        }
+        if requests.paused != self.seen.paused {
+            self.seen.paused = requests.paused;
+            return Some(if requests.paused {
+                Request::Pause
+            } else {
+                Request::Resume
+            });
+        }
+        None
+    }
+}
@@ -0,0 +1,773 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use clap::Parser;
+use serde::Serialize;
+use tokio::sync::mpsc::Sender;
+
+use crate::{
+    ArchiveMode, Args, CollisionMode, ColorSpace, ContainerMode, ContentHash, Context, CopyMode,
+    ExistingJxl, GateAction, IcoMode, MultipageMode, Normalization, PlanFormat, PreserveTimes,
+    QualityGate, RevalidateMode, ScriptFormat, SymlinkMode, Transfer, VerifyFailAction,
+    control::Handle,
+    events::{Event, Events, Messages},
+    plan::Plan,
+    process::CommandRunner,
+};
+
+/// Converts a directory tree the way the command line does, for programs
+/// that embed bulk-jxl. Takes the same options, and goes on after the
+/// overview without asking unless given a [`Converter::prompt`]:
+///
+/// ```no_run
+/// # async fn example() -> Result<(), bulk_jxl::Error> {
+/// use bulk_jxl::{Event, ExistingJxl};
+///
+/// let (events, mut received) = tokio::sync::mpsc::channel(64);
+/// let converter = bulk_jxl::Converter::new("photos", "photos-jxl")
+///     .recursive()
+///     .distance(1.0)
+///     .existing_jxl(ExistingJxl::Skip);
+/// let (outcome, _) = tokio::join!(converter.run(events), async {
+///     while let Some(event) = received.recv().await {
+///         match event {
+///             Event::FileFinished(result) => {
+///                 println!("{}: {:?}", result.source.display(), result.action)
+///             }
+///             Event::RunFinished { summary } => println!("{} files", summary.files),
+///             _ => {}
+///         }
+///     }
+/// });
+/// outcome
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Converter {
+    args: Args,
+    /// The --policy file, read when a conversion starts.
+    policy: Option<PathBuf>,
+    runner: Option<Arc<dyn CommandRunner>>,
+    /// Shared by the clones, so any of them stops what the others run.
+    handle: Handle,
+    prompt: Option<crate::Prompt>,
+}
+
+impl Converter {
+    pub fn new(input: impl AsRef<Path>, output: impl AsRef<Path>) -> Self {
+        // The defaults of every option, as the command line has them.
+        let mut args = Args::parse_from(["bulk-jxl", "--input", "", "--output", ""]);
+        args.tree.input = input.as_ref().to_string_lossy().into_owned();
+        args.tree.output = output.as_ref().to_string_lossy().into_owned();
+        Self::from_args(args)
+    }
+
+    /// The conversion the command line parsed `args` into.
+    pub fn from_args(args: Args) -> Self {
+        Converter {
+            args,
+            policy: None,
+            runner: None,
+            handle: Handle::default(),
+            prompt: None,
+        }
+    }
+
+    fn with(mut self, set: impl FnOnce(&mut Args)) -> Self {
+        set(&mut self.args);
+        self
+    }
+
+    pub fn recursive(self) -> Self {
+        self.with(|args| args.tree.recursive = true)
+    }
+
+    pub fn jobs(self, jobs: usize) -> Self {
+        self.with(|args| args.parallel.jobs = jobs)
+    }
+
+    /// Runs between `min` and `max` conversions at a time, as many as there
+    /// are cores other programs leave idle, instead of [`Converter::jobs`].
+    pub fn adaptive_jobs(self, min: usize, max: usize) -> Self {
+        self.with(|args| args.adaptive_jobs = Some(crate::adaptive::JobRange { min, max }))
+    }
+
+    /// Shares out `threads` encoder threads among the running conversions.
+    pub fn threads(self, threads: u64) -> Self {
+        self.with(|args| args.total_threads = Some(threads))
+    }
+
+    /// Copies the files that aren't converted to the output as well.
+    pub fn copy_all(self) -> Self {
+        self.with(|args| args.copy_all = true)
+    }
+
+    /// How many files are copied at once, besides the conversions.
+    pub fn copy_jobs(self, jobs: usize) -> Self {
+        self.with(|args| args.copy_jobs = jobs)
+    }
+
+    pub fn copy_mode(self, mode: CopyMode) -> Self {
+        self.with(|args| args.copy_mode = mode)
+    }
+
+    /// Treats files with these extensions as images, without the dot,
+    /// instead of the built-in list.
+    pub fn extensions<S: AsRef<str>>(self, extensions: impl IntoIterator<Item = S>) -> Self {
+        let extensions = extensions
+            .into_iter()
+            .map(|extension| extension.as_ref().to_string())
+            .collect();
+        self.with(|args| args.extensions = Some(extensions))
+    }
+
+    /// Treats files with this extension as images as well.
+    pub fn add_extension(self, extension: impl Into<String>) -> Self {
+        self.with(|args| args.add_ext.push(extension.into()))
+    }
+
+    /// Stops treating files with this extension as images.
+    pub fn remove_extension(self, extension: impl Into<String>) -> Self {
+        self.with(|args| args.remove_ext.push(extension.into()))
+    }
+
+    /// Leaves out directories with this name, wherever they are.
+    pub fn exclude_dir(self, name: impl Into<String>) -> Self {
+        self.with(|args| args.filters.exclude_dir.push(name.into()))
+    }
+
+    /// Walks into .git, node_modules and the other directories left out by
+    /// default as well.
+    pub fn no_default_excludes(self) -> Self {
+        self.with(|args| args.filters.no_default_excludes = true)
+    }
+
+    /// Leaves out hidden files and directories.
+    pub fn skip_hidden(self) -> Self {
+        self.with(|args| args.filters.skip_hidden = true)
+    }
+
+    /// Leaves out what .gitignore files and the global git excludes ignore.
+    pub fn respect_gitignore(self) -> Self {
+        self.with(|args| args.respect_gitignore = true)
+    }
+
+    /// Converts only the files listed in `list`, a path per line, instead
+    /// of walking the input.
+    pub fn files_from(self, list: impl AsRef<Path>) -> Self {
+        let list = list.as_ref().to_string_lossy().into_owned();
+        self.with(|args| args.files_from = Some(list))
+    }
+
+    /// As [`Converter::files_from`], with the paths separated by NUL bytes.
+    pub fn files_from_nul(self, list: impl AsRef<Path>) -> Self {
+        self.files_from(list).with(|args| args.null = true)
+    }
+
+    pub fn follow_symlinks(self) -> Self {
+        self.with(|args| args.follow_symlinks = true)
+    }
+
+    /// What to do with symlinks to files.
+    pub fn symlinks(self, mode: SymlinkMode) -> Self {
+        self.with(|args| args.symlinks = mode)
+    }
+
+    /// Converts every hard link on its own, instead of linking the outputs
+    /// of the later ones to the output of the first.
+    pub fn no_preserve_hardlinks(self) -> Self {
+        self.with(|args| args.no_preserve_hardlinks = true)
+    }
+
+    pub fn effort(self, effort: u32) -> Self {
+        self.with(|args| args.effort = Some(effort))
+    }
+
+    pub fn distance(self, distance: f32) -> Self {
+        self.with(|args| args.distance = Some(distance))
+    }
+
+    /// Picks lossless, lossy or JPEG reconstruction per file.
+    pub fn smart(self) -> Self {
+        self.with(|args| args.smart = true)
+    }
+
+    /// Reads the encoder settings per extension from the TOML file at
+    /// `path` when a conversion starts.
+    pub fn policy(mut self, path: impl AsRef<Path>) -> Self {
+        self.policy = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Aims for at most this many bytes per converted file.
+    pub fn target_size(self, bytes: u64) -> Self {
+        self.with(|args| args.target_size = Some(bytes))
+    }
+
+    /// Copies images smaller than this many bytes instead of converting them.
+    pub fn convert_min_size(self, bytes: u64) -> Self {
+        self.with(|args| args.convert_min_size = bytes)
+    }
+
+    /// Checks every lossy conversion against `gate`, and does `action` with
+    /// those that fail it.
+    pub fn quality_gate(self, gate: QualityGate, action: GateAction) -> Self {
+        self.with(|args| {
+            args.quality_gate = Some(gate);
+            args.quality_gate_action = action;
+        })
+    }
+
+    pub fn container(self, mode: ContainerMode) -> Self {
+        self.with(|args| args.container = mode)
+    }
+
+    /// Leaves animated sources out.
+    pub fn skip_animated(self) -> Self {
+        self.with(|args| args.skip_animated = true)
+    }
+
+    /// Applies the EXIF orientation to the pixels.
+    pub fn bake_orientation(self) -> Self {
+        self.with(|args| args.bake_orientation = true)
+    }
+
+    /// Tags sources without colour metadata as using these primaries.
+    pub fn assume_color_space(self, space: ColorSpace) -> Self {
+        self.with(|args| args.assume_color_space = Some(space))
+    }
+
+    /// Tags sources without colour metadata as using this transfer function.
+    pub fn assume_transfer(self, transfer: Transfer) -> Self {
+        self.with(|args| args.assume_transfer = Some(transfer))
+    }
+
+    /// Applies the assumed colour tags to sources with tags of their own too.
+    pub fn force_color_tags(self) -> Self {
+        self.with(|args| args.force_color_tags = true)
+    }
+
+    /// Encodes grayscale sources as RGB.
+    pub fn force_rgb(self) -> Self {
+        self.with(|args| args.force_rgb = true)
+    }
+
+    /// Leaves out images with fewer pixels than this.
+    pub fn min_pixels(self, pixels: u64) -> Self {
+        self.with(|args| args.min_pixels = Some(pixels))
+    }
+
+    /// Leaves out images with more pixels than this.
+    pub fn max_pixels(self, pixels: u64) -> Self {
+        self.with(|args| args.max_pixels = Some(pixels))
+    }
+
+    /// Leaves out images narrower or lower than this.
+    pub fn min_dimensions(self, width: u32, height: u32) -> Self {
+        let dimensions = crate::dimensions::Dimensions { width, height };
+        self.with(|args| args.min_dimensions = Some(dimensions))
+    }
+
+    /// Probes every image once after collection, for the overview.
+    pub fn probe(self) -> Self {
+        self.with(|args| args.probe = true)
+    }
+
+    /// Probes this many images at the same time.
+    pub fn probe_jobs(self, jobs: u64) -> Self {
+        self.probe().with(|args| args.probe_jobs = Some(jobs))
+    }
+
+    pub fn multipage(self, mode: MultipageMode) -> Self {
+        self.with(|args| args.multipage = mode)
+    }
+
+    pub fn ico(self, mode: IcoMode) -> Self {
+        self.with(|args| args.ico = mode)
+    }
+
+    /// What to do with files that are already JPEG XL.
+    pub fn existing_jxl(self, mode: ExistingJxl) -> Self {
+        self.with(|args| args.existing_jxl = mode)
+    }
+
+    /// What to do with zip and cbz archives of images.
+    pub fn archives(self, mode: ArchiveMode) -> Self {
+        self.with(|args| args.archives = mode)
+    }
+
+    /// Identifies images by their content as well as their extension.
+    pub fn sniff(self) -> Self {
+        self.with(|args| args.sniff = true)
+    }
+
+    /// Checks image headers before converting and leaves out the files that
+    /// fail.
+    pub fn prevalidate(self) -> Self {
+        self.with(|args| args.prevalidate = true)
+    }
+
+    /// Copies the files that fail [`Converter::prevalidate`] into
+    /// `_corrupt/` in the output.
+    pub fn quarantine_corrupt(self) -> Self {
+        self.prevalidate()
+            .with(|args| args.quarantine_corrupt = true)
+    }
+
+    /// Retries files ffmpeg can't decode through ImageMagick.
+    pub fn magick_fallback(self) -> Self {
+        self.with(|args| args.magick_fallback = true)
+    }
+
+    /// Runs this ffmpeg instead of the one on the PATH, with ffprobe next
+    /// to it.
+    pub fn ffmpeg_path(self, path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_string_lossy().into_owned();
+        self.with(|args| args.ffmpeg_path = Some(path))
+    }
+
+    /// Keeps the source metadata in a sidecar when the output doesn't embed
+    /// it.
+    pub fn metadata_sidecar(self) -> Self {
+        self.with(|args| args.metadata_sidecar = true)
+    }
+
+    /// Decodes every output and compares it with its source.
+    pub fn verify(self) -> Self {
+        self.with(|args| args.verify = true)
+    }
+
+    /// Verifies the outputs, and does `action` with those that fail.
+    pub fn on_verify_fail(self, action: VerifyFailAction) -> Self {
+        self.verify().with(|args| args.on_verify_fail = action)
+    }
+
+    /// Skips files modified within `time` or still growing.
+    pub fn stable(self, time: Duration) -> Self {
+        self.with(|args| args.stable_seconds = Some(time.as_secs()))
+    }
+
+    /// Checks the outputs of earlier runs before skipping their sources.
+    pub fn revalidate_existing(self, mode: RevalidateMode) -> Self {
+        self.with(|args| args.revalidate_existing = Some(mode))
+    }
+
+    /// Fails instead of warning when the output looks too small.
+    pub fn require_free_space(self) -> Self {
+        self.with(|args| args.require_free_space = true)
+    }
+
+    /// What to do with outputs that would land on the same file.
+    pub fn on_collision(self, mode: CollisionMode) -> Self {
+        self.with(|args| args.on_collision = mode)
+    }
+
+    /// The Unicode normalization of output names.
+    pub fn normalize_names(self, form: Normalization) -> Self {
+        self.with(|args| args.normalize_names = form)
+    }
+
+    /// Names outputs after the SHA-256 of their source or of themselves.
+    pub fn content_addressed(self, hash: ContentHash) -> Self {
+        self.with(|args| args.content_addressed = Some(hash))
+    }
+
+    /// Source timestamps to carry over to the outputs.
+    pub fn preserve_times(self, which: PreserveTimes) -> Self {
+        self.with(|args| args.preserve_times = which)
+    }
+
+    /// How far an output's modification time may read back off.
+    pub fn mtime_tolerance(self, tolerance: Duration) -> Self {
+        self.with(|args| args.mtime_tolerance = tolerance.as_secs_f64())
+    }
+
+    /// Copies extended attributes from each source to its output.
+    pub fn preserve_xattrs(self) -> Self {
+        self.with(|args| args.preserve_xattrs = true)
+    }
+
+    /// Gives outputs and output directories the owner and group of their
+    /// sources.
+    pub fn preserve_owner(self) -> Self {
+        self.with(|args| args.preserve_owner = true)
+    }
+
+    /// Gives outputs and created output directories this user and group.
+    pub fn chown(self, uid: Option<u32>, gid: Option<u32>) -> Self {
+        self.with(|args| args.chown = Some(crate::perms::Owner { uid, gid }))
+    }
+
+    /// Gives created output directories this mode, e.g. `0o2775`.
+    pub fn dir_mode(self, mode: u32) -> Self {
+        self.with(|args| args.dir_mode = Some(mode))
+    }
+
+    /// Gives outputs this mode, e.g. `0o664`.
+    pub fn file_mode(self, mode: u32) -> Self {
+        self.with(|args| args.file_mode = Some(mode))
+    }
+
+    /// Flushes every output to the disk before counting it as done.
+    pub fn fsync(self) -> Self {
+        self.with(|args| args.fsync = true)
+    }
+
+    /// Starts converting while the input is still walked, instead of
+    /// collecting every file first.
+    pub fn stream(self) -> Self {
+        self.with(|args| args.stream = true)
+    }
+
+    /// How often to retry I/O that fails with transient errors.
+    pub fn io_retries(self, retries: u32) -> Self {
+        self.with(|args| args.io_retries = retries)
+    }
+
+    /// Stops a tool that runs longer than `timeout`, in whole seconds.
+    pub fn tool_timeout(self, timeout: Duration) -> Self {
+        let seconds = timeout.as_secs() + u64::from(timeout.subsec_nanos() > 0);
+        self.with(|args| args.tool_timeout = Some(seconds.max(1)))
+    }
+
+    /// How long running files get to finish after a stop that aborts them.
+    pub fn shutdown_grace(self, grace: Duration) -> Self {
+        self.with(|args| args.shutdown_grace = grace.as_secs())
+    }
+
+    /// Also stops the running encoders while paused.
+    pub fn pause_encoders(self) -> Self {
+        self.with(|args| args.pause_encoders = true)
+    }
+
+    /// Waits for another run using the output to finish, instead of
+    /// failing with [`Error::Locked`].
+    pub fn wait_lock(self) -> Self {
+        self.with(|args| args.wait_lock = true)
+    }
+
+    /// Keeps no journal of started and finished files in the output.
+    pub fn no_journal(self) -> Self {
+        self.with(|args| args.no_journal = true)
+    }
+
+    /// Leaves the temporary outputs of failed conversions in place.
+    pub fn keep_partials(self) -> Self {
+        self.with(|args| args.keep_partials = true)
+    }
+
+    /// Fails the run when an output it counts isn't on disk as it should be.
+    pub fn strict(self) -> Self {
+        self.with(|args| args.strict = true)
+    }
+
+    /// Starts no new files once the run has written this many bytes.
+    pub fn max_output_bytes(self, bytes: u64) -> Self {
+        self.with(|args| args.max_output_bytes = Some(bytes))
+    }
+
+    /// Starts no new files once the sources done add up to this many bytes.
+    pub fn max_input_bytes(self, bytes: u64) -> Self {
+        self.with(|args| args.max_input_bytes = Some(bytes))
+    }
+
+    /// Reads and copies at most this many bytes per second.
+    pub fn rate_limit(self, bytes_per_second: u64) -> Self {
+        self.with(|args| args.rate_limit = Some(bytes_per_second))
+    }
+
+    /// Before asking to go on, converts this many sample files to estimate
+    /// the output size and time. Only done with a [`Converter::prompt`].
+    pub fn pilot(self, samples: usize) -> Self {
+        self.with(|args| args.pilot = samples)
+    }
+
+    /// Instead of converting, writes the commands that would to `path` as a
+    /// script for `format`.
+    pub fn emit_script(self, path: impl AsRef<Path>, format: ScriptFormat) -> Self {
+        let path = path.as_ref().to_owned();
+        self.with(|args| {
+            args.emit_script = Some(path);
+            args.emit_script_format = format;
+        })
+    }
+
+    /// Sends which file is worked on and the output of the tools as well.
+    pub fn verbose(self) -> Self {
+        self.with(|args| args.verbose = true)
+    }
+
+    /// Writes the report of the run to `path`, as tab separated values.
+    pub fn report(self, path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_string_lossy().into_owned();
+        self.with(|args| args.report = Some(path))
+    }
+
+    /// Writes the commands, stderr and source details of every file that
+    /// fails to a text file in `dir`.
+    pub fn error_dir(self, dir: impl AsRef<Path>) -> Self {
+        let dir = dir.as_ref().to_owned();
+        self.with(|args| args.error_dir = Some(dir))
+    }
+
+    /// Copies the first this many bytes of every failed source to
+    /// [`Converter::error_dir`] as well.
+    pub fn error_sample_bytes(self, bytes: u64) -> Self {
+        self.with(|args| args.error_sample_bytes = Some(bytes))
+    }
+
+    /// Keeps a JSON file with the state of the run up to date at `path`.
+    pub fn status_file(self, path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_owned();
+        self.with(|args| args.status_file = Some(path))
+    }
+
+    /// Adds up the files per directory down to this many levels below the
+    /// input, for the summary.
+    pub fn summary_depth(self, depth: usize) -> Self {
+        self.with(|args| args.summary_depth = depth)
+    }
+
+    /// Times the phases of the run and the stages of every file.
+    pub fn profile(self) -> Self {
+        self.with(|args| args.profile = true)
+    }
+
+    /// Has `runner` run ffmpeg, ffprobe, cjxl, ImageMagick and exiftool
+    /// instead of starting them as processes.
+    pub fn runner(mut self, runner: impl CommandRunner + 'static) -> Self {
+        self.runner = Some(Arc::new(runner));
+        self
+    }
+
+    /// Asks `ask` whether to go on after the overview, with the question and
+    /// the answer an empty reply gives, unless the options say yes already.
+    pub fn prompt(
+        mut self,
+        ask: impl Fn(&str, bool) -> std::io::Result<bool> + Send + Sync + 'static,
+    ) -> Self {
+        self.prompt = Some(Arc::new(ask));
+        self
+    }
+
+    /// What stops or pauses the runs of this converter, as signals do on
+    /// the command line. The process's own signals are left alone.
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    /// Rejects what the command line rejects while parsing: options that go
+    /// against each other, or out of range.
+    fn check(&self) -> Result<(), Error> {
+        let args = &self.args;
+        let first_set = |options: &[(&'static str, bool)]| {
+            options
+                .iter()
+                .find(|(_, set)| *set)
+                .map(|(option, _)| *option)
+        };
+        let conflict = |option: &str, other: &str| {
+            Err(Error::Options(format!(
+                "{} can't be used with {}",
+                option, other
+            )))
+        };
+        if args.stream {
+            if let Some(other) = first_set(&[
+                ("--prevalidate", args.prevalidate),
+                ("--stable-seconds", args.stable_seconds.is_some()),
+                ("--require-free-space", args.require_free_space),
+                ("--min-pixels", args.min_pixels.is_some()),
+                ("--max-pixels", args.max_pixels.is_some()),
+                ("--min-dimensions", args.min_dimensions.is_some()),
+                ("--probe", args.probe),
+                ("--files-from", args.files_from.is_some()),
+                ("--dry-run", args.dry_run),
+            ]) {
+                return conflict("--stream", other);
+            }
+            if !args.yes && self.prompt.is_some() {
+                return Err(Error::Options(
+                    "--stream has no overview to confirm, it needs --yes".to_string(),
+                ));
+            }
+        }
+        if args.emit_script.is_some()
+            && let Some(other) = first_set(&[
+                ("--dry-run", args.dry_run),
+                ("--stream", args.stream),
+                ("--target-size", args.target_size.is_some()),
+                ("--quality-gate", args.quality_gate.is_some()),
+                ("--verify", args.verify),
+                ("--content-addressed", args.content_addressed.is_some()),
+                ("--metadata-sidecar", args.metadata_sidecar),
+                ("--preserve-xattrs", args.preserve_xattrs),
+                ("--preserve-owner", args.preserve_owner),
+                ("--chown", args.chown.is_some()),
+                ("--dir-mode", args.dir_mode.is_some()),
+                ("--fsync", args.fsync),
+                ("--rate-limit", args.rate_limit.is_some()),
+                ("--print-outputs", args.print_outputs.is_some()),
+            ])
+        {
+            return conflict("--emit-script", other);
+        }
+        if args.error_sample_bytes.is_some() && args.error_dir.is_none() {
+            return Err(Error::Options(
+                "--error-sample-bytes needs --error-dir".to_string(),
+            ));
+        }
+        if let Some(option) = first_set(&[
+            ("--jobs", args.parallel.jobs == 0),
+            ("--total-threads", args.total_threads == Some(0)),
+            ("--probe-jobs", args.probe_jobs == Some(0)),
+            ("--rate-limit", args.rate_limit == Some(0)),
+        ]) {
+            return Err(Error::Options(format!("{} must be 1 or more", option)));
+        }
+        if let Some(range) = args.adaptive_jobs
+            && !(1..=range.max).contains(&range.min)
+        {
+            return Err(Error::Options(format!(
+                "--adaptive-jobs {}..{} needs 1 or more, at most as many as the most",
+                range.min, range.max
+            )));
+        }
+        Ok(())
+    }
+
+    /// The context of a conversion with these options, after checking them.
+    fn context(&self, messages: Messages) -> Result<Context, Error> {
+        self.check()?;
+        let mut args = self.args.clone();
+        if let Some(path) = &self.policy {
+            let policy = crate::policy::Policy::load(&path.to_string_lossy());
+            args.policy = Some(policy.map_err(Error::Options)?);
+        }
+        let mut context = Context::new(args, self.runner.clone(), messages);
+        context.control = self.handle.clone();
+        context.prompt = self.prompt.clone();
+        Ok(context)
+    }
+
+    /// Walks the input and returns what would be done with each file, as
+    /// `--dry-run` does, without converting or copying anything. Nothing is
+    /// written, not even the output directory; one that doesn't exist yet is
+    /// taken for empty.
+    pub async fn plan(&self) -> Result<Plan, Error> {
+        let context = self.context(Messages::discard())?;
+        Ok(crate::plan(context).await?)
+    }
+
+    /// Runs the conversion and sends its progress to `events`, with what
+    /// happened to every file as soon as it's done. Files that fail don't
+    /// fail the run; they arrive as [`Action::Failed`].
+    ///
+    /// Outcomes are never dropped: while the channel is full the run waits
+    /// for the receiver. Of the scan progress that finds it full only the
+    /// newest waits for room, and the final count always arrives.
+    pub async fn run(&self, events: Sender<Event>) -> Result<(), Error> {
+        let events = Events::channel(events);
+        crate::run::run(self.context(events.messages())?, events).await?;
+        Ok(())
+    }
+
+    /// Runs the conversion as the command line does, printing its progress
+    /// and summary and adding it to the history of `bulk-jxl stats`, unless
+    /// the options leave it out. With `--dry-run` it prints the plan
+    /// instead.
+    pub async fn run_in_terminal(&self) -> Result<(), Error> {
+        if self.args.dry_run {
+            let mut context = self.context(Messages::print())?;
+            context.args.print_plan.get_or_insert(PlanFormat::Text);
+            crate::plan(context).await?;
+            return Ok(());
+        }
+        let events = Events::print();
+        let mut context = self.context(events.messages())?;
+        context.history = !context.args.no_history;
+        crate::run::run(context, events).await?;
+        Ok(())
+    }
+}
+
+/// What happened to a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    Converted,
+    Copied,
+    /// A symlink recreated as such.
+    Linked,
+    /// The original was put in the output instead of the conversion, e.g.
+    /// after it failed `--verify` or a quality gate.
+    KeptOriginal,
+    /// Both the conversion and the original are in the output.
+    KeptBoth,
+    /// Its output was there already, or it was left out by policy.
+    Skipped,
+    Failed,
+}
+
+/// The outcome of a single file of a [`Converter::run`].
+#[derive(Clone, Debug, Serialize)]
+pub struct FileResult {
+    pub source: PathBuf,
+    /// Where the result was planned to go.
+    pub output: Option<PathBuf>,
+    pub action: Action,
+    /// The size of the source, for conversions.
+    pub original_size: u64,
+    /// The size of the output, for conversions.
+    pub converted_size: u64,
+    /// How long the file took, from its start to its output being finished.
+    pub duration: Duration,
+    /// What the report notes for the file, or why it failed.
+    pub notes: Vec<String>,
+}
+
+/// Why a run didn't complete.
+#[derive(Debug)]
+pub enum Error {
+    /// The options were rejected, as they would be on the command line.
+    Options(String),
+    /// A Ctrl-C, a signal or [`Handle::stop`] stopped the run, and the
+    /// command line exits with this code.
+    Stopped(i32),
+    /// A tool the run needs, like ffmpeg or ffprobe, couldn't be started.
+    MissingTool { tool: String, message: String },
+    /// The input directory is missing or isn't a directory.
+    Input { path: PathBuf, message: String },
+    /// The output directory can't be made or written to.
+    Output { path: PathBuf, message: String },
+    /// Another run, with this PID, is using the output directory.
+    Locked { pid: u32, message: String },
+    /// Anything else that ended the run, such as a policy file that can't
+    /// be read.
+    Failed(anyhow::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Options(e) => write!(f, "{}", e),
+            Error::Stopped(code) => write!(f, "the run was stopped (exit code {})", code),
+            Error::MissingTool { message, .. }
+            | Error::Input { message, .. }
+            | Error::Output { message, .. }
+            | Error::Locked { message, .. } => write!(f, "{}", message),
+            Error::Failed(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        error.downcast::<Error>().unwrap_or_else(Error::Failed)
+    }
+}
@@ -0,0 +1,157 @@
+use std::path::{Path, PathBuf};
+
+use filetime::FileTime;
+use tokio::task::JoinSet;
+
+use crate::{
+    PartialFile, container,
+    events::Messages,
+    partial_path,
+    process::{self, CommandRunner, Spawn},
+    restore,
+};
+
+/// Decodes the JPEG XL files in a tree back to images other programs can
+/// open: the JPEG a lossless transcode was made from, and PNG for the rest.
+#[derive(clap::Args)]
+pub struct DecodeArgs {
+    #[command(flatten)]
+    tree: crate::Tree,
+
+    #[command(flatten)]
+    parallel: crate::Jobs,
+
+    #[command(flatten)]
+    filters: crate::Filters,
+}
+
+pub async fn run(args: DecodeArgs, messages: &Messages) -> anyhow::Result<()> {
+    let input_path = PathBuf::from(&args.tree.input);
+    if !input_path.is_dir() {
+        return Err(anyhow::anyhow!("Input path is not a directory"));
+    }
+    let output_path = PathBuf::from(&args.tree.output);
+    std::fs::create_dir_all(&output_path)?;
+
+    let files = args
+        .filters
+        .files(&input_path, args.tree.recursive)
+        .map(|entry| entry.into_path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("jxl"))
+        })
+        .collect::<Vec<_>>();
+
+    let mut pending = files.iter().cloned();
+    let mut set = JoinSet::new();
+    let mut decoded = 0;
+    let mut skipped = 0;
+    let mut errors = 0;
+    loop {
+        while set.len() < args.parallel.jobs.max(1)
+            && let Some(file) = pending.next()
+        {
+            let relative = file.strip_prefix(&input_path)?.to_owned();
+            let (output_path, spawn) = (output_path.clone(), Spawn::new(messages));
+            set.spawn(async move {
+                let decoded = decode(&spawn, &file, &output_path.join(relative)).await;
+                (file, decoded)
+            });
+        }
+        let Some(task_result) = set.join_next().await else {
+            break;
+        };
+        match task_result {
+            Ok((file, Ok(Some(output)))) => {
+                say!(
+                    messages,
+                    "   Decoded {} -> {}",
+                    file.display(),
+                    output.display()
+                );
+                decoded += 1;
+            }
+            Ok((_, Ok(None))) => skipped += 1,
+            Ok((file, Err(e))) => {
+                say_error!(messages, "Error decoding {}: {}", file.display(), e);
+                errors += 1;
+            }
+            Err(e) => say_error!(messages, "Task join error: {}", e),
+        }
+    }
+
+    say!(messages, "{}", "-".repeat(60));
+    say!(messages, "Decode Summary:");
+    say!(messages, "  JPEG XL files found:   {}", files.len());
+    say!(messages, "  Files decoded:         {}", decoded);
+    say!(messages, "  Skipped (existing):    {}", skipped);
+    say!(messages, "  Files with errors:     {}", errors);
+    say!(messages, "{}", "-".repeat(60));
+    Ok(())
+}
+
+/// Decodes `file` next to where `output` says, as `.jpg` when it holds the
+/// data to rebuild a JPEG and `.png` otherwise, with the first frame of an
+/// animation. Returns the path written, or `None` when it was there already.
+async fn decode(
+    runner: &dyn CommandRunner,
+    file: &Path,
+    output: &Path,
+) -> anyhow::Result<Option<PathBuf>> {
+    let jpeg = {
+        let file = file.to_owned();
+        tokio::task::spawn_blocking(move || container::has_jpeg_reconstruction(&file)).await??
+    };
+    let output = output.with_extension(if jpeg { "jpg" } else { "png" });
+    if tokio::fs::try_exists(&output).await? {
+        return Ok(None);
+    }
+    if let Some(parent) = output.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let partial = PartialFile {
+        path: partial_path(&output, std::process::id()),
+        keep: false,
+    };
+    if jpeg {
+        // djxl picks the format by the extension of what it writes.
+        let rebuilt = partial.path.with_extension("jpg");
+        let rebuilt = PartialFile {
+            path: rebuilt,
+            keep: false,
+        };
+        restore::reconstruct(runner, file, &rebuilt.path).await?;
+        tokio::fs::rename(&rebuilt.path, &partial.path).await?;
+    } else {
+        let finished = runner
+            .run(
+                process::command("ffmpeg")
+                    .arg("-v")
+                    .arg("error")
+                    .arg("-i")
+                    .arg(process::file_arg(file))
+                    .arg("-frames:v")
+                    .arg("1")
+                    .arg("-c:v")
+                    .arg("png")
+                    .arg("-f")
+                    .arg("image2")
+                    .arg("-update")
+                    .arg("1")
+                    .arg(process::file_arg(&partial.path)),
+                false,
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to run ffmpeg: {}", e))?;
+        if !finished.status.success() {
+            return Err(anyhow::anyhow!("{}", finished.message("ffmpeg failed")));
+        }
+    }
+    tokio::fs::rename(&partial.path, &output).await?;
+    let modified = FileTime::from_last_modification_time(&tokio::fs::metadata(file).await?);
+    let finished = output.clone();
+    tokio::task::spawn_blocking(move || filetime::set_file_mtime(&finished, modified)).await??;
+    Ok(Some(output))
+}
@@ -0,0 +1,333 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::{
+    events::Messages,
+    report::{self, Record},
+};
+
+/// Compares the `--report` of two runs over the same input, e.g. before and
+/// after changing the settings.
+#[derive(clap::Args)]
+pub struct DiffArgs {
+    /// Tab separated `--report` of the earlier run
+    #[clap(value_name = "OLD.tsv")]
+    old: PathBuf,
+
+    /// Tab separated `--report` of the later run
+    #[clap(value_name = "NEW.tsv")]
+    new: PathBuf,
+
+    /// Print the differences as a JSON object instead of tables
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Default, Serialize)]
+struct Diff {
+    /// The settings of each run, when they differ.
+    settings: Option<(String, String)>,
+    /// Files whose status differs, e.g. `error` before and `converted` now.
+    changed: Vec<Changed>,
+    /// Files converted by both runs to outputs of different sizes.
+    resized: Vec<Resized>,
+    /// Files whose source has another size in each report, so the runs
+    /// didn't convert the same thing.
+    source_changed: Vec<PathBuf>,
+    only_old: Vec<PathBuf>,
+    only_new: Vec<PathBuf>,
+    totals: Totals,
+}
+
+#[derive(Serialize)]
+struct Changed {
+    source: PathBuf,
+    old_status: String,
+    new_status: String,
+    /// What the later report notes, such as its error.
+    details: String,
+}
+
+#[derive(Serialize)]
+struct Resized {
+    source: PathBuf,
+    old_size: u64,
+    new_size: u64,
+}
+
+/// The sizes of the conversions of each run, counting only the files both
+/// reports have.
+#[derive(Default, Serialize)]
+struct Totals {
+    old_converted: usize,
+    new_converted: usize,
+    old_original_size: u64,
+    new_original_size: u64,
+    old_converted_size: u64,
+    new_converted_size: u64,
+}
+
+pub fn run(args: DiffArgs, messages: &Messages) -> anyhow::Result<()> {
+    let read = |path: &Path| {
+        report::read(path).map_err(|e| anyhow::anyhow!("Reading {}: {}", path.display(), e))
+    };
+    let (old, new) = (read(&args.old)?, read(&args.new)?);
+    // Runs over an input that was moved, or given as another path, still
+    // line up by the path below it, which reports have had for a while.
+    let relative = old
+        .iter()
+        .chain(&new)
+        .all(|record| record.relative_source.is_some());
+    let diff = compare(&by_source(old, relative), &by_source(new, relative));
+    if args.json {
+        say!(messages, "{}", serde_json::to_string_pretty(&diff)?);
+    } else {
+        print(&diff, messages);
+    }
+    Ok(())
+}
+
+/// The records of a report by source, relative to the input with
+/// `relative`. A source with several lines, like the pages of a multi-page
+/// file, goes by its last.
+fn by_source(records: Vec<Record>, relative: bool) -> BTreeMap<PathBuf, Record> {
+    records
+        .into_iter()
+        .map(|record| {
+            let source = match &record.relative_source {
+                Some(relative_source) if relative => relative_source.clone(),
+                _ => record.source.clone(),
+            };
+            (source, record)
+        })
+        .collect()
+}
+
+fn compare(old: &BTreeMap<PathBuf, Record>, new: &BTreeMap<PathBuf, Record>) -> Diff {
+    let mut diff = Diff::default();
+    let settings = |records: &BTreeMap<PathBuf, Record>| {
+        records
+            .values()
+            .map(|record| record.settings.clone())
+            .find(|settings| !settings.is_empty())
+            .unwrap_or_default()
+    };
+    let (old_settings, new_settings) = (settings(old), settings(new));
+    if old_settings != new_settings {
+        diff.settings = Some((old_settings, new_settings));
+    }
+    diff.only_old = old
+        .keys()
+        .filter(|source| !new.contains_key(*source))
+        .cloned()
+        .collect();
+    for (source, after) in new {
+        let Some(before) = old.get(source) else {
+            diff.only_new.push(source.clone());
+            continue;
+        };
+        if let (Some(before), Some(after)) = (before.source_size, after.source_size)
+            && before != after
+        {
+            diff.source_changed.push(source.clone());
+        }
+        let totals = &mut diff.totals;
+        if before.status == "converted" {
+            totals.old_converted += 1;
+            totals.old_original_size += before.original_size;
+            totals.old_converted_size += before.converted_size;
+        }
+        if after.status == "converted" {
+            totals.new_converted += 1;
+            totals.new_original_size += after.original_size;
+            totals.new_converted_size += after.converted_size;
+        }
+        if before.status != after.status {
+            diff.changed.push(Changed {
+                source: source.clone(),
+                old_status: before.status.clone(),
+                new_status: after.status.clone(),
+                details: after.details.clone(),
+            });
+        } else if after.status == "converted" && before.converted_size != after.converted_size {
+            diff.resized.push(Resized {
+                source: source.clone(),
+                old_size: before.converted_size,
+                new_size: after.converted_size,
+            });
+        }
+    }
+    diff
+}
+
+/// `new - old` in bytes with its sign, e.g. `-1.2 MiB`.
+fn delta(old: u64, new: u64) -> String {
+    let sign = if new < old { "-" } else { "+" };
+    format!(
+        "{}{}",
+        sign,
+        human_bytes::human_bytes(new.abs_diff(old) as f64)
+    )
+}
+
+fn print(diff: &Diff, messages: &Messages) {
+    let size = |bytes: u64| human_bytes::human_bytes(bytes as f64);
+    if let Some((old, new)) = &diff.settings {
+        say!(messages, "Settings: {} -> {}", old, new);
+    }
+    if !diff.changed.is_empty() {
+        say!(messages, "Outcome changed ({}):", diff.changed.len());
+        for changed in &diff.changed {
+            say!(
+                messages,
+                "   {} -> {}  {}",
+                changed.old_status,
+                changed.new_status,
+                changed.source.display()
+            );
+            if changed.new_status == "error" && !changed.details.is_empty() {
+                say!(messages, "      {}", changed.details);
+            }
+        }
+    }
+    if !diff.resized.is_empty() {
+        say!(messages, "Size changed ({}):", diff.resized.len());
+        for resized in &diff.resized {
+            say!(
+                messages,
+                "   {:>10} -> {:>10} ({:>10})  {}",
+                size(resized.old_size),
+                size(resized.new_size),
+                delta(resized.old_size, resized.new_size),
+                resized.source.display()
+            );
+        }
+    }
+    for (label, sources) in [
+        ("Source changed", &diff.source_changed),
+        ("Only in the old report", &diff.only_old),
+        ("Only in the new report", &diff.only_new),
+    ] {
+        if !sources.is_empty() {
+            say!(messages, "{} ({}):", label, sources.len());
+            for source in sources {
+                say!(messages, "   {}", source.display());
+            }
+        }
+    }
+    let totals = &diff.totals;
+    say!(messages, "{}", "-".repeat(60));
+    say!(messages, "Totals of the files in both reports:");
+    say!(
+        messages,
+        "  Converted:             {} -> {}",
+        totals.old_converted,
+        totals.new_converted
+    );
+    say!(
+        messages,
+        "  Original size:         {} -> {}",
+        size(totals.old_original_size),
+        size(totals.new_original_size)
+    );
+    say!(
+        messages,
+        "  Converted size:        {} -> {} ({})",
+        size(totals.old_converted_size),
+        size(totals.new_converted_size),
+        delta(totals.old_converted_size, totals.new_converted_size)
+    );
+    say!(messages, "{}", "-".repeat(60));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(source: &str, status: &str, converted_size: u64) -> Record {
+        Record {
+            status: status.to_string(),
+            source: PathBuf::from("/photos").join(source),
+            original_size: 1000,
+            converted_size,
+            details: String::new(),
+            source_size: Some(1000),
+            settings: "distance=1".to_string(),
+            relative_source: Some(PathBuf::from(source)),
+            source_sha256: None,
+        }
+    }
+
+    #[test]
+    fn outcomes_sizes_and_missing_files_are_told_apart() {
+        let old = vec![
+            record("a.png", "converted", 400),
+            record("b.png", "error", 0),
+            record("c.png", "converted", 500),
+            record("gone.png", "converted", 100),
+        ];
+        let mut failed = record("a.png", "error", 0);
+        failed.details = "encoder crashed".to_string();
+        let new = vec![
+            failed,
+            record("b.png", "converted", 300),
+            record("c.png", "converted", 450),
+            record("new.png", "converted", 200),
+        ];
+        let diff = compare(&by_source(old, true), &by_source(new, true));
+
+        let changed: Vec<_> = diff
+            .changed
+            .iter()
+            .map(|c| (c.source.to_str().unwrap(), &*c.old_status, &*c.new_status))
+            .collect();
+        assert_eq!(
+            changed,
+            [
+                ("a.png", "converted", "error"),
+                ("b.png", "error", "converted")
+            ]
+        );
+        assert_eq!(diff.changed[0].details, "encoder crashed");
+        let resized: Vec<_> = diff
+            .resized
+            .iter()
+            .map(|r| (r.source.to_str().unwrap(), r.old_size, r.new_size))
+            .collect();
+        assert_eq!(resized, [("c.png", 500, 450)]);
+        assert_eq!(delta(500, 450), "-50 B");
+        assert_eq!(diff.only_old, [PathBuf::from("gone.png")]);
+        assert_eq!(diff.only_new, [PathBuf::from("new.png")]);
+        assert!(diff.settings.is_none());
+        // Only the files in both reports count.
+        let totals = &diff.totals;
+        assert_eq!((totals.old_converted, totals.new_converted), (2, 2));
+        assert_eq!(
+            (totals.old_converted_size, totals.new_converted_size),
+            (900, 750)
+        );
+    }
+
+    #[test]
+    fn sources_line_up_below_a_moved_input() {
+        let old = vec![record("a.png", "converted", 400)];
+        let mut moved = record("a.png", "converted", 400);
+        moved.source = PathBuf::from("/mnt/backup/photos/a.png");
+        let diff = compare(
+            &by_source(old.clone(), true),
+            &by_source(vec![moved.clone()], true),
+        );
+        assert!(diff.only_old.is_empty() && diff.only_new.is_empty());
+
+        // Older reports without the column go by the full path.
+        let mut older = moved;
+        older.relative_source = None;
+        let diff = compare(&by_source(old, false), &by_source(vec![older], false));
+        assert_eq!(diff.only_old, [PathBuf::from("/photos/a.png")]);
+        assert_eq!(diff.only_new, [PathBuf::from("/mnt/backup/photos/a.png")]);
+    }
+}
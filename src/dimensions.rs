@@ -0,0 +1,120 @@
+use std::path::Path;
+
+use crate::encoder::InputSelection;
+
+/// Width and height in pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Dimensions {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Dimensions {
+    pub fn pixels(self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+}
+
+impl std::fmt::Display for Dimensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+/// Reads the dimensions of `path` from its header, without decoding it, and
+/// asks ffprobe for the formats the header parser doesn't know. `format` is
+/// the ffmpeg format to read the file as, for content sniffed under another
+/// extension.
+pub async fn probe(
+    runner: &dyn crate::process::CommandRunner,
+    path: &Path,
+    format: Option<&str>,
+) -> anyhow::Result<Dimensions> {
+    let header = path.to_owned();
+    let parsed = tokio::task::spawn_blocking(move || imagesize::size(header)).await?;
+    if let Ok(size) = parsed
+        && let (Ok(width), Ok(height)) = (u32::try_from(size.width), u32::try_from(size.height))
+    {
+        return Ok(Dimensions { width, height });
+    }
+    let stream = crate::probe::stream_info(runner, path, format, InputSelection::Whole).await?;
+    if stream.width == 0 || stream.height == 0 {
+        return Err(anyhow::anyhow!("ffprobe reports no dimensions"));
+    }
+    Ok(Dimensions {
+        width: stream.width,
+        height: stream.height,
+    })
+}
+
+/// The bounds of `--min-pixels`, `--max-pixels` and `--min-dimensions`.
+#[derive(Clone, Copy, Default)]
+pub struct Limits {
+    pub min_pixels: Option<u64>,
+    pub max_pixels: Option<u64>,
+    pub min_dimensions: Option<Dimensions>,
+}
+
+impl Limits {
+    pub fn is_set(&self) -> bool {
+        self.min_pixels.is_some() || self.max_pixels.is_some() || self.min_dimensions.is_some()
+    }
+
+    /// Why an image of `size` is left out, `None` when it is within bounds.
+    pub fn rejects(&self, size: Dimensions) -> Option<String> {
+        if let Some(min) = self.min_pixels
+            && size.pixels() < min
+        {
+            return Some(format!("{} is below --min-pixels {}", size, min));
+        }
+        if let Some(max) = self.max_pixels
+            && size.pixels() > max
+        {
+            return Some(format!("{} is above --max-pixels {}", size, max));
+        }
+        if let Some(min) = self.min_dimensions
+            && (size.width < min.width || size.height < min.height)
+        {
+            return Some(format!("{} is smaller than --min-dimensions {}", size, min));
+        }
+        None
+    }
+}
+
+/// Parses a pixel count, with an optional `K`, `M` or `G` for thousands,
+/// millions or billions, e.g. `1M` or `2.5M`. An `MP` suffix reads as `M`.
+pub fn parse_pixels(value: &str) -> Result<u64, String> {
+    let trimmed = value.trim().to_ascii_uppercase();
+    let trimmed = trimmed.strip_suffix('P').unwrap_or(&trimmed);
+    let (number, multiplier) = match trimmed.char_indices().last() {
+        Some((at, 'K')) => (&trimmed[..at], 1e3),
+        Some((at, 'M')) => (&trimmed[..at], 1e6),
+        Some((at, 'G')) => (&trimmed[..at], 1e9),
+        _ => (trimmed, 1.0),
+    };
+    let number = number
+        .trim()
+        .parse::<f64>()
+        .map_err(|e| format!("invalid pixel count {}: {}", value, e))?;
+    if !(number >= 0.0 && number.is_finite()) {
+        return Err(format!("invalid pixel count {}", value));
+    }
+    Ok((number * multiplier) as u64)
+}
+
+/// Parses `WIDTHxHEIGHT`, e.g. `1024x768`.
+pub fn parse_dimensions(value: &str) -> Result<Dimensions, String> {
+    let (width, height) = value
+        .trim()
+        .split_once(['x', 'X'])
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT, not {}", value))?;
+    let parse = |side: &str| {
+        side.trim()
+            .parse::<u32>()
+            .map_err(|e| format!("invalid dimensions {}: {}", value, e))
+    };
+    Ok(Dimensions {
+        width: parse(width)?,
+        height: parse(height)?,
+    })
+}
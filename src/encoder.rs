@@ -0,0 +1,265 @@
+use std::{future::Future, path::Path, pin::Pin};
+
+use crate::{
+    color,
+    process::{self, CommandRunner},
+};
+
+/// Which part of the input the encoder should decode.
+#[derive(Clone, Copy)]
+pub enum InputSelection {
+    /// The first video stream, which is all most files have.
+    Whole,
+    /// A page of a multi-page TIFF, numbered from 1.
+    TiffPage(usize),
+    /// A specific stream of the input, e.g. one image of an ICO file.
+    Stream(usize),
+}
+
+/// What an encoder is asked to do with one image.
+#[derive(Clone, Copy)]
+pub struct EncodeOptions<'a> {
+    /// The format to read the input as, when its name doesn't tell.
+    pub input_format: Option<&'a str>,
+    pub selection: InputSelection,
+    /// Compression effort (1-9).
+    pub effort: u32,
+    /// Butteraugli distance, 0 for lossless. `None` leaves the encoder default.
+    pub distance: Option<f32>,
+    /// Threads for this one conversion.
+    pub threads: u64,
+    /// Encode all frames of the input as an animation.
+    pub animated: bool,
+    /// How often the animation plays, 0 for forever.
+    pub plays: u32,
+    /// Filter applied to the decoded pixels, e.g. to rotate them upright.
+    pub video_filter: Option<&'static str>,
+    /// Pixel format to encode, `None` lets the encoder pick one.
+    pub pixel_format: Option<&'static str>,
+    /// Colour metadata to write instead of the source's.
+    pub color_tags: color::ColorTags,
+    /// Print the output of the encoder as it runs.
+    pub verbose: bool,
+    /// Runs the tools the encoder needs.
+    pub runner: &'a dyn CommandRunner,
+}
+
+/// What an encoder has to say about a finished encode, for the report.
+#[derive(Default)]
+pub struct EncodeOutcome {
+    pub notes: Vec<String>,
+}
+
+pub type EncodeFuture<'a> =
+    Pin<Box<dyn Future<Output = anyhow::Result<EncodeOutcome>> + Send + 'a>>;
+
+/// Writes a JPEG XL file from a source image. Only the encoding itself:
+/// temporary names, output checks, timestamps and verification are done
+/// around it the same way whichever encoder runs.
+///
+/// An encoder that can't read its input fails with a [`DecodeError`], so
+/// the input can be decoded another way and encoded again.
+pub trait Encoder: Sync {
+    fn encode<'a>(
+        &'a self,
+        input: &'a Path,
+        output: &'a Path,
+        options: EncodeOptions<'a>,
+    ) -> EncodeFuture<'a>;
+}
+
+/// Decodes the input with ffmpeg and encodes it with libjxl.
+pub struct Ffmpeg;
+
+impl Encoder for Ffmpeg {
+    fn encode<'a>(
+        &'a self,
+        input: &'a Path,
+        output: &'a Path,
+        options: EncodeOptions<'a>,
+    ) -> EncodeFuture<'a> {
+        Box::pin(run_ffmpeg(input, output, options))
+    }
+}
+
+async fn run_ffmpeg(
+    input_path: &Path,
+    output_file_path: &Path,
+    options: EncodeOptions<'_>,
+) -> anyhow::Result<EncodeOutcome> {
+    // Convert the image to JXL format using ffmpeg.
+    let mut command = process::command("ffmpeg");
+    // Keep stderr down to the messages that explain a failure.
+    command.arg("-hide_banner").arg("-nostats");
+    if options.video_filter.is_some() {
+        // The filter does the rotation, ffmpeg must not rotate a second time.
+        command.arg("-noautorotate");
+    }
+    if let InputSelection::TiffPage(page) = options.selection {
+        // Decoder option of the TIFF decoder, pages are numbered from 1.
+        command.arg("-page").arg(page.to_string());
+    }
+    // Content and extension disagree, or the name would be read as a pattern,
+    // don't let ffmpeg go by the file name.
+    if let Some(format) = process::input_format(input_path, options.input_format).await {
+        command.arg("-f").arg(format);
+    }
+    let map = match options.selection {
+        InputSelection::Stream(index) => format!("0:{}", index),
+        InputSelection::Whole | InputSelection::TiffPage(_) => "0".to_string(),
+    };
+    command
+        .arg("-i")
+        .arg(process::file_arg(input_path))
+        .arg("-map")
+        .arg(map)
+        .arg("-c:v");
+    if options.animated {
+        // Keep every frame with its own duration instead of resampling to a fixed rate.
+        command
+            .arg("libjxl_anim")
+            .arg("-fps_mode")
+            .arg("passthrough");
+        // Forever is what the encoder writes without being told.
+        if options.plays > 0 {
+            command.arg("-loop").arg(options.plays.to_string());
+        }
+    } else {
+        command.arg("libjxl");
+    }
+    if let Some(distance) = options.distance {
+        command.arg("-distance").arg(distance.to_string());
+    }
+    if let Some(filter) = options.video_filter {
+        // The pixels end up upright, so the output must no longer ask for a
+        // rotation. The decoder hands the EXIF orientation on as a display
+        // matrix, which libjxl would write as the orientation of the image;
+        // the image2 muxer ignores `-metadata`, so only removing the matrix
+        // resets it.
+        command.arg("-vf").arg(format!(
+            "{},sidedata=mode=delete:type=DISPLAYMATRIX",
+            filter
+        ));
+    }
+    if let Some(pixel_format) = options.pixel_format {
+        command.arg("-pix_fmt").arg(pixel_format);
+    }
+    // Tags only, these don't make ffmpeg convert the pixels.
+    if let Some(primaries) = options.color_tags.primaries {
+        command.arg("-color_primaries").arg(primaries);
+    }
+    if let Some(trc) = options.color_tags.trc {
+        command.arg("-color_trc").arg(trc);
+    }
+    if let Some(matrix) = options.color_tags.matrix {
+        command.arg("-colorspace").arg(matrix);
+    }
+    command
+        // .arg("-lossless") // Lossless compression
+        .arg("-effort")
+        .arg(options.effort.to_string()) // Compression effort (1-9)
+        .arg("-threads")
+        .arg(options.threads.to_string())
+        .arg("-map_metadata")
+        .arg("0") // Copy metadata from input to output
+        // The output name need not end in .jxl, so don't let ffmpeg guess the muxer.
+        .arg("-f")
+        .arg("image2")
+        .arg("-update")
+        .arg("1")
+        .arg(process::file_arg(output_file_path));
+
+    let finished = options
+        .runner
+        .run(&mut command, options.verbose)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to run ffmpeg: {}", e))?;
+    if !finished.status.success() {
+        if let Some(line) = finished
+            .stderr_tail
+            .iter()
+            .find(|line| is_decode_error(line))
+        {
+            return Err(DecodeError(line.trim().to_string()).into());
+        }
+        return Err(anyhow::anyhow!(
+            "Failed to convert image: {}",
+            finished.message("ffmpeg failed without output")
+        ));
+    }
+    Ok(EncodeOutcome::default())
+}
+
+// What ffmpeg logs when it can't read the input, as opposed to failing to
+// encode it, in lower case: its versions capitalise some of them differently.
+const DECODE_ERRORS: &[&str] = &[
+    "invalid data found when processing input",
+    "could not find codec parameters",
+    "decoder not found",
+    "no decoder for",
+    "error while decoding",
+    "unknown input format",
+];
+
+fn is_decode_error(line: &str) -> bool {
+    let line = line.to_lowercase();
+    DECODE_ERRORS.iter().any(|error| line.contains(error))
+}
+
+/// The encoder failed to decode the input, which another decoder may still
+/// manage.
+#[derive(Debug)]
+pub struct DecodeError(String);
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ffmpeg cannot decode the input: {}", self.0)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Transcodes a JPEG to JPEG XL with cjxl without decoding it, keeping the
+/// data needed to restore the original file exactly. Takes only effort and
+/// threads from the options, the rest is fixed by the JPEG.
+pub struct JpegTranscode;
+
+impl Encoder for JpegTranscode {
+    fn encode<'a>(
+        &'a self,
+        input: &'a Path,
+        output: &'a Path,
+        options: EncodeOptions<'a>,
+    ) -> EncodeFuture<'a> {
+        Box::pin(transcode_jpeg(input, output, options))
+    }
+}
+
+async fn transcode_jpeg(
+    input_path: &Path,
+    output_file_path: &Path,
+    options: EncodeOptions<'_>,
+) -> anyhow::Result<EncodeOutcome> {
+    let finished = options
+        .runner
+        .run(
+            process::command("cjxl")
+                .arg(process::tool_arg(input_path))
+                .arg(process::tool_arg(output_file_path))
+                .arg("--lossless_jpeg=1")
+                .arg("--effort")
+                .arg(options.effort.to_string())
+                .arg("--num_threads")
+                .arg(options.threads.to_string()),
+            options.verbose,
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to run cjxl: {}", e))?;
+    if !finished.status.success() {
+        return Err(anyhow::anyhow!(
+            "Failed to transcode JPEG: {}",
+            finished.message("cjxl failed")
+        ));
+    }
+    Ok(EncodeOutcome::default())
+}
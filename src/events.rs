@@ -0,0 +1,748 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::mpsc::{Sender, error::TrySendError};
+
+use crate::converter::{Action, FileResult};
+
+/// How often the progress line is printed at most.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How often `--status-file` is written while the run goes on.
+pub const STATUS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// What a run tells about its progress, in the order it happens. The command
+/// line prints its progress lines from these too.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// Files found so far and their total size, which is only known when the
+    /// input was walked before the conversion. `complete` once the walk is
+    /// done, so `found` is the number of files the run will finish.
+    ScanProgress {
+        found: usize,
+        bytes: Option<u64>,
+        complete: bool,
+    },
+    /// How many conversions run at once, sent at the start and every time
+    /// `--adaptive-jobs` changes it.
+    Jobs {
+        jobs: usize,
+    },
+    /// Bytes per second that went through `--rate-limit` lately, and the
+    /// limit, sent before each finished file while one is set.
+    Throughput {
+        bytes_per_second: u64,
+        limit: u64,
+    },
+    FileStarted {
+        path: PathBuf,
+    },
+    /// No more files are started until [`Event::Resumed`]; `running` files
+    /// still finish, or wait stopped with `--pause-encoders`.
+    Paused {
+        running: usize,
+    },
+    Resumed,
+    FileFinished(FileResult),
+    /// A line the command line prints along the way, such as the overview,
+    /// a warning or the error of a file. `error` for the lines it prints to
+    /// the standard error. The summary it prints last comes as
+    /// [`Event::RunFinished`] instead.
+    Message {
+        text: String,
+        error: bool,
+    },
+    /// Sent once no more files will finish and the steps after them, such
+    /// as hard links, quarantine, `--strict` and `--fsync`, are done, also
+    /// when the run was stopped. Nothing follows it.
+    RunFinished {
+        summary: Summary,
+    },
+}
+
+/// The totals of a run, counted from its [`Event::FileFinished`] events.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Summary {
+    pub files: usize,
+    pub converted: usize,
+    pub copied: usize,
+    pub linked: usize,
+    pub kept_original: usize,
+    pub kept_both: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    /// The size of the converted sources.
+    pub original_size: u64,
+    /// The size of their conversions.
+    pub converted_size: u64,
+    /// Files that were running when a second Ctrl-C or the end of the
+    /// shutdown grace aborted them.
+    pub cancelled: usize,
+    /// How long the run worked, without the time it was paused.
+    pub duration: Duration,
+    pub paused: Duration,
+    /// The totals of each directory down to `--summary-depth`, in name order.
+    pub directories: Vec<DirectorySummary>,
+}
+
+/// The totals of the files in a directory of the input, and below it.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct DirectorySummary {
+    /// Relative to the input, `.` for the files directly in it.
+    pub directory: PathBuf,
+    pub files: usize,
+    pub converted: usize,
+    pub failed: usize,
+    /// The size of the converted sources.
+    pub original_size: u64,
+    /// The size of their conversions.
+    pub converted_size: u64,
+}
+
+/// What `--status-file` holds: where the run is, for a program watching it
+/// from elsewhere. Rewritten every [`STATUS_INTERVAL`] and once more at the
+/// end, with the complete summary.
+#[derive(Clone, Debug, Serialize)]
+pub struct Status {
+    #[serde(rename = "status")]
+    pub state: State,
+    /// Files found so far.
+    pub found: usize,
+    /// The files the run will finish, once the walk is done.
+    pub total: Option<usize>,
+    /// The totals so far, the final ones when the run is finished.
+    pub summary: Summary,
+    /// Converted source bytes per second of working time.
+    pub bytes_per_second: u64,
+    /// Working time left at the pace so far.
+    pub time_left: Option<Duration>,
+    /// The files being worked on, in name order.
+    pub running: Vec<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Running,
+    Paused,
+    Finished,
+}
+
+/// Keeps track of what goes into the status file between writes.
+struct StatusFile {
+    path: PathBuf,
+    found: usize,
+    total: Option<usize>,
+    running: BTreeSet<PathBuf>,
+}
+
+impl StatusFile {
+    fn update(&mut self, event: &Event) {
+        match event {
+            Event::ScanProgress {
+                found, complete, ..
+            } => {
+                self.found = *found;
+                if *complete {
+                    self.total = Some(*found);
+                }
+            }
+            Event::FileStarted { path } => {
+                self.running.insert(path.clone());
+            }
+            Event::FileFinished(result) => {
+                self.running.remove(&result.source);
+            }
+            _ => {}
+        }
+    }
+
+    /// Replaces the file through a temporary one next to it, so a reader
+    /// never sees half of it.
+    fn write(&self, status: &Status) -> std::io::Result<()> {
+        let mut temporary = self.path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let json = serde_json::to_vec_pretty(status)?;
+        std::fs::write(&temporary, json)?;
+        std::fs::rename(&temporary, &self.path)
+    }
+}
+
+/// Adds up the finished files per directory, cut off at a depth below the
+/// input, for the table after the summary.
+pub(crate) struct Breakdown {
+    input: PathBuf,
+    depth: usize,
+    directories: BTreeMap<PathBuf, DirectorySummary>,
+}
+
+impl Breakdown {
+    pub fn new(input: &Path, depth: usize) -> Self {
+        Breakdown {
+            input: input.to_owned(),
+            depth,
+            directories: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, result: &FileResult) {
+        if self.depth == 0 {
+            return;
+        }
+        let directory = result
+            .source
+            .strip_prefix(&self.input)
+            .ok()
+            .and_then(Path::parent)
+            .map(|parent| parent.components().take(self.depth).collect::<PathBuf>())
+            .filter(|directory| !directory.as_os_str().is_empty())
+            .unwrap_or_else(|| PathBuf::from("."));
+        let totals = self
+            .directories
+            .entry(directory)
+            .or_insert_with_key(|directory| DirectorySummary {
+                directory: directory.clone(),
+                ..Default::default()
+            });
+        totals.files += 1;
+        match result.action {
+            Action::Converted => totals.converted += 1,
+            Action::Failed => totals.failed += 1,
+            _ => {}
+        }
+        totals.original_size += result.original_size;
+        totals.converted_size += result.converted_size;
+    }
+
+    pub fn finish(self) -> Vec<DirectorySummary> {
+        self.directories.into_values().collect()
+    }
+}
+
+/// Prints the totals of each directory as a table.
+pub(crate) fn print_directories(directories: &[DirectorySummary], messages: &Messages) {
+    let width = directories
+        .iter()
+        .map(|totals| totals.directory.to_string_lossy().chars().count())
+        .max()
+        .unwrap_or(0)
+        .max("Directory".len());
+    messages.say("Per directory:".to_string());
+    messages.say(format!(
+        "  {:<width$}  {:>9}  {:>6}  {:>10}  {:>10}  {:>10}",
+        "Directory", "Converted", "Failed", "Original", "Converted", "Saved"
+    ));
+    let size = |bytes: u64| human_bytes::human_bytes(bytes as f64);
+    for totals in directories {
+        messages.say(format!(
+            "  {:<width$}  {:>9}  {:>6}  {:>10}  {:>10}  {:>10}",
+            totals.directory.to_string_lossy(),
+            format!("{}/{}", totals.converted, totals.files),
+            totals.failed,
+            size(totals.original_size),
+            size(totals.converted_size),
+            size(totals.original_size.saturating_sub(totals.converted_size)),
+        ));
+    }
+}
+
+impl Summary {
+    pub(crate) fn add(&mut self, result: &FileResult) {
+        self.files += 1;
+        match result.action {
+            Action::Converted => self.converted += 1,
+            Action::Copied => self.copied += 1,
+            Action::Linked => self.linked += 1,
+            Action::KeptOriginal => self.kept_original += 1,
+            Action::KeptBoth => self.kept_both += 1,
+            Action::Skipped => self.skipped += 1,
+            Action::Failed => self.failed += 1,
+        }
+        self.original_size += result.original_size;
+        self.converted_size += result.converted_size;
+    }
+}
+
+/// Where the lines of a run go: to the terminal, to the program using the
+/// library as [`Event::Message`], which go out with the next event, or
+/// nowhere.
+#[derive(Clone)]
+pub(crate) struct Messages {
+    target: Target,
+    /// Whether the per-file details of `--verbose` are wanted.
+    verbose: bool,
+    /// Whether printed lines all go to the standard error, which leaves the
+    /// standard output to `--print-outputs`.
+    stderr: bool,
+}
+
+#[derive(Clone)]
+enum Target {
+    Print,
+    Queue(Arc<Mutex<Vec<Event>>>),
+    Discard,
+}
+
+impl Messages {
+    pub fn print() -> Self {
+        Messages {
+            target: Target::Print,
+            verbose: false,
+            stderr: false,
+        }
+    }
+
+    pub fn discard() -> Self {
+        Messages {
+            target: Target::Discard,
+            verbose: false,
+            stderr: false,
+        }
+    }
+
+    pub fn verbose(self, verbose: bool) -> Self {
+        Messages { verbose, ..self }
+    }
+
+    /// Prints every line to the standard error.
+    pub fn only_stderr(self) -> Self {
+        Messages {
+            stderr: true,
+            ..self
+        }
+    }
+
+    pub fn is_verbose(&self) -> bool {
+        self.verbose
+    }
+
+    /// Whether the lines go to the terminal, and progress bars with them.
+    pub fn prints(&self) -> bool {
+        matches!(self.target, Target::Print)
+    }
+
+    pub fn say(&self, text: String) {
+        self.line(text, false);
+    }
+
+    /// A line the command line prints to the standard error.
+    pub fn error(&self, text: String) {
+        self.line(text, true);
+    }
+
+    fn line(&self, text: String, error: bool) {
+        match &self.target {
+            Target::Print if error || self.stderr => eprintln!("{}", text),
+            Target::Print => println!("{}", text),
+            Target::Queue(queue) => queue.lock().unwrap().push(Event::Message { text, error }),
+            Target::Discard => {}
+        }
+    }
+
+    /// The lines queued since the last call.
+    fn take(&self) -> Vec<Event> {
+        match &self.target {
+            Target::Queue(queue) => std::mem::take(&mut *queue.lock().unwrap()),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Where the events of a run go.
+pub(crate) struct Events {
+    sink: Sink,
+    summary: Summary,
+    started: Instant,
+    /// Since when the run is paused, if it is.
+    paused_at: Option<Instant>,
+    /// The time of the pauses that are over.
+    paused: Duration,
+    status: Option<StatusFile>,
+    messages: Messages,
+}
+
+enum Sink {
+    /// The progress lines of the command line.
+    Print(Progress),
+    /// A program using the library, see [`crate::Converter::run`], and the
+    /// newest scan tick that didn't fit in the channel, to go out when there
+    /// is room.
+    Channel(Sender<Event>, Option<Event>),
+}
+
+impl Events {
+    pub fn print() -> Self {
+        Self::new(Sink::Print(Progress::default()), Messages::print())
+    }
+
+    pub fn channel(sender: Sender<Event>) -> Self {
+        let messages = Messages {
+            target: Target::Queue(Arc::default()),
+            verbose: false,
+            stderr: false,
+        };
+        Self::new(Sink::Channel(sender, None), messages)
+    }
+
+    fn new(sink: Sink, messages: Messages) -> Self {
+        Events {
+            sink,
+            summary: Summary::default(),
+            started: Instant::now(),
+            paused_at: None,
+            paused: Duration::ZERO,
+            status: None,
+            messages,
+        }
+    }
+
+    /// Where the run puts the lines it prints, so they arrive in order with
+    /// the events.
+    pub fn messages(&self) -> Messages {
+        self.messages.clone()
+    }
+
+    /// Prints the progress lines to the standard error, like the messages
+    /// of [`Messages::only_stderr`].
+    pub fn only_stderr(&mut self) {
+        self.messages = self.messages.clone().only_stderr();
+    }
+
+    /// Keeps `path` up to date with the [`Status`] of the run.
+    pub fn status_file(&mut self, path: &Path) {
+        self.status = Some(StatusFile {
+            path: path.to_owned(),
+            found: 0,
+            total: None,
+            running: BTreeSet::new(),
+        });
+    }
+
+    /// Writes the status file, if there is one.
+    pub fn write_status(&self) {
+        let Some(file) = &self.status else {
+            return;
+        };
+        let paused = self.paused();
+        let working = self.started.elapsed().saturating_sub(paused);
+        let mut summary = self.summary.clone();
+        summary.paused = paused;
+        summary.duration = working;
+        let state = if self.paused_at.is_some() {
+            State::Paused
+        } else {
+            State::Running
+        };
+        let status = Status {
+            state,
+            found: file.found,
+            total: file.total,
+            bytes_per_second: (summary.original_size as f64
+                / working.as_secs_f64().max(f64::EPSILON)) as u64,
+            time_left: file
+                .total
+                .and_then(|total| time_left(working, summary.files, total))
+                .filter(|_| state == State::Running),
+            summary,
+            running: file.running.iter().cloned().collect(),
+        };
+        if let Err(e) = file.write(&status) {
+            self.messages.say(format!(
+                "   Warning: writing {}: {}",
+                file.path.display(),
+                e
+            ));
+        }
+    }
+
+    /// The time paused so far.
+    fn paused(&self) -> Duration {
+        self.paused + self.paused_at.map_or(Duration::ZERO, |at| at.elapsed())
+    }
+
+    pub async fn send(&mut self, event: Event) {
+        match &event {
+            Event::FileFinished(result) => self.summary.add(result),
+            Event::Paused { .. } => {
+                self.paused_at.get_or_insert_with(Instant::now);
+            }
+            Event::Resumed => {
+                if let Some(at) = self.paused_at.take() {
+                    self.paused += at.elapsed();
+                }
+            }
+            _ => {}
+        }
+        if let Some(status) = &mut self.status {
+            status.update(&event);
+        }
+        let working = self.started.elapsed().saturating_sub(self.paused());
+        match &mut self.sink {
+            Sink::Print(progress) => progress.show(&event, working, &self.messages),
+            Sink::Channel(sender, waiting) => {
+                // The lines printed since the last event came before it.
+                for message in self.messages.take() {
+                    let _ = sender.send(message).await;
+                }
+                // Scan ticks come faster than a slow reader takes them. When
+                // the channel is full the oldest waiting tick gives way to the
+                // newer one, which says more; the final tick always arrives.
+                if let Some(tick) = waiting.take()
+                    && let Err(TrySendError::Full(tick)) = sender.try_send(tick)
+                {
+                    *waiting = Some(tick);
+                }
+                match event {
+                    Event::ScanProgress {
+                        complete: false, ..
+                    } => {
+                        if let Err(TrySendError::Full(tick)) = sender.try_send(event) {
+                            *waiting = Some(tick);
+                        }
+                    }
+                    event => {
+                        if matches!(event, Event::ScanProgress { .. }) {
+                            *waiting = None;
+                        }
+                        // Nobody listening any more is no reason to stop.
+                        let _ = sender.send(event).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Ends the run with its summary.
+    pub async fn finish(mut self, cancelled: usize, directories: Vec<DirectorySummary>) -> Summary {
+        let mut summary = std::mem::take(&mut self.summary);
+        summary.cancelled = cancelled;
+        summary.directories = directories;
+        summary.paused = self.paused();
+        summary.duration = self.started.elapsed().saturating_sub(summary.paused);
+        if let Some(file) = &self.status {
+            let status = Status {
+                state: State::Finished,
+                found: file.found,
+                total: file.total,
+                bytes_per_second: (summary.original_size as f64
+                    / summary.duration.as_secs_f64().max(f64::EPSILON))
+                    as u64,
+                time_left: None,
+                summary: summary.clone(),
+                running: file.running.iter().cloned().collect(),
+            };
+            if let Err(e) = file.write(&status) {
+                self.messages.say(format!(
+                    "   Warning: writing {}: {}",
+                    file.path.display(),
+                    e
+                ));
+            }
+        }
+        self.send(Event::RunFinished {
+            summary: summary.clone(),
+        })
+        .await;
+        summary
+    }
+}
+
+/// The progress line, kept up to date from the events.
+#[derive(Default)]
+struct Progress {
+    completed: usize,
+    found: usize,
+    total: Option<usize>,
+    jobs: Option<usize>,
+    /// Bytes per second lately and the limit, with --rate-limit.
+    throughput: Option<(u64, u64)>,
+    /// The size of the sources converted so far, and of their conversions.
+    original_size: u64,
+    converted_size: u64,
+    paused: bool,
+    /// How long the run worked until the last event, for the time left.
+    working: Duration,
+    /// The count of the last line printed.
+    shown: usize,
+    last: Option<Instant>,
+}
+
+impl Progress {
+    fn show(&mut self, event: &Event, working: Duration, messages: &Messages) {
+        self.working = working;
+        match event {
+            Event::ScanProgress {
+                found, complete, ..
+            } => {
+                self.found = *found;
+                if *complete {
+                    self.total = Some(*found);
+                }
+            }
+            Event::Jobs { jobs } => self.jobs = Some(*jobs),
+            Event::Throughput {
+                bytes_per_second,
+                limit,
+            } => self.throughput = Some((*bytes_per_second, *limit)),
+            Event::FileStarted { .. } | Event::Message { .. } => {}
+            Event::Paused { .. } => self.paused = true,
+            Event::Resumed => self.paused = false,
+            Event::FileFinished(result) => {
+                self.completed += 1;
+                self.original_size += result.original_size;
+                self.converted_size += result.converted_size;
+                // Printed a few times a second at most, as each line is a
+                // write of its own when the output is piped.
+                if self.last.is_none_or(|at| at.elapsed() >= PROGRESS_INTERVAL)
+                    || self.total == Some(self.completed)
+                {
+                    self.print(messages);
+                }
+            }
+            Event::RunFinished { .. } => {
+                if self.shown != self.completed {
+                    self.print(messages);
+                }
+            }
+        }
+    }
+
+    fn print(&mut self, messages: &Messages) {
+        let mut suffix = self
+            .jobs
+            .map(|jobs| format!(" ({} conversions at a time)", jobs))
+            .unwrap_or_default();
+        if self.original_size > 0 {
+            let size = |bytes: u64| human_bytes::human_bytes(bytes as f64);
+            let change = match self.original_size.checked_sub(self.converted_size) {
+                Some(saved) => format!("saved {}", size(saved)),
+                None => format!("grew {}", size(self.converted_size - self.original_size)),
+            };
+            suffix.push_str(&format!(
+                ", {} ({} to {}, {:.1}%)",
+                change,
+                size(self.original_size),
+                size(self.converted_size),
+                self.converted_size as f64 * 100.0 / self.original_size as f64
+            ));
+        }
+        if let Some((throughput, limit)) = self.throughput {
+            suffix.push_str(&format!(
+                ", {}/s of {}/s",
+                human_bytes::human_bytes(throughput as f64),
+                human_bytes::human_bytes(limit as f64)
+            ));
+        }
+        let state = if self.paused { "PAUSED, " } else { "" };
+        match self.total {
+            Some(total) => {
+                if !self.paused
+                    && let Some(left) = time_left(self.working, self.completed, total)
+                {
+                    suffix.push_str(&format!(", about {} left", format_duration(left)));
+                }
+                say!(
+                    messages,
+                    "Progress: {}{}/{} files processed{}",
+                    state,
+                    self.completed,
+                    total,
+                    suffix
+                )
+            }
+            None => say!(
+                messages,
+                "Progress: {}{} done, scan in progress ({} found so far){}",
+                state,
+                self.completed,
+                self.found,
+                suffix
+            ),
+        }
+        self.last = Some(Instant::now());
+        self.shown = self.completed;
+    }
+}
+
+/// The working time the files left will take, from the pace so far, which
+/// doesn't count the pauses.
+fn time_left(working: Duration, completed: usize, total: usize) -> Option<Duration> {
+    (completed > 0 && completed < total)
+        .then(|| working.mul_f64((total - completed) as f64 / completed as f64))
+}
+
+/// `duration` in whole seconds, as `1h 02m`, `3m 05s` or `12s`.
+pub(crate) fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {:02}s", m, s),
+        (h, m, _) => format!("{}h {:02}m", h, m),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tick(found: usize, complete: bool) -> Event {
+        Event::ScanProgress {
+            found,
+            bytes: None,
+            complete,
+        }
+    }
+
+    fn found(event: &Event) -> Option<(usize, bool)> {
+        match event {
+            Event::ScanProgress {
+                found, complete, ..
+            } => Some((*found, *complete)),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn a_full_channel_keeps_the_newest_tick() {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(2);
+        let mut events = Events::channel(sender);
+        // Two fit, 3 gives way to 4.
+        for n in 1..=4 {
+            events.send(tick(n, false)).await;
+        }
+        assert_eq!(found(&receiver.recv().await.unwrap()), Some((1, false)));
+        assert_eq!(found(&receiver.recv().await.unwrap()), Some((2, false)));
+        // The waiting tick goes out with the next event.
+        events.send(Event::Jobs { jobs: 2 }).await;
+        assert_eq!(found(&receiver.recv().await.unwrap()), Some((4, false)));
+        assert!(matches!(
+            receiver.recv().await.unwrap(),
+            Event::Jobs { jobs: 2 }
+        ));
+    }
+
+    #[tokio::test]
+    async fn the_final_tick_always_arrives() {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+        let mut events = Events::channel(sender);
+        let sending = tokio::spawn(async move {
+            for n in 1..=5 {
+                events.send(tick(n, false)).await;
+            }
+            // Waits for room instead of being dropped.
+            events.send(tick(5, true)).await;
+        });
+        let mut received = Vec::new();
+        while let Some(event) = receiver.recv().await {
+            received.extend(found(&event));
+        }
+        sending.await.unwrap();
+        assert_eq!(received, [(1, false), (5, true)]);
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    cell::RefCell,
+    ffi::{OsStr, OsString},
+    fmt::Write as _,
+    io::Read,
+    path::{Component, Path, PathBuf},
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use crate::{
+    FileResult,
+    process::{CommandRunner, Finished},
+};
+
+// Long enough for any relative path a bug report needs, short enough for
+// the name limit of every filesystem with the extension added.
+const MAX_NAME_LEN: usize = 200;
+
+tokio::task_local! {
+    /// The commands the current task ran, with what came of them.
+    pub static COMMANDS: RefCell<Vec<Ran>>;
+}
+
+/// A command a conversion ran, for `--error-dir`.
+pub struct Ran {
+    pub command: Vec<OsString>,
+    /// Its exit status, or why it couldn't be started.
+    pub outcome: Result<std::process::ExitStatus, String>,
+    pub stderr_tail: Vec<String>,
+    pub duration: Duration,
+}
+
+/// Runs the commands with another runner and keeps them in [`COMMANDS`], so
+/// a failure can be written down with everything that led to it.
+pub struct Capture(pub std::sync::Arc<dyn CommandRunner>);
+
+impl CommandRunner for Capture {
+    fn run<'a>(
+        &'a self,
+        command: &'a mut tokio::process::Command,
+        verbose: bool,
+    ) -> std::pin::Pin<Box<dyn Future<Output = std::io::Result<Finished>> + Send + 'a>> {
+        Box::pin(async move {
+            let recorded = {
+                let command = command.as_std();
+                std::iter::once(command.get_program())
+                    .chain(command.get_args())
+                    .map(OsStr::to_os_string)
+                    .collect()
+            };
+            let started = Instant::now();
+            let finished = self.0.run(command, verbose).await;
+            let ran = Ran {
+                command: recorded,
+                outcome: match &finished {
+                    Ok(finished) => Ok(finished.status),
+                    Err(e) => Err(e.to_string()),
+                },
+                stderr_tail: finished
+                    .as_ref()
+                    .map(|finished| finished.stderr_tail.clone())
+                    .unwrap_or_default(),
+                duration: started.elapsed(),
+            };
+            // Commands run outside of a file's task aren't kept.
+            let _ = COMMANDS.try_with(|commands| commands.borrow_mut().push(ran));
+            finished
+        })
+    }
+}
+
+/// The name the files of a failed source get in `--error-dir`: its path
+/// relative to the input with the directories joined by `__`, and anything
+/// a filesystem may not take replaced by `_`.
+fn name(relative: &Path) -> String {
+    let mut name = String::new();
+    for component in relative.components() {
+        let Component::Normal(part) = component else {
+            continue;
+        };
+        if !name.is_empty() {
+            name.push_str("__");
+        }
+        name.extend(part.to_string_lossy().chars().map(|c| {
+            if c.is_control() || r#"<>:"/\|?*"#.contains(c) {
+                '_'
+            } else {
+                c
+            }
+        }));
+    }
+    if name.len() > MAX_NAME_LEN {
+        // The end has the file name, which says the most.
+        let mut start = name.len() - MAX_NAME_LEN;
+        while !name.is_char_boundary(start) {
+            start += 1;
+        }
+        name.replace_range(..start, "");
+    }
+    name
+}
+
+/// Writes what's known about a failed file to `<name>.txt` in `dir`: the
+/// error, how long it took, the source and every command it ran with its
+/// exit status and the end of its stderr. With `sample_bytes` the start of
+/// the source is copied next to it as `<name>.sample`.
+pub fn write(
+    dir: &Path,
+    relative: &Path,
+    failed: &FileResult,
+    commands: &[Ran],
+    sample_bytes: Option<u64>,
+) -> std::io::Result<PathBuf> {
+    let name = name(relative);
+    let source = &failed.source;
+    let mut text = String::new();
+    let _ = writeln!(text, "Source:      {}", source.display());
+    if let Some(output) = &failed.output {
+        let _ = writeln!(text, "Output:      {}", output.display());
+    }
+    for note in &failed.notes {
+        let _ = writeln!(
+            text,
+            "Error:       {}",
+            note.replace('\n', "\n             ")
+        );
+    }
+    let _ = writeln!(text, "Time:        {:.2?}", failed.duration);
+    match std::fs::metadata(source) {
+        Ok(metadata) => {
+            let _ = writeln!(text, "Size:        {} bytes", metadata.len());
+            if let Some(modified) = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            {
+                let _ = writeln!(
+                    text,
+                    "Modified:    {} UTC",
+                    crate::history::date(modified.as_secs())
+                );
+            }
+        }
+        Err(e) => {
+            let _ = writeln!(text, "Size:        unknown ({})", e);
+        }
+    }
+    let content = match crate::sniff::detect(source) {
+        Ok(Some(kind)) => kind.extensions()[0].to_string(),
+        Ok(None) => "not recognised".to_string(),
+        Err(e) => format!("unreadable ({})", e),
+    };
+    let _ = writeln!(text, "Content:     {}", content);
+    if let Ok(size) = imagesize::size(source) {
+        let _ = writeln!(text, "Dimensions:  {}x{}", size.width, size.height);
+    }
+    if let Some(bytes) = sample_bytes {
+        let sample = dir.join(format!("{}.sample", name));
+        let mut head = Vec::new();
+        let copied = std::fs::File::open(source)
+            .and_then(|file| file.take(bytes).read_to_end(&mut head))
+            .and_then(|_| std::fs::write(&sample, &head));
+        let _ = match copied {
+            Ok(()) => writeln!(
+                text,
+                "Sample:      the first {} bytes in {}",
+                head.len(),
+                sample.display()
+            ),
+            Err(e) => writeln!(text, "Sample:      not copied ({})", e),
+        };
+    }
+    if commands.is_empty() {
+        let _ = writeln!(text, "\nNo command was run.");
+    }
+    for ran in commands {
+        let mut line = Vec::new();
+        for (index, word) in ran.command.iter().enumerate() {
+            if index > 0 {
+                line.push(b' ');
+            }
+            line.extend_from_slice(&crate::script::quote_sh(word));
+        }
+        let outcome = match &ran.outcome {
+            Ok(status) => status.to_string(),
+            Err(e) => format!("not started: {}", e),
+        };
+        let _ = writeln!(
+            text,
+            "\n$ {}\n{} after {:.2?}",
+            String::from_utf8_lossy(&line),
+            outcome,
+            ran.duration
+        );
+        for line in &ran.stderr_tail {
+            let _ = writeln!(text, "| {}", line);
+        }
+    }
+    let path = dir.join(format!("{}.txt", name));
+    std::fs::write(&path, text)?;
+    Ok(path)
+}
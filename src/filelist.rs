@@ -0,0 +1,122 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+};
+
+/// The longest record read, well above the longest path any platform takes,
+/// so a newline-separated list read with `--null` isn't read whole as one
+/// path.
+const MAX_RECORD: usize = 64 << 10;
+
+/// The paths of `--files-from`, read a record at a time so huge lists are
+/// never held whole. Records are separated by newlines, or with `--null` by
+/// NUL bytes as `find -print0` and `fd -0` write them; empty records are
+/// left out.
+pub struct FileList {
+    reader: Box<dyn BufRead>,
+    null: bool,
+    /// Whether a separator was found yet.
+    separated: bool,
+    /// The record read last, reused for the next.
+    record: Vec<u8>,
+}
+
+impl FileList {
+    /// Opens the list at `source`, `-` for the standard input.
+    pub fn open(source: &str, null: bool) -> anyhow::Result<Self> {
+        let reader: Box<dyn BufRead> = if source == "-" {
+            Box::new(BufReader::new(std::io::stdin()))
+        } else {
+            let file = File::open(source)
+                .map_err(|e| anyhow::anyhow!("Opening the file list {}: {}", source, e))?;
+            Box::new(BufReader::new(file))
+        };
+        Ok(FileList {
+            reader,
+            null,
+            separated: false,
+            record: Vec::new(),
+        })
+    }
+
+    /// Reads the next record into `record`, without its separator. Returns
+    /// whether the separator ended it, rather than the end of the list or
+    /// [`MAX_RECORD`].
+    fn read_record(&mut self, separator: u8) -> std::io::Result<bool> {
+        self.record.clear();
+        while self.record.len() <= MAX_RECORD {
+            let buffer = match self.reader.fill_buf() {
+                Ok(buffer) => buffer,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if buffer.is_empty() {
+                return Ok(false);
+            }
+            match buffer.iter().position(|&byte| byte == separator) {
+                Some(end) => {
+                    self.record.extend_from_slice(&buffer[..end]);
+                    self.reader.consume(end + 1);
+                    return Ok(true);
+                }
+                None => {
+                    let read = buffer.len();
+                    self.record.extend_from_slice(buffer);
+                    self.reader.consume(read);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    fn read(&mut self) -> anyhow::Result<Option<PathBuf>> {
+        let separator = if self.null { b'\0' } else { b'\n' };
+        loop {
+            let ended = self.read_record(separator)?;
+            self.separated |= ended;
+            if !self.null && self.record.last() == Some(&b'\r') {
+                self.record.pop();
+            }
+            if self.record.is_empty() {
+                if ended {
+                    continue;
+                }
+                return Ok(None);
+            }
+            if self.null
+                && self.record.contains(&b'\n')
+                && (!self.separated || self.record.len() > MAX_RECORD)
+            {
+                return Err(anyhow::anyhow!(
+                    "The file list looks newline-separated, but --null expects NUL-separated \
+                     paths as `find -print0` writes them; leave out --null for a list with a \
+                     path per line"
+                ));
+            }
+            if self.record.len() > MAX_RECORD {
+                return Err(anyhow::anyhow!(
+                    "A path in the file list is longer than {} bytes",
+                    MAX_RECORD
+                ));
+            }
+            if !self.null && self.record.contains(&b'\0') {
+                return Err(anyhow::anyhow!(
+                    "The file list contains NUL bytes; use --null for paths separated by them, \
+                     as `find -print0` writes them"
+                ));
+            }
+            return Ok(Some(crate::report::path_from_bytes(std::mem::take(
+                &mut self.record,
+            ))));
+        }
+    }
+}
+
+impl Iterator for FileList {
+    type Item = anyhow::Result<PathBuf>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read().transpose()
+    }
+}
@@ -0,0 +1,86 @@
+use ignore::{
+    Match,
+    gitignore::{Gitignore, GitignoreBuilder},
+};
+
+use crate::events::Messages;
+
+/// The .gitignore rules in effect while walking the input for
+/// `--respect-gitignore`: the global excludes, then a matcher for every
+/// directory on the way down that has a .gitignore. The walk goes depth
+/// first, so the directories of the entry being looked at are always the
+/// last ones on the stack.
+pub struct Stack {
+    global: Gitignore,
+    /// Matchers with the depth of their directory, the innermost last.
+    dirs: Vec<(usize, Gitignore)>,
+    /// Where the rules that can't be read are reported.
+    messages: Messages,
+}
+
+impl Stack {
+    pub fn new(messages: &Messages) -> Self {
+        let (global, error) = Gitignore::global();
+        if let Some(e) = error {
+            messages.say(format!("   Warning: global gitignore: {}", e));
+        }
+        Stack {
+            global,
+            dirs: Vec::new(),
+            messages: messages.clone(),
+        }
+    }
+
+    /// Whether the rules ignore `entry`. Its parent directories were entered
+    /// already, and weren't ignored themselves.
+    pub fn ignores(&mut self, entry: &walkdir::DirEntry) -> bool {
+        while self
+            .dirs
+            .last()
+            .is_some_and(|(depth, _)| *depth >= entry.depth())
+        {
+            self.dirs.pop();
+        }
+        let is_dir = entry.file_type().is_dir();
+        // A deeper .gitignore overrides the ones above it, as in git.
+        let matched = self
+            .dirs
+            .iter()
+            .rev()
+            .map(|(_, rules)| rules.matched(entry.path(), is_dir))
+            .chain([self.global.matched(entry.path(), is_dir)])
+            .find(|matched| !matched.is_none());
+        matches!(matched, Some(Match::Ignore(_)))
+    }
+
+    /// Reads the rules of the directory `entry` the walk goes into next. At
+    /// the input itself, the repository's own excludes count as well.
+    pub fn enter(&mut self, entry: &walkdir::DirEntry) {
+        let dir = entry.path();
+        let mut builder = GitignoreBuilder::new(dir);
+        // The later file wins where both match, like .gitignore over the
+        // excludes in git.
+        let mut files = Vec::new();
+        if entry.depth() == 0 {
+            files.push(dir.join(".git/info/exclude"));
+        }
+        files.push(dir.join(".gitignore"));
+        let mut found = false;
+        for file in files.iter().filter(|file| file.is_file()) {
+            found = true;
+            if let Some(e) = builder.add(file) {
+                self.messages
+                    .say(format!("   Warning: {}: {}", file.display(), e));
+            }
+        }
+        if !found {
+            return;
+        }
+        match builder.build() {
+            Ok(rules) => self.dirs.push((entry.depth(), rules)),
+            Err(e) => self
+                .messages
+                .say(format!("   Warning: {}: {}", dir.display(), e)),
+        }
+    }
+}
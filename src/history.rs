@@ -0,0 +1,414 @@
+use std::{
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Summary,
+    events::{Messages, format_duration},
+};
+
+const FILE_NAME: &str = "history.jsonl";
+
+/// Past this size the oldest runs are folded into one line.
+const COMPACT_SIZE: u64 = 512 * 1024;
+
+/// The runs compaction leaves as they are, the newest.
+const KEEP_RUNS: usize = 500;
+
+/// How long a run waits for another one writing the history before it
+/// leaves itself out.
+const LOCK_WAIT: Duration = Duration::from_secs(2);
+
+/// Prints the totals of the runs so far, from the history every run adds
+/// itself to.
+#[derive(clap::Args)]
+pub struct StatsArgs {
+    /// List the runs one by one before the totals
+    #[clap(long)]
+    history: bool,
+}
+
+/// A line of the history: a finished run, or the oldest runs folded into
+/// one when the file was compacted.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Entry {
+    /// When the run ended, in seconds since the Unix epoch. For folded runs
+    /// when the first of them ended.
+    time: u64,
+    /// How many runs the line stands for.
+    runs: usize,
+    input: PathBuf,
+    output: PathBuf,
+    /// The encoder settings, as in the report.
+    settings: String,
+    files: usize,
+    converted: usize,
+    copied: usize,
+    skipped: usize,
+    failed: usize,
+    cancelled: usize,
+    original_size: u64,
+    converted_size: u64,
+    /// How long the run worked, in seconds.
+    seconds: f64,
+}
+
+impl Entry {
+    fn add(&mut self, other: &Entry) {
+        if self.time == 0 || other.time < self.time {
+            self.time = other.time;
+        }
+        self.runs += other.runs;
+        self.files += other.files;
+        self.converted += other.converted;
+        self.copied += other.copied;
+        self.skipped += other.skipped;
+        self.failed += other.failed;
+        self.cancelled += other.cancelled;
+        self.original_size += other.original_size;
+        self.converted_size += other.converted_size;
+        self.seconds += other.seconds;
+    }
+}
+
+/// Where the history is kept: `$XDG_STATE_HOME/bulk-jxl`, or
+/// `~/.local/state/bulk-jxl`, and `%LOCALAPPDATA%\bulk-jxl` on Windows.
+fn path() -> Option<PathBuf> {
+    let dir = if cfg!(windows) {
+        PathBuf::from(std::env::var_os("LOCALAPPDATA")?)
+    } else if let Some(dir) = std::env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+        PathBuf::from(dir)
+    } else {
+        PathBuf::from(std::env::var_os("HOME")?).join(".local/state")
+    };
+    Some(dir.join("bulk-jxl").join(FILE_NAME))
+}
+
+/// Adds a finished run to the history. Quietly does nothing when the
+/// history can't be written, as it's no reason to fail a run.
+pub fn record(input: &Path, output: &Path, settings: String, summary: &Summary) {
+    let Some(path) = path() else {
+        return;
+    };
+    let entry = Entry {
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        runs: 1,
+        input: std::path::absolute(input).unwrap_or_else(|_| input.to_path_buf()),
+        output: std::path::absolute(output).unwrap_or_else(|_| output.to_path_buf()),
+        settings,
+        files: summary.files,
+        converted: summary.converted,
+        copied: summary.copied,
+        skipped: summary.skipped,
+        failed: summary.failed,
+        cancelled: summary.cancelled,
+        original_size: summary.original_size,
+        converted_size: summary.converted_size,
+        seconds: summary.duration.as_secs_f64(),
+    };
+    let _ = append(&path, &entry);
+}
+
+fn append(path: &Path, entry: &Entry) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let Some(_lock) = HistoryLock::acquire(path)? else {
+        return Ok(());
+    };
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    file.write_all(&line)?;
+    if file.metadata()?.len() > COMPACT_SIZE {
+        compact(path)?;
+    }
+    Ok(())
+}
+
+/// Folds all but the newest [`KEEP_RUNS`] lines into one, rewriting the
+/// history through a temporary file so a reader never sees half of it.
+fn compact(path: &Path) -> std::io::Result<()> {
+    let mut entries = read(path)?;
+    if entries.len() <= KEEP_RUNS {
+        return Ok(());
+    }
+    let newest = entries.split_off(entries.len() - KEEP_RUNS);
+    let mut folded = Entry {
+        output: PathBuf::from("(earlier runs)"),
+        ..Entry::default()
+    };
+    for entry in &entries {
+        folded.add(entry);
+    }
+    let mut text = Vec::new();
+    for entry in std::iter::once(&folded).chain(&newest) {
+        serde_json::to_writer(&mut text, entry)?;
+        text.push(b'\n');
+    }
+    let temporary = path.with_extension("jsonl.tmp");
+    std::fs::write(&temporary, text)?;
+    std::fs::rename(&temporary, path)
+}
+
+/// The runs of the history, oldest first. Lines that can't be read, like
+/// one a full disk cut short, are left out.
+fn read(path: &Path) -> std::io::Result<Vec<Entry>> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut entries = Vec::new();
+    for line in std::io::BufReader::new(file).lines() {
+        if let Ok(entry) = serde_json::from_str::<Entry>(&line?) {
+            entries.push(entry);
+        }
+    }
+    entries.sort_by_key(|entry| entry.time);
+    Ok(entries)
+}
+
+/// Keeps other runs from writing the history at the same time, so a
+/// compaction doesn't lose the line of a run that ends meanwhile. Locked the
+/// way the output tree is, so one a crashed run held is free again.
+struct HistoryLock {
+    path: PathBuf,
+    _file: std::fs::File,
+}
+
+impl HistoryLock {
+    /// Takes the lock, or returns `None` if another run holds it for longer
+    /// than [`LOCK_WAIT`].
+    fn acquire(history: &Path) -> std::io::Result<Option<Self>> {
+        let path = history.with_extension("jsonl.lock");
+        let started = std::time::Instant::now();
+        loop {
+            // Quietly, as the history is written.
+            if let Some(file) = crate::lock::take(&path, &Messages::discard())? {
+                return Ok(Some(HistoryLock { path, _file: file }));
+            }
+            if started.elapsed() > LOCK_WAIT {
+                return Ok(None);
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+impl Drop for HistoryLock {
+    fn drop(&mut self) {
+        // While still locked, as for the lock of the output tree.
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+pub fn run(args: StatsArgs, messages: &Messages) -> anyhow::Result<()> {
+    let path = path().ok_or_else(|| anyhow::anyhow!("No directory to keep the history in"))?;
+    let entries = read(&path).map_err(|e| anyhow::anyhow!("Reading {}: {}", path.display(), e))?;
+    let mut stats = Vec::new();
+    write_stats(&mut stats, &path, &entries, args.history)?;
+    for line in String::from_utf8_lossy(&stats).lines() {
+        say!(messages, "{}", line);
+    }
+    Ok(())
+}
+
+/// Writes the totals of the history at `path`, which holds `entries`, and
+/// with `history` the runs one by one before them.
+fn write_stats(
+    out: &mut impl Write,
+    path: &Path,
+    entries: &[Entry],
+    history: bool,
+) -> std::io::Result<()> {
+    if entries.is_empty() {
+        writeln!(out, "No runs in {} yet.", path.display())?;
+        return Ok(());
+    }
+    let size = |bytes: u64| human_bytes::human_bytes(bytes as f64);
+    if history {
+        writeln!(
+            out,
+            "{:<16}  {:>7}  {:>9}  {:>6}  {:>10}  {:>10}  {:>8}  Settings / Output",
+            "Ended (UTC)", "Files", "Converted", "Failed", "Original", "JXL", "Time"
+        )?;
+        for entry in entries {
+            let ended = if entry.runs == 1 {
+                date(entry.time)
+            } else {
+                format!("{} runs", entry.runs)
+            };
+            writeln!(
+                out,
+                "{:<16}  {:>7}  {:>9}  {:>6}  {:>10}  {:>10}  {:>8}  {} / {}",
+                ended,
+                entry.files,
+                entry.converted,
+                entry.failed,
+                size(entry.original_size),
+                size(entry.converted_size),
+                format_duration(Duration::from_secs_f64(entry.seconds)),
+                if entry.settings.is_empty() {
+                    "-"
+                } else {
+                    &entry.settings
+                },
+                entry.output.display()
+            )?;
+        }
+        writeln!(out, "{}", "-".repeat(60))?;
+    }
+    let mut totals = Entry::default();
+    for entry in entries {
+        totals.add(entry);
+    }
+    writeln!(out, "Totals of {}:", path.display())?;
+    writeln!(
+        out,
+        "  Runs:                  {} (since {})",
+        totals.runs,
+        date(totals.time)
+    )?;
+    writeln!(out, "  Files processed:       {}", totals.files)?;
+    writeln!(out, "  Files converted:       {}", totals.converted)?;
+    writeln!(out, "  Files copied:          {}", totals.copied)?;
+    writeln!(out, "  Files skipped:         {}", totals.skipped)?;
+    writeln!(out, "  Files with errors:     {}", totals.failed)?;
+    if totals.cancelled > 0 {
+        writeln!(out, "  Files cancelled:       {}", totals.cancelled)?;
+    }
+    writeln!(
+        out,
+        "  Total original size (converted files): {}",
+        size(totals.original_size)
+    )?;
+    writeln!(
+        out,
+        "  Total converted size (converted files): {}",
+        size(totals.converted_size)
+    )?;
+    let saved = totals.original_size.saturating_sub(totals.converted_size);
+    if totals.original_size > 0 {
+        writeln!(
+            out,
+            "  Total storage saved (converted files): {} ({:.1}%)",
+            size(saved),
+            saved as f64 * 100.0 / totals.original_size as f64
+        )?;
+    }
+    writeln!(
+        out,
+        "  Time converting:       {}",
+        format_duration(Duration::from_secs_f64(totals.seconds))
+    )?;
+    writeln!(out, "{}", "-".repeat(60))
+}
+
+/// `seconds` since the Unix epoch as a UTC date and time, e.g.
+/// `2024-03-09 17:05`.
+pub fn date(seconds: u64) -> String {
+    let (days, time) = (seconds / 86400, seconds % 86400);
+    // Days to a civil date, after Howard Hinnant's `civil_from_days`.
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(time: u64, runs: usize, converted: usize, sizes: (u64, u64)) -> Entry {
+        Entry {
+            time,
+            runs,
+            output: PathBuf::from("/out"),
+            files: converted + 1,
+            converted,
+            skipped: 1,
+            original_size: sizes.0,
+            converted_size: sizes.1,
+            seconds: 30.0,
+            ..Entry::default()
+        }
+    }
+
+    #[test]
+    fn totals_add_up_every_run_folded_ones_included() {
+        let entries = [
+            // Two runs folded by a compaction, then two more.
+            entry(1_709_000_000, 2, 10, (4096, 1024)),
+            entry(1_710_003_900, 1, 5, (3072, 1024)),
+            entry(1_710_000_000, 1, 0, (0, 0)),
+        ];
+        let mut out = Vec::new();
+        write_stats(&mut out, Path::new("history.jsonl"), &entries, false).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        for line in [
+            "Totals of history.jsonl:",
+            "  Runs:                  4 (since 2024-02-27 02:13)",
+            "  Files processed:       18",
+            "  Files converted:       15",
+            "  Files skipped:         3",
+            "  Total original size (converted files): 7 KiB",
+            "  Total converted size (converted files): 2 KiB",
+            "  Total storage saved (converted files): 5 KiB (71.4%)",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{}\n{}", line, text);
+        }
+        assert!(!text.contains("cancelled"));
+    }
+
+    #[test]
+    fn the_history_lists_folded_runs_by_their_count() {
+        let entries = [
+            entry(1_709_000_000, 2, 10, (4096, 1024)),
+            entry(1_710_000_000, 1, 0, (0, 0)),
+        ];
+        let mut out = Vec::new();
+        write_stats(&mut out, Path::new("history.jsonl"), &entries, true).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert!(lines[1].starts_with("2 runs "), "{}", text);
+        assert!(lines[2].starts_with("2024-03-09 16:00 "), "{}", text);
+        assert!(lines[2].ends_with("- / /out"), "{}", text);
+
+        let mut out = Vec::new();
+        write_stats(&mut out, Path::new("history.jsonl"), &[], true).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "No runs in history.jsonl yet.\n"
+        );
+    }
+}
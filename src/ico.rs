@@ -0,0 +1,59 @@
+use std::path::Path;
+
+/// One image stored in an ICO file, as listed in the icon directory.
+pub struct IcoEntry {
+    pub width: u32,
+    pub height: u32,
+    pub bits_per_pixel: u16,
+    /// Whether the image data is a PNG stream rather than a BMP DIB.
+    pub png: bool,
+}
+
+/// Reads the icon directory of an ICO file. Entries are returned in file
+/// order, which is also the order ffmpeg exposes them as streams.
+pub fn entries(path: &Path) -> anyhow::Result<Vec<IcoEntry>> {
+    let data = std::fs::read(path)?;
+
+    if data.len() < 6 || data[0..4] != [0, 0, 1, 0] {
+        return Err(anyhow::anyhow!("Not an ICO file"));
+    }
+    let count = u16::from_le_bytes([data[4], data[5]]) as usize;
+
+    let mut entries = Vec::with_capacity(count);
+    for index in 0..count {
+        let entry = data
+            .get(6 + index * 16..6 + (index + 1) * 16)
+            .ok_or_else(|| anyhow::anyhow!("Truncated ICO directory"))?;
+
+        let size = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as usize;
+        let offset = u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]]) as usize;
+        let image = offset
+            .checked_add(size)
+            .and_then(|end| data.get(offset..end))
+            .ok_or_else(|| anyhow::anyhow!("ICO image {} lies outside the file", index))?;
+
+        let png = image.starts_with(b"\x89PNG\r\n\x1a\n");
+        let (width, height) = if png && image.len() >= 24 {
+            // The directory can only express sizes up to 256, the PNG header has the real one.
+            (
+                u32::from_be_bytes([image[16], image[17], image[18], image[19]]),
+                u32::from_be_bytes([image[20], image[21], image[22], image[23]]),
+            )
+        } else {
+            // A zero in the directory means 256 pixels.
+            (
+                if entry[0] == 0 { 256 } else { entry[0] as u32 },
+                if entry[1] == 0 { 256 } else { entry[1] as u32 },
+            )
+        };
+
+        entries.push(IcoEntry {
+            width,
+            height,
+            bits_per_pixel: u16::from_le_bytes([entry[6], entry[7]]),
+            png,
+        });
+    }
+
+    Ok(entries)
+}
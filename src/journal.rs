@@ -0,0 +1,231 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tokio::io::{AsyncWriteExt, BufWriter};
+
+use crate::{
+    events::Messages,
+    report::{escape_path, unescape_path},
+};
+
+const JOURNAL_NAME: &str = ".bulk-jxl.journal";
+
+/// How often the run writes out the buffered entries with `flush`, so a crash
+/// loses at most the last moment of the record.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Append-only record in the output directory of which files were started
+/// and which finished, so a crash or power loss leaves a trace of the files
+/// that were in flight. Written through `tokio::fs`, off the runtime.
+pub struct Journal {
+    out: Option<BufWriter<tokio::fs::File>>,
+    messages: Messages,
+}
+
+/// A file an earlier run started but never finished.
+pub struct Unfinished {
+    pub source: PathBuf,
+    /// The temporary outputs it left behind and that were removed: its
+    /// partial output, and those of its pages, images and attempts.
+    pub removed_partials: Vec<PathBuf>,
+}
+
+/// Reads the journal an earlier run left in `output` and removes the
+/// temporary outputs of the files it never finished unless `keep_partials`.
+/// Leaves the journal itself alone, so a run that goes no further than this
+/// doesn't lose it; [`Journal::open`] replaces it. Blocks, so runs call it
+/// off the runtime.
+pub fn recover(output: &Path, keep_partials: bool) -> Vec<Unfinished> {
+    match std::fs::read(output.join(JOURNAL_NAME)) {
+        Ok(bytes) => unfinished(&String::from_utf8_lossy(&bytes), keep_partials),
+        Err(_) => Vec::new(),
+    }
+}
+
+impl Journal {
+    /// Starts a new journal in `output`, in place of the one [`recover`]
+    /// read. A journal that stops working is reported to `messages`.
+    pub async fn open(output: &Path, messages: &Messages) -> anyhow::Result<Self> {
+        let mut out = BufWriter::new(tokio::fs::File::create(output.join(JOURNAL_NAME)).await?);
+        out.write_all(format!("run\t{}\n", std::process::id()).as_bytes())
+            .await?;
+        Ok(Journal {
+            out: Some(out),
+            messages: messages.clone(),
+        })
+    }
+
+    /// Notes that work on `source`, written to `output`, has begun.
+    pub async fn started(&mut self, source: &Path, output: Option<&Path>) {
+        let output = output.map(escape_path).unwrap_or_default();
+        self.write(&format!("started\t{}\t{}", escape_path(source), output))
+            .await;
+    }
+
+    /// Notes that work on `source` has ended with `outcome`.
+    pub async fn finished(&mut self, source: &Path, outcome: &str) {
+        self.write(&format!("finished\t{}\t{}", escape_path(source), outcome))
+            .await;
+    }
+
+    /// Writes out the buffered entries.
+    pub async fn flush(&mut self) {
+        if let Some(out) = &mut self.out {
+            let result = out.flush().await;
+            self.check(result);
+        }
+    }
+
+    async fn write(&mut self, line: &str) {
+        if let Some(out) = &mut self.out {
+            let result = out.write_all(format!("{}\n", line).as_bytes()).await;
+            self.check(result);
+        }
+    }
+
+    // A journal that can't be written mustn't stop the conversions, so the
+    // first failure is reported and journaling stops.
+    fn check(&mut self, result: std::io::Result<()>) {
+        if let Err(e) = result {
+            self.messages.say(format!(
+                "   Warning: could not write the journal, stopping it: {}",
+                e
+            ));
+            self.out = None;
+        }
+    }
+}
+
+/// Finds the files the journal `text` has a start but no finish for, and
+/// removes their temporary outputs unless `keep_partials`.
+fn unfinished(text: &str, keep_partials: bool) -> Vec<Unfinished> {
+    let mut pid = 0;
+    // Sources started but not finished so far, with the output their run
+    // wrote to, and the order they were started in.
+    let mut open = HashMap::new();
+    let mut order = Vec::new();
+    for line in text.lines() {
+        let fields = line.split('\t').collect::<Vec<_>>();
+        match fields.as_slice() {
+            ["run", run_pid] => pid = run_pid.parse().unwrap_or(0),
+            ["started", source, output] => {
+                let source = unescape_path(source);
+                let output = (!output.is_empty()).then(|| unescape_path(output));
+                if open.insert(source.clone(), output).is_none() {
+                    order.push(source);
+                }
+            }
+            ["finished", source, _] => {
+                open.remove(&unescape_path(source));
+            }
+            _ => {}
+        }
+    }
+
+    order
+        .into_iter()
+        .filter_map(|source| {
+            let output = open.remove(&source)?;
+            let removed_partials = output
+                .filter(|_| !keep_partials)
+                .map(|output| crate::partials_of(&output, pid))
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|partial| std::fs::remove_file(partial).is_ok())
+                .collect();
+            Some(Unfinished {
+                source,
+                removed_partials,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sources(unfinished: &[Unfinished]) -> Vec<&Path> {
+        unfinished
+            .iter()
+            .map(|file| file.source.as_path())
+            .collect()
+    }
+
+    #[test]
+    fn files_started_but_not_finished_are_found_in_order() {
+        let text = "run\t42\n\
+                    started\t/in/b.png\t/out/b.jxl\n\
+                    started\t/in/a.png\t/out/a.jxl\n\
+                    started\t/in/c.txt\t\n\
+                    finished\t/in/a.png\tdone\n\
+                    started\t/in/b.png\t/out/b.jxl\n";
+        let unfinished = unfinished(text, true);
+        assert_eq!(
+            sources(&unfinished),
+            [Path::new("/in/b.png"), Path::new("/in/c.txt")]
+        );
+        assert!(
+            unfinished
+                .iter()
+                .all(|file| file.removed_partials.is_empty())
+        );
+    }
+
+    #[test]
+    fn the_partials_of_unfinished_files_are_removed_unless_kept() {
+        let temp = tempfile::tempdir().unwrap();
+        let output = temp.path().join("a.jxl");
+        // The pages and attempts of the file are named after its output.
+        let partials = [
+            output.clone(),
+            output.with_extension("attempt0.jxl"),
+            output.with_extension("p001.jxl"),
+        ]
+        .map(|path| crate::partial_path(&path, 42));
+        let others = [
+            crate::partial_path(&temp.path().join("ab.jxl"), 42),
+            crate::partial_path(&output, 43),
+        ];
+        let text = format!("run\t42\nstarted\t/in/a.png\t{}\n", escape_path(&output));
+        for path in partials.iter().chain(&others) {
+            std::fs::write(path, b"half").unwrap();
+        }
+
+        assert!(unfinished(&text, true)[0].removed_partials.is_empty());
+        assert!(partials.iter().all(|partial| partial.exists()));
+        let mut removed = unfinished(&text, false).remove(0).removed_partials;
+        removed.sort();
+        let mut expected = partials.to_vec();
+        expected.sort();
+        assert_eq!(removed, expected);
+        assert!(partials.iter().all(|partial| !partial.exists()));
+        assert!(others.iter().all(|other| other.exists()));
+    }
+
+    #[tokio::test]
+    async fn a_new_run_reads_the_last_journal_and_starts_over() {
+        let temp = tempfile::tempdir().unwrap();
+        let messages = Messages::discard();
+        assert!(recover(temp.path(), false).is_empty());
+        let mut journal = Journal::open(temp.path(), &messages).await.unwrap();
+        journal
+            .started(Path::new("/in/a.png"), Some(&temp.path().join("a.jxl")))
+            .await;
+        journal.started(Path::new("/in/b.png"), None).await;
+        journal.finished(Path::new("/in/a.png"), "done").await;
+        journal.flush().await;
+        drop(journal);
+
+        // Until a new journal is started, e.g. when the run was declined.
+        for _ in 0..2 {
+            let unfinished = recover(temp.path(), false);
+            assert_eq!(sources(&unfinished), [Path::new("/in/b.png")]);
+        }
+        drop(Journal::open(temp.path(), &messages).await.unwrap());
+        assert!(recover(temp.path(), false).is_empty());
+    }
+}
@@ -0,0 +1,5932 @@
+/// Prints a line of the run, such as a warning, to `messages`: the terminal,
+/// or the program running the conversion as an [`Event::Message`].
+macro_rules! say {
+    ($messages:expr) => {
+        $messages.say(String::new())
+    };
+    ($messages:expr, $($arg:tt)*) => {
+        $messages.say(format!($($arg)*))
+    };
+}
+
+/// As `say!`, for the errors the command line prints to the standard error.
+macro_rules! say_error {
+    ($messages:expr, $($arg:tt)*) => {
+        $messages.error(format!($($arg)*))
+    };
+}
+
+/// Prints a per-file message, such as which file is being converted. Only
+/// shown with `--verbose`: with hundreds of thousands of small files, writing
+/// them costs more than the conversions.
+macro_rules! detail {
+    ($messages:expr, $($arg:tt)*) => {
+        if $messages.is_verbose() {
+            $messages.say(format!($($arg)*));
+        }
+    };
+}
+
+mod adaptive;
+mod apng;
+#[cfg(feature = "archives")]
+mod archive;
+mod collect;
+mod collision;
+mod color;
+/// The subcommands besides `convert`, which print what they find to the
+/// terminal.
+pub mod commands;
+mod container;
+mod content;
+mod control;
+mod converter;
+mod decode;
+mod diff;
+mod dimensions;
+mod encoder;
+mod events;
+mod failures;
+mod filelist;
+mod gitignore;
+mod history;
+mod ico;
+mod journal;
+mod listing;
+mod lock;
+mod owner;
+mod pause;
+mod perms;
+mod pilot;
+mod plan;
+mod policy;
+mod probe;
+mod process;
+mod profile;
+mod prune;
+mod quality;
+mod ratelimit;
+mod reflink;
+mod report;
+mod restore;
+mod retry;
+mod run;
+mod script;
+mod shutdown;
+mod sidecar;
+mod smart;
+mod sniff;
+mod space;
+mod sparse;
+mod stream;
+mod sync;
+mod tiff;
+mod times;
+mod validate;
+mod verify;
+mod xattrs;
+
+use std::sync::Arc;
+
+pub use control::Handle;
+pub use converter::{Action, Converter, Error, FileResult};
+pub use events::{DirectorySummary, Event, State, Status, Summary};
+pub use plan::{ConvertSettings, Plan, PlanAction, PlannedAction};
+pub use process::{CommandRunner, Finished};
+
+pub use collision::Normalization;
+pub use color::{ColorSpace, Transfer};
+pub use quality::QualityGate;
+pub use report::read_directories as read_report_directories;
+pub use script::Format as ScriptFormat;
+pub use times::PreserveTimes;
+
+use clap::{Parser, ValueEnum};
+use encoder::{Encoder, InputSelection};
+use human_bytes::human_bytes;
+use indicatif::{ProgressBar, ProgressStyle};
+use tokio::task::JoinSet;
+
+// The directory trees every subcommand works on. Not a doc comment, which
+// clap would show as the description of the subcommands.
+#[derive(clap::Args, Clone)]
+struct Tree {
+    /// Directory with the files to work on
+    #[clap(short, long)]
+    input: String,
+
+    /// Directory the results are written to
+    #[clap(short, long)]
+    output: String,
+
+    /// Include subdirectories
+    #[clap(short, long)]
+    recursive: bool,
+}
+
+// How many files the subcommands that work in parallel take on at once.
+#[derive(clap::Args, Clone)]
+struct Jobs {
+    /// Files worked on at the same time
+    #[clap(short, long, default_value_t = 2)]
+    jobs: usize,
+}
+
+// Which parts of a tree the walks of every subcommand leave out.
+#[derive(clap::Args, Clone)]
+struct Filters {
+    /// Leave directories with this name out wherever they are, without
+    /// looking inside them (repeatable)
+    #[clap(long = "exclude-dir", value_name = "NAME")]
+    exclude_dir: Vec<String>,
+
+    /// Walk into .git, node_modules and the other directories left out by
+    /// default as well
+    #[clap(long)]
+    no_default_excludes: bool,
+
+    /// Leave out files and directories whose name starts with a dot, and
+    /// those with the hidden attribute on Windows
+    #[clap(long)]
+    skip_hidden: bool,
+}
+
+impl Filters {
+    /// The files in `root` a subcommand works on, with those in its
+    /// subdirectories when `recursive`, less what the filters leave out.
+    fn files(
+        &self,
+        root: &std::path::Path,
+        recursive: bool,
+    ) -> impl Iterator<Item = walkdir::DirEntry> {
+        let excluded = excluded_dirs(self);
+        let skip_hidden = self.skip_hidden;
+        let mut walkdir = walkdir::WalkDir::new(root);
+        if !recursive {
+            walkdir = walkdir.max_depth(1);
+        }
+        walkdir
+            .into_iter()
+            .filter_entry(move |entry| {
+                entry.depth() == 0
+                    || !((entry.file_type().is_dir() && excluded.contains(entry.file_name()))
+                        || (skip_hidden && is_hidden(entry)))
+            })
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            // What a conversion into this tree is still writing.
+            .filter(|entry| partial_pid(&entry.file_name().to_string_lossy()).is_none())
+    }
+}
+
+// The options of a conversion, as the command line takes them. Not a doc
+// comment, which clap would show as the description of `convert`.
+#[derive(Parser, Clone)]
+#[command(name = "bulk-jxl")]
+pub struct Args {
+    #[command(flatten)]
+    tree: Tree,
+
+    #[command(flatten)]
+    parallel: Jobs,
+
+    /// Run between MIN and MAX conversions at a time (`MIN..MAX`), as many
+    /// as there are cores other programs leave idle, instead of --jobs
+    #[clap(long, value_name = "MIN..MAX", value_parser = adaptive::parse_range)]
+    adaptive_jobs: Option<adaptive::JobRange>,
+
+    /// Encoder threads to share out among the conversions running at once,
+    /// the number of cores when not given
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    total_threads: Option<u64>,
+
+    /// Files copied at the same time, on top of the --jobs conversions
+    #[clap(long, default_value_t = 2)]
+    copy_jobs: usize,
+
+    #[clap(short, long)]
+    copy_all: bool,
+
+    /// Treat files with this extension as images as well, e.g. a format
+    /// your ffmpeg can decode (repeatable)
+    #[clap(long = "add-ext", value_name = "EXT")]
+    add_ext: Vec<String>,
+
+    /// Stop treating files with this extension as images (repeatable)
+    #[clap(long = "remove-ext", value_name = "EXT")]
+    remove_ext: Vec<String>,
+
+    /// Comma-separated image extensions to use instead of the built-in list
+    #[clap(long, value_delimiter = ',', value_name = "EXT,...")]
+    extensions: Option<Vec<String>>,
+
+    #[command(flatten)]
+    filters: Filters,
+
+    /// Leave out what the .gitignore files in the input, and the global git
+    /// excludes, ignore
+    #[clap(long)]
+    respect_gitignore: bool,
+
+    /// Convert only the files listed in this file, `-` for the standard
+    /// input, instead of walking the input; one path per line, inside the
+    /// input directory
+    #[clap(long, value_name = "FILE")]
+    files_from: Option<String>,
+
+    /// The --files-from list is separated by NUL bytes, as `find -print0`
+    /// and `fd -0` write it
+    #[clap(short = '0', long, requires = "files_from")]
+    null: bool,
+
+    /// Follow symbolic links while walking the input. A file reachable through
+    /// several paths is converted once, under the first of them
+    #[clap(long)]
+    follow_symlinks: bool,
+
+    /// Convert or copy every input path that is a hard link to a file already
+    /// seen on its own, instead of hard-linking its output to the output of
+    /// the first path
+    #[clap(long)]
+    no_preserve_hardlinks: bool,
+
+    /// What to do with symlinks to files
+    #[clap(long, value_enum, default_value_t = SymlinkMode::Follow)]
+    symlinks: SymlinkMode,
+
+    /// How files are copied to the output
+    #[clap(long, value_enum, default_value_t = CopyMode::Auto)]
+    copy_mode: CopyMode,
+
+    /// Compression effort (1-9), 7 when neither given here nor by --policy
+    #[clap(short, long)]
+    effort: Option<u32>,
+
+    /// Source timestamps to carry over to the outputs
+    #[clap(long, value_enum, default_value_t = times::PreserveTimes::Modified)]
+    preserve_times: times::PreserveTimes,
+
+    /// Seconds a modification time may read back off from the one set before
+    /// it counts as not preserved, as filesystems like FAT store them in
+    /// 2-second steps
+    #[clap(long, default_value_t = 2.0)]
+    mtime_tolerance: f64,
+
+    /// Start converting while the input is still being walked instead of
+    /// collecting every file first, so memory use stays flat on huge trees,
+    /// short of an id per file kept to catch symlinks to files already
+    /// converted (none with --symlinks skip or preserve). There's no overview
+    /// to confirm, so this needs --yes
+    #[clap(
+        long,
+        requires = "yes",
+        conflicts_with_all = [
+            "prevalidate",
+            "stable_seconds",
+            "require_free_space",
+            "min_pixels",
+            "max_pixels",
+            "min_dimensions",
+            "probe",
+            "files_from"
+        ]
+    )]
+    stream: bool,
+
+    /// How often to retry copies, renames and timestamp updates that fail with
+    /// transient I/O errors, as network filesystems report now and then
+    #[clap(long, default_value_t = 2)]
+    io_retries: u32,
+
+    /// Seconds running files get to finish after SIGTERM or SIGHUP (a console
+    /// close on Windows) before they are aborted
+    #[clap(long, default_value_t = 30)]
+    shutdown_grace: u64,
+
+    /// Stop ffmpeg, ffprobe or another tool that runs for longer than this
+    /// many seconds, failing the file it worked on
+    #[clap(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    tool_timeout: Option<u64>,
+
+    /// While paused with SIGUSR1, also stop the running encoders (SIGSTOP)
+    /// instead of letting them finish, to free the CPU right away
+    #[clap(long)]
+    pause_encoders: bool,
+
+    /// Wait for another run using the same output directory to finish instead
+    /// of refusing to start
+    #[clap(long)]
+    wait_lock: bool,
+
+    /// Don't keep a journal of started and finished files in the output
+    /// directory
+    #[clap(long)]
+    no_journal: bool,
+
+    /// Leave the temporary outputs of failed and interrupted conversions in
+    /// place, for debugging, instead of removing them
+    #[clap(long)]
+    keep_partials: bool,
+
+    /// Check after the run that every output it counts is on disk, not empty
+    /// and has its source's modification time, and fail if not
+    #[clap(long)]
+    strict: bool,
+
+    /// Copy extended attributes (Finder tags and labels, custom attributes)
+    /// from each source to its output
+    #[clap(long)]
+    preserve_xattrs: bool,
+
+    /// Give outputs and the output directories the owner and group of their
+    /// sources (Unix, needs root)
+    #[clap(long)]
+    preserve_owner: bool,
+
+    /// Give created output directories this octal mode, e.g. 2775 (Unix)
+    #[clap(long, value_parser = perms::parse_mode)]
+    dir_mode: Option<u32>,
+
+    /// Give outputs this octal mode, e.g. 664 (Unix)
+    #[clap(long, value_parser = perms::parse_mode)]
+    file_mode: Option<u32>,
+
+    /// Give outputs and created output directories this owner, as user,
+    /// user:group or :group; overrides --preserve-owner (Unix, needs root)
+    #[clap(long, value_parser = perms::parse_owner)]
+    chown: Option<perms::Owner>,
+
+    /// Flush every output and its directory to the disk before counting it
+    /// as done, and the output filesystem before the summary
+    #[clap(long)]
+    fsync: bool,
+
+    /// Butteraugli distance for lossy encoding, 0 is mathematically lossless,
+    /// 1 visually lossless. Leaves the encoder default when not given
+    #[clap(long)]
+    distance: Option<f32>,
+
+    /// Reject lossy conversions whose similarity to the source falls below a
+    /// threshold, e.g. `ssim=0.98` or `psnr=45`
+    #[clap(long, value_parser = quality::QualityGate::parse)]
+    quality_gate: Option<quality::QualityGate>,
+
+    /// Whether outputs are wrapped in the ISOBMFF container (needed to carry
+    /// EXIF and other metadata) or written as a bare codestream
+    #[clap(long, value_enum, default_value_t = ContainerMode::Auto)]
+    container: ContainerMode,
+
+    /// Leave animated sources (APNG) out instead of converting them to animated JXL
+    #[clap(long)]
+    skip_animated: bool,
+
+    /// Apply the EXIF orientation to the pixels and write the output without
+    /// one, for viewers that ignore it
+    #[clap(long)]
+    bake_orientation: bool,
+
+    /// Tag sources without colour metadata as using these primaries, without
+    /// converting any pixels
+    #[clap(long, value_enum)]
+    assume_color_space: Option<color::ColorSpace>,
+
+    /// Tag sources without colour metadata as using this transfer function
+    #[clap(long, value_enum)]
+    assume_transfer: Option<color::Transfer>,
+
+    /// Apply --assume-color-space and --assume-transfer even to sources that
+    /// carry their own colour tags
+    #[clap(long)]
+    force_color_tags: bool,
+
+    /// Encode grayscale sources as RGB instead of keeping them single-channel
+    #[clap(long)]
+    force_rgb: bool,
+
+    /// Aim for at most this many bytes per converted file by searching for the
+    /// lowest distance that fits, e.g. `500K` or `2M`
+    #[clap(long, value_parser = parse_size)]
+    target_size: Option<u64>,
+
+    /// Copy images smaller than this many bytes to the output unchanged
+    /// instead of converting them, e.g. `4K`
+    #[clap(long, value_parser = parse_size, default_value = "0")]
+    convert_min_size: u64,
+
+    /// Leave out images with fewer pixels than this, e.g. `1M` to skip
+    /// thumbnails
+    #[clap(long, value_parser = dimensions::parse_pixels)]
+    min_pixels: Option<u64>,
+
+    /// Leave out images with more pixels than this, e.g. `500M`
+    #[clap(long, value_parser = dimensions::parse_pixels)]
+    max_pixels: Option<u64>,
+
+    /// Leave out images narrower or lower than this, e.g. `1024x768`
+    #[clap(long, value_name = "WxH", value_parser = dimensions::parse_dimensions)]
+    min_dimensions: Option<dimensions::Dimensions>,
+
+    /// Probe every image once after collection and show what the run deals
+    /// with in the overview: stills, animations, CMYK and high bit depth
+    #[clap(long)]
+    probe: bool,
+
+    /// Images probed at the same time for --probe, the number of cores when
+    /// not given
+    #[clap(long, requires = "probe", value_parser = clap::value_parser!(u64).range(1..))]
+    probe_jobs: Option<u64>,
+
+    /// Stop starting new files once this run has written this many bytes to
+    /// the output, e.g. `200G`; running files still finish
+    #[clap(long, value_parser = parse_size)]
+    max_output_bytes: Option<u64>,
+
+    /// Stop starting new files once the sources converted or copied add up
+    /// to this many bytes, e.g. `50G`; running files still finish
+    #[clap(long, value_parser = parse_size)]
+    max_input_bytes: Option<u64>,
+
+    /// Copy at most this many MiB per second, and start conversions only as
+    /// fast as their sources can be read at that rate, e.g. `20` to leave
+    /// room on a shared NAS
+    #[clap(long, value_name = "MIB_PER_SEC", value_parser = parse_rate)]
+    rate_limit: Option<u64>,
+
+    /// What to do with conversions that fail --quality-gate
+    #[clap(long, value_enum, default_value_t = GateAction::Error, requires = "quality_gate")]
+    quality_gate_action: GateAction,
+
+    #[clap(short, long)]
+    yes: bool,
+
+    /// Before asking to proceed, convert this many sample files spread over
+    /// the extensions and sizes to their outputs, and estimate the output
+    /// size and time from them
+    #[clap(long, default_value_t = 0)]
+    pilot: usize,
+
+    /// Print what will be done with every file before starting, as text or
+    /// as a JSON object per line
+    #[clap(long, value_enum, value_name = "FORMAT")]
+    print_plan: Option<PlanFormat>,
+
+    /// Collect the files and plan the run, print the plan and stop without
+    /// converting or copying anything
+    #[clap(long, conflicts_with = "stream")]
+    dry_run: bool,
+
+    /// Instead of converting, write the commands that would to this file as
+    /// a script with a line per output, to run or schedule elsewhere
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = [
+            "dry_run",
+            "stream",
+            "target_size",
+            "quality_gate",
+            "verify",
+            "content_addressed",
+            "metadata_sidecar",
+            "preserve_xattrs",
+            "preserve_owner",
+            "chown",
+            "dir_mode",
+            "fsync",
+            "rate_limit",
+            "print_outputs"
+        ]
+    )]
+    emit_script: Option<std::path::PathBuf>,
+
+    /// The shell --emit-script writes for
+    #[clap(long, value_enum, default_value_t = script::Format::Sh, requires = "emit_script")]
+    emit_script_format: script::Format,
+
+    /// Show which file is being worked on and the output of ffmpeg and the
+    /// other tools while they run, instead of only the end of it when they fail
+    #[clap(short, long)]
+    verbose: bool,
+
+    /// How to handle TIFF files that contain more than one page
+    #[clap(long, value_enum, default_value_t = MultipageMode::First)]
+    multipage: MultipageMode,
+
+    /// Which images to convert from multi-resolution ICO files
+    #[clap(long, value_enum, default_value_t = IcoMode::Largest)]
+    ico: IcoMode,
+
+    /// What to do with files that are already JPEG XL
+    #[clap(long, value_enum, default_value_t = ExistingJxl::Copy)]
+    existing_jxl: ExistingJxl,
+
+    /// Identify images by their content, so files with a missing or wrong
+    /// extension are converted too
+    #[clap(long)]
+    sniff: bool,
+
+    /// Check image headers with a quick ffprobe pass before converting and
+    /// leave files that fail the check out of the conversion
+    #[clap(long)]
+    prevalidate: bool,
+
+    /// Copy files that fail --prevalidate as-is into `_corrupt/` in the output
+    #[clap(long, requires = "prevalidate")]
+    quarantine_corrupt: bool,
+
+    /// Pick lossless, lossy or JPEG reconstruction per file from the source's
+    /// bit depth, palette, size per megapixel and JPEG quality. --policy and
+    /// --distance take precedence
+    #[clap(long)]
+    smart: bool,
+
+    /// TOML file with encoder settings per extension, e.g. `[png] mode =
+    /// "lossless"`. --distance and --effort take precedence over it
+    #[clap(long, value_parser = policy::Policy::load)]
+    policy: Option<policy::Policy>,
+
+    /// What to do with zip and cbz archives of images
+    #[clap(long, value_enum, default_value_t = ArchiveMode::Skip)]
+    archives: ArchiveMode,
+
+    /// Retry files ffmpeg cannot decode by converting them to PNG with
+    /// ImageMagick first
+    #[clap(long)]
+    magick_fallback: bool,
+
+    /// ffmpeg binary to run instead of the one on the PATH, ffprobe is
+    /// expected next to it
+    #[clap(long)]
+    ffmpeg_path: Option<String>,
+
+    /// Don't add this run to the history `bulk-jxl stats` totals up
+    #[clap(long)]
+    no_history: bool,
+
+    /// Keep the source metadata in a `name.jxl.xmp` sidecar (written with
+    /// exiftool) when the output doesn't embed it
+    #[clap(long)]
+    metadata_sidecar: bool,
+
+    /// Decode every conversion again and check it against the source
+    #[clap(long)]
+    verify: bool,
+
+    /// What to do with conversions that fail --verify
+    #[clap(long, value_enum, default_value_t = VerifyFailAction::Error, requires = "verify")]
+    on_verify_fail: VerifyFailAction,
+
+    /// Skip files modified within this many seconds or still growing, e.g.
+    /// while an import or rsync is writing them
+    #[clap(long)]
+    stable_seconds: Option<u64>,
+
+    /// Check outputs left by earlier runs before skipping their sources, and
+    /// convert again those that fail
+    #[clap(long, value_enum, num_args = 0..=1, default_missing_value = "signature")]
+    revalidate_existing: Option<RevalidateMode>,
+
+    /// Abort instead of warning when the output filesystem looks too small
+    /// for the estimated outputs
+    #[clap(long)]
+    require_free_space: bool,
+
+    /// What to do when outputs would land on the same file on a
+    /// case-insensitive filesystem, e.g. for `Cover.png` and `cover.png`, or
+    /// under names that only differ in their Unicode normalization
+    #[clap(long, value_enum, default_value_t = CollisionMode::Error)]
+    on_collision: CollisionMode,
+
+    /// Unicode normalization of output names, so names from macOS (NFD) and
+    /// from elsewhere (NFC) give the same output
+    #[clap(long, value_enum, default_value_t = collision::Normalization::Nfc)]
+    normalize_names: collision::Normalization,
+
+    /// Name outputs after the SHA-256 of their source, or of the output
+    /// itself, as `ab/cdef….jxl` under the output directory, skipping those
+    /// already there and listing every source in `index.jsonl`
+    #[clap(long, value_enum, num_args = 0..=1, default_missing_value = "source")]
+    content_addressed: Option<ContentHash>,
+
+    /// Write a tab separated report of every processed file to this path
+    #[clap(long, value_name = "FILE.tsv")]
+    report: Option<String>,
+
+    /// Write the commands, stderr and source details of every failed file
+    /// to a text file in this directory
+    #[clap(long, value_name = "DIR")]
+    error_dir: Option<std::path::PathBuf>,
+
+    /// Copy the first this many bytes of every failed source to --error-dir
+    /// as well, e.g. `64K`
+    #[clap(long, value_name = "SIZE", value_parser = parse_size, requires = "error_dir")]
+    error_sample_bytes: Option<u64>,
+
+    /// Keep a JSON file with the counts, throughput, time left and running
+    /// files of the run up to date at this path, for watching it from
+    /// elsewhere
+    #[clap(long)]
+    status_file: Option<std::path::PathBuf>,
+
+    /// Print the path of every output this run creates to the standard
+    /// output, a line each or NUL-separated with =nul, and everything else
+    /// to the standard error
+    #[clap(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "newline",
+        conflicts_with = "dry_run"
+    )]
+    print_outputs: Option<OutputSeparator>,
+
+    /// Add up the files per directory down to this many levels below the
+    /// input, in a table after the summary; 0 leaves the table out
+    #[clap(long, default_value_t = 1)]
+    summary_depth: usize,
+
+    /// Time the phases of the run and the stages of every file, print a
+    /// breakdown at the end and add the timings of each file to the report
+    #[clap(long)]
+    profile: bool,
+}
+
+/// Parses a byte count with an optional binary `K`, `M`, `G` or `T` suffix.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number = number
+        .parse::<f64>()
+        .map_err(|e| format!("invalid size {}: {}", value, e))?;
+    let multiplier = match unit.trim().to_uppercase().as_str() {
+        "" | "B" => 1u64,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        other => return Err(format!("unknown size unit {}", other)),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Parses MiB per second into bytes per second.
+fn parse_rate(value: &str) -> Result<u64, String> {
+    let rate = value
+        .trim()
+        .parse::<f64>()
+        .map_err(|e| format!("invalid rate {}: {}", value, e))?;
+    if !(rate > 0.0 && rate.is_finite()) {
+        return Err(format!("the rate must be more than 0, not {}", value));
+    }
+    Ok(((rate * (1 << 20) as f64) as u64).max(1))
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MultipageMode {
+    /// Convert only the first page and warn about the dropped pages
+    First,
+    /// Convert every page to its own `name.pNNN.jxl` file
+    All,
+    /// Leave multi-page files out of the conversion
+    Skip,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IcoMode {
+    /// Convert only the largest embedded image
+    Largest,
+    /// Convert every embedded image to its own `name.SIZE.jxl` file
+    All,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExistingJxl {
+    /// Copy them to the output tree as they are
+    Copy,
+    /// Leave them out of the output
+    Skip,
+    /// Re-encode them losslessly at the current effort, keeping the result only when smaller
+    /// and identical when decoded
+    Recompress,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum PlanFormat {
+    /// A line per file
+    Text,
+    /// A JSON object per file and line
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum OutputSeparator {
+    /// A path per line
+    Newline,
+    /// Paths ended by NUL bytes, for `xargs -0` and names with newlines
+    Nul,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyFailAction {
+    /// Discard the conversion and report an error
+    Error,
+    /// Discard the conversion and put the original in the output tree
+    CopyOriginal,
+    /// Put the original in the output tree and keep the conversion as `name.jxl.suspect`
+    KeepBoth,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CollisionMode {
+    /// Stop before converting anything and list the collisions
+    Error,
+    /// Keep the first file in name order and give the others a `~2`, `~3`... suffix
+    Suffix,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveMode {
+    /// Treat them like any other non-image file
+    Skip,
+    /// Unpack them to a directory with the images converted
+    Extract,
+    /// Write a new archive of the same name with the images converted
+    Repack,
+}
+
+const ARCHIVE_EXTENSIONS: &[&str] = &["zip", "cbz"];
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContainerMode {
+    /// Keep what the encoder writes, a container only when there is metadata to embed
+    Auto,
+    /// Always wrap the codestream in a container
+    Always,
+    /// Always write the bare codestream, dropping embedded metadata
+    Never,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RevalidateMode {
+    /// JPEG XL outputs need a signature and a plausible size, other outputs
+    /// the size of their source
+    Signature,
+    /// As `signature`, and the modification time of the source as well
+    Mtime,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentHash {
+    /// Hash the source, so sources already converted aren't converted again
+    Source,
+    /// Hash the output, so identical outputs of different sources are stored once
+    Output,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymlinkMode {
+    /// Convert or copy the file a symlink points to, as if it were in its place
+    Follow,
+    /// Leave symlinks out
+    Skip,
+    /// Recreate symlinks in the output with the same target (needs --copy-all)
+    Preserve,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CopyMode {
+    /// Clone the file where the filesystem can, and copy its contents elsewhere
+    Auto,
+    /// Always copy the contents
+    Content,
+    /// Clone the file, failing where the filesystem can't
+    Reflink,
+    /// Hard-link the output to the source, copying where that isn't possible
+    Hardlink,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GateAction {
+    /// Discard the conversion and put the original in the output tree
+    KeepOriginal,
+    /// Re-encode at lower distances until the gate passes
+    ReEncode,
+    /// Discard the conversion and report an error
+    Error,
+}
+
+const DEFAULT_EFFORT: u32 = 7;
+
+// Outputs rarely take more than this share of their sources, so the free
+// space check errs on the side of warning.
+const ESTIMATED_OUTPUT_RATIO: f64 = 0.6;
+
+/// Encoder parameters passed to libjxl.
+#[derive(Clone, Copy)]
+struct EncodeSettings<'a> {
+    effort: u32,
+    /// Transcode JPEG input losslessly with cjxl instead of decoding it.
+    jpeg_reconstruct: bool,
+    /// Butteraugli distance, `None` leaves the encoder default.
+    distance: Option<f32>,
+    /// Pixel format handed to the encoder, `None` lets ffmpeg pick one.
+    pixel_format: Option<&'static str>,
+    /// Encode all frames of the input as an animation.
+    animated: bool,
+    /// How often the animation plays, 0 for forever.
+    plays: u32,
+    /// Filter applied to the decoded pixels, e.g. to rotate them upright.
+    video_filter: Option<&'static str>,
+    /// Colour metadata to write instead of the source's.
+    color_tags: color::ColorTags,
+    /// ImageMagick binary to decode with when ffmpeg can't.
+    magick: Option<&'static str>,
+    /// Print the output of the external tools as they run.
+    verbose: bool,
+    container: ContainerMode,
+    /// Source timestamps the output gets.
+    preserve_times: times::PreserveTimes,
+    /// How far the output's modification time may be rounded off.
+    mtime_tolerance: std::time::Duration,
+    /// Set once the run has warned that the output filesystem rounds
+    /// modification times, which is the same for every output.
+    rounding_warned: &'a std::sync::atomic::AtomicBool,
+    /// Retries for transient I/O errors while writing the output.
+    io_retries: u32,
+    copy_mode: CopyMode,
+    /// Limits the rate of copies for --rate-limit.
+    rate_limit: Option<&'a Arc<ratelimit::RateLimit>>,
+    /// Encoder threads of one conversion.
+    threads: u64,
+    /// What writes the JPEG XL files.
+    encoder: &'static dyn encoder::Encoder,
+    /// Runs the external tools the encoders need.
+    runner: &'a dyn process::CommandRunner,
+    /// Where warnings and the details of `--verbose` go.
+    messages: &'a events::Messages,
+    /// Leave the temporary output of a failed conversion in place.
+    keep_partials: bool,
+    /// Checked on every output before it is renamed into place.
+    quality_gate: Option<quality::QualityGate>,
+    gate_action: GateAction,
+}
+
+impl<'a> EncodeSettings<'a> {
+    /// Looks up the `--policy` entry for a file and applies it. Returns a
+    /// description of the entry that was used, for the report.
+    fn resolve_policy(
+        &mut self,
+        policy: &policy::Policy,
+        extension: &str,
+        format_extension: &str,
+        args: &Args,
+    ) -> String {
+        match policy.lookup(extension, format_extension) {
+            Some((key, entry)) => {
+                let is_jpeg = sniff::ImageKind::Jpeg.matches_extension(format_extension);
+                self.apply_policy(entry, args, is_jpeg);
+                format!("policy [{}] {}", key, entry)
+            }
+            None => "policy: global flags".to_string(),
+        }
+    }
+
+    /// Probes a source and applies what `--smart` picks for it, unless an
+    /// explicit --distance was given. Returns a description for the report.
+    async fn apply_smart(
+        &mut self,
+        file: &std::path::Path,
+        format_extension: &str,
+        input_format: Option<&str>,
+        context: &Context,
+    ) -> anyhow::Result<String> {
+        let _probing = profile::span(profile::Stage::Probe);
+        let stream = match context.probed.get(file) {
+            Some(info) => info.stream(),
+            None => {
+                probe::stream_info(context.runner(), file, input_format, InputSelection::Whole)
+                    .await?
+            }
+        };
+        let jpeg_quality = if sniff::ImageKind::Jpeg.matches_extension(format_extension) {
+            smart::jpeg_quality(file).unwrap_or(None)
+        } else {
+            None
+        };
+        let choice = smart::choose(&smart::SourceInfo {
+            format_extension,
+            pix_fmt: &stream.pix_fmt,
+            width: stream.width,
+            height: stream.height,
+            bit_depth: stream.bits_per_raw_sample,
+            file_size: source_metadata(file).await?.len(),
+            jpeg_quality,
+        });
+
+        let chosen = if choice.jpeg_reconstruct {
+            "reconstruct".to_string()
+        } else {
+            match choice.distance {
+                Some(0.0) => "lossless".to_string(),
+                Some(distance) => format!("distance={}", distance),
+                None => "encoder default".to_string(),
+            }
+        };
+        let mut note = format!("smart: {} ({}", chosen, choice.reason);
+        if let Some(quality) = jpeg_quality {
+            note.push_str(&format!(", jpeg quality ~{}", quality));
+        }
+        note.push(')');
+
+        if context.args.distance.is_some() {
+            return Ok(format!("{}, overridden by --distance", note));
+        }
+        self.distance = choice.distance;
+        self.jpeg_reconstruct = choice.jpeg_reconstruct;
+        Ok(note)
+    }
+
+    /// What keeps cjxl from transcoding the JPEG: it takes the JPEG as it
+    /// is, so a pixel format, filter or colour tags need ffmpeg.
+    fn reconstruction_blocker(&self) -> Option<&'static str> {
+        if self.video_filter.is_some() {
+            Some("rotated")
+        } else if self.pixel_format.is_some() {
+            Some("pixel format")
+        } else if !self.color_tags.is_empty() {
+            Some("color tags")
+        } else {
+            None
+        }
+    }
+
+    /// Applies a policy entry underneath the explicit command line flags.
+    fn apply_policy(&mut self, entry: policy::Entry, args: &Args, is_jpeg: bool) {
+        if let (None, Some(effort)) = (args.effort, entry.effort) {
+            self.effort = effort;
+        }
+        if args.distance.is_some() {
+            return;
+        }
+        match entry.mode {
+            Some(policy::Mode::Lossless) => {
+                self.jpeg_reconstruct = false;
+                self.distance = Some(0.0);
+            }
+            Some(policy::Mode::Reconstruct) if is_jpeg => self.jpeg_reconstruct = true,
+            Some(policy::Mode::Reconstruct) => self.distance = Some(0.0),
+            Some(policy::Mode::Lossy) => {
+                self.jpeg_reconstruct = false;
+                if entry.distance.is_some() {
+                    self.distance = entry.distance;
+                }
+            }
+            None => {
+                if entry.distance.is_some() {
+                    self.distance = entry.distance;
+                }
+            }
+        }
+    }
+
+    fn from_context(context: &'a Context) -> Self {
+        let args = &context.args;
+        Self {
+            effort: args.effort.unwrap_or(DEFAULT_EFFORT),
+            jpeg_reconstruct: false,
+            distance: args.distance,
+            pixel_format: None,
+            animated: false,
+            plays: 0,
+            video_filter: None,
+            color_tags: color::ColorTags::default(),
+            magick: context.magick,
+            verbose: args.verbose,
+            container: args.container,
+            preserve_times: args.preserve_times,
+            mtime_tolerance: std::time::Duration::from_secs_f64(args.mtime_tolerance),
+            rounding_warned: &context.rounding_warned,
+            io_retries: args.io_retries,
+            copy_mode: args.copy_mode,
+            rate_limit: context.rate_limiter.as_ref(),
+            threads: threads_per_job(args.total_threads.unwrap_or(1), conversion_jobs(args)),
+            keep_partials: args.keep_partials,
+            encoder: context.encoder,
+            runner: context.runner(),
+            messages: &context.messages,
+            quality_gate: args.quality_gate,
+            gate_action: args.quality_gate_action,
+        }
+    }
+
+    /// What the encoder gets to know for encoding `selection` of an input
+    /// read as `input_format`.
+    fn encode_options(
+        self,
+        input_format: Option<&'a str>,
+        selection: InputSelection,
+    ) -> encoder::EncodeOptions<'a> {
+        encoder::EncodeOptions {
+            input_format,
+            selection,
+            effort: self.effort,
+            distance: self.distance,
+            threads: self.threads,
+            animated: self.animated,
+            plays: self.plays,
+            video_filter: self.video_filter,
+            pixel_format: self.pixel_format,
+            color_tags: self.color_tags,
+            verbose: self.verbose,
+            runner: self.runner,
+        }
+    }
+}
+
+const ACCEPTED_EXTENSIONS: &[&str] = &[
+    // Joint Photographic Experts Group
+    "jpg",
+    "jpeg",
+    "jpe",
+    "jif",
+    "jfif",
+    "jfi",
+    // Portable Network Graphics
+    "png",
+    // WebP
+    "webp",
+    // Graphics Interchange Format
+    "gif",
+    // Bitmap
+    "bmp",
+    "dib",
+    // Portable Pixmap
+    "ppm",
+    // Portable Graymap
+    "pgm",
+    // Portable Anymap
+    "pam",
+    // Tagged Image File Format
+    "tif",
+    "tiff",
+    // Targa Graphics Format
+    "tga",
+    "icb",
+    "vda",
+    "vst",
+    // DirectDraw Surface
+    "dds",
+    // OpenEXR
+    "exr",
+    // High Dynamic Range
+    "hdr",
+    "pic",
+    // Microsoft Icon
+    "ico",
+    // Flexible Image Transport System
+    "fits",
+    // Alias/Wavefront PIX image
+    "pix",
+    // BRender PIX image
+    "brender_pix",
+    // CompuServe Graphics Interchange Format (GIF) - already included
+    // piped gif sequence - already included
+    // piped hdr sequence - already included
+    // Microsoft Windows ICO - already included
+    // piped j2k sequence (JPEG 2000)
+    "j2k",
+    "jp2",
+    "jpt",
+    // piped jpeg sequence - already included
+    // piped jpegls sequence (JPEG-LS)
+    "jls",
+    // Animated JPEG XL - already included
+    // piped jpegxl sequence - already included
+    // piped pam sequence - already included
+    // piped pbm sequence (Portable Bitmap)
+    "pbm",
+    // piped pcx sequence (PC Paintbrush)
+    "pcx",
+    // piped pfm sequence (Portable FloatMap)
+    "pfm",
+    // piped pgm sequence - already included
+    // piped pgmyuv sequence (Portable Graymap with YUV)
+    "pgmyuv",
+    // piped pgx sequence (JPEG 2000 part 1)
+    "pgx",
+    // piped phm sequence (Portable Half FloatMap)
+    "phm",
+    // piped photocd sequence (Photo CD)
+    "pcd",
+    // piped pictor sequence (Pictor)
+    "pct",
+    "pict",
+    // piped png sequence - already included
+    // piped ppm sequence - already included
+    // piped psd sequence (Adobe Photoshop)
+    "psd",
+    // piped qdraw sequence (QuickDraw)
+    "qdraw",
+    // piped qoi sequence (Quite OK Image)
+    "qoi",
+    // piped sgi sequence (Silicon Graphics Image)
+    "sgi",
+    // piped sunrast sequence (Sun Raster)
+    "ras",
+    // piped tiff sequence - already included
+    // piped vbn sequence (VBN)
+    "vbn",
+    // piped webp sequence - already included
+    // piped xbm sequence (XBM)
+    "xbm",
+    // piped xpm sequence (XPM)
+    "xpm",
+    // piped xwd sequence (XWD)
+    "xwd",
+];
+
+/// The image extensions `args` ask for: the built-in ones or those of
+/// --extensions, with --add-ext added and --remove-ext removed.
+fn accepted_extensions(args: &Args) -> std::collections::HashSet<String> {
+    // Matched against lowercased extensions, so given ones are lowercased too.
+    let normalize = |extension: &String| extension.trim_start_matches('.').to_lowercase();
+    let mut accepted: std::collections::HashSet<String> = match &args.extensions {
+        Some(extensions) => extensions.iter().map(normalize).collect(),
+        None => ACCEPTED_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+    };
+    accepted.extend(args.add_ext.iter().map(normalize));
+    for extension in &args.remove_ext {
+        accepted.remove(&normalize(extension));
+    }
+    accepted.remove("");
+    accepted
+}
+
+/// Directories that hold tool or cache files rather than images anyone wants
+/// converted: version control, package managers, thumbnail caches and the
+/// index directories Synology NASes put everywhere.
+const DEFAULT_EXCLUDED_DIRS: &[&str] = &[".git", "node_modules", ".thumbnails", "@eaDir", ".cache"];
+
+/// The directory names `filters` leave out of the walk: the built-in ones,
+/// unless --no-default-excludes, and those of --exclude-dir.
+fn excluded_dirs(filters: &Filters) -> std::collections::HashSet<std::ffi::OsString> {
+    let defaults = if filters.no_default_excludes {
+        &[][..]
+    } else {
+        DEFAULT_EXCLUDED_DIRS
+    };
+    defaults
+        .iter()
+        .copied()
+        .chain(filters.exclude_dir.iter().map(String::as_str))
+        .map(std::ffi::OsString::from)
+        .collect()
+}
+
+/// A conversion run: its options and what it works out from them and from
+/// the collection, shared by all of its tasks.
+#[derive(Clone)]
+struct Context {
+    args: Args,
+    /// The image extensions of this run, resolved from the built-in list and
+    /// the options before anything is walked.
+    accepted: Arc<std::collections::HashSet<String>>,
+    /// The directory names left out of the walk, from the filters.
+    excluded_dirs: Arc<std::collections::HashSet<std::ffi::OsString>>,
+    /// What --probe found for every image, for the conversions to use
+    /// instead of probing again.
+    probed: Arc<std::collections::HashMap<std::path::PathBuf, probe::Info>>,
+    /// The results of the files converted by --pilot, which the run reports
+    /// instead of converting them again.
+    piloted: Arc<std::sync::Mutex<std::collections::HashMap<std::path::PathBuf, Converted>>>,
+    /// The limiter of --rate-limit, shared by every file of the run.
+    rate_limiter: Option<Arc<ratelimit::RateLimit>>,
+    /// The ImageMagick binary found at startup when --magick-fallback is on.
+    magick: Option<&'static str>,
+    /// What writes the JPEG XL files, picked when the run starts. JPEGs
+    /// transcoded losslessly go to cjxl whichever it is.
+    encoder: &'static dyn encoder::Encoder,
+    /// What runs the external tools: the processes themselves, or the runner
+    /// the library was given, within --tool-timeout.
+    runner: Arc<dyn process::CommandRunner>,
+    /// Whether the run is added to the history, which only the command line
+    /// does, so programs using the library keep their runs to themselves.
+    history: bool,
+    /// Held while an output of a hash is written or looked for, so identical
+    /// sources converting at the same time don't write the same file twice.
+    addressing: Addressing,
+    /// The output directories the run made, for --dir-mode and --chown.
+    created_dirs: perms::CreatedDirs,
+    /// Whether the run has warned about rounded modification times.
+    rounding_warned: Arc<std::sync::atomic::AtomicBool>,
+    /// The tools the run started itself, which --pause-encoders stops.
+    children: process::Children,
+    /// Where the lines of the run go: printed by the command line, sent as
+    /// events or dropped by the library.
+    messages: events::Messages,
+    /// What stops and pauses the run: the signals the command line listens
+    /// for, or the handle of the converter.
+    control: control::Handle,
+    /// Asks whether to go on after the overview. Without one the run goes
+    /// on without asking.
+    prompt: Option<Prompt>,
+}
+
+/// Asks the user a yes or no question, with the answer an empty reply gives.
+type Prompt = Arc<dyn Fn(&str, bool) -> std::io::Result<bool> + Send + Sync>;
+
+impl Context {
+    /// The context of a run with `args`, whose tools `runner` runs, or
+    /// processes the run keeps track of when there is none.
+    fn new(
+        args: Args,
+        runner: Option<Arc<dyn process::CommandRunner>>,
+        messages: events::Messages,
+    ) -> Self {
+        let mut messages = messages.verbose(args.verbose);
+        // Before anything is printed, so all of it goes to the standard
+        // error.
+        if args.print_outputs.is_some() {
+            messages = messages.only_stderr();
+        }
+        let children = process::Children::default();
+        let runner = runner.unwrap_or_else(|| {
+            Arc::new(process::Spawn {
+                children: children.clone(),
+                messages: messages.clone(),
+            })
+        });
+        let runner: Arc<dyn process::CommandRunner> = match &args.ffmpeg_path {
+            Some(path) => Arc::new(process::FfmpegPath::new(runner, path)),
+            None => runner,
+        };
+        let runner: Arc<dyn process::CommandRunner> = match args.tool_timeout {
+            Some(seconds) => Arc::new(process::Timeout(
+                runner,
+                std::time::Duration::from_secs(seconds),
+            )),
+            None => runner,
+        };
+        Context {
+            accepted: Arc::new(accepted_extensions(&args)),
+            excluded_dirs: Arc::new(excluded_dirs(&args.filters)),
+            probed: Arc::default(),
+            piloted: Arc::default(),
+            rate_limiter: args
+                .rate_limit
+                .map(|rate| Arc::new(ratelimit::RateLimit::new(rate))),
+            magick: None,
+            encoder: &encoder::Ffmpeg,
+            runner,
+            history: false,
+            addressing: Arc::default(),
+            created_dirs: perms::CreatedDirs::default(),
+            rounding_warned: Arc::default(),
+            children,
+            messages,
+            control: control::Handle::default(),
+            prompt: None,
+            args,
+        }
+    }
+
+    fn runner(&self) -> &dyn process::CommandRunner {
+        &*self.runner
+    }
+
+    /// `bar`, hidden unless the run prints its lines.
+    fn progress_bar(&self, bar: ProgressBar) -> ProgressBar {
+        if !self.messages.prints() {
+            bar.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        }
+        bar
+    }
+}
+
+impl Args {
+    fn dimension_limits(&self) -> dimensions::Limits {
+        dimensions::Limits {
+            min_pixels: self.min_pixels,
+            max_pixels: self.max_pixels,
+            min_dimensions: self.min_dimensions,
+        }
+    }
+}
+
+/// Lowercased extension of `path` for matching against the known extensions.
+/// The rest of the name may be any bytes; an extension that isn't valid UTF-8
+/// matches none of them.
+fn extension_of(path: &std::path::Path) -> String {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+enum ProcessResult {
+    Converted {
+        original_size: u64,
+        converted_size: u64,
+        source_pages: usize,
+        pages: usize,
+        /// The files written for the pages or images of the source, when
+        /// they don't go to the planned output
+        parts: Vec<std::path::PathBuf>,
+        /// Whether the output is still larger than --target-size
+        over_target: bool,
+        /// Whether the output holds what `restore-jpeg` rebuilds the source
+        /// JPEG from
+        restorable: bool,
+        /// What was done beyond a plain conversion, for the report
+        notes: Vec<String>,
+    },
+    Copied {
+        /// "reflink", "hardlink" or "copy", for the report
+        method: &'static str,
+        /// An image copied for being below --convert-min-size
+        below_threshold: bool,
+    },
+    /// A symlink recreated in the output under `--symlinks preserve`
+    Linked,
+    /// The original copied to the output because the conversion failed the quality gate
+    KeptOriginal {
+        notes: Vec<String>,
+    },
+    /// A conversion that failed --verify, handled per --on-verify-fail
+    VerifyFailed {
+        action: VerifyFailAction,
+        reason: String,
+        notes: Vec<String>,
+    },
+    /// A zip or cbz archive with its images converted
+    Archive {
+        original_size: u64,
+        converted_size: u64,
+        pages: usize,
+        notes: Vec<String>,
+    },
+    /// An existing JPEG XL file copied through unchanged
+    CopiedJxl {
+        notes: Vec<String>,
+    },
+    /// An existing JPEG XL file re-encoded at the current effort
+    RecompressedJxl {
+        original_size: u64,
+        converted_size: u64,
+    },
+    Skipped,
+    /// An existing JPEG XL file left out by the policy
+    SkippedJxl,
+    /// Skipped a source that has more than one page
+    SkippedMultipage,
+    Error(anyhow::Error),
+}
+
+impl ProcessResult {
+    /// What the library reports about the file `source` this is the result of.
+    fn file_result(
+        &self,
+        source: &std::path::Path,
+        output: Option<&std::path::Path>,
+        duration: std::time::Duration,
+    ) -> FileResult {
+        let (action, original_size, converted_size, notes) = match self {
+            ProcessResult::Converted {
+                original_size,
+                converted_size,
+                notes,
+                ..
+            }
+            | ProcessResult::Archive {
+                original_size,
+                converted_size,
+                notes,
+                ..
+            } => (
+                Action::Converted,
+                *original_size,
+                *converted_size,
+                notes.clone(),
+            ),
+            ProcessResult::RecompressedJxl {
+                original_size,
+                converted_size,
+            } => (
+                Action::Converted,
+                *original_size,
+                *converted_size,
+                vec!["recompressed existing jxl".to_string()],
+            ),
+            ProcessResult::Copied { method, .. } => {
+                (Action::Copied, 0, 0, vec![method.to_string()])
+            }
+            ProcessResult::CopiedJxl { notes } => (Action::Copied, 0, 0, notes.clone()),
+            ProcessResult::Linked => (Action::Linked, 0, 0, Vec::new()),
+            ProcessResult::KeptOriginal { notes } => (Action::KeptOriginal, 0, 0, notes.clone()),
+            ProcessResult::VerifyFailed {
+                action,
+                reason,
+                notes,
+            } => {
+                let action = match action {
+                    VerifyFailAction::Error => Action::Failed,
+                    VerifyFailAction::CopyOriginal => Action::KeptOriginal,
+                    VerifyFailAction::KeepBoth => Action::KeptBoth,
+                };
+                let mut notes = notes.clone();
+                notes.push(format!("verification failed: {}", reason));
+                (action, 0, 0, notes)
+            }
+            ProcessResult::Skipped
+            | ProcessResult::SkippedJxl
+            | ProcessResult::SkippedMultipage => (Action::Skipped, 0, 0, Vec::new()),
+            ProcessResult::Error(e) => (Action::Failed, 0, 0, vec![e.to_string()]),
+        };
+        FileResult {
+            source: source.to_owned(),
+            output: output.map(std::path::Path::to_owned),
+            action,
+            original_size,
+            converted_size,
+            duration,
+            notes,
+        }
+    }
+}
+
+// Even a 1x1 image needs a signature, headers and some pixel data. That's
+// all the size can tell, though: a tiny or flat image encodes to a couple of
+// dozen bytes, so a higher bar would fail conversions that are fine. An
+// output cut short further on keeps its signature as well, and only --verify,
+// decoding it again, finds it.
+const MIN_JXL_SIZE: u64 = 16;
+
+/// Checks that a file written by the encoder has a JPEG XL signature and a
+/// plausible size.
+fn check_jxl_output(path: &std::path::Path) -> anyhow::Result<()> {
+    let size = std::fs::metadata(path)?.len();
+    if size < MIN_JXL_SIZE {
+        return Err(anyhow::anyhow!("only {} bytes long", size));
+    }
+    if sniff::detect(path)? != Some(sniff::ImageKind::Jxl) {
+        return Err(anyhow::anyhow!("no JPEG XL signature"));
+    }
+    Ok(())
+}
+
+/// A finished conversion of one output file.
+struct Conversion {
+    original_size: u64,
+    converted_size: u64,
+    /// Whether the output is still larger than --target-size
+    over_target: bool,
+    /// How the encode deviated from the plain path, for the report
+    notes: Vec<String>,
+}
+
+/// A checked encoder output.
+struct Encoded {
+    size: u64,
+    notes: Vec<String>,
+}
+
+async fn convert_image(
+    input_path: &std::path::Path,
+    output_file_path: &std::path::Path,
+    input_format: Option<&str>,
+    settings: EncodeSettings<'_>,
+    selection: InputSelection,
+) -> anyhow::Result<Conversion> {
+    detail!(
+        settings.messages,
+        "   Converting {} -> {}",
+        input_path.display(),
+        output_file_path.display()
+    );
+
+    // Work on a temporary file so an interrupted run never leaves something at
+    // the final path that the next run would take for a finished output.
+    let partial = PartialFile {
+        path: partial_path(output_file_path, std::process::id()),
+        keep: settings.keep_partials,
+    };
+    let partial_path = partial.path.as_path();
+    async {
+        let mut encoded =
+            encode_image(input_path, partial_path, input_format, settings, selection).await?;
+        if let Some(gate) = settings.quality_gate {
+            let score = gate
+                .measure(
+                    settings.runner,
+                    input_path,
+                    input_format,
+                    selection,
+                    partial_path,
+                )
+                .await?;
+            if score < gate.threshold() {
+                return Err(quality::BelowThreshold { gate, score }.into());
+            }
+            encoded.notes.push(format!("{}={:.4}", gate.name(), score));
+        }
+        let (original_size, converted_size) =
+            finish_conversion(input_path, partial_path, settings).await?;
+        rename_output(partial_path, output_file_path, settings).await?;
+        Ok(Conversion {
+            original_size,
+            converted_size,
+            over_target: false,
+            notes: encoded.notes,
+        })
+    }
+    .await
+}
+
+/// Removes a temporary output when dropped, whether the conversion failed or
+/// its future was dropped half way. After a successful rename there is
+/// nothing left to remove.
+struct PartialFile {
+    path: std::path::PathBuf,
+    /// Leave it in place for --keep-partials.
+    keep: bool,
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+// Temporary outputs are hidden and named so that no source gives an output
+// like it: a crashed run's can be removed without taking anything else.
+const PARTIAL_PREFIX: &str = ".bulk-jxl-partial-";
+
+/// The process that wrote the temporary file `name`, from the
+/// `.bulk-jxl-partial-PID.` it starts with.
+fn partial_pid(name: &str) -> Option<u32> {
+    let rest = name.strip_prefix(PARTIAL_PREFIX)?;
+    rest.split('.').next()?.parse().ok()
+}
+
+/// The temporary name the process `pid` writes an output under before it is
+/// complete, in the same directory so the final rename stays on one
+/// filesystem. Temporaries made from a partial output, like the JPEG a
+/// recompression rebuilds, add an extension to it.
+fn partial_path(output_file_path: &std::path::Path, pid: u32) -> std::path::PathBuf {
+    let name = output_file_path.file_name().unwrap_or_default();
+    let mut partial = std::ffi::OsString::from(format!("{}{}.", PARTIAL_PREFIX, pid));
+    partial.push(name);
+    output_file_path.with_file_name(partial)
+}
+
+/// The temporary files the process `pid` left for the output
+/// `output_file_path`: its partial output and those of its pages, images,
+/// attempts and intermediate files, which are all named after it.
+fn partials_of(output_file_path: &std::path::Path, pid: u32) -> Vec<std::path::PathBuf> {
+    let (Some(directory), Some(stem)) = (output_file_path.parent(), output_file_path.file_stem())
+    else {
+        return Vec::new();
+    };
+    let mut prefix = std::ffi::OsString::from(format!("{}{}.", PARTIAL_PREFIX, pid));
+    prefix.push(stem);
+    prefix.push(".");
+    let Ok(entries) = std::fs::read_dir(directory) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry
+                .file_name()
+                .as_encoded_bytes()
+                .starts_with(prefix.as_encoded_bytes())
+        })
+        .map(|entry| entry.path())
+        .collect()
+}
+
+/// Runs the encoder once and checks its output, without touching timestamps.
+/// Returns the size of the written file. Can be called repeatedly for the same
+/// input with different settings.
+async fn encode_image(
+    input_path: &std::path::Path,
+    output_file_path: &std::path::Path,
+    input_format: Option<&str>,
+    settings: EncodeSettings<'_>,
+    selection: InputSelection,
+) -> anyhow::Result<Encoded> {
+    let _encoding = profile::span(profile::Stage::Encode);
+    let options = settings.encode_options(input_format, selection);
+    let notes = if settings.jpeg_reconstruct {
+        encoder::JpegTranscode
+            .encode(input_path, output_file_path, options)
+            .await?
+            .notes
+    } else {
+        match settings
+            .encoder
+            .encode(input_path, output_file_path, options)
+            .await
+        {
+            Err(e) if settings.magick.is_some() && e.is::<encoder::DecodeError>() => {
+                say!(
+                    settings.messages,
+                    "      {}, retrying {} via ImageMagick",
+                    e,
+                    input_path.display()
+                );
+                let mut notes =
+                    encode_via_magick(input_path, output_file_path, settings, selection).await?;
+                notes.push("via imagemagick".to_string());
+                notes
+            }
+            result => result?.notes,
+        }
+    };
+
+    // ffmpeg can exit cleanly without having written a usable image, e.g. on
+    // some mapping errors, so make sure the output at least looks like JPEG XL.
+    if let Err(e) = check_jxl_output(output_file_path) {
+        let _ = std::fs::remove_file(output_file_path);
+        return Err(anyhow::anyhow!("Invalid JXL output: {}", e));
+    }
+
+    match settings.container {
+        ContainerMode::Auto => {}
+        ContainerMode::Always => container::wrap(output_file_path)?,
+        ContainerMode::Never => {
+            let dropped = container::unwrap(output_file_path)?;
+            if !dropped.is_empty() {
+                say!(
+                    settings.messages,
+                    "      Warning: dropped {} from {} to write a bare codestream",
+                    dropped.join(", "),
+                    output_file_path.display()
+                );
+            }
+        }
+    }
+
+    Ok(Encoded {
+        size: tokio::fs::metadata(output_file_path).await?.len(),
+        notes,
+    })
+}
+
+/// Has ImageMagick decode the input to a temporary PNG next to the output and
+/// encodes that instead. The PNG is removed whether or not this succeeds, and
+/// when the conversion is dropped half-way.
+async fn encode_via_magick(
+    input_path: &std::path::Path,
+    output_file_path: &std::path::Path,
+    settings: EncodeSettings<'_>,
+    selection: InputSelection,
+) -> anyhow::Result<Vec<String>> {
+    let magick = settings
+        .magick
+        .ok_or_else(|| anyhow::anyhow!("ImageMagick is not available"))?;
+    // Named like a partial output, so a crashed run's is swept up too.
+    let png = PartialFile {
+        path: {
+            let mut path = partial_path(
+                &output_file_path.with_extension("magick"),
+                std::process::id(),
+            )
+            .into_os_string();
+            path.push(".png");
+            path.into()
+        },
+        keep: false,
+    };
+    let png_path = png.path.as_path();
+
+    // ImageMagick selects frames and pages with a `[n]` suffix, counted from 0.
+    let mut source = process::tool_arg(input_path).into_os_string();
+    source.push(match selection {
+        InputSelection::Whole => "[0]".to_string(),
+        InputSelection::TiffPage(page) => format!("[{}]", page - 1),
+        InputSelection::Stream(index) => format!("[{}]", index),
+    });
+    let mut target = std::ffi::OsString::from("png:");
+    target.push(process::tool_arg(png_path));
+
+    let finished = settings
+        .runner
+        .run(
+            process::command(magick).arg(&source).arg(&target),
+            settings.verbose,
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to run {}: {}", magick, e))?;
+    if !finished.status.success() {
+        return Err(anyhow::anyhow!(
+            "ImageMagick failed to decode the image: {}",
+            finished.message("no output")
+        ));
+    }
+    let options = EncodeSettings {
+        animated: false,
+        ..settings
+    }
+    .encode_options(None, InputSelection::Whole);
+    let encoded = settings
+        .encoder
+        .encode(png_path, output_file_path, options)
+        .await?;
+    Ok(encoded.notes)
+}
+
+/// The most conversions that run at once.
+fn conversion_jobs(args: &Args) -> u64 {
+    match args.adaptive_jobs {
+        Some(range) => range.max as u64,
+        None => args.parallel.jobs.max(1) as u64,
+    }
+}
+
+/// Shares `total` encoder threads out evenly among `jobs` conversions,
+/// rounding down so they never use more than `total` together, but giving
+/// each at least one.
+fn threads_per_job(total: u64, jobs: u64) -> u64 {
+    (total / jobs.max(1)).max(1)
+}
+
+/// How `total` threads are shared among `jobs` conversions, for the
+/// overview, e.g. `16 / 4 conversions = 4 per conversion`.
+fn thread_division(total: u64, jobs: u64) -> String {
+    let threads = threads_per_job(total, jobs);
+    format!(
+        "{} / {} conversions = {} per conversion{}",
+        total,
+        jobs,
+        threads,
+        match (threads * jobs).cmp(&total) {
+            std::cmp::Ordering::Less => format!(", {} left over", total - threads * jobs),
+            std::cmp::Ordering::Greater => format!(", {} in all", threads * jobs),
+            std::cmp::Ordering::Equal => String::new(),
+        }
+    )
+}
+
+/// An output directory can't be created because a file has its name or that
+/// of one of its parents, e.g. left there by an earlier `--copy-all` run.
+#[derive(Debug)]
+struct DirectoryConflict {
+    dir: std::path::PathBuf,
+    file: std::path::PathBuf,
+}
+
+impl std::fmt::Display for DirectoryConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cannot create directory {}: {} is a file",
+            self.dir.display(),
+            self.file.display()
+        )
+    }
+}
+
+impl std::error::Error for DirectoryConflict {}
+
+/// The closest existing ancestor of `dir`, itself included, that isn't a
+/// directory.
+fn blocking_file(dir: &std::path::Path) -> Option<std::path::PathBuf> {
+    dir.ancestors()
+        .find_map(|ancestor| {
+            std::fs::metadata(ancestor)
+                .ok()
+                .map(|metadata| (ancestor, metadata))
+        })
+        .filter(|(_, metadata)| !metadata.is_dir())
+        .map(|(ancestor, _)| ancestor.to_owned())
+}
+
+/// Creates the output directory `dir` and its parents, naming the file in
+/// the way if that's why it can't.
+async fn create_output_dir(dir: &std::path::Path, context: &Context) -> anyhow::Result<()> {
+    let (created, owned) = (context.created_dirs.clone(), dir.to_owned());
+    match tokio::task::spawn_blocking(move || created.create(&owned)).await? {
+        Ok(()) => Ok(()),
+        Err(e) => match blocking_file(dir) {
+            Some(file) => Err(DirectoryConflict {
+                dir: dir.to_owned(),
+                file,
+            }
+            .into()),
+            None => Err(e.into()),
+        },
+    }
+}
+
+/// Carries the source's timestamps over to a finished output and reports the
+/// sizes of both.
+async fn finish_conversion(
+    input_path: &std::path::Path,
+    output_file_path: &std::path::Path,
+    settings: EncodeSettings<'_>,
+) -> anyhow::Result<(u64, u64)> {
+    let src_fs_metadata = source_metadata(input_path).await?;
+    let modified_timestamp = src_fs_metadata.modified()?;
+
+    detail!(
+        settings.messages,
+        "      Setting modified timestamp to {:?}",
+        modified_timestamp
+    );
+    preserve_times(&src_fs_metadata, output_file_path, settings).await?;
+
+    let src_size = src_fs_metadata.len();
+    let output_file_path = std::path::PathBuf::from(&output_file_path);
+    let dst_size = tokio::fs::metadata(output_file_path).await?.len();
+
+    detail!(
+        settings.messages,
+        "      Compressed from {} -> {}",
+        human_bytes(src_size as f64),
+        human_bytes(dst_size as f64)
+    );
+
+    Ok((src_size, dst_size)) // Return sizes
+}
+
+/// Gives `output_path` the timestamps of the source with `source` metadata,
+/// retrying transient errors. Setting times is the step SMB shares fail most.
+/// A filesystem rounding them is warned about once per run.
+async fn preserve_times(
+    source: &std::fs::Metadata,
+    output_path: &std::path::Path,
+    settings: EncodeSettings<'_>,
+) -> std::io::Result<()> {
+    let _setting = profile::span(profile::Stage::Metadata);
+    let off = retry::io(
+        "Setting timestamps",
+        settings.io_retries,
+        settings.messages,
+        || {
+            let (source, output_path) = (source.clone(), output_path.to_owned());
+            async move {
+                tokio::task::spawn_blocking(move || {
+                    times::preserve(
+                        &source,
+                        &output_path,
+                        settings.preserve_times,
+                        settings.mtime_tolerance,
+                    )
+                })
+                .await?
+            }
+        },
+    )
+    .await?;
+    if !off.is_zero()
+        && !settings
+            .rounding_warned
+            .swap(true, std::sync::atomic::Ordering::Relaxed)
+    {
+        say!(
+            settings.messages,
+            "   Warning: the output filesystem rounds modification times (by {:?} for {}), \
+             outputs get the source times only as closely as it allows",
+            off,
+            output_path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Moves a finished output to its final name, retrying transient errors.
+async fn rename_output(
+    from: &std::path::Path,
+    to: &std::path::Path,
+    settings: EncodeSettings<'_>,
+) -> std::io::Result<()> {
+    let _renaming = profile::span(profile::Stage::Rename);
+    retry::io("Renaming", settings.io_retries, settings.messages, || {
+        tokio::fs::rename(from, to)
+    })
+    .await
+}
+
+/// Copies `file` to `output_file_path` along with its timestamps, retrying
+/// transient errors of either step. Returns how the copy was made: "reflink",
+/// "hardlink" or "copy".
+async fn copy_with_times(
+    file: &std::path::Path,
+    output_file_path: &std::path::Path,
+    settings: EncodeSettings<'_>,
+) -> std::io::Result<&'static str> {
+    let copying = retry::io("Copying", settings.io_retries, settings.messages, || {
+        let (file, output_file_path) = (file.to_owned(), output_file_path.to_owned());
+        let (mode, limit) = (settings.copy_mode, settings.rate_limit.cloned());
+        async move {
+            tokio::task::spawn_blocking(move || {
+                copy_file(&file, &output_file_path, mode, limit.as_deref())
+            })
+            .await?
+        }
+    });
+    let method = profile::time(profile::Stage::Copy, copying).await?;
+    preserve_times(&source_metadata(file).await?, output_file_path, settings).await?;
+    Ok(method)
+}
+
+/// Copies `from` to `to` the way `mode` asks, falling back to copying the
+/// contents where the filesystem can't clone or link, e.g. when the output is
+/// on another filesystem. Copied contents stay within `limit`, clones and
+/// links move no data. Returns how the copy was made.
+fn copy_file(
+    from: &std::path::Path,
+    to: &std::path::Path,
+    mode: CopyMode,
+    limit: Option<&ratelimit::RateLimit>,
+) -> std::io::Result<&'static str> {
+    match mode {
+        CopyMode::Content => {}
+        CopyMode::Reflink => return reflink::clone(from, to).map(|()| "reflink"),
+        CopyMode::Auto => {
+            if reflink::clone(from, to).is_ok() {
+                return Ok("reflink");
+            }
+        }
+        CopyMode::Hardlink => {
+            // A link can't replace an existing file.
+            match std::fs::remove_file(to) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            if std::fs::hard_link(from, to).is_ok() {
+                return Ok("hardlink");
+            }
+        }
+    }
+    sparse::copy(from, to, limit).map(|_| "copy")
+}
+
+/// The outputs of the `pages` of a multi-page TIFF, as `tiff::pages` finds
+/// them, numbered in order as `name.pNNN.jxl`.
+fn page_parts(
+    output_file_path: &std::path::Path,
+    pages: &[usize],
+) -> Vec<(std::path::PathBuf, InputSelection)> {
+    pages
+        .iter()
+        .enumerate()
+        .map(|(index, &page)| {
+            (
+                output_file_path.with_extension(format!("p{:03}.jxl", index + 1)),
+                InputSelection::TiffPage(page),
+            )
+        })
+        .collect()
+}
+
+/// An image of an ICO file as the report describes it, e.g. `32x32 png`.
+fn describe_icon(entry: &ico::IcoEntry) -> String {
+    format!(
+        "{}x{} {}",
+        entry.width,
+        entry.height,
+        if entry.png {
+            "png".to_string()
+        } else {
+            format!("{}bpp", entry.bits_per_pixel)
+        }
+    )
+}
+
+/// The outputs of the images of an ICO file with `entries` that `mode`
+/// converts: the largest to `name.jxl`, or each to `name.SIZE.jxl`, with the
+/// bit depth or the place in the file, from 1, after sizes that repeat.
+fn icon_parts(
+    input_path: &std::path::Path,
+    entries: &[ico::IcoEntry],
+    output_file_path: &std::path::Path,
+    mode: IcoMode,
+    messages: &events::Messages,
+) -> Vec<(std::path::PathBuf, InputSelection)> {
+    match mode {
+        IcoMode::Largest => {
+            let (index, entry) = entries
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, e)| (e.width as u64 * e.height as u64, e.bits_per_pixel))
+                .unwrap();
+            detail!(
+                messages,
+                "   Icon {} holds {} images, converting the largest ({})",
+                input_path.display(),
+                entries.len(),
+                describe_icon(entry)
+            );
+            vec![(output_file_path.to_owned(), InputSelection::Stream(index))]
+        }
+        IcoMode::All => entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| {
+                let size = if entry.width == entry.height {
+                    entry.width.to_string()
+                } else {
+                    format!("{}x{}", entry.width, entry.height)
+                };
+                // Icons may hold the same size at several bit depths, keep those
+                // apart. PNG images mostly leave the depth at 0, so where that
+                // doesn't tell them apart either, their place in the file does.
+                let same_size =
+                    |e: &&ico::IcoEntry| e.width == entry.width && e.height == entry.height;
+                let duplicates = entries.iter().filter(same_size).count();
+                let same_depth = entries
+                    .iter()
+                    .filter(same_size)
+                    .filter(|e| e.bits_per_pixel == entry.bits_per_pixel)
+                    .count();
+                let suffix = if same_depth > 1 {
+                    format!("{}.{}.jxl", size, index + 1)
+                } else if duplicates > 1 {
+                    format!("{}.{}bpp.jxl", size, entry.bits_per_pixel)
+                } else {
+                    format!("{}.jxl", size)
+                };
+                (
+                    output_file_path.with_extension(suffix),
+                    InputSelection::Stream(index),
+                )
+            })
+            .collect(),
+    }
+}
+
+/// Probes what encoding the image of `file` that `selection` picks takes
+/// beyond the settings it starts with: the frames of an animated PNG, the
+/// rotation of --bake-orientation, the colour tags to assume and a
+/// single-channel pixel format for grayscale. Returns the input format to
+/// read it as and notes for the report, or `None` for an animated PNG left
+/// out by --skip-animated.
+async fn probe_encoding<'a>(
+    file: &std::path::Path,
+    format_extension: &str,
+    mut input_format: Option<&'a str>,
+    selection: InputSelection,
+    settings: &mut EncodeSettings<'_>,
+    context: &Context,
+) -> Option<(Option<&'a str>, Vec<String>)> {
+    let args = &context.args;
+    let mut source_notes = Vec::new();
+    // Animated PNGs look like stills by their extension, ffmpeg would only read the first frame.
+    if format_extension == "png" {
+        match apng::animation(file) {
+            Ok(Some(animation)) if animation.frames > 1 => {
+                if args.skip_animated {
+                    say!(
+                        context.messages,
+                        "   Skipping animated PNG ({} frames): {}",
+                        animation.frames,
+                        file.display()
+                    );
+                    return None;
+                }
+                input_format = Some("apng");
+                settings.animated = true;
+                settings.plays = animation.plays;
+                source_notes.push(format!(
+                    "png (animated, {} frames, {})",
+                    animation.frames,
+                    match animation.plays {
+                        0 => "loops forever".to_string(),
+                        plays => format!("plays {} times", plays),
+                    }
+                ));
+            }
+            _ => source_notes.push("png (still)".to_string()),
+        }
+    }
+
+    if args.bake_orientation && !settings.animated {
+        let orientation = probe::orientation(settings.runner, file, input_format, selection)
+            .await
+            .unwrap_or(1);
+        if let Some(filter) = probe::orientation_filter(orientation) {
+            detail!(
+                context.messages,
+                "   Baking orientation {} into {}",
+                orientation,
+                file.display()
+            );
+            settings.video_filter = Some(filter);
+            source_notes.push(format!("rotated (orientation {})", orientation));
+        }
+    }
+
+    if args.assume_color_space.is_some() || args.assume_transfer.is_some() {
+        match assumed_color_tags(file, input_format, selection, context).await {
+            Ok((tags, status)) => {
+                settings.color_tags = tags;
+                source_notes.push(format!("color tags {}", status));
+            }
+            Err(e) => say!(
+                context.messages,
+                "   Warning: could not probe color tags of {}: {}",
+                file.display(),
+                e
+            ),
+        }
+    }
+
+    if settings.jpeg_reconstruct
+        && let Some(reason) = settings.reconstruction_blocker()
+    {
+        settings.jpeg_reconstruct = false;
+        settings.distance = Some(0.0);
+        source_notes.push(format!("lossless instead of reconstruct ({})", reason));
+    }
+
+    // Keep grayscale sources single-channel instead of letting them be expanded to RGB.
+    // --probe only looked at the first image.
+    let probed = context
+        .probed
+        .get(file)
+        .filter(|_| matches!(selection, InputSelection::Whole));
+    let pix_fmt = match probed {
+        Some(info) => Ok(info.pix_fmt.clone()),
+        None => probe::pixel_format(settings.runner, file, input_format, selection).await,
+    };
+    if let Ok(pix_fmt) = pix_fmt {
+        let source_channels = probe::channel_count(&pix_fmt);
+        let output_channels = match probe::grayscale_encoder_format(&pix_fmt) {
+            Some(gray_format) if !args.force_rgb => {
+                // cjxl keeps the single channel of a grayscale JPEG anyway.
+                if !settings.jpeg_reconstruct {
+                    settings.pixel_format = Some(gray_format);
+                }
+                source_channels
+            }
+            // libjxl keeps alpha, everything else is encoded as RGB.
+            _ if matches!(source_channels, 2 | 4) => 4,
+            _ => 3,
+        };
+        source_notes.push(format!(
+            "channels {} -> {}",
+            source_channels, output_channels
+        ));
+    }
+    Some((input_format, source_notes))
+}
+
+/// The outputs of an image, each with the part of the source it holds.
+struct ImageParts {
+    /// `name.jxl`, or the images of an ICO file as --ico picks them, or the
+    /// pages of a multi-page TIFF with `--multipage all`.
+    parts: Vec<(std::path::PathBuf, InputSelection)>,
+    /// The pages of a TIFF, 1 for other images.
+    pages: usize,
+    /// The images of an ICO file, for the report.
+    icon: Option<String>,
+}
+
+/// Finds the outputs of the image `file` whose output is planned at
+/// `output`, as process_image and --emit-script go about them.
+async fn image_parts(
+    file: &std::path::Path,
+    format_extension: &str,
+    output: &std::path::Path,
+    context: &Context,
+) -> anyhow::Result<ImageParts> {
+    let args = &context.args;
+    if format_extension == "ico" {
+        let entries = {
+            let file = file.to_owned();
+            tokio::task::spawn_blocking(move || ico::entries(&file)).await??
+        };
+        if entries.is_empty() {
+            return Err(anyhow::anyhow!("ICO file is empty"));
+        }
+        let icon = format!(
+            "ico with {} images: {}",
+            entries.len(),
+            entries
+                .iter()
+                .map(describe_icon)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        return Ok(ImageParts {
+            parts: icon_parts(file, &entries, output, args.ico, &context.messages),
+            pages: 1,
+            icon: Some(icon),
+        });
+    }
+
+    // Multi-page TIFFs need their page count before we can decide on the outputs.
+    let pages = if format_extension == "tif" || format_extension == "tiff" {
+        let _probing = profile::span(profile::Stage::Probe);
+        let file = file.to_owned();
+        tokio::task::spawn_blocking(move || tiff::pages(&file))
+            .await?
+            .unwrap_or_else(|_| vec![1])
+    } else {
+        vec![1]
+    };
+    let parts = match args.multipage {
+        MultipageMode::All if pages.len() > 1 => page_parts(output, &pages),
+        _ => vec![(output.to_owned(), InputSelection::Whole)],
+    };
+    Ok(ImageParts {
+        parts,
+        pages: pages.len(),
+        icon: None,
+    })
+}
+
+/// Converts a single image file, handling multi-page and multi-image sources.
+/// Every page or image is probed, encoded and checked on its own, the way a
+/// plain image is. `format_extension` is the canonical extension of the image
+/// format, which is the file's own extension unless sniffing found otherwise.
+async fn process_image(
+    file: &std::path::Path,
+    output_file_path: &std::path::Path,
+    format_extension: &str,
+    input_format: Option<&str>,
+    settings: EncodeSettings<'_>,
+    context: &Context,
+) -> anyhow::Result<ProcessResult> {
+    let args = &context.args;
+    let ImageParts {
+        parts,
+        pages: page_count,
+        icon,
+    } = match image_parts(file, format_extension, output_file_path, context).await {
+        Ok(found) => found,
+        Err(e) => return Ok(ProcessResult::Error(e)),
+    };
+
+    if page_count > 1 {
+        match args.multipage {
+            MultipageMode::Skip => {
+                say!(
+                    context.messages,
+                    "   Skipping multi-page TIFF ({} pages): {}",
+                    page_count,
+                    file.display()
+                );
+                return Ok(ProcessResult::SkippedMultipage);
+            }
+            MultipageMode::First => {
+                say!(
+                    context.messages,
+                    "   Warning: {} has {} pages, only the first will be converted",
+                    file.display(),
+                    page_count
+                );
+            }
+            MultipageMode::All => {}
+        }
+    }
+
+    if parts.iter().all(|(part, _)| part.exists()) {
+        detail!(
+            context.messages,
+            "   Skipping existing JXL: {}",
+            parts
+                .iter()
+                .map(|(part, _)| part.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        if page_count > 1 {
+            return Ok(ProcessResult::SkippedMultipage);
+        }
+        return Ok(ProcessResult::Skipped);
+    }
+
+    let (mut original_size, mut converted_size, mut over_target) = (0, 0, false);
+    let mut restorable = false;
+    let mut notes: Vec<String> = Vec::new();
+    let mut written = Vec::new();
+    let mut read_as = input_format;
+    for (part, selection) in &parts {
+        if part.exists() {
+            detail!(
+                context.messages,
+                "   Skipping existing JXL: {}",
+                part.display()
+            );
+            continue;
+        }
+        if let Some(parent) = part.parent() {
+            create_output_dir(parent, context).await?;
+        }
+
+        let mut settings = settings;
+        let probing = profile::span(profile::Stage::Probe);
+        let Some((input_format, mut part_notes)) = probe_encoding(
+            file,
+            format_extension,
+            input_format,
+            *selection,
+            &mut settings,
+            context,
+        )
+        .await
+        else {
+            return Ok(ProcessResult::Skipped);
+        };
+        drop(probing);
+        read_as = input_format;
+
+        let conversion = match args.target_size {
+            Some(target_size) => {
+                convert_to_target_size(file, part, input_format, settings, *selection, target_size)
+                    .await
+            }
+            None => convert_gated(file, part, input_format, settings, *selection).await,
+        };
+        let conversion = match conversion {
+            Ok(conversion) => conversion,
+            Err(e) => {
+                let e = if e.is::<quality::BelowThreshold>() {
+                    // The file as a whole fails the gate, the parts already
+                    // written go too.
+                    for (written, _) in &written {
+                        let _ = std::fs::remove_file(written);
+                    }
+                    e
+                } else if parts.len() > 1 {
+                    anyhow::anyhow!("{}: {}", part.display(), e)
+                } else {
+                    e
+                };
+                return conversion_failed(file, output_file_path, e, settings).await;
+            }
+        };
+        original_size = conversion.original_size;
+        converted_size += conversion.converted_size;
+        over_target |= conversion.over_target;
+        // A bare codestream drops the reconstruction data.
+        restorable |= settings.jpeg_reconstruct && args.container != ContainerMode::Never;
+
+        if args.metadata_sidecar
+            && let Some(note) =
+                profile::time(profile::Stage::Metadata, keep_metadata(file, part, context)).await
+        {
+            part_notes.push(note);
+        }
+        part_notes.extend(conversion.notes);
+        if let Ok(layout) = container::layout(part) {
+            part_notes.push(layout.to_string());
+        }
+        // The parts of a source mostly come out alike, the report only
+        // needs to say so once.
+        for note in part_notes {
+            if !notes.contains(&note) {
+                notes.push(note);
+            }
+        }
+        written.push((part.clone(), *selection));
+    }
+
+    if let Some(icon) = icon {
+        let converted = match args.ico {
+            IcoMode::Largest => "converted largest".to_string(),
+            IcoMode::All => format!("converted {} of {} images", written.len(), parts.len()),
+        };
+        notes.splice(0..0, [icon, converted]);
+    } else if parts.len() > 1 {
+        notes.insert(
+            0,
+            format!("{} of {} pages converted", written.len(), page_count),
+        );
+    } else if page_count > 1 {
+        notes.push(format!("first of {} pages converted", page_count));
+    }
+    let result = ProcessResult::Converted {
+        original_size,
+        converted_size,
+        source_pages: page_count,
+        pages: written.len(),
+        // Only listed when they aren't the planned output.
+        parts: if written.iter().any(|(part, _)| part != output_file_path) {
+            written.iter().map(|(part, _)| part.clone()).collect()
+        } else {
+            Vec::new()
+        },
+        over_target,
+        restorable,
+        notes,
+    };
+    if args.verify {
+        return verify_conversion(file, output_file_path, &written, read_as, context, result).await;
+    }
+    Ok(result)
+}
+
+/// Works out which of `--assume-color-space` and `--assume-transfer` apply to
+/// a file, and whether its tags end up `assumed`, `inherited` or `forced`.
+async fn assumed_color_tags(
+    file: &std::path::Path,
+    input_format: Option<&str>,
+    selection: InputSelection,
+    context: &Context,
+) -> anyhow::Result<(color::ColorTags, &'static str)> {
+    let args = &context.args;
+    let transfer = args.assume_transfer.or(args
+        .assume_color_space
+        .map(color::ColorSpace::default_transfer));
+    let wanted = color::ColorTags {
+        primaries: args.assume_color_space.map(color::ColorSpace::primaries),
+        matrix: args.assume_color_space.map(color::ColorSpace::matrix),
+        trc: transfer.map(color::Transfer::trc),
+    };
+    if args.force_color_tags {
+        return Ok((wanted, "forced"));
+    }
+
+    let info = probe::color_info(context.runner(), file, input_format, selection).await?;
+    let tags = color::ColorTags {
+        primaries: wanted
+            .primaries
+            .filter(|_| color::is_untagged(&info.primaries)),
+        matrix: wanted.matrix.filter(|_| color::is_untagged(&info.matrix)),
+        trc: wanted.trc.filter(|_| color::is_untagged(&info.transfer)),
+    };
+    let status = if tags.is_empty() {
+        "inherited"
+    } else {
+        "assumed"
+    };
+    Ok((tags, status))
+}
+
+/// Writes a metadata sidecar for a converted file when its output lacks
+/// metadata the source has, either because of `--container never` or because
+/// the encoder didn't carry it. Returns where the metadata ended up, for the
+/// report.
+async fn keep_metadata(
+    file: &std::path::Path,
+    output_file_path: &std::path::Path,
+    context: &Context,
+) -> Option<String> {
+    let embedded = match container::box_types(output_file_path) {
+        Ok(types) => types
+            .into_iter()
+            .filter(|t| t == "Exif" || t == "xml ")
+            .collect::<Vec<_>>(),
+        Err(_) => Vec::new(),
+    };
+    let dropped = match sidecar::held(file) {
+        Ok(Some(held)) => {
+            (held.exif && !embedded.iter().any(|t| t == "Exif"))
+                || (held.xmp && !embedded.iter().any(|t| t == "xml "))
+                || held.other
+        }
+        // Sources not read here may hold anything the output lacks.
+        _ => embedded.len() < 2,
+    };
+    if !dropped {
+        return (!embedded.is_empty()).then(|| "metadata embedded".to_string());
+    }
+
+    let sidecar_path = sidecar::path_for(output_file_path);
+    match sidecar::write(context.runner(), file, &sidecar_path).await {
+        Ok(true) if embedded.is_empty() => Some("metadata in sidecar".to_string()),
+        Ok(true) => Some("metadata embedded and in sidecar".to_string()),
+        Ok(false) if embedded.is_empty() => None,
+        Ok(false) => Some("metadata embedded".to_string()),
+        Err(e) => {
+            say!(
+                context.messages,
+                "      Warning: could not write metadata sidecar {}: {}",
+                sidecar_path.display(),
+                e
+            );
+            Some(format!("metadata sidecar failed: {}", e))
+        }
+    }
+}
+
+// libjxl accepts distances up to 25.
+const MAX_DISTANCE: f32 = 25.0;
+// Bisection steps after the initial attempt(s), each one a full encode.
+const TARGET_SIZE_ITERATIONS: usize = 5;
+
+/// Encodes the image of a file that `selection` picks at varying distances
+/// to find the best quality that still fits in `target_size` bytes.
+/// Intermediate attempts are written next to the output and removed again,
+/// only the chosen one is kept. The chosen attempt has to pass the quality
+/// gate; with `--quality-gate-action re-encode` the attempts at lower
+/// distances the search made are measured next, even though they are over
+/// the target.
+async fn convert_to_target_size(
+    file: &std::path::Path,
+    output_file_path: &std::path::Path,
+    input_format: Option<&str>,
+    settings: EncodeSettings<'_>,
+    selection: InputSelection,
+    target_size: u64,
+) -> anyhow::Result<Conversion> {
+    detail!(
+        settings.messages,
+        "   Converting {} -> {} (target {})",
+        file.display(),
+        output_file_path.display(),
+        human_bytes(target_size as f64)
+    );
+
+    let mut settings = settings;
+    let initial = settings.distance.unwrap_or(1.0).clamp(0.0, MAX_DISTANCE);
+
+    // Temporary files like any other output, so they are removed when the
+    // run is interrupted and swept up by the next one after a crash.
+    let mut attempts: Vec<(f32, u64, PartialFile)> = Vec::new();
+    let mut encode_notes = Vec::new();
+    let mut encode_at = async |distance: f32| -> anyhow::Result<u64> {
+        let attempt = PartialFile {
+            path: partial_path(
+                &output_file_path.with_extension(format!("attempt{}.jxl", attempts.len())),
+                std::process::id(),
+            ),
+            keep: settings.keep_partials,
+        };
+        settings.distance = Some(distance);
+        let result = encode_image(file, &attempt.path, input_format, settings, selection).await;
+        result.map(|encoded| {
+            attempts.push((distance, encoded.size, attempt));
+            // Every attempt decodes the same way, the notes only need keeping once.
+            encode_notes = encoded.notes;
+            encoded.size
+        })
+    };
+
+    let search = async {
+        let initial_size = encode_at(initial).await?;
+        // The range that still contains the boundary between fitting and not fitting.
+        let (mut low, mut high) = if initial_size <= target_size {
+            (0.0, initial)
+        } else if encode_at(MAX_DISTANCE).await? <= target_size {
+            (initial, MAX_DISTANCE)
+        } else {
+            return Ok(());
+        };
+        for _ in 0..TARGET_SIZE_ITERATIONS {
+            let distance = (low + high) / 2.0;
+            if encode_at(distance).await? <= target_size {
+                high = distance;
+            } else {
+                low = distance;
+            }
+        }
+        anyhow::Ok(())
+    }
+    .await;
+
+    // The other attempts go when they are dropped, at the latest on return.
+    search?;
+    // Lowest distance that fits, or the smallest output when none does.
+    let chosen = attempts
+        .iter()
+        .filter(|(_, size, _)| *size <= target_size)
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+        .or_else(|| attempts.iter().min_by_key(|(_, size, _)| *size))
+        .map(|(distance, size, attempt)| (*distance, *size, attempt.path.clone()))
+        .unwrap();
+    let mut notes = encode_notes;
+    let (distance, size, path) = match settings.quality_gate {
+        Some(gate) => {
+            // A lower distance only gets larger, so the attempts to fall back
+            // on are those below the chosen one, closest first.
+            let mut candidates = vec![chosen.clone()];
+            if settings.gate_action == GateAction::ReEncode {
+                let mut lower: Vec<_> = attempts
+                    .iter()
+                    .filter(|(distance, _, _)| *distance < chosen.0)
+                    .map(|(distance, size, attempt)| (*distance, *size, attempt.path.clone()))
+                    .collect();
+                lower.sort_by(|a, b| b.0.total_cmp(&a.0));
+                candidates.extend(lower);
+            }
+            let mut passed = None;
+            let mut below = None;
+            for (distance, size, path) in candidates {
+                let score = gate
+                    .measure(settings.runner, file, input_format, selection, &path)
+                    .await?;
+                if score >= gate.threshold() {
+                    notes.push(format!("{}={:.4}", gate.name(), score));
+                    passed = Some((distance, size, path));
+                    break;
+                }
+                let failed = quality::BelowThreshold { gate, score };
+                if settings.gate_action == GateAction::ReEncode {
+                    say!(
+                        settings.messages,
+                        "   {} at distance {}, trying {} at a lower one",
+                        failed,
+                        distance,
+                        file.display()
+                    );
+                }
+                below = Some(failed);
+            }
+            match passed {
+                Some(passed) => passed,
+                None => return Err(below.unwrap().into()),
+            }
+        }
+        None => chosen,
+    };
+    let (original_size, converted_size) = finish_conversion(file, &path, settings).await?;
+    rename_output(&path, output_file_path, settings).await?;
+
+    let over_target = size > target_size;
+    notes.push(format!("distance={}", distance));
+    if over_target {
+        say!(
+            settings.messages,
+            "      Warning: {} is {} at distance {}, over the target of {}",
+            output_file_path.display(),
+            human_bytes(size as f64),
+            distance,
+            human_bytes(target_size as f64)
+        );
+        notes.push(format!("over target size ({} > {})", size, target_size));
+    }
+
+    Ok(Conversion {
+        original_size,
+        converted_size,
+        over_target,
+        notes,
+    })
+}
+
+/// Converts one image, or one page or embedded image of it, under the
+/// quality gate that convert_image checks before the output is renamed into
+/// place. With `--quality-gate-action re-encode` an output below the
+/// threshold is encoded again at half the distance, a quarter and
+/// losslessly, until one passes.
+async fn convert_gated(
+    file: &std::path::Path,
+    output_file_path: &std::path::Path,
+    input_format: Option<&str>,
+    settings: EncodeSettings<'_>,
+    selection: InputSelection,
+) -> anyhow::Result<Conversion> {
+    let mut result = convert_image(file, output_file_path, input_format, settings, selection).await;
+    if settings.gate_action != GateAction::ReEncode {
+        return result;
+    }
+    // libjxl defaults to distance 1, halve it twice before falling back to lossless.
+    let start = settings.distance.unwrap_or(1.0);
+    for distance in [start / 2.0, start / 4.0, 0.0] {
+        let below = match &result {
+            Err(e) => match e.downcast_ref::<quality::BelowThreshold>() {
+                Some(below) => below.to_string(),
+                None => break,
+            },
+            Ok(_) => break,
+        };
+        say!(
+            settings.messages,
+            "   {}, re-encoding {} at distance {}",
+            below,
+            file.display(),
+            distance
+        );
+        let settings = EncodeSettings {
+            distance: Some(distance),
+            ..settings
+        };
+        result = convert_image(file, output_file_path, input_format, settings, selection)
+            .await
+            .map(|mut conversion| {
+                conversion.notes.push(format!("distance={}", distance));
+                conversion
+            });
+    }
+    result
+}
+
+/// What becomes of a file whose conversion failed with `error`. A conversion
+/// below the quality gate puts the original in the output tree instead under
+/// `--quality-gate-action keep-original`, and is an error otherwise.
+async fn conversion_failed(
+    file: &std::path::Path,
+    output_file_path: &std::path::Path,
+    error: anyhow::Error,
+    settings: EncodeSettings<'_>,
+) -> anyhow::Result<ProcessResult> {
+    let below = match error.downcast::<quality::BelowThreshold>() {
+        Ok(below) => below,
+        Err(error) => return Ok(ProcessResult::Error(error)),
+    };
+    if settings.gate_action != GateAction::KeepOriginal {
+        return Ok(ProcessResult::Error(anyhow::anyhow!(
+            "Quality gate failed: {}",
+            below
+        )));
+    }
+    say!(settings.messages, "   Quality gate failed ({})", below);
+    copy_original(file, output_file_path, settings).await?;
+    Ok(ProcessResult::KeptOriginal {
+        notes: vec![below.to_string()],
+    })
+}
+
+/// Puts the source itself in the output tree next to where its conversion
+/// would have gone, keeping its name and timestamps.
+async fn copy_original(
+    file: &std::path::Path,
+    output_file_path: &std::path::Path,
+    settings: EncodeSettings<'_>,
+) -> anyhow::Result<std::path::PathBuf> {
+    let original_path = output_file_path.with_file_name(
+        file.file_name()
+            .ok_or_else(|| anyhow::anyhow!("Invalid source path"))?,
+    );
+    detail!(
+        settings.messages,
+        "   Keeping original {} -> {}",
+        file.display(),
+        original_path.display()
+    );
+    copy_with_times(file, &original_path, settings).await?;
+    Ok(original_path)
+}
+
+/// Decodes the `outputs` of a finished conversion with `--verify`, each
+/// against the image of the source it holds, and applies `--on-verify-fail`
+/// to all of them when one doesn't hold up.
+async fn verify_conversion(
+    file: &std::path::Path,
+    output_file_path: &std::path::Path,
+    outputs: &[(std::path::PathBuf, InputSelection)],
+    input_format: Option<&str>,
+    context: &Context,
+    result: ProcessResult,
+) -> anyhow::Result<ProcessResult> {
+    let args = &context.args;
+    if !matches!(result, ProcessResult::Converted { .. }) {
+        return Ok(result);
+    }
+    let mut failure = None;
+    for (output, selection) in outputs {
+        let verifying =
+            validate::verify_output(context.runner(), file, input_format, *selection, output);
+        if let Err(reason) = profile::time(profile::Stage::Verify, verifying).await {
+            failure = Some((output, reason));
+            break;
+        }
+    }
+    let Some((failed, reason)) = failure else {
+        return Ok(result);
+    };
+    let ProcessResult::Converted { mut notes, .. } = result else {
+        unreachable!()
+    };
+
+    say!(
+        context.messages,
+        "   Verification failed for {}: {}",
+        failed.display(),
+        reason
+    );
+    notes.push(format!("verification failed: {}", reason));
+    match args.on_verify_fail {
+        VerifyFailAction::Error => {
+            for (output, _) in outputs {
+                tokio::fs::remove_file(output).await?;
+            }
+        }
+        VerifyFailAction::CopyOriginal => {
+            for (output, _) in outputs {
+                tokio::fs::remove_file(output).await?;
+            }
+            copy_original(
+                file,
+                output_file_path,
+                EncodeSettings::from_context(context),
+            )
+            .await?;
+        }
+        VerifyFailAction::KeepBoth => {
+            for (output, _) in outputs {
+                let mut suspect_path = output.as_os_str().to_owned();
+                suspect_path.push(".suspect");
+                let suspect_path = std::path::PathBuf::from(suspect_path);
+                tokio::fs::rename(output, &suspect_path).await?;
+                notes.push(format!("suspect output kept as {}", suspect_path.display()));
+            }
+            copy_original(
+                file,
+                output_file_path,
+                EncodeSettings::from_context(context),
+            )
+            .await?;
+        }
+    }
+    Ok(ProcessResult::VerifyFailed {
+        action: args.on_verify_fail,
+        reason,
+        notes,
+    })
+}
+
+/// Applies the `--existing-jxl` policy to a file that already is JPEG XL.
+async fn process_existing_jxl(
+    file: &std::path::Path,
+    output_file_path: &std::path::Path,
+    context: &Context,
+) -> anyhow::Result<ProcessResult> {
+    let args = &context.args;
+    if args.existing_jxl == ExistingJxl::Skip {
+        detail!(
+            context.messages,
+            "   Skipping existing JPEG XL source: {}",
+            file.display()
+        );
+        return Ok(ProcessResult::SkippedJxl);
+    }
+
+    if output_file_path.exists() {
+        detail!(
+            context.messages,
+            "   Skipping existing JXL: {}",
+            output_file_path.display()
+        );
+        return Ok(ProcessResult::Skipped);
+    }
+
+    if let Some(parent) = output_file_path.parent() {
+        create_output_dir(parent, context).await?;
+    }
+
+    match args.existing_jxl {
+        ExistingJxl::Copy => {
+            copy_existing_jxl(
+                file,
+                output_file_path,
+                Vec::new(),
+                EncodeSettings::from_context(context),
+            )
+            .await
+        }
+        ExistingJxl::Recompress => recompress_jxl(file, output_file_path, context).await,
+        ExistingJxl::Skip => unreachable!(),
+    }
+}
+
+/// Copies an existing JPEG XL file into the output tree, keeping its timestamps.
+async fn copy_existing_jxl(
+    file: &std::path::Path,
+    output_file_path: &std::path::Path,
+    mut notes: Vec<String>,
+    settings: EncodeSettings<'_>,
+) -> anyhow::Result<ProcessResult> {
+    detail!(
+        settings.messages,
+        "   Copying JPEG XL {} -> {}",
+        file.display(),
+        output_file_path.display()
+    );
+    match copy_with_times(file, output_file_path, settings).await {
+        Ok(method) => notes.push(method.to_string()),
+        Err(e) => return Ok(ProcessResult::Error(anyhow::anyhow!("Copy failed: {}", e))),
+    }
+    Ok(ProcessResult::CopiedJxl { notes })
+}
+
+/// Re-encodes an existing JPEG XL file losslessly next to the output, and
+/// only keeps the result when it came out smaller and decodes to exactly what
+/// the original does. A file transcoded from a JPEG is transcoded again by
+/// cjxl from the JPEG djxl rebuilds, so the result can still restore it.
+/// Otherwise the original is copied through unchanged.
+async fn recompress_jxl(
+    file: &std::path::Path,
+    output_file_path: &std::path::Path,
+    context: &Context,
+) -> anyhow::Result<ProcessResult> {
+    detail!(
+        context.messages,
+        "   Recompressing {} -> {}",
+        file.display(),
+        output_file_path.display()
+    );
+
+    let original_size = source_metadata(file).await?.len();
+    let mut settings = EncodeSettings::from_context(context);
+    // Nothing a decoder of the original sees may change.
+    settings.distance = Some(0.0);
+    let partial = PartialFile {
+        path: partial_path(
+            &output_file_path.with_extension("recompress.jxl"),
+            std::process::id(),
+        ),
+        keep: settings.keep_partials,
+    };
+    let attempt_path = partial.path.as_path();
+    // djxl picks the output format by extension, so the rebuilt JPEG needs a
+    // .jpg name.
+    let jpeg = container::has_jpeg_reconstruction(file)
+        .unwrap_or(false)
+        .then(|| {
+            let mut path = attempt_path.as_os_str().to_owned();
+            path.push(".jpg");
+            PartialFile {
+                path: path.into(),
+                keep: settings.keep_partials,
+            }
+        });
+    // encode_image already rejects outputs without a valid JPEG XL signature.
+    let attempt = async {
+        let encoded = match &jpeg {
+            Some(jpeg) => {
+                restore::reconstruct(settings.runner, file, &jpeg.path).await?;
+                let settings = EncodeSettings {
+                    jpeg_reconstruct: true,
+                    ..settings
+                };
+                encode_image(
+                    &jpeg.path,
+                    attempt_path,
+                    None,
+                    settings,
+                    InputSelection::Whole,
+                )
+                .await?
+            }
+            None => {
+                encode_image(
+                    file,
+                    attempt_path,
+                    Some(sniff::ImageKind::Jxl.ffmpeg_format()),
+                    settings,
+                    InputSelection::Whole,
+                )
+                .await?
+            }
+        };
+        anyhow::Ok(encoded.size)
+    }
+    .await;
+
+    let reason = match attempt {
+        Ok(size) if size < original_size => {
+            let jpeg = jpeg.as_ref().map(|jpeg| jpeg.path.as_path());
+            match verify_recompression(file, attempt_path, jpeg, settings.runner).await {
+                Ok(()) => {
+                    let (original_size, converted_size) =
+                        finish_conversion(file, attempt_path, settings).await?;
+                    rename_output(attempt_path, output_file_path, settings).await?;
+                    return Ok(ProcessResult::RecompressedJxl {
+                        original_size,
+                        converted_size,
+                    });
+                }
+                Err(e) => format!("recompression changed the image: {}", e),
+            }
+        }
+        Ok(size) => format!("recompression not smaller ({} >= {})", size, original_size),
+        Err(e) => format!("recompression failed: {}", e),
+    };
+
+    drop((partial, jpeg));
+    say!(
+        context.messages,
+        "      Keeping original {}: {}",
+        file.display(),
+        reason
+    );
+    copy_existing_jxl(file, output_file_path, vec![reason], settings).await
+}
+
+/// Checks that a recompressed JPEG XL file decodes to exactly what the
+/// original does: to the same JPEG as `jpeg`, rebuilt from the original, or
+/// otherwise to the same pixels.
+async fn verify_recompression(
+    original: &std::path::Path,
+    attempt: &std::path::Path,
+    jpeg: Option<&std::path::Path>,
+    runner: &dyn process::CommandRunner,
+) -> anyhow::Result<()> {
+    let Some(jpeg) = jpeg else {
+        return validate::same_pixels(
+            runner,
+            original,
+            attempt,
+            Some(sniff::ImageKind::Jxl.ffmpeg_format()),
+        )
+        .await
+        .map_err(anyhow::Error::msg);
+    };
+    let mut path = attempt.as_os_str().to_owned();
+    path.push(".check.jpg");
+    let check = PartialFile {
+        path: path.into(),
+        keep: false,
+    };
+    restore::reconstruct(runner, attempt, &check.path).await?;
+    if tokio::fs::read(&check.path).await? != tokio::fs::read(jpeg).await? {
+        return Err(anyhow::anyhow!("the rebuilt JPEG differs"));
+    }
+    Ok(())
+}
+
+/// Converts the images inside a zip or cbz archive, either into a new archive
+/// or an extracted directory at `output_path`. The work happens in a temporary
+/// directory next to the output, so a failure on any page leaves nothing
+/// behind.
+async fn process_archive(
+    file: &std::path::Path,
+    output_path: &std::path::Path,
+    context: &Context,
+) -> anyhow::Result<ProcessResult> {
+    let args = &context.args;
+    if tokio::fs::try_exists(output_path).await.unwrap_or(false) {
+        detail!(
+            context.messages,
+            "   Skipping existing archive output: {}",
+            output_path.display()
+        );
+        return Ok(ProcessResult::Skipped);
+    }
+
+    let mut work_name = std::ffi::OsString::from(".");
+    work_name.push(
+        output_path
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("Invalid output path"))?,
+    );
+    work_name.push(".bulk-jxl-tmp");
+    let work_dir = output_path.with_file_name(work_name);
+    if tokio::fs::try_exists(&work_dir).await? {
+        tokio::fs::remove_dir_all(&work_dir).await?;
+    }
+    tokio::fs::create_dir_all(&work_dir).await?;
+
+    detail!(
+        context.messages,
+        "   Unpacking archive {} -> {}",
+        file.display(),
+        output_path.display()
+    );
+    let result = convert_archive(file, output_path, &work_dir, context).await;
+    // After a successful extract the work directory has become the output.
+    let _ = tokio::fs::remove_dir_all(&work_dir).await;
+
+    match result {
+        Ok((pages, member_count)) => {
+            let src_fs_metadata = source_metadata(file).await?;
+            preserve_times(
+                &src_fs_metadata,
+                output_path,
+                EncodeSettings::from_context(context),
+            )
+            .await?;
+            let output = tokio::fs::metadata(output_path).await?;
+            let converted_size = if output.is_dir() {
+                let output_path = output_path.to_owned();
+                tokio::task::spawn_blocking(move || {
+                    walkdir::WalkDir::new(output_path)
+                        .into_iter()
+                        .filter_map(|e| e.ok())
+                        .filter_map(|e| e.metadata().ok())
+                        .filter(|m| m.is_file())
+                        .map(|m| m.len())
+                        .sum()
+                })
+                .await?
+            } else {
+                output.len()
+            };
+            Ok(ProcessResult::Archive {
+                original_size: src_fs_metadata.len(),
+                converted_size,
+                pages,
+                notes: vec![format!(
+                    "{} {} of {} members converted",
+                    match args.archives {
+                        ArchiveMode::Extract => "extracted,",
+                        _ => "repacked,",
+                    },
+                    pages,
+                    member_count
+                )],
+            })
+        }
+        Err(e) => Ok(ProcessResult::Error(anyhow::anyhow!(
+            "Archive {} left out: {}",
+            file.display(),
+            e
+        ))),
+    }
+}
+
+/// Stands in for the conversion of archives in builds without them, which
+/// `prepare` doesn't let get this far.
+#[cfg(not(feature = "archives"))]
+async fn convert_archive(
+    _file: &std::path::Path,
+    _output_path: &std::path::Path,
+    _work_dir: &std::path::Path,
+    _context: &Context,
+) -> anyhow::Result<(usize, usize)> {
+    Err(anyhow::anyhow!("compiled without the 'archives' feature"))
+}
+
+/// Unpacks an archive into `work_dir`, converts its images and puts the
+/// result in place. Returns the number of converted pages and members.
+#[cfg(feature = "archives")]
+async fn convert_archive(
+    file: &std::path::Path,
+    output_path: &std::path::Path,
+    work_dir: &std::path::Path,
+    context: &Context,
+) -> anyhow::Result<(usize, usize)> {
+    let args = &context.args;
+    let extract_dir = work_dir.join("members");
+    let members = {
+        let (file, extract_dir) = (file.to_owned(), extract_dir.clone());
+        tokio::task::spawn_blocking(move || archive::extract_all(&file, &extract_dir)).await??
+    };
+    let settings = EncodeSettings::from_context(context);
+
+    let images: Vec<_> = members
+        .iter()
+        .filter(|m| !m.is_dir && context.accepted.contains(&extension_of(&m.path)))
+        .map(|m| m.index)
+        .collect();
+    // Before anything is converted, so no page overwrites another.
+    let mut names = archive::converted_names(&members, &images)?;
+
+    let mut replaced = std::collections::HashMap::new();
+    for member in &members {
+        let Some(jxl_name) = names.remove(&member.index) else {
+            continue;
+        };
+        let page_path = extract_dir.join(&member.path);
+        let jxl_path = page_path.with_file_name(
+            std::path::Path::new(&jxl_name)
+                .file_name()
+                .unwrap_or_default(),
+        );
+        match convert_gated(&page_path, &jxl_path, None, settings, InputSelection::Whole).await {
+            Ok(_) => {}
+            // The member stays in the archive as it is.
+            Err(e)
+                if settings.gate_action == GateAction::KeepOriginal
+                    && e.is::<quality::BelowThreshold>() =>
+            {
+                say!(
+                    context.messages,
+                    "   Quality gate failed for {} ({}), keeping it",
+                    member.name,
+                    e
+                );
+                continue;
+            }
+            Err(e) => return Err(anyhow::anyhow!("{}: {}", member.name, e)),
+        }
+        tokio::fs::remove_file(&page_path).await?;
+        replaced.insert(member.index, (jxl_name, jxl_path));
+    }
+
+    let pages = replaced.len();
+    if let Some(parent) = output_path.parent() {
+        create_output_dir(parent, context).await?;
+    }
+    match args.archives {
+        ArchiveMode::Extract => tokio::fs::rename(&extract_dir, output_path).await?,
+        _ => {
+            let partial = work_dir.join("repacked");
+            let (file, destination) = (file.to_owned(), partial.clone());
+            tokio::task::spawn_blocking(move || archive::repack(&file, &destination, &replaced))
+                .await??;
+            tokio::fs::rename(&partial, output_path).await?;
+        }
+    }
+    Ok((pages, members.len()))
+}
+
+/// What a collected file is to the conversion, from its extension and, with
+/// --sniff, its content.
+#[derive(Clone, Copy)]
+enum FileKind {
+    ExistingJxl,
+    Archive,
+    Image(Option<sniff::ImageKind>),
+    /// A symlink recreated as such under `--symlinks preserve`.
+    Symlink,
+    /// An image below `--convert-min-size`, copied as it is.
+    Small,
+    Other,
+}
+
+impl FileKind {
+    /// Whether the file is copied rather than converted, which takes one of
+    /// the `--copy-jobs` slots instead of a `--jobs` one.
+    fn is_copy(self, args: &Args) -> bool {
+        match self {
+            FileKind::Other | FileKind::Symlink | FileKind::Small => true,
+            FileKind::ExistingJxl => args.existing_jxl == ExistingJxl::Copy,
+            FileKind::Archive | FileKind::Image(_) => false,
+        }
+    }
+
+    /// What `source` is, from its extension, its header where that's read,
+    /// and the size the walk found.
+    fn of(source: &SourceFile, context: &Context) -> Self {
+        let args = &context.args;
+        let file = source.path.as_path();
+        if args.symlinks == SymlinkMode::Preserve
+            && source.metadata.as_ref().is_some_and(|m| m.is_symlink())
+        {
+            return FileKind::Symlink;
+        }
+        let file_extension = extension_of(file);
+
+        // Files that already are JPEG XL, whatever their extension says, must
+        // not be run through the regular conversion.
+        let detected = source.sniffed.filter(|_| args.sniff);
+        let is_jxl = file_extension == "jxl" || source.sniffed == Some(sniff::ImageKind::Jxl);
+
+        if is_jxl {
+            FileKind::ExistingJxl
+        } else if args.archives != ArchiveMode::Skip
+            && ARCHIVE_EXTENSIONS.contains(&file_extension.as_str())
+        {
+            FileKind::Archive
+        } else if context.accepted.contains(&file_extension) || detected.is_some() {
+            // Starting the encoder takes longer than such files deserve.
+            if args.convert_min_size > 0
+                && source
+                    .metadata
+                    .as_ref()
+                    .is_some_and(|m| m.len() < args.convert_min_size)
+            {
+                FileKind::Small
+            } else {
+                FileKind::Image(detected)
+            }
+        } else {
+            FileKind::Other
+        }
+    }
+
+    /// Where the result for a file of this kind goes, `None` when it is left out.
+    fn output_path(
+        self,
+        relative_path: &std::path::Path,
+        output_base_path: &std::path::Path,
+        args: &Args,
+    ) -> Option<std::path::PathBuf> {
+        let output_file_path =
+            output_base_path.join(collision::normalize(relative_path, args.normalize_names));
+        match self {
+            FileKind::ExistingJxl | FileKind::Image(_) => {
+                Some(output_file_path.with_extension("jxl"))
+            }
+            FileKind::Archive if args.archives == ArchiveMode::Extract => {
+                Some(output_file_path.with_extension(""))
+            }
+            FileKind::Archive => Some(output_file_path),
+            FileKind::Symlink | FileKind::Small => Some(output_file_path),
+            FileKind::Other if args.copy_all => Some(output_file_path),
+            FileKind::Other => None,
+        }
+    }
+}
+
+/// A file found by the walk, with the metadata read while walking so the
+/// source isn't stat'ed again for every step.
+#[derive(Clone)]
+struct SourceFile {
+    path: std::path::PathBuf,
+    /// `None` where the walk had none for the file, e.g. for symlinks it
+    /// didn't follow.
+    metadata: Option<std::fs::Metadata>,
+    /// The format its header shows, read by `readable_size` for the files
+    /// the run looks into: all of them with --sniff, otherwise those with an
+    /// image extension, which may be JPEG XL.
+    sniffed: Option<sniff::ImageKind>,
+}
+
+impl SourceFile {
+    /// The file behind a walked `entry`.
+    fn walked(entry: &walkdir::DirEntry, args: &Args) -> Self {
+        // Without following, a symlink's own metadata is of no use, unless
+        // the link itself is what gets copied.
+        let metadata = if entry.path_is_symlink() && args.symlinks == SymlinkMode::Preserve {
+            entry.path().symlink_metadata().ok()
+        } else if entry.path_is_symlink() && !args.follow_symlinks {
+            None
+        } else {
+            entry.metadata().ok()
+        };
+        SourceFile {
+            path: entry.path().to_owned(),
+            metadata,
+            sniffed: None,
+        }
+    }
+
+    /// Its size as walked, 0 where there was no metadata.
+    fn size(&self) -> u64 {
+        self.metadata.as_ref().map_or(0, |metadata| metadata.len())
+    }
+}
+
+/// The input and output directories of a run.
+#[derive(Clone)]
+struct RunPaths {
+    input: std::path::PathBuf,
+    /// Whether the input argument is a symlink, and `input` the directory it
+    /// resolves to.
+    input_is_link: bool,
+    output: std::path::PathBuf,
+    /// Whether the output directory was made by this run.
+    created_output: bool,
+    canonical_output: std::path::PathBuf,
+    /// Where the output directory shows up in the walk, when it's inside the
+    /// input.
+    excluded_output: Option<std::path::PathBuf>,
+}
+
+impl RunPaths {
+    /// The input as given, and where it leads if that's elsewhere.
+    fn input_display(&self, args: &Args) -> String {
+        if self.input_is_link {
+            format!("{} -> {}", args.tree.input, self.input.display())
+        } else {
+            args.tree.input.clone()
+        }
+    }
+
+    /// Whether the walk goes into `entry`, which it doesn't for the output
+    /// directory.
+    fn is_walked(&self, entry: &walkdir::DirEntry) -> bool {
+        self.excluded_output.as_deref() != Some(entry.path())
+            // A followed symlink can lead into the output directory too.
+            && !(entry.path_is_symlink()
+                && entry.file_type().is_dir()
+                && entry
+                    .path()
+                    .canonicalize()
+                    .is_ok_and(|p| p == self.canonical_output))
+    }
+}
+
+/// Entries the walk left out by name or attribute, before looking at them
+/// any further.
+#[derive(Default, Clone, Copy)]
+struct Pruned {
+    /// Directories --exclude-dir kept the walk out of.
+    dirs: usize,
+    /// Hidden files and directories left out by --skip-hidden.
+    hidden_files: usize,
+    hidden_dirs: usize,
+    /// Files and directories left out by --respect-gitignore.
+    ignored_files: usize,
+    ignored_dirs: usize,
+}
+
+impl Pruned {
+    /// Whether the walk leaves `entry` out, with everything in it, counting
+    /// it if so. Decided from the name and the type the directory listing
+    /// gives, so pruning stats nothing outside Windows but the .gitignore
+    /// files of `gitignore`. Those rules go first, then the other filters.
+    fn prunes(
+        &mut self,
+        entry: &walkdir::DirEntry,
+        context: &Context,
+        mut gitignore: Option<&mut gitignore::Stack>,
+    ) -> bool {
+        let args = &context.args;
+        let is_dir = entry.file_type().is_dir();
+        // The input itself is walked whatever its name.
+        if entry.depth() == 0 {
+            if let Some(gitignore) = gitignore
+                && is_dir
+            {
+                gitignore.enter(entry);
+            }
+            return false;
+        }
+        if let Some(gitignore) = gitignore.as_deref_mut()
+            && gitignore.ignores(entry)
+        {
+            if is_dir {
+                self.ignored_dirs += 1;
+            } else {
+                self.ignored_files += 1;
+            }
+            return true;
+        }
+        if is_dir && context.excluded_dirs.contains(entry.file_name()) {
+            self.dirs += 1;
+            return true;
+        }
+        if args.filters.skip_hidden && is_hidden(entry) {
+            if is_dir {
+                self.hidden_dirs += 1;
+            } else {
+                self.hidden_files += 1;
+            }
+            return true;
+        }
+        if let Some(gitignore) = gitignore
+            && is_dir
+        {
+            gitignore.enter(entry);
+        }
+        false
+    }
+}
+
+/// Whether `entry` is hidden: its name starts with a dot, or on Windows it
+/// has the hidden attribute.
+fn is_hidden(entry: &walkdir::DirEntry) -> bool {
+    if entry.file_name().as_encoded_bytes().starts_with(b".") {
+        return true;
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+        // Windows listings come with the metadata, so this reads nothing.
+        if entry
+            .metadata()
+            .is_ok_and(|m| m.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+        {
+            return true;
+        }
+    }
+    false
+}
+
+/// Whether a walked `entry` is a file this run has something to do with.
+fn is_candidate(entry: &walkdir::DirEntry, context: &Context) -> bool {
+    let args = &context.args;
+    // Symlinks to files are kept, including dangling ones, for `Scan::symlink`
+    // to decide on.
+    if !(entry.file_type().is_file() || (entry.path_is_symlink() && !entry.path().is_dir())) {
+        return false;
+    }
+    if args.copy_all {
+        // If copy_all is true, include all files
+        return true;
+    }
+    // Otherwise, only include accepted image extensions
+    let extension = extension_of(entry.path());
+    context.accepted.contains(&extension)
+        || extension == "jxl"
+        || (args.archives != ArchiveMode::Skip && ARCHIVE_EXTENSIONS.contains(&extension.as_str()))
+        || (args.sniff && matches!(sniff::detect(entry.path()), Ok(Some(_))))
+}
+
+/// Files the collection left out or came across, for the summary and the
+/// report.
+#[derive(Default)]
+struct Scan {
+    /// Paths of files already seen under another path, with that path.
+    duplicates: Vec<(std::path::PathBuf, std::path::PathBuf)>,
+    /// The duplicates that are hard links, until their outputs are placed.
+    links: Vec<SourceFile>,
+    /// Where the hard links among the duplicates get their outputs, placed
+    /// with the planned ones so collisions are resolved for both.
+    link_outputs: std::collections::HashMap<std::path::PathBuf, std::path::PathBuf>,
+    inaccessible: Vec<(std::path::PathBuf, String)>,
+    empty_sources: Vec<std::path::PathBuf>,
+    unstable: Vec<std::path::PathBuf>,
+    failed_validation: Vec<(std::path::PathBuf, String)>,
+    /// The sizes of the files the collection left out, as walked, for the
+    /// report.
+    left_out_sizes: std::collections::HashMap<std::path::PathBuf, u64>,
+    /// Dimensions of the images probed for --min-pixels and the like.
+    dimensions: std::collections::HashMap<std::path::PathBuf, dimensions::Dimensions>,
+    /// Images those options left out, with the reason.
+    outside_dimensions: Vec<(std::path::PathBuf, String)>,
+    /// Images whose dimensions couldn't be read, which are kept.
+    unknown_dimensions: usize,
+    /// What --probe found for every image.
+    probed: std::collections::HashMap<std::path::PathBuf, probe::Info>,
+    /// Images --probe couldn't probe.
+    probe_failed: usize,
+    /// What --pilot converted.
+    piloted: std::collections::HashMap<std::path::PathBuf, Converted>,
+    /// The files --pilot wrote to the output.
+    pilot_outputs: Vec<std::path::PathBuf>,
+    /// Sources left out because their output is taken, with the reason.
+    collided: Vec<(std::path::PathBuf, String)>,
+    walk_warnings: usize,
+    pruned: Pruned,
+    /// Symlinks to files the walk came across.
+    symlinks: usize,
+    /// Symlinks whose target doesn't exist, with that target.
+    dangling: Vec<(std::path::PathBuf, std::path::PathBuf)>,
+    /// Symlinks left out under `--symlinks skip`.
+    skipped_symlinks: Vec<std::path::PathBuf>,
+    empty_outputs: usize,
+    empty_outputs_replaced: usize,
+    /// Existing outputs that passed `--revalidate-existing`.
+    revalidated: usize,
+    /// Existing outputs that failed it and were removed to be converted
+    /// again, with the reason.
+    repaired: Vec<(std::path::PathBuf, String)>,
+    /// How long the phases of the collection took.
+    profile: profile::Profile,
+    /// What is going to happen to every file, made for --print-plan and
+    /// --dry-run.
+    plan: plan::Plan,
+}
+
+impl Scan {
+    /// Keeps the size of `source`, which the collection leaves out, for its
+    /// line in the report.
+    fn left_out(&mut self, source: &SourceFile) {
+        if let Some(metadata) = &source.metadata {
+            self.left_out_sizes
+                .insert(source.path.clone(), metadata.len());
+        }
+    }
+
+    /// Whether `source` is a file `seen` already has under another path, as
+    /// happens through symlinks and hard links. Converts it only once.
+    fn is_duplicate(&mut self, source: &SourceFile, seen: &mut Seen, context: &Context) -> bool {
+        let args = &context.args;
+        // Links kept as links are distinct, whatever they point to.
+        if source.metadata.as_ref().is_some_and(|m| m.is_symlink()) {
+            return false;
+        }
+        let followed = source.metadata.is_none() || source.path.is_symlink();
+        let hard_link = !followed && other_links(source);
+        let linked = followed || args.follow_symlinks || args.files_from.is_some() || hard_link;
+        if !linked && args.symlinks != SymlinkMode::Follow {
+            return false;
+        }
+        let Some(id) = file_id(source) else {
+            return false;
+        };
+        // With --no-preserve-hardlinks every hard link is converted on its
+        // own; only a symlink met later still leads to the first of them.
+        let original = match seen.linked.get(&id) {
+            _ if hard_link && args.no_preserve_hardlinks => None,
+            Some(original) => Some(original.clone()),
+            None if seen.single.contains(&id) => Some(seen.walked_path(&source.path)),
+            None => None,
+        };
+        if let Some(original) = original {
+            say!(
+                context.messages,
+                "   Duplicate: {} is {}",
+                source.path.display(),
+                original.display()
+            );
+            self.left_out(source);
+            self.duplicates.push((source.path.clone(), original));
+            if hard_link {
+                self.links.push(source.clone());
+            }
+            return true;
+        }
+        if linked {
+            seen.linked.entry(id).or_insert_with(|| source.path.clone());
+        } else {
+            seen.single.insert(id);
+        }
+        false
+    }
+
+    /// Takes the hard links `is_duplicate` put aside, with the outputs they
+    /// would get as files of their own, for their place among the planned
+    /// outputs.
+    fn take_links(
+        &mut self,
+        context: &Context,
+        paths: &RunPaths,
+    ) -> Vec<(std::path::PathBuf, std::path::PathBuf)> {
+        std::mem::take(&mut self.links)
+            .into_iter()
+            .filter_map(|source| {
+                let relative = source.path.strip_prefix(&paths.input).ok()?;
+                let output = FileKind::of(&source, context).output_path(
+                    relative,
+                    &paths.output,
+                    &context.args,
+                )?;
+                Some((source.path, output))
+            })
+            .collect()
+    }
+
+    /// Notes down a symlink to a file the walk came across, and returns
+    /// whether it goes on to be planned.
+    fn symlink(&mut self, entry: &walkdir::DirEntry, context: &Context) -> bool {
+        let args = &context.args;
+        self.symlinks += 1;
+        match args.symlinks {
+            SymlinkMode::Skip => {
+                say!(
+                    context.messages,
+                    "   Skipping symlink: {}",
+                    entry.path().display()
+                );
+                self.skipped_symlinks.push(entry.path().to_owned());
+                false
+            }
+            // A dangling link is mirrored like any other.
+            SymlinkMode::Preserve => true,
+            SymlinkMode::Follow => match std::fs::metadata(entry.path()) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    let target = std::fs::read_link(entry.path()).unwrap_or_default();
+                    say!(
+                        context.messages,
+                        "   Warning: dangling symlink {} -> {}",
+                        entry.path().display(),
+                        target.display()
+                    );
+                    self.dangling.push((entry.path().to_owned(), target));
+                    false
+                }
+                _ => true,
+            },
+        }
+    }
+
+    /// Takes the result of `readable_size` for `source` and returns the size
+    /// if there's something to convert, noting down why not otherwise.
+    fn readable(
+        &mut self,
+        source: &SourceFile,
+        size: std::io::Result<u64>,
+        context: &Context,
+    ) -> Option<u64> {
+        match size {
+            // Nothing to decode, don't spawn a tool just to have it fail.
+            Ok(0)
+                if !matches!(
+                    FileKind::of(source, context),
+                    FileKind::Other | FileKind::Symlink
+                ) =>
+            {
+                say!(context.messages, "   Empty file: {}", source.path.display());
+                self.left_out(source);
+                self.empty_sources.push(source.path.clone());
+                None
+            }
+            Ok(size) => Some(size),
+            Err(e) => {
+                say!(
+                    context.messages,
+                    "   Inaccessible: {}: {}",
+                    source.path.display(),
+                    e
+                );
+                self.inaccessible.push((source.path.clone(), e.to_string()));
+                None
+            }
+        }
+    }
+
+    /// Removes an output an earlier failed run left empty, so it's converted
+    /// again.
+    fn replace_empty_output(&mut self, output: &std::path::Path, messages: &events::Messages) {
+        say!(messages, "   Removing empty output {}", output.display());
+        self.empty_outputs += 1;
+        match std::fs::remove_file(output) {
+            Ok(()) => self.empty_outputs_replaced += 1,
+            Err(e) => say_error!(
+                messages,
+                "Error removing empty output {}: {}",
+                output.display(),
+                e
+            ),
+        }
+    }
+
+    /// Checks the output an earlier run left for `source` under
+    /// `--revalidate-existing`, and returns why it doesn't count as done.
+    /// Missing outputs, directories and empty ones, which are replaced
+    /// anyway, aren't checked.
+    fn revalidate(
+        &mut self,
+        source: &SourceFile,
+        output: &std::path::Path,
+        args: &Args,
+    ) -> Option<String> {
+        let mode = args.revalidate_existing?;
+        let metadata = output.metadata().ok()?;
+        if !metadata.is_file() || is_empty_output(source, output) {
+            return None;
+        }
+        let source_metadata = source.metadata.as_ref()?;
+        let failure = if extension_of(output) == "jxl" {
+            check_jxl_output(output).err().map(|e| e.to_string())
+        } else if metadata.len() != source_metadata.len() {
+            Some(format!(
+                "{} bytes, the source has {}",
+                metadata.len(),
+                source_metadata.len()
+            ))
+        } else {
+            None
+        };
+        let failure = failure.or_else(|| {
+            let (Ok(wanted), Ok(got)) = (source_metadata.modified(), metadata.modified()) else {
+                return None;
+            };
+            let off = wanted
+                .duration_since(got)
+                .or_else(|_| got.duration_since(wanted))
+                .unwrap_or_default();
+            (mode == RevalidateMode::Mtime
+                && off > std::time::Duration::from_secs_f64(args.mtime_tolerance))
+            .then(|| format!("modification time {:.0?} off from the source's", off))
+        });
+        if failure.is_none() {
+            self.revalidated += 1;
+        }
+        failure
+    }
+
+    /// Removes an output that failed `--revalidate-existing`, so it's converted
+    /// again.
+    fn replace_invalid_output(
+        &mut self,
+        output: &std::path::Path,
+        reason: String,
+        messages: &events::Messages,
+    ) {
+        say!(
+            messages,
+            "   Removing invalid output {}: {}",
+            output.display(),
+            reason
+        );
+        match std::fs::remove_file(output) {
+            Ok(()) => self.repaired.push((output.to_owned(), reason)),
+            Err(e) => say_error!(
+                messages,
+                "Error removing invalid output {}: {}",
+                output.display(),
+                e
+            ),
+        }
+    }
+}
+
+/// Whether `output` was left empty by an earlier failed run, and so doesn't
+/// count as done.
+fn is_empty_output(source: &SourceFile, output: &std::path::Path) -> bool {
+    output.metadata().is_ok_and(|m| m.is_file() && m.len() == 0)
+        && source.metadata.as_ref().is_some_and(|m| m.len() > 0)
+}
+
+/// Whether `output` is too long for tools without long path support, and for
+/// Windows without the opt-in, even though we can write it.
+fn is_long_output(output: &std::path::Path) -> bool {
+    cfg!(windows) && classic_path_length(output) >= MAX_PATH
+}
+
+// Paths listed per message in the errors at the end of a run.
+const ERROR_PATHS_SHOWN: usize = 5;
+
+/// Lists the errors of a run grouped by message, the most frequent first, so
+/// they can be found after the progress output. `in_report` says whether the
+/// full list of paths is in the report.
+fn print_errors(
+    errors: &[(std::path::PathBuf, String)],
+    in_report: bool,
+    messages: &events::Messages,
+) {
+    let mut groups: Vec<(&str, Vec<&std::path::Path>)> = Vec::new();
+    let mut index = std::collections::HashMap::new();
+    for (file, message) in errors {
+        let group = *index.entry(message.as_str()).or_insert_with(|| {
+            groups.push((message, Vec::new()));
+            groups.len() - 1
+        });
+        groups[group].1.push(file);
+    }
+    // Stable, so messages that are as frequent stay in the order they came.
+    groups.sort_by_key(|(_, files)| std::cmp::Reverse(files.len()));
+    for (_, files) in &mut groups {
+        files.sort();
+    }
+
+    say!(messages, "Errors ({}):", errors.len());
+    for (message, files) in &groups {
+        say!(messages, "  {} ({}):", message, files.len());
+        for file in files.iter().take(ERROR_PATHS_SHOWN) {
+            say!(messages, "    {}", file.display());
+        }
+        if files.len() > ERROR_PATHS_SHOWN {
+            say!(
+                messages,
+                "    ... and {} more{}",
+                files.len() - ERROR_PATHS_SHOWN,
+                if in_report { ", see the report" } else { "" }
+            );
+        }
+    }
+}
+
+fn print_collision(collision: &collision::Collision, messages: &events::Messages) {
+    match &collision.renamed {
+        Some(renamed) => say!(
+            messages,
+            "   Collision: {} -> {} ({} is taken by {})",
+            collision.source.display(),
+            renamed.display(),
+            collision.output.display(),
+            collision.holder.display()
+        ),
+        None => say!(
+            messages,
+            "   Collision: {} -> {} is taken by {}",
+            collision.source.display(),
+            collision.output.display(),
+            collision.holder.display()
+        ),
+    }
+}
+
+tokio::task_local! {
+    /// The source the current task works on.
+    static SOURCE: SourceFile;
+}
+
+/// Metadata of `file`, as read by the walk when it's the source of the
+/// current task, and from the filesystem otherwise.
+async fn source_metadata(file: &std::path::Path) -> std::io::Result<std::fs::Metadata> {
+    let cached = SOURCE
+        .try_with(|source| source.metadata.clone().filter(|_| source.path == file))
+        .ok()
+        .flatten();
+    match cached {
+        Some(metadata) => Ok(metadata),
+        None => tokio::fs::metadata(file).await,
+    }
+}
+
+/// A collected file with its kind and where its output goes.
+type PlannedFile = (SourceFile, FileKind, Option<std::path::PathBuf>);
+
+/// The result of processing a file, and where --content-addressed put it.
+type Converted = (anyhow::Result<ProcessResult>, Option<content::Addressed>);
+
+/// What the run will do with a collected file, as far as that is known
+/// before it starts. Outputs in `replaced` are removed before the run, so
+/// don't count as existing.
+fn planned_action(
+    (source, kind, output): &PlannedFile,
+    replaced: &std::collections::HashSet<&std::path::PathBuf>,
+    context: &Context,
+) -> plan::PlannedAction {
+    let args = &context.args;
+    // The outputs of a source that becomes several files, named as
+    // process_image names them.
+    let mut page_count = 1;
+    let mut outputs = Vec::new();
+    if let (FileKind::Image(detected), Some(output)) = (kind, output) {
+        let extension = extension_of(&source.path);
+        let format_extension = match detected {
+            Some(kind) if !kind.matches_extension(&extension) => kind.extensions()[0],
+            _ => extension.as_str(),
+        };
+        match format_extension {
+            "ico" if args.ico == IcoMode::All => {
+                if let Ok(entries) = ico::entries(&source.path) {
+                    let parts = icon_parts(
+                        &source.path,
+                        &entries,
+                        output,
+                        IcoMode::All,
+                        &context.messages,
+                    );
+                    outputs = parts.into_iter().map(|(part, _)| part).collect();
+                }
+            }
+            "tif" | "tiff" if args.multipage != MultipageMode::First => {
+                let pages = tiff::pages(&source.path).unwrap_or_else(|_| vec![1]);
+                page_count = pages.len();
+                if page_count > 1 && args.multipage == MultipageMode::All {
+                    let parts = page_parts(output, &pages);
+                    outputs = parts.into_iter().map(|(page, _)| page).collect();
+                }
+            }
+            _ => {}
+        }
+    }
+    let is_there = |output: &std::path::PathBuf| {
+        !replaced.contains(output) && output.symlink_metadata().is_ok()
+    };
+    // Those of several files are only skipped once all of them are there.
+    let exists = if outputs.is_empty() {
+        output.as_ref().is_some_and(is_there)
+    } else {
+        outputs.iter().all(is_there)
+    };
+    let convert = |settings: EncodeSettings<'_>| plan::PlanAction::Convert {
+        settings: plan::ConvertSettings {
+            effort: settings.effort,
+            distance: settings.distance,
+            jpeg_reconstruct: settings.jpeg_reconstruct,
+            smart: args.smart,
+        },
+    };
+    let action = match kind {
+        _ if output.is_none() => plan::PlanAction::Skip {
+            reason: "not an image".to_string(),
+        },
+        FileKind::ExistingJxl if args.existing_jxl == ExistingJxl::Skip => plan::PlanAction::Skip {
+            reason: "existing jxl".to_string(),
+        },
+        _ if exists => plan::PlanAction::Skip {
+            reason: "output exists".to_string(),
+        },
+        FileKind::Image(_) if page_count > 1 && args.multipage == MultipageMode::Skip => {
+            plan::PlanAction::Skip {
+                reason: format!("multi-page TIFF ({} pages)", page_count),
+            }
+        }
+        FileKind::Symlink => plan::PlanAction::Link,
+        FileKind::Other | FileKind::Small => plan::PlanAction::Copy,
+        FileKind::ExistingJxl if args.existing_jxl == ExistingJxl::Copy => plan::PlanAction::Copy,
+        FileKind::ExistingJxl | FileKind::Archive => convert(EncodeSettings::from_context(context)),
+        FileKind::Image(detected) => {
+            let extension = extension_of(&source.path);
+            let format_extension = match detected {
+                Some(kind) if !kind.matches_extension(&extension) => kind.extensions()[0],
+                _ => extension.as_str(),
+            };
+            let mut settings = EncodeSettings::from_context(context);
+            if let Some(policy) = &args.policy {
+                settings.resolve_policy(policy, &extension, format_extension, args);
+            }
+            convert(settings)
+        }
+    };
+    plan::PlannedAction {
+        source: source.path.clone(),
+        destination: output.clone(),
+        outputs,
+        action,
+    }
+}
+
+/// The encoder settings of the run for the `settings` column of the report,
+/// as the plan shows them for a file without a policy.
+fn report_settings(context: &Context) -> String {
+    let args = &context.args;
+    let settings = EncodeSettings::from_context(context);
+    let mut shown = plan::ConvertSettings {
+        effort: settings.effort,
+        distance: settings.distance,
+        jpeg_reconstruct: settings.jpeg_reconstruct,
+        smart: args.smart,
+    }
+    .to_string();
+    if args.policy.is_some() {
+        shown.push_str(", policy");
+    }
+    shown
+}
+
+fn print_plan(
+    plan: &plan::Plan,
+    format: PlanFormat,
+    messages: &events::Messages,
+) -> anyhow::Result<()> {
+    match format {
+        PlanFormat::Text => {
+            say!(messages, "Plan ({} files):", plan.actions.len());
+            for action in &plan.actions {
+                say!(messages, "   {}", action);
+            }
+            say!(messages);
+        }
+        PlanFormat::Json => {
+            for action in &plan.actions {
+                say!(messages, "{}", serde_json::to_string(action)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The mutex of each hash whose output a file writes or looks for, see
+/// [`lock_hash`].
+type Addressing =
+    Arc<std::sync::Mutex<std::collections::BTreeMap<String, Arc<tokio::sync::Mutex<()>>>>>;
+
+/// Waits until no other file writes or looks for the output of `hash`, and
+/// holds it until the returned guard is dropped.
+async fn lock_hash(context: &Context, hash: &str) -> HashLock {
+    let lock = context
+        .addressing
+        .lock()
+        .unwrap()
+        .entry(hash.to_owned())
+        .or_default()
+        .clone();
+    HashLock {
+        guard: Some(lock.lock_owned().await),
+        addressing: context.addressing.clone(),
+        hash: hash.to_owned(),
+    }
+}
+
+/// The hold of [`lock_hash`] on a hash. The last one to let go of a hash
+/// removes its mutex, so the map only holds the hashes in use.
+struct HashLock {
+    guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+    addressing: Addressing,
+    hash: String,
+}
+
+impl Drop for HashLock {
+    fn drop(&mut self) {
+        // The guard holds the mutex too.
+        drop(self.guard.take());
+        let mut addressing = self.addressing.lock().unwrap();
+        // Others waiting for it took their hold under the same lock.
+        if addressing
+            .get(&self.hash)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            addressing.remove(&self.hash);
+        }
+    }
+}
+
+/// Processes `file` for --content-addressed. Hashing the source tells where
+/// the output goes before converting, so an output already there is kept.
+/// Hashing the output converts to the staging directory first and moves the
+/// result into place, or drops it when the same output is there already.
+async fn process_addressed(
+    file: &std::path::Path,
+    kind: FileKind,
+    planned_output: &std::path::Path,
+    output_dir: &std::path::Path,
+    hash_of: ContentHash,
+    context: &Context,
+) -> (anyhow::Result<ProcessResult>, Option<content::Addressed>) {
+    let extension = planned_output.extension().unwrap_or_default().to_owned();
+    let hash = |path: std::path::PathBuf| async move {
+        tokio::task::spawn_blocking(move || content::hash_file(&path)).await?
+    };
+    match hash_of {
+        ContentHash::Source => {
+            let hash = match hash(file.to_owned()).await {
+                Ok(hash) => hash,
+                Err(e) => {
+                    return (
+                        Err(anyhow::anyhow!("Hashing the source failed: {}", e)),
+                        None,
+                    );
+                }
+            };
+            let output = content::path_for(output_dir, &hash, &extension);
+            let _writing = lock_hash(context, &hash).await;
+            if output.exists() {
+                detail!(
+                    context.messages,
+                    "   Same source as existing {}",
+                    output.display()
+                );
+                let addressed = content::Addressed {
+                    hash,
+                    output,
+                    deduplicated: true,
+                };
+                return (Ok(ProcessResult::Skipped), Some(addressed));
+            }
+            if let Some(parent) = output.parent()
+                && let Err(e) = create_output_dir(parent, context).await
+            {
+                return (Err(e), None);
+            }
+            let result = process_file(file, kind, Some(&output), context).await;
+            // Kept originals and failures have no output to name.
+            if !output.is_file() {
+                return (result, None);
+            }
+            let addressed = content::Addressed {
+                hash,
+                output,
+                deduplicated: false,
+            };
+            (result, Some(addressed))
+        }
+        ContentHash::Output => {
+            let relative = planned_output
+                .strip_prefix(output_dir)
+                .unwrap_or(planned_output);
+            let staged = output_dir.join(content::STAGING_DIR).join(relative);
+            if let Some(parent) = staged.parent()
+                && let Err(e) = create_output_dir(parent, context).await
+            {
+                return (Err(e), None);
+            }
+            let result = process_file(file, kind, Some(&staged), context).await;
+            // Kept originals and failures have no output to name.
+            if !matches!(
+                result,
+                Ok(ProcessResult::Converted { .. }
+                    | ProcessResult::CopiedJxl { .. }
+                    | ProcessResult::RecompressedJxl { .. })
+            ) || !staged.is_file()
+            {
+                return (result, None);
+            }
+            let hash = match hash(staged.clone()).await {
+                Ok(hash) => hash,
+                Err(e) => {
+                    return (
+                        Err(anyhow::anyhow!("Hashing the output failed: {}", e)),
+                        None,
+                    );
+                }
+            };
+            let output = content::path_for(output_dir, &hash, &extension);
+            let _writing = lock_hash(context, &hash).await;
+            let deduplicated = output.exists();
+            let placed = async {
+                if deduplicated {
+                    detail!(
+                        context.messages,
+                        "   Same output as existing {}",
+                        output.display()
+                    );
+                    tokio::fs::remove_file(&staged).await?;
+                } else {
+                    if let Some(parent) = output.parent() {
+                        create_output_dir(parent, context).await?;
+                    }
+                    tokio::fs::rename(&staged, &output).await?;
+                }
+                anyhow::Ok(())
+            };
+            if let Err(e) = placed.await {
+                return (
+                    Err(anyhow::anyhow!("Placing the output failed: {}", e)),
+                    None,
+                );
+            }
+            let addressed = content::Addressed {
+                hash,
+                output,
+                deduplicated,
+            };
+            (result, Some(addressed))
+        }
+    }
+}
+
+/// Adds the line of the source `file` under `input` to the index of
+/// --content-addressed.
+fn index_source(
+    index: &mut content::Index,
+    input: &std::path::Path,
+    file: &std::path::Path,
+    addressed: &content::Addressed,
+) -> anyhow::Result<()> {
+    let source = file.strip_prefix(input)?;
+    index
+        .append(source, addressed)
+        .map_err(|e| anyhow::anyhow!("Writing {} failed: {}", content::INDEX_FILE, e))
+}
+
+/// Processes a planned file, under its hash in `output_dir` with
+/// --content-addressed.
+async fn convert_planned(
+    file: &std::path::Path,
+    kind: FileKind,
+    output_file_path: Option<&std::path::Path>,
+    output_dir: &std::path::Path,
+    context: &Context,
+) -> Converted {
+    let args = &context.args;
+    match (args.content_addressed, output_file_path, kind) {
+        (Some(hash_of), Some(planned), FileKind::Image(_) | FileKind::ExistingJxl)
+            if !(matches!(kind, FileKind::ExistingJxl)
+                && args.existing_jxl == ExistingJxl::Skip) =>
+        {
+            process_addressed(file, kind, planned, output_dir, hash_of, context).await
+        }
+        _ => (
+            process_file(file, kind, output_file_path, context).await,
+            None,
+        ),
+    }
+}
+
+/// The format an image is read as and the settings it starts with: its
+/// content where the extension says otherwise, then what --smart picks, then
+/// the --policy entry. Returns the canonical extension of the format, the
+/// input format for ffmpeg, the settings and notes for the report.
+async fn image_settings<'a, 'b>(
+    file: &std::path::Path,
+    detected: Option<sniff::ImageKind>,
+    file_extension: &'a str,
+    context: &'b Context,
+) -> (
+    &'a str,
+    Option<&'static str>,
+    EncodeSettings<'b>,
+    Vec<String>,
+) {
+    let args = &context.args;
+    let mut sniff_notes = Vec::new();
+    let mut input_format = None;
+    let format_extension = match detected {
+        Some(kind) if !kind.matches_extension(file_extension) => {
+            if file_extension.is_empty() {
+                sniff_notes.push(format!("detected {}", kind.extensions()[0]));
+            } else {
+                say!(
+                    context.messages,
+                    "   Warning: {} has extension {} but contains {}",
+                    file.display(),
+                    file_extension,
+                    kind.extensions()[0]
+                );
+                sniff_notes.push(format!(
+                    "extension {} but content is {}",
+                    file_extension,
+                    kind.extensions()[0]
+                ));
+            }
+            input_format = Some(kind.ffmpeg_format());
+            kind.extensions()[0]
+        }
+        _ => file_extension,
+    };
+
+    let mut settings = EncodeSettings::from_context(context);
+    if args.smart {
+        match settings
+            .apply_smart(file, format_extension, input_format, context)
+            .await
+        {
+            Ok(note) => sniff_notes.push(note),
+            Err(e) => say!(
+                context.messages,
+                "   Warning: --smart could not inspect {}, using the global flags: {}",
+                file.display(),
+                e
+            ),
+        }
+    }
+    // Applied after --smart so a matching policy entry overrides it.
+    if let Some(policy) = &args.policy {
+        sniff_notes.push(settings.resolve_policy(policy, file_extension, format_extension, args));
+    }
+    (format_extension, input_format, settings, sniff_notes)
+}
+
+/// Does what was planned for a single collected file.
+async fn process_file(
+    file: &std::path::Path,
+    kind: FileKind,
+    output_file_path: Option<&std::path::Path>,
+    context: &Context,
+) -> anyhow::Result<ProcessResult> {
+    let file_extension = extension_of(file);
+
+    let Some(output_file_path) = output_file_path else {
+        // This is a non-image file and copy_all is false, skip
+        say!(
+            context.messages,
+            "   Skipping non-image file: {}",
+            file.display()
+        );
+        return Ok(ProcessResult::Skipped);
+    };
+
+    match kind {
+        FileKind::ExistingJxl => process_existing_jxl(file, output_file_path, context).await,
+        FileKind::Archive => process_archive(file, output_file_path, context).await,
+        FileKind::Image(detected) => {
+            let (format_extension, input_format, settings, sniff_notes) =
+                image_settings(file, detected, &file_extension, context).await;
+            let mut result = process_image(
+                file,
+                output_file_path,
+                format_extension,
+                input_format,
+                settings,
+                context,
+            )
+            .await?;
+            if let ProcessResult::Converted { notes, .. } = &mut result {
+                notes.splice(0..0, sniff_notes);
+            }
+            Ok(result)
+        }
+        FileKind::Symlink => copy_symlink(file, output_file_path, context).await,
+        FileKind::Other | FileKind::Small => {
+            // A non-image file with copy_all, or an image below
+            // --convert-min-size, attempt copy
+            if output_file_path.exists() {
+                detail!(
+                    context.messages,
+                    "   Skipping existing file: {}",
+                    output_file_path.display()
+                );
+                return Ok(ProcessResult::Skipped);
+            }
+
+            if let Some(parent) = output_file_path.parent() {
+                create_output_dir(parent, context).await?;
+            }
+
+            detail!(
+                context.messages,
+                "   Copying {} -> {}",
+                file.display(),
+                output_file_path.display()
+            );
+            match copy_with_times(
+                file,
+                output_file_path,
+                EncodeSettings::from_context(context),
+            )
+            .await
+            {
+                Ok(method) => Ok(ProcessResult::Copied {
+                    method,
+                    below_threshold: matches!(kind, FileKind::Small),
+                }),
+                Err(e) => Ok(ProcessResult::Error(anyhow::anyhow!("Copy failed: {}", e))), // Wrap copy error
+            }
+        }
+    }
+}
+
+/// Recreates the symlink `file` at `output_file_path`, pointing to the same
+/// target, so relative links keep working in a mirrored tree.
+async fn copy_symlink(
+    file: &std::path::Path,
+    output_file_path: &std::path::Path,
+    context: &Context,
+) -> anyhow::Result<ProcessResult> {
+    let messages = &context.messages;
+    if tokio::fs::symlink_metadata(output_file_path).await.is_ok() {
+        detail!(
+            messages,
+            "   Skipping existing file: {}",
+            output_file_path.display()
+        );
+        return Ok(ProcessResult::Skipped);
+    }
+    if let Some(parent) = output_file_path.parent() {
+        create_output_dir(parent, context).await?;
+    }
+    let target = tokio::fs::read_link(file).await?;
+    detail!(
+        messages,
+        "   Linking {} -> {}",
+        output_file_path.display(),
+        target.display()
+    );
+    match make_symlink(file, &target, output_file_path).await {
+        Ok(()) => Ok(ProcessResult::Linked),
+        Err(e) => Ok(ProcessResult::Error(anyhow::anyhow!(
+            "Creating symlink failed: {}",
+            e
+        ))),
+    }
+}
+
+#[cfg(unix)]
+async fn make_symlink(
+    _file: &std::path::Path,
+    target: &std::path::Path,
+    link: &std::path::Path,
+) -> std::io::Result<()> {
+    tokio::fs::symlink(target, link).await
+}
+
+// Windows has separate links for files and directories; a dangling one is
+// taken for a file link.
+#[cfg(windows)]
+async fn make_symlink(
+    file: &std::path::Path,
+    target: &std::path::Path,
+    link: &std::path::Path,
+) -> std::io::Result<()> {
+    if tokio::fs::metadata(file).await.is_ok_and(|m| m.is_dir()) {
+        tokio::fs::symlink_dir(target, link).await
+    } else {
+        tokio::fs::symlink_file(target, link).await
+    }
+}
+
+/// Runs the quick validation over every image in `files`, `--jobs` at a time,
+/// and splits them into the files to process and the rejected ones with the
+/// reason they were rejected.
+async fn prevalidate(
+    files: Vec<SourceFile>,
+    context: &Context,
+) -> (Vec<SourceFile>, Vec<(SourceFile, String)>) {
+    let args = &context.args;
+    let pb = context.progress_bar(ProgressBar::new(files.len() as u64));
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] Validating {pos}/{len} {msg}")
+            .unwrap(),
+    );
+
+    // Only as many tasks as run at once exist, so a huge file list doesn't
+    // turn into as many futures.
+    let mut files = files.into_iter();
+    let mut set = JoinSet::new();
+    let mut valid = Vec::new();
+    let mut failed = Vec::new();
+    loop {
+        while set.len() < args.parallel.jobs.max(1)
+            && let Some(source) = files.next()
+        {
+            set.spawn(validate_source(
+                context.runner.clone(),
+                source,
+                context.accepted.clone(),
+                args.sniff,
+            ));
+        }
+        let Some(task_result) = set.join_next().await else {
+            break;
+        };
+        pb.inc(1);
+        match task_result {
+            Ok((source, Ok(()))) => valid.push(source),
+            Ok((source, Err(reason))) => failed.push((source, reason)),
+            Err(e) => say_error!(context.messages, "Task join error: {}", e),
+        }
+    }
+    pb.finish_with_message(format!("{} failed validation.", failed.len()));
+
+    // Join order is arbitrary, give the caller a stable order.
+    valid.sort_by(|a, b| a.path.cmp(&b.path));
+    failed.sort_by(|(a, _), (b, _)| a.path.cmp(&b.path));
+    (valid, failed)
+}
+
+/// Probes every image in `files` that gets converted for --probe,
+/// --probe-jobs at a time, and keeps what it finds in `scan`.
+async fn probe_sources(files: &[SourceFile], context: &Context, scan: &mut Scan) {
+    let args = &context.args;
+    let jobs = args.probe_jobs.map_or_else(
+        || std::thread::available_parallelism().map_or(1, |n| n.get()),
+        |jobs| jobs as usize,
+    );
+    let pb = context.progress_bar(ProgressBar::new(files.len() as u64));
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] Probing {pos}/{len} {msg}")
+            .unwrap(),
+    );
+
+    let mut files = files.iter();
+    let mut set = JoinSet::new();
+    loop {
+        while set.len() < jobs
+            && let Some(source) = files.next()
+        {
+            let FileKind::Image(detected) = FileKind::of(source, context) else {
+                pb.inc(1);
+                continue;
+            };
+            let path = source.path.clone();
+            let runner = context.runner.clone();
+            set.spawn(async move {
+                let format = detected.map(|kind| kind.ffmpeg_format());
+                let info = probe::info(&*runner, &path, format).await;
+                (path, info)
+            });
+        }
+        let Some(task_result) = set.join_next().await else {
+            break;
+        };
+        pb.inc(1);
+        match task_result {
+            Ok((path, Ok(info))) => {
+                scan.dimensions.insert(
+                    path.clone(),
+                    dimensions::Dimensions {
+                        width: info.width,
+                        height: info.height,
+                    },
+                );
+                scan.probed.insert(path, info);
+            }
+            Ok((path, Err(e))) => {
+                pb.suspend(|| {
+                    say!(
+                        context.messages,
+                        "   Warning: could not probe {}: {}",
+                        path.display(),
+                        e
+                    )
+                });
+                scan.probe_failed += 1;
+            }
+            Err(e) => say_error!(context.messages, "Task join error: {}", e),
+        }
+    }
+    pb.finish_with_message(format!("{} probed.", scan.probed.len()));
+}
+
+/// Reads the dimensions of every image in `files` that gets converted,
+/// `--jobs` at a time, and leaves out those outside the bounds of
+/// --min-pixels and the like. Images whose dimensions can't be read are
+/// kept. Returns the files to process.
+async fn filter_dimensions(
+    files: Vec<SourceFile>,
+    context: &Context,
+    scan: &mut Scan,
+) -> Vec<SourceFile> {
+    let args = &context.args;
+    let limits = args.dimension_limits();
+    let pb = context.progress_bar(ProgressBar::new(files.len() as u64));
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] Reading dimensions {pos}/{len} {msg}")
+            .unwrap(),
+    );
+
+    let mut files = files.into_iter();
+    let mut set = JoinSet::new();
+    let mut kept = Vec::new();
+    loop {
+        while set.len() < args.parallel.jobs.max(1)
+            && let Some(source) = files.next()
+        {
+            let FileKind::Image(detected) = FileKind::of(&source, context) else {
+                kept.push(source);
+                continue;
+            };
+            // Read already when --probe looked at the file.
+            let known = scan.dimensions.get(&source.path).copied();
+            let runner = context.runner.clone();
+            set.spawn(async move {
+                let probed = match known {
+                    Some(size) => Ok(size),
+                    None => {
+                        let format = detected.map(|kind| kind.ffmpeg_format());
+                        dimensions::probe(&*runner, &source.path, format).await
+                    }
+                };
+                (source, probed)
+            });
+        }
+        let Some(task_result) = set.join_next().await else {
+            break;
+        };
+        pb.inc(1);
+        match task_result {
+            Ok((source, Ok(size))) => {
+                scan.dimensions.insert(source.path.clone(), size);
+                match limits.rejects(size) {
+                    Some(reason) => {
+                        scan.left_out(&source);
+                        scan.outside_dimensions.push((source.path, reason));
+                    }
+                    None => kept.push(source),
+                }
+            }
+            Ok((source, Err(e))) => {
+                pb.suspend(|| {
+                    say!(
+                        context.messages,
+                        "   Dimensions unknown, kept: {}: {}",
+                        source.path.display(),
+                        e
+                    )
+                });
+                scan.unknown_dimensions += 1;
+                kept.push(source);
+            }
+            Err(e) => say_error!(context.messages, "Task join error: {}", e),
+        }
+    }
+    pb.finish_with_message(format!(
+        "{} outside the dimensions.",
+        scan.outside_dimensions.len()
+    ));
+
+    // Join order is arbitrary, give the caller a stable order.
+    kept.sort_by(|a, b| a.path.cmp(&b.path));
+    scan.outside_dimensions.sort();
+    kept
+}
+
+/// Decodes `source` if it is an image that gets converted.
+async fn validate_source(
+    runner: Arc<dyn process::CommandRunner>,
+    source: SourceFile,
+    accepted: Arc<std::collections::HashSet<String>>,
+    sniff: bool,
+) -> (SourceFile, Result<(), String>) {
+    let file = &source.path;
+    let extension = extension_of(file);
+    let is_image = accepted.contains(&extension) || (sniff && source.sniffed.is_some());
+
+    // Existing JPEG XL files and plain copies aren't decoded, nothing to validate.
+    let result = if is_image && extension != "jxl" {
+        validate::validate(&*runner, file, &extension, source.size(), source.sniffed).await
+    } else {
+        Ok(())
+    };
+    (source, result)
+}
+
+/// Opens `source` for reading and returns its size, so files that vanished,
+/// became dangling or lost their permissions since they were collected are
+/// caught before any work is queued for them. Fills in the metadata the walk
+/// had none for, and while the file is open, the format its header shows.
+fn readable_size(source: &mut SourceFile, context: &Context) -> std::io::Result<u64> {
+    // A link kept as a link isn't read, and may well be dangling.
+    if source.metadata.as_ref().is_some_and(|m| m.is_symlink()) {
+        return Ok(0);
+    }
+    let mut file = std::fs::File::open(&source.path)?;
+    if source.metadata.is_none() {
+        source.metadata = Some(file.metadata()?);
+    }
+    if context.args.sniff || context.accepted.contains(&extension_of(&source.path)) {
+        source.sniffed = sniff::detect_in(&mut file).unwrap_or(None);
+    }
+    Ok(source
+        .metadata
+        .as_ref()
+        .map_or(0, |metadata| metadata.len()))
+}
+
+// Files stat'ed and opened at the same time while collecting. Network shares
+// answer many requests at once about as fast as one.
+const SCAN_CONCURRENCY: usize = 32;
+
+/// Reads the metadata of every walked file and opens it with
+/// `readable_size`, several at a time, so a cold network share doesn't take
+/// one round trip per file in turn. Results come back in the order of
+/// `entries`.
+async fn scan_files(
+    entries: Vec<walkdir::DirEntry>,
+    context: &Context,
+) -> Vec<(SourceFile, std::io::Result<u64>)> {
+    let pb = context.progress_bar(ProgressBar::new_spinner());
+    pb.set_style(
+        ProgressStyle::default_spinner()
+            .template("{spinner:.green} [{elapsed_precise}] {msg}")
+            .unwrap(),
+    );
+    pb.set_message("Scanning files...");
+
+    let total = entries.len();
+    let shared = Arc::new(context.clone());
+    let mut entries = entries.into_iter().enumerate();
+    let mut set = JoinSet::new();
+    let mut results = (0..total).map(|_| None).collect::<Vec<_>>();
+    let mut scanned = 0;
+    let mut scanned_size = 0;
+    loop {
+        while set.len() < SCAN_CONCURRENCY
+            && let Some((index, entry)) = entries.next()
+        {
+            let context = shared.clone();
+            set.spawn_blocking(move || {
+                let mut source = SourceFile::walked(&entry, &context.args);
+                let size = readable_size(&mut source, &context);
+                (index, source, size)
+            });
+        }
+        let Some(task_result) = set.join_next().await else {
+            break;
+        };
+        match task_result {
+            Ok((index, source, size)) => {
+                scanned += 1;
+                scanned_size += size.as_ref().map_or(0, |size| *size);
+                pb.set_message(format!(
+                    "Scanned {} of {} files, {}",
+                    scanned,
+                    total,
+                    human_bytes::human_bytes(scanned_size as f64)
+                ));
+                results[index] = Some((source, size));
+            }
+            Err(e) => say_error!(context.messages, "Task join error: {}", e),
+        }
+    }
+    pb.finish_with_message(format!(
+        "Scanned {} files, {}.",
+        scanned,
+        human_bytes::human_bytes(scanned_size as f64)
+    ));
+    results.into_iter().flatten().collect()
+}
+
+// How long to wait before checking whether files are still growing.
+const STABILITY_RECHECK: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Splits `files` into those that look complete and those that were modified
+/// within the last `seconds` or changed size across a short pause.
+async fn split_unstable(
+    files: Vec<SourceFile>,
+    seconds: u64,
+) -> (Vec<SourceFile>, Vec<SourceFile>) {
+    let sizes = files
+        .iter()
+        .map(|source| source.metadata.as_ref().map(|m| m.len()))
+        .collect::<Vec<_>>();
+    if !files.is_empty() {
+        tokio::time::sleep(STABILITY_RECHECK).await;
+    }
+
+    // Fresh metadata, the point is to see whether it changed. A stat per
+    // file, off the runtime; files it couldn't stat count as changing.
+    let paths: Vec<_> = files.iter().map(|source| source.path.clone()).collect();
+    let fresh = tokio::task::spawn_blocking(move || {
+        paths
+            .iter()
+            .map(|path| std::fs::metadata(path).ok())
+            .collect::<Vec<_>>()
+    })
+    .await
+    .unwrap_or_default()
+    .into_iter()
+    .chain(std::iter::repeat_with(|| None));
+
+    let now = std::time::SystemTime::now();
+    let mut stable = Vec::new();
+    let mut unstable = Vec::new();
+    for ((mut source, size), metadata) in files.into_iter().zip(sizes).zip(fresh) {
+        let is_stable = metadata.as_ref().is_some_and(|metadata| {
+            // A modification time in the future counts as recent.
+            let settled = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .is_some_and(|age| age.as_secs() >= seconds);
+            settled && size == Some(metadata.len())
+        });
+        if is_stable {
+            source.metadata = metadata;
+            stable.push(source);
+        } else {
+            unstable.push(source);
+        }
+    }
+    (stable, unstable)
+}
+
+// The classic Windows path limit, which extended-length paths aren't subject to.
+const MAX_PATH: usize = 260;
+
+/// Length of `path` in UTF-16 units as classic Windows APIs count it, without
+/// the extended-length prefix.
+fn classic_path_length(path: &std::path::Path) -> usize {
+    let text = path.to_string_lossy();
+    let length = text.encode_utf16().count();
+    if text.starts_with(r"\\?\UNC\") {
+        // `\\?\UNC\server` is `\\server`.
+        length - 6
+    } else if text.starts_with(r"\\?\") {
+        length - 4
+    } else {
+        length
+    }
+}
+
+/// The files `Scan::is_duplicate` has come across, kept only as far as they
+/// can come up again under another path.
+struct Seen {
+    /// Files a path other than their own can lead to: those with other hard
+    /// links, symlinks, and every file when the walk follows symlinks to
+    /// directories or takes its paths from a list. With the path they first
+    /// came up under.
+    linked: std::collections::HashMap<FileId, std::path::PathBuf>,
+    /// Files with a single link, which only a symlink met later can lead to
+    /// again, under --symlinks follow. The path is where that symlink
+    /// resolves to, so only the id is kept.
+    single: std::collections::HashSet<FileId>,
+    input: std::path::PathBuf,
+    canonical_input: Option<std::path::PathBuf>,
+}
+
+impl Seen {
+    fn new(paths: &RunPaths) -> Self {
+        Seen {
+            linked: std::collections::HashMap::new(),
+            single: std::collections::HashSet::new(),
+            input: paths.input.clone(),
+            canonical_input: paths.input.canonicalize().ok(),
+        }
+    }
+
+    /// The path the walk found the file behind `link` under, which for a file
+    /// with a single link is where the link resolves to, in the input as
+    /// walked.
+    fn walked_path(&self, link: &std::path::Path) -> std::path::PathBuf {
+        let Ok(target) = link.canonicalize() else {
+            return link.to_owned();
+        };
+        match self
+            .canonical_input
+            .as_deref()
+            .and_then(|root| target.strip_prefix(root).ok())
+        {
+            Some(relative) => self.input.join(relative),
+            None => target,
+        }
+    }
+}
+
+/// Whether the file behind `source` has other hard links, under which it
+/// can come up again.
+#[cfg(unix)]
+fn other_links(source: &SourceFile) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    source.metadata.as_ref().is_some_and(|m| m.nlink() > 1)
+}
+
+/// Hard links go unnoticed elsewhere.
+#[cfg(not(unix))]
+fn other_links(_source: &SourceFile) -> bool {
+    false
+}
+
+/// What tells physical files apart: device and inode on Unix, which also
+/// catches hard links, and the canonical path elsewhere.
+#[cfg(unix)]
+type FileId = (u64, u64);
+#[cfg(not(unix))]
+type FileId = std::path::PathBuf;
+
+/// Identifies the physical file behind `source`, following symlinks.
+#[cfg(unix)]
+fn file_id(source: &SourceFile) -> Option<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = match &source.metadata {
+        Some(metadata) => metadata.clone(),
+        None => std::fs::metadata(&source.path).ok()?,
+    };
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(source: &SourceFile) -> Option<FileId> {
+    source.path.canonicalize().ok()
+}
+
+/// Gives `output` the contents of `original_output` as a hard link, or as a
+/// copy with the same modification time where the output filesystem has no
+/// hard links. Returns whether it could link.
+fn link_output(
+    original_output: &std::path::Path,
+    output: &std::path::Path,
+    context: &Context,
+) -> std::io::Result<bool> {
+    let messages = &context.messages;
+    if let Some(parent) = output.parent() {
+        context.created_dirs.create(parent)?;
+    }
+    detail!(
+        messages,
+        "   Linking {} -> {}",
+        output.display(),
+        original_output.display()
+    );
+    if std::fs::hard_link(original_output, output).is_ok() {
+        return Ok(true);
+    }
+    sparse::copy(original_output, output, None)?;
+    filetime::set_file_mtime(
+        output,
+        filetime::FileTime::from_last_modification_time(&std::fs::metadata(original_output)?),
+    )?;
+    Ok(false)
+}
+
+/// Whether `output` is what `link_output` made of `original_output` in an
+/// earlier run: a link to it, or a copy with its size and modification time.
+fn is_linked_output(original_output: &std::path::Path, output: &std::path::Path) -> bool {
+    match (
+        std::fs::metadata(original_output),
+        std::fs::metadata(output),
+    ) {
+        (Ok(original), Ok(output)) => {
+            original.len() == output.len() && original.modified().ok() == output.modified().ok()
+        }
+        _ => false,
+    }
+}
+
+/// Removes the temporary files of conversions this process didn't finish.
+async fn remove_partial_outputs(output_path: &std::path::Path, messages: &events::Messages) {
+    let output_path = output_path.to_owned();
+    let found = tokio::task::spawn_blocking(move || {
+        walkdir::WalkDir::new(output_path)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .filter(|e| partial_pid(&e.file_name().to_string_lossy()) == Some(std::process::id()))
+            .map(walkdir::DirEntry::into_path)
+            .collect::<Vec<_>>()
+    })
+    .await;
+    let found = match found {
+        Ok(found) => found,
+        Err(e) => {
+            say_error!(messages, "Task join error: {}", e);
+            return;
+        }
+    };
+    for path in found {
+        say!(messages, "   Removing partial output {}", path.display());
+        let _ = tokio::fs::remove_file(&path).await;
+    }
+}
+
+// A partial output whose process may still be running is only taken for
+// abandoned after this long.
+const STALE_PARTIAL_AGE: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
+/// Removes the temporary outputs that earlier runs left anywhere in
+/// `output_path` when they crashed or were killed. Returns how many were
+/// removed and their combined size.
+fn remove_stale_partials(output_path: &std::path::Path) -> (usize, u64) {
+    let mut removed = 0;
+    let mut reclaimed = 0;
+    for entry in walkdir::WalkDir::new(output_path)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+    {
+        let Some(pid) = partial_pid(&entry.file_name().to_string_lossy()) else {
+            continue;
+        };
+        if pid == std::process::id() {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let old = metadata
+            .modified()
+            .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > STALE_PARTIAL_AGE));
+        // Other systems can't tell whether the process is still there, and
+        // are left with the age.
+        #[cfg(unix)]
+        let abandoned = old || !lock::is_running(pid);
+        #[cfg(not(unix))]
+        let abandoned = old;
+        if abandoned && std::fs::remove_file(entry.path()).is_ok() {
+            removed += 1;
+            reclaimed += metadata.len();
+        }
+    }
+    (removed, reclaimed)
+}
+
+/// Makes sure ffmpeg and ffprobe can be started at all, and cjxl and djxl
+/// when the options may need them, so a missing binary stops the run up front
+/// instead of failing every file.
+async fn check_tools(context: &Context) -> anyhow::Result<()> {
+    let args = &context.args;
+    let mut tools = vec![("ffmpeg", "-version"), ("ffprobe", "-version")];
+    // JPEG reconstruction transcodes with cjxl, recompressing an existing
+    // transcode rebuilds its JPEG with djxl first.
+    let reconstructs = args.smart
+        || args.policy.as_ref().is_some_and(|policy| {
+            policy
+                .entries()
+                .any(|(_, entry)| entry.mode == Some(policy::Mode::Reconstruct))
+        });
+    let recompresses = args.existing_jxl == ExistingJxl::Recompress;
+    if reconstructs || recompresses {
+        tools.push(("cjxl", "--version"));
+    }
+    if recompresses {
+        tools.push(("djxl", "--version"));
+    }
+    for (name, version_flag) in tools {
+        let version = context
+            .runner()
+            .run(process::command(name).arg(version_flag), false)
+            .await;
+        match version {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let message = match (version_flag, &args.ffmpeg_path) {
+                    ("--version", _) => format!(
+                        "{} not found in PATH; install the libjxl tools for JPEG reconstruction",
+                        name
+                    ),
+                    (_, Some(ffmpeg)) => {
+                        let path = match name {
+                            "ffprobe" => process::ffprobe_next_to(std::path::Path::new(ffmpeg)),
+                            _ => std::path::PathBuf::from(ffmpeg),
+                        };
+                        format!("{} not found at '{}'", name, path.display())
+                    }
+                    (_, None) => format!(
+                        "{name} not found in PATH (looked for '{name}'); install it or pass --ffmpeg-path"
+                    ),
+                };
+                return Err(Error::MissingTool {
+                    tool: name.to_string(),
+                    message,
+                }
+                .into());
+            }
+            Err(e) => return Err(anyhow::anyhow!("Failed to run {}: {}", name, e)),
+        }
+    }
+    Ok(())
+}
+
+/// Creates and removes a file in the output directory, so an output that
+/// isn't writable stops the run up front instead of failing every file.
+fn check_writable(output_path: &std::path::Path) -> anyhow::Result<()> {
+    let probe = output_path.join(format!(".bulk-jxl-write-test-{}", std::process::id()));
+    std::fs::File::create_new(&probe).map_err(|e| Error::Output {
+        path: output_path.to_path_buf(),
+        message: format!(
+            "Output directory {} is not writable: {}",
+            output_path.display(),
+            e
+        ),
+    })?;
+    let _ = std::fs::remove_file(&probe);
+    Ok(())
+}
+
+/// Finds the ImageMagick binary, `magick` for version 7 and `convert` before.
+async fn find_magick(runner: &dyn process::CommandRunner) -> Option<&'static str> {
+    for binary in ["magick", "convert"] {
+        let version = runner
+            .run(process::command(binary).arg("-version"), false)
+            .await;
+        if version.is_ok_and(|version| version.status.success()) {
+            return Some(binary);
+        }
+    }
+    None
+}
+
+/// Checks the options and fills in the ones that depend on the machine.
+async fn prepare(context: &mut Context) -> anyhow::Result<()> {
+    // A plan only names what the tools would be run on.
+    if !context.args.dry_run {
+        check_tools(context).await?;
+    }
+    let args = &mut context.args;
+
+    if !(args.mtime_tolerance >= 0.0 && args.mtime_tolerance.is_finite()) {
+        return Err(anyhow::anyhow!(
+            "--mtime-tolerance must be 0 or more seconds"
+        ));
+    }
+
+    if args.symlinks == SymlinkMode::Preserve && !args.copy_all {
+        return Err(anyhow::anyhow!("--symlinks preserve needs --copy-all"));
+    }
+
+    if args.archives != ArchiveMode::Skip && !cfg!(feature = "archives") {
+        return Err(anyhow::anyhow!(
+            "--archives needs the 'archives' feature, this build was compiled without it"
+        ));
+    }
+
+    if args.content_addressed.is_some()
+        && (args.copy_all
+            || args.archives != ArchiveMode::Skip
+            || args.multipage == MultipageMode::All
+            || args.ico == IcoMode::All)
+    {
+        return Err(anyhow::anyhow!(
+            "--content-addressed only names single images, not --copy-all, --archives, --multipage all or --ico all"
+        ));
+    }
+
+    if args.total_threads.is_none() {
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        args.total_threads = Some(cores as u64);
+    }
+
+    if args.magick_fallback {
+        context.magick = Some(find_magick(context.runner()).await.ok_or_else(|| {
+            anyhow::anyhow!(
+                "--magick-fallback needs ImageMagick (`magick` or `convert`) on the PATH"
+            )
+        })?);
+    }
+
+    let (args, messages) = (&mut context.args, &context.messages);
+    if args.preserve_owner && !owner::can_chown() {
+        say!(
+            messages,
+            "Warning: --preserve-owner needs root on Unix, outputs keep the owner of this process"
+        );
+        args.preserve_owner = false;
+    }
+    if cfg!(not(unix))
+        && (args.dir_mode.is_some() || args.file_mode.is_some() || args.chown.is_some())
+    {
+        say!(
+            messages,
+            "Warning: --dir-mode, --file-mode and --chown only work on Unix, ignoring them"
+        );
+        args.dir_mode = None;
+        args.file_mode = None;
+        args.chown = None;
+    }
+    if args.chown.is_some() && !owner::can_chown() {
+        say!(
+            messages,
+            "Warning: --chown needs root, outputs keep the owner of this process"
+        );
+        args.chown = None;
+    }
+    // An explicit owner wins over the source's.
+    if args.chown.is_some() {
+        args.preserve_owner = false;
+    }
+    Ok(())
+}
+
+/// Checks the input and output directories, making the output if needed.
+/// With --dry-run nothing is made or written, and an output that isn't there
+/// yet is taken for empty.
+fn resolve_paths(context: &Context) -> anyhow::Result<RunPaths> {
+    let args = &context.args;
+    let input_path = std::path::PathBuf::from(&args.tree.input);
+    let input_error = |message: &str| Error::Input {
+        path: input_path.clone(),
+        message: message.to_string(),
+    };
+    if !input_path.exists() {
+        return Err(input_error("Input path does not exist").into());
+    }
+    if !input_path.is_dir() {
+        return Err(input_error("Input path is not a directory").into());
+    }
+
+    let output_path = std::path::PathBuf::from(&args.tree.output);
+    let output_error = |message: String| Error::Output {
+        path: output_path.clone(),
+        message,
+    };
+    let created_output = !output_path.exists();
+    if let Some(file) = blocking_file(&output_path) {
+        return Err(output_error(format!(
+            "Output path {} can't be a directory: {} is a file",
+            output_path.display(),
+            file.display()
+        ))
+        .into());
+    }
+    if created_output && !args.dry_run {
+        context
+            .created_dirs
+            .create(&output_path)
+            .map_err(|e| output_error(format!("Creating {}: {}", output_path.display(), e)))?;
+    }
+    if output_path.exists() && !output_path.is_dir() {
+        return Err(output_error("Output path is not a directory".to_string()).into());
+    }
+    if !args.dry_run {
+        check_writable(&output_path)?;
+    }
+    // Canonical paths see through symlinks and `..` between the two.
+    let canonical_input = input_path.canonicalize()?;
+    let canonical_output = canonical_as_far_as_it_exists(&output_path)?;
+    if canonical_input == canonical_output {
+        // Outputs would be written next to their sources, and --copy-all
+        // would copy files onto themselves.
+        return Err(output_error(format!(
+            "Input and output are the same directory ({}), choose a separate output directory",
+            canonical_input.display()
+        ))
+        .into());
+    }
+    if canonical_input.starts_with(&canonical_output) {
+        // Outputs and everything the run keeps in the output directory would
+        // end up next to or among the sources, and a run over the output
+        // would take the sources for outputs.
+        return Err(output_error(format!(
+            "The input ({}) is inside the output directory ({}), choose an output directory outside it",
+            canonical_input.display(),
+            canonical_output.display()
+        ))
+        .into());
+    }
+    // On Windows canonical paths have the extended-length `\\?\` form, which
+    // isn't limited to MAX_PATH, and every path joined onto them inherits it.
+    // ffmpeg accepts such paths from version 5.1 on.
+    #[cfg(windows)]
+    let (input_path, output_path) = (canonical_input.clone(), canonical_output.clone());
+    // An input that is a symlink is walked at the directory it leads to, so
+    // every source path starts with the same root whatever the platform does
+    // with the link.
+    let input_is_link = std::path::Path::new(&args.tree.input).is_symlink();
+    let input_path = if input_is_link {
+        canonical_input.clone()
+    } else {
+        input_path
+    };
+    // An output directory inside the input tree would have the outputs of
+    // earlier runs collected again.
+    let excluded_output = canonical_output
+        .strip_prefix(&canonical_input)
+        .ok()
+        .map(|relative| input_path.join(relative));
+
+    Ok(RunPaths {
+        input: input_path,
+        input_is_link,
+        output: output_path,
+        created_output,
+        canonical_output,
+        excluded_output,
+    })
+}
+
+/// `path` made canonical as far as it exists, with the rest joined on as it
+/// is, for an output directory --dry-run doesn't make.
+fn canonical_as_far_as_it_exists(path: &std::path::Path) -> std::io::Result<std::path::PathBuf> {
+    let path = std::path::absolute(path)?;
+    for ancestor in path.ancestors() {
+        if let Ok(canonical) = ancestor.canonicalize() {
+            let rest = path
+                .strip_prefix(ancestor)
+                .unwrap_or(std::path::Path::new(""));
+            return Ok(canonical.join(rest));
+        }
+    }
+    Ok(path)
+}
+
+/// What the options of `context` would do with every file, without
+/// converting or copying anything.
+async fn plan(mut context: Context) -> anyhow::Result<plan::Plan> {
+    context.args.dry_run = true;
+    prepare(&mut context).await?;
+    let paths = resolve_paths(&context)?;
+    Ok(collect::collect(&context, &paths)
+        .await?
+        .map(|(_, scan)| scan.plan)
+        .unwrap_or_default())
+}
+
+/// Plans the run `context` describes and writes it to `path` as a script of
+/// the commands the run would start, for --emit-script.
+async fn emit_script(mut context: Context, path: &std::path::Path) -> anyhow::Result<()> {
+    if context.args.container != ContainerMode::Auto {
+        return Err(anyhow::anyhow!(
+            "--emit-script writes what the encoder writes, so it can't follow --container"
+        ));
+    }
+    // Unlike a plan, the script has ffprobe look at the sources.
+    check_tools(&context).await?;
+    context.args.dry_run = true;
+    prepare(&mut context).await?;
+    let paths = resolve_paths(&context)?;
+    let Some((planned, scan)) = collect::collect(&context, &paths).await? else {
+        return Ok(());
+    };
+    let args = &context.args;
+
+    let mut script = script::Script::new(args.emit_script_format);
+    let mut converted = 0; // Track files written as conversions
+    let mut copied = 0; // Track files written as copies or links
+    let mut left_out = 0; // Track files the script leaves out
+    for ((source, kind, _), action) in planned.iter().zip(&scan.plan.actions) {
+        let lines = match (&action.action, &action.destination) {
+            (plan::PlanAction::Skip { reason }, _) => Err(reason.clone()),
+            (action, Some(output)) => {
+                script_lines(&source.path, *kind, output, action, &context).await
+            }
+            (_, None) => Err("not an image".to_string()),
+        };
+        // Lines can still fail on names a batch file can't hold.
+        let written = lines.and_then(|lines| {
+            lines
+                .iter()
+                .try_for_each(|line| script.line(line))
+                .map_err(|e| e.to_string())
+        });
+        match written {
+            Ok(()) => match action.action {
+                plan::PlanAction::Convert { .. } => converted += 1,
+                _ => copied += 1,
+            },
+            Err(reason) => {
+                left_out += 1;
+                script.comment(&format!("{}: {}", source.path.display(), reason));
+            }
+        }
+    }
+    let summary = format!(
+        "{} conversions, {} copies, {} files left out",
+        converted, copied, left_out
+    );
+    script.write(path, &summary)?;
+    say!(context.messages, "Wrote {} to {}", summary, path.display());
+    Ok(())
+}
+
+/// The lines of --emit-script for a planned file, one per output, made with
+/// the same settings and encoder commands as the run would use. Fails with
+/// why the file can't be done by a script.
+async fn script_lines(
+    file: &std::path::Path,
+    kind: FileKind,
+    output: &std::path::Path,
+    action: &plan::PlanAction,
+    context: &Context,
+) -> Result<Vec<Vec<script::Step>>, String> {
+    let args = &context.args;
+    use script::Step;
+
+    let absolute = |path: &std::path::Path| std::path::absolute(path).map_err(|e| e.to_string());
+    // Like a run, every output gets its source's modification time and
+    // --file-mode.
+    let finish = |source: std::path::PathBuf, output: std::path::PathBuf| {
+        let mut steps = vec![Step::Touch {
+            source,
+            output: output.clone(),
+        }];
+        if let Some(mode) = args.file_mode {
+            steps.push(Step::Chmod { mode, path: output });
+        }
+        steps
+    };
+    // Every line creates the directory of its output, so it stands alone.
+    let start = |output: &std::path::Path| match output.parent() {
+        Some(parent) => vec![Step::Mkdir(parent.to_owned())],
+        None => Vec::new(),
+    };
+    let (source, target) = (absolute(file)?, absolute(output)?);
+    match (action, kind) {
+        (plan::PlanAction::Link, _) => {
+            let mut steps = start(&target);
+            steps.push(Step::Symlink {
+                target: std::fs::read_link(file).map_err(|e| e.to_string())?,
+                link: target,
+            });
+            Ok(vec![steps])
+        }
+        (plan::PlanAction::Copy, _) => {
+            let mut steps = start(&target);
+            steps.push(Step::Copy {
+                from: source.clone(),
+                to: target.clone(),
+                mode: args.copy_mode,
+            });
+            steps.extend(finish(source, target));
+            Ok(vec![steps])
+        }
+        (plan::PlanAction::Convert { .. }, FileKind::Image(detected)) => {
+            let file_extension = extension_of(file);
+            let (format_extension, input_format, settings, _) =
+                image_settings(file, detected, &file_extension, context).await;
+            // The outputs as process_image goes about them.
+            let found = image_parts(file, format_extension, output, context)
+                .await
+                .map_err(|e| e.to_string())?;
+            if found.pages > 1 && matches!(args.multipage, MultipageMode::Skip) {
+                return Err(format!("multi-page TIFF ({} pages)", found.pages));
+            }
+            let mut lines = Vec::new();
+            for (part, selection) in found.parts {
+                if part.exists() {
+                    continue;
+                }
+                let mut settings = settings;
+                let Some((input_format, _)) = probe_encoding(
+                    file,
+                    format_extension,
+                    input_format,
+                    selection,
+                    &mut settings,
+                    context,
+                )
+                .await
+                else {
+                    return Err("animated PNG".to_string());
+                };
+                let encoder: &dyn encoder::Encoder = if settings.jpeg_reconstruct {
+                    &encoder::JpegTranscode
+                } else {
+                    settings.encoder
+                };
+                // Only the encoder is recorded, the probes above ran for real
+                // as they would in a run.
+                let recorder = script::Recorder::default();
+                settings.runner = &recorder;
+                let part = absolute(&part)?;
+                let partial = partial_path(&part, std::process::id());
+                let encoded = encoder
+                    .encode(
+                        &source,
+                        &partial,
+                        settings.encode_options(input_format, selection),
+                    )
+                    .await;
+                let commands = recorder.take();
+                encoded.map_err(|e| e.to_string())?;
+                let mut steps = start(&part);
+                steps.extend(commands.into_iter().map(Step::Run));
+                steps.push(Step::Move {
+                    from: partial,
+                    to: part.clone(),
+                });
+                steps.extend(finish(source.clone(), part));
+                lines.push(steps);
+            }
+            Ok(lines)
+        }
+        (plan::PlanAction::Convert { .. }, FileKind::Archive) => {
+            Err("archives are only converted by a run".to_string())
+        }
+        (plan::PlanAction::Convert { .. }, _) => {
+            Err("existing JPEG XL files are only recompressed by a run".to_string())
+        }
+        (plan::PlanAction::Skip { reason }, _) => Err(reason.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Mutex};
+
+    use super::*;
+
+    /// Stands in for ffmpeg: writes `writes` to the output it is given and
+    /// fails afterwards if told to.
+    struct MockEncoder {
+        writes: &'static [u8],
+        fails: bool,
+        outputs: Mutex<Vec<std::path::PathBuf>>,
+    }
+
+    impl MockEncoder {
+        fn new(writes: &'static [u8], fails: bool) -> &'static MockEncoder {
+            Box::leak(Box::new(MockEncoder {
+                writes,
+                fails,
+                outputs: Mutex::new(Vec::new()),
+            }))
+        }
+    }
+
+    impl encoder::Encoder for MockEncoder {
+        fn encode<'a>(
+            &'a self,
+            _input: &'a Path,
+            output: &'a Path,
+            _options: encoder::EncodeOptions<'a>,
+        ) -> encoder::EncodeFuture<'a> {
+            Box::pin(async move {
+                self.outputs.lock().unwrap().push(output.to_owned());
+                std::fs::write(output, self.writes)?;
+                if self.fails {
+                    return Err(anyhow::anyhow!("encoder crashed"));
+                }
+                Ok(encoder::EncodeOutcome::default())
+            })
+        }
+    }
+
+    const JXL: &[u8] = b"\xff\x0a a codestream long enough to pass for one";
+
+    fn args(options: &[&str]) -> Args {
+        let mut argv = vec!["bulk-jxl", "-i", "in", "-o", "out"];
+        argv.extend_from_slice(options);
+        Args::parse_from(argv)
+    }
+
+    /// The context of a run with `options` that starts the tools as
+    /// processes and prints nothing.
+    fn context(options: &[&str]) -> Context {
+        let messages = events::Messages::discard();
+        Context::new(args(options), None, messages)
+    }
+
+    /// A source with an old modification time in a fresh directory, and the
+    /// path of its output in the `out` directory next to it.
+    fn source() -> (tempfile::TempDir, std::path::PathBuf, std::path::PathBuf) {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("a.png");
+        std::fs::write(&source, b"\x89PNG\r\n\x1a\n pixels").unwrap();
+        filetime::set_file_mtime(
+            &source,
+            filetime::FileTime::from_unix_time(1_000_000_000, 0),
+        )
+        .unwrap();
+        std::fs::create_dir(temp.path().join("out")).unwrap();
+        let output = temp.path().join("out/a.jxl");
+        (temp, source, output)
+    }
+
+    fn files_in(dir: &Path) -> Vec<std::ffi::OsString> {
+        let mut names: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn icons_of_the_same_size_get_outputs_of_their_own() {
+        let entry = |size, bits_per_pixel, png| ico::IcoEntry {
+            width: size,
+            height: size,
+            bits_per_pixel,
+            png,
+        };
+        let entries = [
+            entry(16, 32, false),
+            entry(32, 8, false),
+            entry(32, 32, false),
+            entry(48, 0, true),
+            entry(48, 0, true),
+        ];
+
+        let parts = icon_parts(
+            Path::new("in/favicon.ico"),
+            &entries,
+            Path::new("out/favicon.jxl"),
+            IcoMode::All,
+            &events::Messages::discard(),
+        );
+
+        let outputs: Vec<_> = parts.iter().map(|(output, _)| output.as_path()).collect();
+        assert_eq!(
+            outputs,
+            [
+                "out/favicon.16.jxl",
+                "out/favicon.32.8bpp.jxl",
+                "out/favicon.32.32bpp.jxl",
+                "out/favicon.48.4.jxl",
+                "out/favicon.48.5.jxl",
+            ]
+            .map(Path::new)
+        );
+        assert!(matches!(parts[4].1, InputSelection::Stream(4)));
+    }
+
+    #[test]
+    fn threads_are_divided_among_the_conversions() {
+        for (total, jobs, each, overview) in [
+            (16, 4, 4, "16 / 4 conversions = 4 per conversion"),
+            (
+                16,
+                5,
+                3,
+                "16 / 5 conversions = 3 per conversion, 1 left over",
+            ),
+            (
+                12,
+                8,
+                1,
+                "12 / 8 conversions = 1 per conversion, 4 left over",
+            ),
+            (2, 6, 1, "2 / 6 conversions = 1 per conversion, 6 in all"),
+            (1, 1, 1, "1 / 1 conversions = 1 per conversion"),
+        ] {
+            assert_eq!(threads_per_job(total, jobs), each, "{} / {}", total, jobs);
+            assert_eq!(thread_division(total, jobs), overview);
+        }
+        let total = |options: &[&str]| {
+            let mut options = options.to_vec();
+            options.extend(["--total-threads", "16"]);
+            EncodeSettings::from_context(&context(&options)).threads
+        };
+        assert_eq!(total(&["-j", "4"]), 4);
+        // Shared for the most conversions adaptive jobs can run.
+        assert_eq!(total(&["--adaptive-jobs", "2..8"]), 2);
+    }
+
+    #[tokio::test]
+    async fn conversions_go_through_a_partial_file_and_keep_the_mtime() {
+        let (temp, source, output) = source();
+        let encoder = MockEncoder::new(JXL, false);
+        let context = context(&[]);
+        let settings = EncodeSettings {
+            encoder,
+            ..EncodeSettings::from_context(&context)
+        };
+
+        let conversion = convert_image(&source, &output, None, settings, InputSelection::Whole)
+            .await
+            .unwrap();
+
+        let written = encoder.outputs.lock().unwrap().clone();
+        assert_eq!(written, [partial_path(&output, std::process::id())]);
+        assert_eq!(std::fs::read(&output).unwrap(), JXL);
+        assert_eq!(conversion.converted_size, JXL.len() as u64);
+        assert_eq!(
+            conversion.original_size,
+            std::fs::metadata(&source).unwrap().len()
+        );
+        let mtime = |path: &Path| {
+            filetime::FileTime::from_last_modification_time(&std::fs::metadata(path).unwrap())
+        };
+        assert_eq!(mtime(&output), mtime(&source));
+        assert_eq!(files_in(&temp.path().join("out")), ["a.jxl"]);
+    }
+
+    #[tokio::test]
+    async fn failed_or_invalid_encodes_leave_nothing() {
+        for (writes, fails, error) in [
+            (JXL, true, "encoder crashed"),
+            (
+                &b"not a jpeg xl file at all"[..],
+                false,
+                "no JPEG XL signature",
+            ),
+            (&b"\xff\x0a"[..], false, "only 2 bytes long"),
+        ] {
+            let (temp, source, output) = source();
+            let mut context = context(&[]);
+            context.encoder = MockEncoder::new(writes, fails);
+            let settings = EncodeSettings::from_context(&context);
+            let result =
+                convert_image(&source, &output, None, settings, InputSelection::Whole).await;
+            let e = result.err().unwrap().to_string();
+            assert!(e.contains(error), "{}", e);
+            assert!(files_in(&temp.path().join("out")).is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn keep_partials_leaves_the_failed_output() {
+        let (temp, source, output) = source();
+        let mut context = context(&["--keep-partials"]);
+        context.encoder = MockEncoder::new(JXL, true);
+        let settings = EncodeSettings::from_context(&context);
+        let result = convert_image(&source, &output, None, settings, InputSelection::Whole).await;
+        assert!(result.is_err());
+        let partial = partial_path(&output, std::process::id());
+        assert_eq!(
+            files_in(&temp.path().join("out")),
+            [partial.file_name().unwrap()]
+        );
+    }
+
+    /// Stands in for an encoder that takes a while: a real process that writes
+    /// its pid to the file given and appends to the output until it is killed.
+    #[cfg(unix)]
+    struct Endless(std::path::PathBuf);
+
+    #[cfg(unix)]
+    impl encoder::Encoder for Endless {
+        fn encode<'a>(
+            &'a self,
+            _input: &'a Path,
+            output: &'a Path,
+            _options: encoder::EncodeOptions<'a>,
+        ) -> encoder::EncodeFuture<'a> {
+            Box::pin(async move {
+                let mut command = process::command("sh");
+                command
+                    .arg("-c")
+                    .arg(r#"echo $$ > "$0"; while :; do echo x >> "$1"; done"#)
+                    .arg(&self.0)
+                    .arg(output);
+                process::Spawn::new(&events::Messages::discard())
+                    .run(&mut command, false)
+                    .await?;
+                Ok(encoder::EncodeOutcome::default())
+            })
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn a_dropped_conversion_leaves_no_encoder_or_partial_file() {
+        let (temp, source, output) = source();
+        let pid_file = temp.path().join("pid");
+        let mut context = context(&[]);
+        context.encoder = Box::leak(Box::new(Endless(pid_file.clone())));
+        let settings = EncodeSettings::from_context(&context);
+        let partial = partial_path(&output, std::process::id());
+
+        // Dropped while the encoder is writing, as an aborted task is.
+        tokio::select! {
+            _ = convert_image(&source, &output, None, settings, InputSelection::Whole) => {
+                panic!("the encoder finished")
+            }
+            _ = async {
+                while !partial.exists() {
+                    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+                }
+            } => {}
+        }
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        assert!(!lock::is_running(pid.trim().parse().unwrap()));
+        // Nor does a write still under way bring the partial file back.
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(files_in(&temp.path().join("out")).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_partials_are_removed_whatever_follows_the_pid() {
+        let temp = tempfile::tempdir().unwrap();
+        // Left by a run that was killed.
+        let stale = [
+            ".bulk-jxl-partial-999999999.a.jxl",
+            ".bulk-jxl-partial-999999999.b.recompress.jxl.jpg",
+            ".bulk-jxl-partial-999999999.c.magick.png",
+        ];
+        let own = format!(".bulk-jxl-partial-{}.d.jxl.jpg", std::process::id());
+        // Named like the partial outputs of earlier versions, but they
+        // could as well be sources copied through.
+        let others = ["e.partial-notes.txt", "f.jxl.partial-999999999"];
+        for name in stale.iter().copied().chain([own.as_str()]).chain(others) {
+            std::fs::write(temp.path().join(name), b"partial").unwrap();
+        }
+
+        assert_eq!(remove_stale_partials(temp.path()), (3, 21));
+        let mut left = vec![own.as_str()];
+        left.extend(others);
+        assert_eq!(files_in(temp.path()), left);
+    }
+
+    /// Answers every command with a failure, like an ffmpeg that can't
+    /// decode what it gets.
+    #[cfg(unix)]
+    struct Undecodable;
+
+    #[cfg(unix)]
+    impl process::CommandRunner for Undecodable {
+        fn run<'a>(
+            &'a self,
+            _command: &'a mut tokio::process::Command,
+            _verbose: bool,
+        ) -> std::pin::Pin<Box<dyn Future<Output = std::io::Result<process::Finished>> + Send + 'a>>
+        {
+            use std::os::unix::process::ExitStatusExt;
+            Box::pin(async {
+                Ok(process::Finished {
+                    status: std::process::ExitStatus::from_raw(1 << 8),
+                    stdout: Vec::new(),
+                    stderr_tail: vec!["Invalid data found when processing input".to_string()],
+                })
+            })
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn no_sidecar_is_written_when_the_output_carries_all_metadata() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("photo.jpg");
+        std::fs::write(
+            &source,
+            b"\xff\xd8\xff\xe1\0\x0cExif\0\0MM\0*\xff\xda\0\x02\xff\xd9",
+        )
+        .unwrap();
+        let output = temp.path().join("photo.jxl");
+        let mut jxl = b"\0\0\0\x0cJXL \r\n\x87\n\0\0\0\x0cExifMM\0*".to_vec();
+        jxl.extend_from_slice(b"\0\0\0\x0ajxlc\xff\x0a");
+        std::fs::write(&output, jxl).unwrap();
+        // exiftool would fail, so a sidecar would show up as a failed one.
+        let context = Context::new(
+            args(&["--metadata-sidecar"]),
+            Some(Arc::new(Undecodable)),
+            events::Messages::discard(),
+        );
+
+        let note = keep_metadata(&source, &output, &context).await;
+
+        assert_eq!(note.as_deref(), Some("metadata embedded"));
+        assert!(!sidecar::path_for(&output).exists());
+
+        // An XMP packet the output lacks is still kept beside it.
+        std::fs::write(
+            &source,
+            b"\xff\xd8\xff\xe1\0\x1fhttp://ns.adobe.com/xap/1.0/\0\xff\xda\0\x02\xff\xd9",
+        )
+        .unwrap();
+        let note = keep_metadata(&source, &output, &context).await.unwrap();
+        assert!(note.starts_with("metadata sidecar failed"), "{note}");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn outputs_failing_verification_are_replaced_by_the_original() {
+        let (temp, source, output) = source();
+        let mut context = Context::new(
+            args(&["--verify", "--on-verify-fail", "copy-original"]),
+            Some(Arc::new(Undecodable)),
+            events::Messages::discard(),
+        );
+        context.encoder = MockEncoder::new(JXL, false);
+        let settings = EncodeSettings::from_context(&context);
+        let conversion = convert_image(&source, &output, None, settings, InputSelection::Whole)
+            .await
+            .unwrap();
+        let converted = ProcessResult::Converted {
+            original_size: conversion.original_size,
+            converted_size: conversion.converted_size,
+            source_pages: 1,
+            pages: 1,
+            parts: Vec::new(),
+            over_target: false,
+            restorable: false,
+            notes: conversion.notes,
+        };
+
+        let outputs = [(output.clone(), InputSelection::Whole)];
+        let result = verify_conversion(&source, &output, &outputs, None, &context, converted)
+            .await
+            .unwrap();
+
+        let file = result.file_result(&source, Some(&output), Default::default());
+        assert_eq!(file.action, Action::KeptOriginal);
+        assert!(
+            file.notes
+                .iter()
+                .any(|n| n.contains("output does not decode: Invalid data"))
+        );
+        // The original takes its own name in the output directory.
+        assert_eq!(files_in(&temp.path().join("out")), ["a.png"]);
+    }
+
+    #[tokio::test]
+    async fn results_add_up_in_the_summary() {
+        let (_temp, source, output) = source();
+        let mut context = context(&[]);
+        context.encoder = MockEncoder::new(JXL, false);
+        let settings = EncodeSettings::from_context(&context);
+        let conversion = convert_image(&source, &output, None, settings, InputSelection::Whole)
+            .await
+            .unwrap();
+        let results = [
+            ProcessResult::Converted {
+                original_size: conversion.original_size,
+                converted_size: conversion.converted_size,
+                source_pages: 1,
+                pages: 1,
+                parts: Vec::new(),
+                over_target: false,
+                restorable: false,
+                notes: conversion.notes,
+            },
+            ProcessResult::Error(anyhow::anyhow!("encoder crashed")),
+            ProcessResult::Skipped,
+            ProcessResult::SkippedMultipage,
+            ProcessResult::KeptOriginal { notes: Vec::new() },
+        ];
+
+        let mut summary = Summary::default();
+        for result in &results {
+            summary.add(&result.file_result(&source, Some(&output), Default::default()));
+        }
+        assert_eq!(summary.files, 5);
+        assert_eq!(summary.converted, 1);
+        assert_eq!(summary.failed, 1);
+        assert_eq!(summary.skipped, 2);
+        assert_eq!(summary.kept_original, 1);
+        // Only the conversion counts towards the sizes.
+        assert_eq!(summary.original_size, conversion.original_size);
+        assert_eq!(summary.converted_size, JXL.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn hash_locks_go_with_their_last_holder() {
+        let context = context(&[]);
+        let hashes = || {
+            let addressing = context.addressing.lock().unwrap();
+            addressing.keys().cloned().collect::<Vec<_>>()
+        };
+        let first = lock_hash(&context, "ab").await;
+        let second = lock_hash(&context, "cd").await;
+        assert_eq!(hashes(), ["ab", "cd"]);
+        drop(first);
+        assert_eq!(hashes(), ["cd"]);
+
+        // Waiting for a hash holds on to it as well.
+        let mut waiting = std::pin::pin!(lock_hash(&context, "cd"));
+        let wait = std::time::Duration::from_millis(10);
+        assert!(tokio::time::timeout(wait, &mut waiting).await.is_err());
+        drop(second);
+        assert_eq!(hashes(), ["cd"]);
+        drop(waiting.await);
+        assert!(hashes().is_empty());
+    }
+}
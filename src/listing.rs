@@ -0,0 +1,107 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Writes the outputs a run creates for `--print-outputs` to the standard
+/// output. The run prints everything else to the standard error meanwhile,
+/// so the stream carries only the paths.
+pub struct Printer {
+    /// Where the paths go, until writing to it fails.
+    out: Option<Box<dyn Write + Send>>,
+    separator: u8,
+}
+
+impl Printer {
+    /// Writes the paths to the standard output, each followed by `separator`.
+    pub fn stdout(separator: u8) -> Self {
+        Printer::new(Box::new(std::io::stdout()), separator)
+    }
+
+    fn new(out: Box<dyn Write + Send>, separator: u8) -> Self {
+        Printer {
+            out: Some(out),
+            separator,
+        }
+    }
+
+    /// Writes `path` in one go, so a reader sees it as soon as it's created.
+    /// Once that fails, e.g. because the reader went away, nothing more is
+    /// written.
+    pub fn print(&mut self, path: &Path) -> std::io::Result<()> {
+        let Some(out) = &mut self.out else {
+            return Ok(());
+        };
+        let mut record = path.as_os_str().as_encoded_bytes().to_vec();
+        record.push(self.separator);
+        out.write_all(&record)
+            .and_then(|()| out.flush())
+            .inspect_err(|_| self.out = None)
+    }
+}
+
+/// The files `result` created at `output`, its planned path.
+pub fn created<'a>(result: &'a crate::ProcessResult, output: &'a Path) -> Vec<&'a Path> {
+    use crate::ProcessResult;
+    match result {
+        ProcessResult::Converted { parts, .. } if !parts.is_empty() => {
+            parts.iter().map(PathBuf::as_path).collect()
+        }
+        ProcessResult::Converted { .. }
+        | ProcessResult::Archive { .. }
+        | ProcessResult::Copied { .. }
+        | ProcessResult::CopiedJxl { .. }
+        | ProcessResult::RecompressedJxl { .. } => vec![output],
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Keeps what is written where the test can read it.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Fails every write, like a pipe whose reader went away.
+    struct Closed;
+
+    impl Write for Closed {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn paths_end_with_the_separator() {
+        let out = Shared::default();
+        let mut printer = Printer::new(Box::new(out.clone()), b'\0');
+        printer.print(Path::new("out/a.jxl")).unwrap();
+        printer.print(Path::new("out/b\nc.jxl")).unwrap();
+
+        assert_eq!(*out.0.lock().unwrap(), b"out/a.jxl\0out/b\nc.jxl\0");
+    }
+
+    #[test]
+    fn nothing_more_is_written_once_writing_failed() {
+        let mut printer = Printer::new(Box::new(Closed), b'\n');
+        assert!(printer.print(Path::new("out/a.jxl")).is_err());
+        assert!(printer.print(Path::new("out/b.jxl")).is_ok());
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::events::Messages;
+
+const LOCK_NAME: &str = ".bulk-jxl.lock";
+
+// How often `--wait-lock` checks whether the other run is done.
+const WAIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keeps other runs out of an output tree for as long as it lives. Dropping
+/// it removes the lock file; `std::process::exit` skips that.
+pub struct Lock {
+    path: PathBuf,
+    // Held open, and on Unix locked with flock, for as long as the run
+    // lasts. A run that crashed no longer holds it, which is how a stale lock
+    // is told from a live one. Windows refuses to delete a file held open
+    // without delete sharing, which does the same there.
+    _file: std::fs::File,
+}
+
+impl Lock {
+    /// Takes the lock on `output`, replacing one a crashed run left behind.
+    /// Fails if another run holds it, unless `wait` is set, in which case it
+    /// waits for that run to finish and tells `messages` it does.
+    pub async fn acquire(output: &Path, wait: bool, messages: &Messages) -> anyhow::Result<Self> {
+        let path = output.join(LOCK_NAME);
+        let mut waiting = false;
+        loop {
+            match take(&path, messages) {
+                Ok(Some(mut file)) => {
+                    let started = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+                    file.set_len(0)?;
+                    writeln!(file, "{}\n{}", std::process::id(), started)?;
+                    return Ok(Lock { path, _file: file });
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    return Err(anyhow::anyhow!(
+                        "Could not create lock file {}: {}",
+                        path.display(),
+                        e
+                    ));
+                }
+            }
+
+            let Some(holder) = Holder::read(&path) else {
+                // The run holding it has only just taken it and not written
+                // it yet.
+                tokio::time::sleep(WAIT_INTERVAL).await;
+                continue;
+            };
+            if !wait {
+                return Err(crate::Error::Locked {
+                    pid: holder.pid,
+                    message: format!(
+                        "Another run (PID {}, started {}) is using {}; pass --wait-lock to wait for it",
+                        holder.pid,
+                        holder.age(),
+                        output.display()
+                    ),
+                }
+                .into());
+            }
+            if !waiting {
+                messages.say(format!(
+                    "Waiting for the run with PID {} (started {}) to finish...",
+                    holder.pid,
+                    holder.age()
+                ));
+                waiting = true;
+            }
+            tokio::time::sleep(WAIT_INTERVAL).await;
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // Removed while still locked, so a run waiting for the lock finds
+        // the file it opened gone once it gets it, and starts over.
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Locks the lock file at `path`, making it if there is none, or replacing
+/// one a crashed run left behind, which `messages` hears about where that
+/// takes removing it. Returns `None` while another run holds it.
+///
+/// Two runs finding the same stale lock can't both take it: the lock is
+/// the kernel's, and it goes with the process that held it, so there is no
+/// file to judge stale and remove.
+#[cfg(unix)]
+pub fn take(path: &Path, _messages: &Messages) -> std::io::Result<Option<std::fs::File>> {
+    use std::os::unix::fs::MetadataExt;
+    loop {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(std::fs::TryLockError::WouldBlock) => return Ok(None),
+            Err(std::fs::TryLockError::Error(e)) => return Err(e),
+        }
+        // The run that held it may have removed it between our opening and
+        // locking it, leaving us a file no one else will look at.
+        let (ours, named) = (file.metadata()?, std::fs::metadata(path));
+        if named.is_ok_and(|named| (named.dev(), named.ino()) == (ours.dev(), ours.ino())) {
+            return Ok(Some(file));
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn take(path: &Path, messages: &Messages) -> std::io::Result<Option<std::fs::File>> {
+    loop {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        // Others may read it, but not delete it while we hold it.
+        #[cfg(windows)]
+        std::os::windows::fs::OpenOptionsExt::share_mode(&mut options, FILE_SHARE_READ);
+        match options.open(path) {
+            Ok(file) => return Ok(Some(file)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+        // Deleting it fails while its run holds it open. Once it succeeds
+        // the stale lock is gone, and no other run can have taken it over
+        // in between.
+        match std::fs::remove_file(path) {
+            Ok(()) => say!(messages, "Removed stale lock file {}", path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(_) => return Ok(None),
+        }
+    }
+}
+
+#[cfg(windows)]
+const FILE_SHARE_READ: u32 = 1;
+
+/// The run a lock file names.
+struct Holder {
+    pid: u32,
+    /// Seconds since the Unix epoch.
+    started: u64,
+}
+
+impl Holder {
+    /// `None` for a lock file that can't be read or isn't ours, e.g. one a
+    /// crash left half written.
+    fn read(path: &Path) -> Option<Self> {
+        let contents = std::fs::read_to_string(path).ok()?;
+        let mut lines = contents.lines();
+        Some(Holder {
+            pid: lines.next()?.trim().parse().ok()?,
+            started: lines.next()?.trim().parse().ok()?,
+        })
+    }
+
+    fn age(&self) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        format!("{}s ago", now.saturating_sub(self.started))
+    }
+}
+
+/// Whether the process `pid` is still running.
+#[cfg(unix)]
+pub fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks whether the process exists.
+    let exists = unsafe { libc::kill(pid, 0) == 0 };
+    // EPERM: it exists but belongs to another user.
+    (exists || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM))
+        && !is_zombie(pid)
+}
+
+// Containers without an init that reaps them keep dead processes around as
+// zombies, which signal 0 still finds.
+#[cfg(target_os = "linux")]
+fn is_zombie(pid: libc::pid_t) -> bool {
+    std::fs::read_to_string(format!("/proc/{}/stat", pid)).is_ok_and(|stat| {
+        // The state follows the command name, which is in parentheses.
+        stat.rsplit_once(')')
+            .is_some_and(|(_, rest)| rest.trim_start().starts_with('Z'))
+    })
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn is_zombie(_pid: libc::pid_t) -> bool {
+    false
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn a_stale_lock_goes_to_one_run_only() {
+        let temp = tempfile::tempdir().unwrap();
+        // Left by a run that was killed, which holds no flock any more.
+        std::fs::write(temp.path().join(LOCK_NAME), "999999999\n0\n").unwrap();
+
+        for _ in 0..20 {
+            let runs: Vec<_> = (0..8)
+                .map(|_| {
+                    let output = temp.path().to_owned();
+                    tokio::spawn(async move {
+                        Lock::acquire(&output, false, &Messages::discard()).await
+                    })
+                })
+                .collect();
+            let mut locks = Vec::new();
+            for run in runs {
+                match run.await.unwrap() {
+                    Ok(lock) => locks.push(lock),
+                    Err(e) => assert!(matches!(
+                        e.downcast_ref::<crate::Error>(),
+                        Some(crate::Error::Locked { .. })
+                    )),
+                }
+            }
+            assert_eq!(locks.len(), 1);
+            drop(locks);
+            assert!(!temp.path().join(LOCK_NAME).exists());
+            std::fs::write(temp.path().join(LOCK_NAME), "999999999\n0\n").unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn a_waiting_run_gets_the_lock_once_it_is_released() {
+        let temp = tempfile::tempdir().unwrap();
+        let held = Lock::acquire(temp.path(), false, &Messages::discard())
+            .await
+            .unwrap();
+        assert!(
+            Lock::acquire(temp.path(), false, &Messages::discard())
+                .await
+                .is_err()
+        );
+
+        let output = temp.path().to_owned();
+        let waiting =
+            tokio::spawn(async move { Lock::acquire(&output, true, &Messages::discard()).await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+        drop(held);
+        let lock = waiting.await.unwrap().unwrap();
+        let holder = Holder::read(&temp.path().join(LOCK_NAME)).unwrap();
+        assert_eq!(holder.pid, std::process::id());
+        drop(lock);
+    }
+}
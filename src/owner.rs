@@ -0,0 +1,52 @@
+use std::{fs::Metadata, path::Path};
+
+/// Whether we may give files to other users, which takes root on Unix.
+#[cfg(unix)]
+pub fn can_chown() -> bool {
+    // SAFETY: geteuid has no preconditions and can't fail.
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+pub fn can_chown() -> bool {
+    false
+}
+
+/// Gives `output` the owner and group of the source with `source` metadata.
+#[cfg(unix)]
+pub fn copy(source: &Metadata, output: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    std::os::unix::fs::lchown(output, Some(source.uid()), Some(source.gid()))
+}
+
+#[cfg(not(unix))]
+pub fn copy(_source: &Metadata, _output: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Gives the directories below `output` the owners of their counterparts
+/// below `input`, and `output` itself that of `input` if `include_root`.
+/// Directories without a counterpart are left alone. Returns a description
+/// of every directory whose owner couldn't be set.
+pub fn copy_dirs(input: &Path, output: &Path, include_root: bool) -> Vec<String> {
+    let mut failures = Vec::new();
+    let walk = walkdir::WalkDir::new(output).min_depth(if include_root { 0 } else { 1 });
+    for entry in walk.into_iter().filter_map(|e| e.ok()) {
+        if !entry.file_type().is_dir() {
+            continue;
+        }
+        let Ok(relative) = entry.path().strip_prefix(output) else {
+            continue;
+        };
+        let Ok(source) = std::fs::metadata(input.join(relative)) else {
+            continue;
+        };
+        if !source.is_dir() {
+            continue;
+        }
+        if let Err(e) = copy(&source, entry.path()) {
+            failures.push(format!("{}: {}", entry.path().display(), e));
+        }
+    }
+    failures
+}
@@ -0,0 +1,48 @@
+/// A request from outside to hold off new files for a while, or to go on.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    Pause,
+    Resume,
+}
+
+/// Listens for SIGUSR1 to pause and SIGUSR2 to resume, for the command line
+/// to pass on to the run like the stop listener.
+#[cfg(unix)]
+pub struct Listener {
+    pause: tokio::signal::unix::Signal,
+    resume: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Listener {
+    pub fn new() -> std::io::Result<Self> {
+        use tokio::signal::unix::{SignalKind, signal};
+        Ok(Listener {
+            pause: signal(SignalKind::user_defined1())?,
+            resume: signal(SignalKind::user_defined2())?,
+        })
+    }
+
+    pub async fn recv(&mut self) -> Request {
+        tokio::select! {
+            _ = self.pause.recv() => Request::Pause,
+            _ = self.resume.recv() => Request::Resume,
+        }
+    }
+}
+
+// Windows has no signals to send a running process; a run there is paused
+// by suspending the process, e.g. from Resource Monitor.
+#[cfg(windows)]
+pub struct Listener;
+
+#[cfg(windows)]
+impl Listener {
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Listener)
+    }
+
+    pub async fn recv(&mut self) -> Request {
+        std::future::pending().await
+    }
+}
@@ -0,0 +1,245 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// The owner `--chown` gives outputs. Either part may be left out to keep
+/// what the output was created with.
+#[derive(Clone, Copy)]
+pub struct Owner {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl Owner {
+    /// The owner and group of the file with `metadata`.
+    #[cfg(unix)]
+    pub fn of(metadata: &std::fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        Owner {
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn of(_metadata: &std::fs::Metadata) -> Self {
+        Owner {
+            uid: None,
+            gid: None,
+        }
+    }
+}
+
+/// Parses an octal mode such as `755` or `0640`.
+pub fn parse_mode(text: &str) -> Result<u32, String> {
+    let mode = u32::from_str_radix(text, 8)
+        .map_err(|_| format!("`{}` is not an octal mode such as 755", text))?;
+    if mode > 0o7777 {
+        return Err(format!("`{}` is not an octal mode such as 755", text));
+    }
+    Ok(mode)
+}
+
+/// Parses `user`, `user:group` or `:group`, by name or number.
+pub fn parse_owner(text: &str) -> Result<Owner, String> {
+    let (user, group) = match text.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (text, None),
+    };
+    let owner = Owner {
+        uid: (!user.is_empty()).then(|| user_id(user)).transpose()?,
+        gid: group
+            .filter(|group| !group.is_empty())
+            .map(group_id)
+            .transpose()?,
+    };
+    if owner.uid.is_none() && owner.gid.is_none() {
+        return Err("expected user, user:group or :group".to_string());
+    }
+    Ok(owner)
+}
+
+#[cfg(unix)]
+fn user_id(name: &str) -> Result<u32, String> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let c_name = std::ffi::CString::new(name).map_err(|e| e.to_string())?;
+    // SAFETY: the name is NUL-terminated, and the entry is read before any
+    // other lookup could reuse it.
+    let entry = unsafe { libc::getpwnam(c_name.as_ptr()) };
+    if entry.is_null() {
+        return Err(format!("no user named `{}`", name));
+    }
+    // SAFETY: not null, so it points to the static entry getpwnam filled in,
+    // which nothing has overwritten since.
+    Ok(unsafe { (*entry).pw_uid })
+}
+
+#[cfg(unix)]
+fn group_id(name: &str) -> Result<u32, String> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let c_name = std::ffi::CString::new(name).map_err(|e| e.to_string())?;
+    // SAFETY: as for getpwnam.
+    let entry = unsafe { libc::getgrnam(c_name.as_ptr()) };
+    if entry.is_null() {
+        return Err(format!("no group named `{}`", name));
+    }
+    // SAFETY: as for the entry of getpwnam.
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+// Only numbers mean anything outside Unix, and the option is ignored there.
+#[cfg(not(unix))]
+fn user_id(name: &str) -> Result<u32, String> {
+    Ok(name.parse().unwrap_or(0))
+}
+
+#[cfg(not(unix))]
+fn group_id(name: &str) -> Result<u32, String> {
+    Ok(name.parse().unwrap_or(0))
+}
+
+/// Gives `path` the permission bits `mode`.
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+pub fn set_mode(_path: &Path, _mode: u32) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Gives `path` the owner `owner`, without following a symlink.
+#[cfg(unix)]
+pub fn set_owner(path: &Path, owner: Owner) -> std::io::Result<()> {
+    std::os::unix::fs::lchown(path, owner.uid, owner.gid)
+}
+
+#[cfg(not(unix))]
+pub fn set_owner(_path: &Path, _owner: Owner) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// The output directories a run made, the only ones --dir-mode and --chown
+/// change. Shared by the files of the run, whichever needs a directory first
+/// makes it.
+#[derive(Clone, Default)]
+pub struct CreatedDirs(Arc<Mutex<Vec<PathBuf>>>);
+
+impl CreatedDirs {
+    /// Creates `dir` and its parents, noting the ones that weren't there.
+    pub fn create(&self, dir: &Path) -> std::io::Result<()> {
+        let missing: Vec<_> = dir
+            .ancestors()
+            .take_while(|ancestor| ancestor.symlink_metadata().is_err())
+            .map(Path::to_owned)
+            .collect();
+        std::fs::create_dir_all(dir)?;
+        self.0.lock().unwrap().extend(missing);
+        Ok(())
+    }
+
+    /// The directories made so far below `output`, or `output` itself,
+    /// parents first. Files making the same directory at once may both
+    /// have noted it.
+    pub fn below(&self, output: &Path) -> Vec<PathBuf> {
+        let mut dirs: Vec<_> = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|dir| dir.starts_with(output))
+            .cloned()
+            .collect();
+        dirs.sort();
+        dirs.dedup();
+        dirs
+    }
+}
+
+/// Applies `mode` and `owner` to `dirs`. They are changed once everything
+/// is written, so a mode without write permission doesn't keep the files
+/// out. Returns a description of every change that failed, and how many of
+/// them were of the owner.
+pub fn apply_dirs(
+    dirs: &[PathBuf],
+    mode: Option<u32>,
+    owner: Option<Owner>,
+) -> (Vec<String>, usize) {
+    let mut failures = Vec::new();
+    let mut owner_failures = 0;
+    for dir in dirs {
+        if let Some(owner) = owner
+            && let Err(e) = set_owner(dir, owner)
+        {
+            failures.push(format!("owner of {}: {}", dir.display(), e));
+            owner_failures += 1;
+        }
+        // After the owner, which may clear the setgid bit.
+        if let Some(mode) = mode
+            && let Err(e) = set_mode(dir, mode)
+        {
+            failures.push(format!("mode of {}: {}", dir.display(), e));
+        }
+    }
+    (failures, owner_failures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes_are_octal_and_at_most_four_digits() {
+        assert_eq!(parse_mode("755"), Ok(0o755));
+        assert_eq!(parse_mode("2775"), Ok(0o2775));
+        assert!(parse_mode("789").is_err());
+        assert!(parse_mode("17777").is_err());
+    }
+
+    #[test]
+    fn owners_take_either_part_by_number() {
+        let owner = parse_owner("1000:100").unwrap();
+        assert_eq!((owner.uid, owner.gid), (Some(1000), Some(100)));
+        let owner = parse_owner(":100").unwrap();
+        assert_eq!((owner.uid, owner.gid), (None, Some(100)));
+        let owner = parse_owner("1000").unwrap();
+        assert_eq!((owner.uid, owner.gid), (Some(1000), None));
+        assert!(parse_owner(":").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_the_directories_a_run_made_change() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let output = temp.path().join("out");
+        std::fs::create_dir_all(output.join("old")).unwrap();
+        set_mode(&output.join("old"), 0o755).unwrap();
+        let created = CreatedDirs::default();
+        created.create(&output.join("old/new/deeper")).unwrap();
+        created.create(&output.join("old/new")).unwrap();
+
+        let dirs = created.below(&output);
+        assert_eq!(
+            dirs,
+            [output.join("old/new"), output.join("old/new/deeper")]
+        );
+        let (failures, _) = apply_dirs(&dirs, Some(0o700), None);
+        assert!(failures.is_empty(), "{:?}", failures);
+        let mode = |dir: &str| {
+            let metadata = std::fs::metadata(output.join(dir)).unwrap();
+            metadata.permissions().mode() & 0o7777
+        };
+        assert_eq!(mode("old"), 0o755);
+        assert_eq!(mode("old/new"), 0o700);
+        assert_eq!(mode("old/new/deeper"), 0o700);
+    }
+}
@@ -0,0 +1,105 @@
+use std::{collections::BTreeMap, time::Duration};
+
+/// A file converted by the pilot, to project the rest of the run from.
+pub struct Sample {
+    pub extension: String,
+    pub original_size: u64,
+    pub converted_size: u64,
+    pub duration: Duration,
+}
+
+/// Where the whole run is expected to end up.
+pub struct Estimate {
+    /// The size of everything the run writes, the pilot's outputs included.
+    pub output_size: u64,
+    /// The time the conversions left will take at `--jobs` at a time.
+    pub time: Duration,
+}
+
+/// Picks up to `count` of `candidates`, given as extension and size, to
+/// convert as samples: every extension gets one while there are enough to
+/// go round, the rest go to the extensions with the most bytes, and within
+/// an extension the picks are spread from the smallest file to the largest.
+/// Returns indices into `candidates`.
+pub fn pick(candidates: &[(String, u64)], count: usize) -> Vec<usize> {
+    let mut by_extension = BTreeMap::<&str, Vec<usize>>::new();
+    for (index, (extension, _)) in candidates.iter().enumerate() {
+        by_extension.entry(extension).or_default().push(index);
+    }
+    let mut groups = by_extension
+        .into_values()
+        .map(|mut indices| {
+            indices.sort_by_key(|&index| candidates[index].1);
+            let bytes = indices
+                .iter()
+                .map(|&index| candidates[index].1)
+                .sum::<u64>();
+            (indices, bytes, 0usize)
+        })
+        .collect::<Vec<_>>();
+    groups.sort_by_key(|(_, bytes, _)| std::cmp::Reverse(*bytes));
+
+    let mut left = count.min(candidates.len());
+    for (_, _, taken) in groups.iter_mut().take(left) {
+        *taken = 1;
+    }
+    left -= groups.len().min(left);
+    while left > 0 {
+        // The group with the most bytes per pick so far.
+        let Some((_, _, taken)) = groups
+            .iter_mut()
+            .filter(|(indices, _, taken)| *taken < indices.len())
+            .max_by(|(_, a, a_taken), (_, b, b_taken)| {
+                (*a as f64 / (*a_taken + 1) as f64).total_cmp(&(*b as f64 / (*b_taken + 1) as f64))
+            })
+        else {
+            break;
+        };
+        *taken += 1;
+        left -= 1;
+    }
+
+    let mut picked = Vec::new();
+    for (indices, _, taken) in groups {
+        // The middle of each of `taken` equal slices of the sizes.
+        picked
+            .extend((0..taken).map(|slice| indices[(2 * slice + 1) * indices.len() / (2 * taken)]));
+    }
+    picked
+}
+
+/// Projects the output size and time of converting `remaining`, given as
+/// extension and size, from the samples of the same extension, or from all
+/// of them for extensions without one. `None` without any samples.
+pub fn estimate(samples: &[Sample], remaining: &[(String, u64)], jobs: usize) -> Option<Estimate> {
+    // Source bytes, output bytes and time per extension, and over all.
+    let mut totals = BTreeMap::<&str, (u64, u64, Duration)>::new();
+    let mut overall = (0, 0, Duration::ZERO);
+    for sample in samples {
+        for total in [totals.entry(&sample.extension).or_default(), &mut overall] {
+            total.0 += sample.original_size;
+            total.1 += sample.converted_size;
+            total.2 += sample.duration;
+        }
+    }
+    if overall.0 == 0 {
+        return None;
+    }
+    let mut output_size = samples
+        .iter()
+        .map(|sample| sample.converted_size)
+        .sum::<u64>() as f64;
+    let mut time = 0.0;
+    for (extension, size) in remaining {
+        let (original, converted, duration) = totals
+            .get(extension.as_str())
+            .filter(|total| total.0 > 0)
+            .unwrap_or(&overall);
+        output_size += *size as f64 * *converted as f64 / *original as f64;
+        time += *size as f64 * duration.as_secs_f64() / *original as f64;
+    }
+    Some(Estimate {
+        output_size: output_size as u64,
+        time: Duration::from_secs_f64(time / jobs.max(1) as f64),
+    })
+}
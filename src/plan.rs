@@ -0,0 +1,96 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+/// What a run will do with the files it collected, in the order it starts
+/// them. Made after collection, with the outputs mapped, collisions renamed
+/// and existing outputs looked at, so nothing in it needs the run itself.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Plan {
+    pub actions: Vec<PlannedAction>,
+}
+
+/// A file of a [`Plan`].
+#[derive(Clone, Debug, Serialize)]
+pub struct PlannedAction {
+    pub source: PathBuf,
+    /// Where its result goes, `None` for files that are left out.
+    pub destination: Option<PathBuf>,
+    /// The files it becomes instead of `destination` when there are several:
+    /// the pages of a multi-page TIFF with `--multipage all`, the images of
+    /// an ICO file with `--ico all`. Empty for the others.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<PathBuf>,
+    #[serde(flatten)]
+    pub action: PlanAction,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum PlanAction {
+    Convert {
+        settings: ConvertSettings,
+    },
+    /// Copied as it is.
+    Copy,
+    /// A symlink recreated as such.
+    Link,
+    Skip {
+        reason: String,
+    },
+}
+
+/// The encoder settings a conversion starts with.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct ConvertSettings {
+    pub effort: u32,
+    /// Butteraugli distance, 0 for lossless. `None` leaves the encoder default.
+    pub distance: Option<f32>,
+    /// JPEGs transcoded losslessly with cjxl.
+    pub jpeg_reconstruct: bool,
+    /// `--smart` probes the file when it is converted and may pick another
+    /// distance.
+    pub smart: bool,
+}
+
+impl std::fmt::Display for ConvertSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "effort {}", self.effort)?;
+        if self.jpeg_reconstruct {
+            write!(f, ", reconstruct")?;
+        } else if let Some(distance) = self.distance {
+            write!(f, ", distance {}", distance)?;
+        }
+        if self.smart {
+            write!(f, ", smart")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for PlannedAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match &self.action {
+            PlanAction::Convert { .. } => "convert",
+            PlanAction::Copy => "copy",
+            PlanAction::Link => "link",
+            PlanAction::Skip { .. } => "skip",
+        };
+        write!(f, "{:<8} {}", name, self.source.display())?;
+        if !self.outputs.is_empty() {
+            let outputs: Vec<_> = self
+                .outputs
+                .iter()
+                .map(|output| output.display().to_string())
+                .collect();
+            write!(f, " -> {}", outputs.join(", "))?;
+        } else if let Some(destination) = &self.destination {
+            write!(f, " -> {}", destination.display())?;
+        }
+        match &self.action {
+            PlanAction::Convert { settings } => write!(f, " ({})", settings),
+            PlanAction::Skip { reason } => write!(f, " ({})", reason),
+            PlanAction::Copy | PlanAction::Link => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::{collections::BTreeMap, path::Path, sync::Arc};
+
+use serde::Deserialize;
+
+/// How files of one type are encoded.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// Distance 0
+    Lossless,
+    /// The entry's distance, or the global one
+    Lossy,
+    /// Lossless JPEG transcoding with cjxl, so the original JPEG can be
+    /// restored bit for bit. Other formats are encoded losslessly.
+    Reconstruct,
+}
+
+/// One table of the policy file, every field is optional.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+    pub mode: Option<Mode>,
+    pub distance: Option<f32>,
+    pub effort: Option<u32>,
+}
+
+impl std::fmt::Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(mode) = self.mode {
+            parts.push(
+                match mode {
+                    Mode::Lossless => "lossless",
+                    Mode::Lossy => "lossy",
+                    Mode::Reconstruct => "reconstruct",
+                }
+                .to_string(),
+            );
+        }
+        if let Some(distance) = self.distance {
+            parts.push(format!("distance={}", distance));
+        }
+        if let Some(effort) = self.effort {
+            parts.push(format!("effort={}", effort));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+/// Encoder settings per file type, read from a TOML file with one table per
+/// extension:
+///
+/// ```toml
+/// [png]
+/// mode = "lossless"
+///
+/// [webp]
+/// mode = "lossy"
+/// distance = 1.0
+/// ```
+#[derive(Clone)]
+pub struct Policy {
+    entries: Arc<BTreeMap<String, Entry>>,
+}
+
+impl Policy {
+    /// Reads and checks a policy file, used as a clap value parser.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(Path::new(path))
+            .map_err(|e| format!("cannot read {}: {}", path, e))?;
+        let entries: BTreeMap<String, Entry> =
+            toml::from_str(&text).map_err(|e| format!("invalid policy {}: {}", path, e))?;
+        for (key, entry) in &entries {
+            if let Some(effort) = entry.effort
+                && !(1..=9).contains(&effort)
+            {
+                return Err(format!(
+                    "invalid policy {}: effort {} of [{}] is not between 1 and 9",
+                    path, effort, key
+                ));
+            }
+        }
+        Ok(Self {
+            entries: Arc::new(
+                entries
+                    .into_iter()
+                    .map(|(key, entry)| (key.to_lowercase(), entry))
+                    .collect(),
+            ),
+        })
+    }
+
+    /// Finds the entry for a file by its own extension first, then by the
+    /// canonical extension of its (possibly sniffed) format. Returns the key
+    /// that matched along with the entry.
+    pub fn lookup(&self, extension: &str, format_extension: &str) -> Option<(&str, Entry)> {
+        let canonical = crate::sniff::ImageKind::from_extension(format_extension)
+            .map(|kind| kind.extensions()[0]);
+        [Some(extension), Some(format_extension), canonical]
+            .into_iter()
+            .flatten()
+            .find_map(|key| {
+                self.entries
+                    .get_key_value(key)
+                    .map(|(key, entry)| (key.as_str(), *entry))
+            })
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &Entry)> {
+        self.entries
+            .iter()
+            .map(|(key, entry)| (key.as_str(), entry))
+    }
+}
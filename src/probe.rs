@@ -0,0 +1,369 @@
+use std::path::Path;
+
+use crate::{encoder::InputSelection, process::CommandRunner};
+
+/// Runs ffprobe with `options` on the part of `path` that `selection` picks:
+/// the first video stream, a page of a TIFF or an image of an ICO file.
+/// Returns what it printed. Fails with `failure` when ffprobe does.
+async fn query(
+    runner: &dyn CommandRunner,
+    path: &Path,
+    input_format: Option<&str>,
+    selection: InputSelection,
+    options: &[&str],
+    failure: &str,
+) -> anyhow::Result<String> {
+    let mut command = crate::process::command("ffprobe");
+    command.arg("-v").arg("error");
+    if let InputSelection::TiffPage(page) = selection {
+        // Decoder option of the TIFF decoder, pages are numbered from 1.
+        command.arg("-page").arg(page.to_string());
+    }
+    if let Some(format) = crate::process::input_format(path, input_format).await {
+        command.arg("-f").arg(format);
+    }
+    let stream = match selection {
+        InputSelection::Stream(index) => index.to_string(),
+        InputSelection::Whole | InputSelection::TiffPage(_) => "v:0".to_string(),
+    };
+    command
+        .arg("-select_streams")
+        .arg(stream)
+        .args(options)
+        .arg(crate::process::file_arg(path));
+    let output = runner.run(&mut command, false).await?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("{}", failure));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Asks ffprobe for the pixel format of the image `selection` picks.
+pub async fn pixel_format(
+    runner: &dyn CommandRunner,
+    path: &Path,
+    input_format: Option<&str>,
+    selection: InputSelection,
+) -> anyhow::Result<String> {
+    let output = query(
+        runner,
+        path,
+        input_format,
+        selection,
+        &[
+            "-show_entries",
+            "stream=pix_fmt",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+        ],
+        "Failed to probe pixel format",
+    )
+    .await?;
+    let pix_fmt = output.trim().to_string();
+    if pix_fmt.is_empty() {
+        return Err(anyhow::anyhow!("No video stream found"));
+    }
+    Ok(pix_fmt)
+}
+
+/// Basic properties of the first video stream.
+pub struct StreamInfo {
+    pub pix_fmt: String,
+    pub width: u32,
+    pub height: u32,
+    /// 0 when ffprobe doesn't report it.
+    pub bits_per_raw_sample: u32,
+}
+
+/// Asks ffprobe for the pixel format, dimensions and bit depth of the image
+/// `selection` picks.
+pub async fn stream_info(
+    runner: &dyn CommandRunner,
+    path: &Path,
+    input_format: Option<&str>,
+    selection: InputSelection,
+) -> anyhow::Result<StreamInfo> {
+    let output = query(
+        runner,
+        path,
+        input_format,
+        selection,
+        &[
+            "-show_entries",
+            "stream=pix_fmt,width,height,bits_per_raw_sample",
+            "-of",
+            "default=noprint_wrappers=1",
+        ],
+        "Failed to probe stream",
+    )
+    .await?;
+    let mut info = StreamInfo {
+        pix_fmt: String::new(),
+        width: 0,
+        height: 0,
+        bits_per_raw_sample: 0,
+    };
+    for line in output.lines() {
+        match line.trim().split_once('=') {
+            Some(("pix_fmt", value)) => info.pix_fmt = value.to_string(),
+            Some(("width", value)) => info.width = value.parse().unwrap_or(0),
+            Some(("height", value)) => info.height = value.parse().unwrap_or(0),
+            Some(("bits_per_raw_sample", value)) => {
+                info.bits_per_raw_sample = value.parse().unwrap_or(0)
+            }
+            _ => {}
+        }
+    }
+    if info.pix_fmt.is_empty() {
+        return Err(anyhow::anyhow!("No video stream found"));
+    }
+    Ok(info)
+}
+
+/// Colour properties of the first video stream as ffprobe names them.
+pub struct ColorInfo {
+    pub primaries: String,
+    pub transfer: String,
+    pub matrix: String,
+}
+
+/// Asks ffprobe for the colour primaries, transfer function and matrix of the
+/// image `selection` picks. Untagged properties come back as `unknown` or empty.
+pub async fn color_info(
+    runner: &dyn CommandRunner,
+    path: &Path,
+    input_format: Option<&str>,
+    selection: InputSelection,
+) -> anyhow::Result<ColorInfo> {
+    let output = query(
+        runner,
+        path,
+        input_format,
+        selection,
+        &[
+            "-show_entries",
+            "stream=color_primaries,color_transfer,color_space",
+            "-of",
+            "default=noprint_wrappers=1",
+        ],
+        "Failed to probe color tags",
+    )
+    .await?;
+    let mut info = ColorInfo {
+        primaries: String::new(),
+        transfer: String::new(),
+        matrix: String::new(),
+    };
+    for line in output.lines() {
+        match line.trim().split_once('=') {
+            Some(("color_primaries", value)) => info.primaries = value.to_string(),
+            Some(("color_transfer", value)) => info.transfer = value.to_string(),
+            Some(("color_space", value)) => info.matrix = value.to_string(),
+            _ => {}
+        }
+    }
+    Ok(info)
+}
+
+/// Reads the EXIF orientation of the first frame of the image `selection`
+/// picks, 1 when it has none.
+pub async fn orientation(
+    runner: &dyn CommandRunner,
+    path: &Path,
+    input_format: Option<&str>,
+    selection: InputSelection,
+) -> anyhow::Result<u32> {
+    let output = query(
+        runner,
+        path,
+        input_format,
+        selection,
+        &[
+            "-read_intervals",
+            "%+#1",
+            "-show_entries",
+            "frame_tags=Orientation",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+        ],
+        "Failed to probe orientation",
+    )
+    .await?;
+    match output.trim() {
+        "" => Ok(1),
+        value => Ok(value.parse()?),
+    }
+}
+
+/// The ffmpeg filter that turns an image with the given EXIF orientation
+/// upright, or `None` when it already is.
+pub fn orientation_filter(orientation: u32) -> Option<&'static str> {
+    match orientation {
+        2 => Some("hflip"),
+        3 => Some("hflip,vflip"),
+        4 => Some("vflip"),
+        // transpose=0 mirrors along the main diagonal, 3 along the other one.
+        5 => Some("transpose=0"),
+        6 => Some("transpose=1"),
+        7 => Some("transpose=3"),
+        8 => Some("transpose=2"),
+        _ => None,
+    }
+}
+
+/// Number of channels, including alpha, in an ffmpeg pixel format.
+pub fn channel_count(pix_fmt: &str) -> usize {
+    if pix_fmt.starts_with("gray") || pix_fmt.starts_with("mono") {
+        1
+    } else if pix_fmt.starts_with("ya") {
+        2
+    } else if pix_fmt.starts_with("rgba")
+        || pix_fmt.starts_with("bgra")
+        || pix_fmt.starts_with("argb")
+        || pix_fmt.starts_with("abgr")
+        || pix_fmt.starts_with("yuva")
+        || pix_fmt.starts_with("gbrap")
+    {
+        4
+    } else {
+        3
+    }
+}
+
+/// The libjxl input format that keeps a grayscale source single-channel (plus
+/// alpha), or `None` for colour sources.
+pub fn grayscale_encoder_format(pix_fmt: &str) -> Option<&'static str> {
+    match pix_fmt {
+        "gray" | "monow" | "monob" => Some("gray"),
+        "ya8" => Some("ya8"),
+        "ya16le" | "ya16be" => Some("ya16"),
+        "grayf32le" | "grayf32be" => Some("grayf32"),
+        _ if pix_fmt.starts_with("gray") => Some("gray16"),
+        _ => None,
+    }
+}
+
+/// What `--probe` learns about a source from one ffprobe run, kept for
+/// everything later in the run that would otherwise probe it again.
+#[derive(Clone, Debug)]
+pub struct Info {
+    pub codec: String,
+    pub pix_fmt: String,
+    pub width: u32,
+    pub height: u32,
+    /// 0 when ffprobe doesn't report it.
+    pub bits_per_raw_sample: u32,
+    /// Frames of an animation, 1 for stills.
+    pub frames: u64,
+    /// Whether the pixels are stored as CMYK, which ffmpeg converts to RGB
+    /// while decoding, so the pixel format doesn't tell.
+    pub cmyk: bool,
+}
+
+impl Info {
+    /// More than 8 bits per sample.
+    pub fn is_high_bit_depth(&self) -> bool {
+        self.bits_per_raw_sample > 8
+            || ["16", "48", "64", "f32", "f16"]
+                .iter()
+                .any(|depth| self.pix_fmt.contains(depth))
+    }
+
+    pub fn stream(&self) -> StreamInfo {
+        StreamInfo {
+            pix_fmt: self.pix_fmt.clone(),
+            width: self.width,
+            height: self.height,
+            bits_per_raw_sample: self.bits_per_raw_sample,
+        }
+    }
+}
+
+/// Asks ffprobe for the codec, pixel format, dimensions, bit depth and frame
+/// count of the first video stream. Frames are counted from the packets, so
+/// nothing is decoded; animated PNGs, which ffmpeg only reads as such when
+/// told, are counted from their header.
+pub async fn info(
+    runner: &dyn CommandRunner,
+    path: &Path,
+    input_format: Option<&str>,
+) -> anyhow::Result<Info> {
+    let output = query(
+        runner,
+        path,
+        input_format,
+        InputSelection::Whole,
+        &[
+            "-count_packets",
+            "-show_entries",
+            "stream=codec_name,pix_fmt,width,height,bits_per_raw_sample,nb_read_packets",
+            "-of",
+            "default=noprint_wrappers=1",
+        ],
+        "Failed to probe stream",
+    )
+    .await?;
+    let mut info = Info {
+        codec: String::new(),
+        pix_fmt: String::new(),
+        width: 0,
+        height: 0,
+        bits_per_raw_sample: 0,
+        frames: 1,
+        cmyk: false,
+    };
+    for line in output.lines() {
+        match line.trim().split_once('=') {
+            Some(("codec_name", value)) => info.codec = value.to_string(),
+            Some(("pix_fmt", value)) => info.pix_fmt = value.to_string(),
+            Some(("width", value)) => info.width = value.parse().unwrap_or(0),
+            Some(("height", value)) => info.height = value.parse().unwrap_or(0),
+            Some(("bits_per_raw_sample", value)) => {
+                info.bits_per_raw_sample = value.parse().unwrap_or(0)
+            }
+            Some(("nb_read_packets", value)) => info.frames = value.parse().unwrap_or(1).max(1),
+            _ => {}
+        }
+    }
+    if info.pix_fmt.is_empty() {
+        return Err(anyhow::anyhow!("No video stream found"));
+    }
+    match info.codec.as_str() {
+        "png" | "apng" => {
+            if let Ok(Some(animation)) = crate::apng::animation(path) {
+                info.frames = animation.frames.max(1) as u64;
+            }
+        }
+        "mjpeg" => info.cmyk = jpeg_components(path).is_ok_and(|count| count == Some(4)),
+        _ => {}
+    }
+    Ok(info)
+}
+
+/// The number of colour components in the frame header of a JPEG, 4 for CMYK
+/// (or YCCK).
+fn jpeg_components(path: &Path) -> std::io::Result<Option<u8>> {
+    use std::io::Read;
+
+    let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut marker = [0u8; 2];
+    file.read_exact(&mut marker)?;
+    if marker != [0xFF, 0xD8] {
+        return Ok(None);
+    }
+    loop {
+        file.read_exact(&mut marker)?;
+        if marker[0] != 0xFF || marker[1] == 0xDA || marker[1] == 0xD9 {
+            return Ok(None);
+        }
+        let mut length = [0u8; 2];
+        file.read_exact(&mut length)?;
+        let length = u16::from_be_bytes(length).saturating_sub(2) as usize;
+        let mut segment = vec![0u8; length];
+        file.read_exact(&mut segment)?;
+        // The start of frame markers, but for DHT, JPG and DAC in between.
+        if matches!(marker[1], 0xC0..=0xCF) && !matches!(marker[1], 0xC4 | 0xC8 | 0xCC) {
+            return Ok(segment.get(5).copied());
+        }
+    }
+}
@@ -0,0 +1,377 @@
+/// Builds a command for an external tool that can't outlive the task running
+/// it: dropping the future kills the child, e.g. when a task is aborted.
+///
+/// On Unix the child also gets its own process group, so a Ctrl-C in the
+/// terminal reaches only us and we decide whether running conversions finish.
+pub fn command(program: &str) -> tokio::process::Command {
+    let mut command = tokio::process::Command::new(program);
+    command.kill_on_drop(true);
+    #[cfg(unix)]
+    command.process_group(0);
+    command
+}
+
+/// The children a run's [`Spawn`] is waiting for, which `--pause-encoders`
+/// stops and continues. Each run has its own, so pausing one leaves the
+/// tools of others running in the same process alone.
+#[derive(Clone, Default)]
+pub struct Children(#[cfg(unix)] std::sync::Arc<std::sync::Mutex<Running>>);
+
+/// The process groups of the children, and whether they are stopped for a
+/// paused run.
+#[cfg(unix)]
+#[derive(Default)]
+struct Running {
+    groups: std::collections::BTreeSet<libc::pid_t>,
+    stopped: bool,
+}
+
+impl Children {
+    /// Stops the running children with SIGSTOP, and the ones started until
+    /// they are continued again with `stop` false.
+    #[cfg(unix)]
+    pub fn stop(&self, stop: bool) {
+        let mut running = self.0.lock().unwrap();
+        running.stopped = stop;
+        let signal = if stop { libc::SIGSTOP } else { libc::SIGCONT };
+        for group in &running.groups {
+            // SAFETY: only sends a signal; a group that is gone gives ESRCH.
+            unsafe { libc::kill(-group, signal) };
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn stop(&self, _stop: bool) {}
+}
+
+/// Keeps a child in its run's [`Children`] while it lives. Dropped before the child has
+/// been waited for, as when the future running it is dropped, it kills the
+/// child's process group and waits until the child is gone: `kill_on_drop`
+/// only sends the signal, and the child could still create or write its output
+/// after the caller removed the partial file.
+#[cfg(unix)]
+struct Registered {
+    children: Children,
+    group: Option<libc::pid_t>,
+    exited: bool,
+}
+
+#[cfg(unix)]
+impl Registered {
+    /// Registers the child with `pid`, its own process group leader.
+    fn new(children: &Children, pid: Option<u32>) -> Self {
+        let group = pid.and_then(|pid| libc::pid_t::try_from(pid).ok());
+        if let Some(group) = group {
+            let mut running = children.0.lock().unwrap();
+            running.groups.insert(group);
+            if running.stopped {
+                // SAFETY: only sends a signal to the child just started.
+                unsafe { libc::kill(-group, libc::SIGSTOP) };
+            }
+        }
+        Registered {
+            children: children.clone(),
+            group,
+            exited: false,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Registered {
+    fn drop(&mut self) {
+        let Some(group) = self.group else {
+            return;
+        };
+        self.children.0.lock().unwrap().groups.remove(&group);
+        if self.exited {
+            return;
+        }
+        // SAFETY: the child hasn't been reaped, so its pid is still ours.
+        unsafe { libc::kill(-group, libc::SIGKILL) };
+        // WNOWAIT leaves the child to be reaped by tokio, which the dropped
+        // `Child` hands it to.
+        loop {
+            // SAFETY: waitid only writes to the siginfo it is given.
+            let waited = unsafe {
+                let mut info: libc::siginfo_t = std::mem::zeroed();
+                libc::waitid(
+                    libc::P_PID,
+                    group as libc::id_t,
+                    &mut info,
+                    libc::WEXITED | libc::WNOWAIT,
+                )
+            };
+            if waited == 0
+                || std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted
+            {
+                break;
+            }
+        }
+    }
+}
+
+/// `path` as a file argument for ffmpeg and ffprobe. The `file:` prefix keeps
+/// names starting with a dash from being read as an option, and names with a
+/// colon from being read as a protocol.
+pub fn file_arg(path: &std::path::Path) -> std::ffi::OsString {
+    let mut arg = std::ffi::OsString::from("file:");
+    arg.push(path);
+    arg
+}
+
+/// `path` as an argument for other tools, which take options anywhere: a
+/// relative path starting with a dash gets `./` in front.
+pub fn tool_arg(path: &std::path::Path) -> std::path::PathBuf {
+    if path.as_os_str().as_encoded_bytes().starts_with(b"-") {
+        std::path::Path::new(".").join(path)
+    } else {
+        path.to_owned()
+    }
+}
+
+/// The input format to give ffmpeg and ffprobe for `path`: `format` if there
+/// is one, and otherwise the content's for names with `%` or glob characters,
+/// which ffmpeg would take for an image sequence pattern. Reads the start of
+/// those off the runtime.
+pub async fn input_format<'a>(path: &std::path::Path, format: Option<&'a str>) -> Option<&'a str> {
+    if format.is_some() {
+        return format;
+    }
+    let name = path.as_os_str().as_encoded_bytes();
+    if !name.iter().any(|b| b"%*?[{".contains(b)) {
+        return None;
+    }
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || crate::sniff::detect(&path))
+        .await
+        .ok()?
+        .ok()
+        .flatten()
+        .map(|kind| kind.ffmpeg_format())
+}
+
+// Enough of the end of a tool's log to show why it failed.
+const STDERR_TAIL_LINES: usize = 20;
+
+/// Exit status, standard output and the end of the stderr of a finished
+/// command.
+pub struct Finished {
+    pub status: std::process::ExitStatus,
+    /// Everything the command wrote to its standard output, which is what
+    /// ffprobe answers with.
+    pub stdout: Vec<u8>,
+    pub stderr_tail: Vec<String>,
+}
+
+impl Finished {
+    /// The retained stderr as one message, or `fallback` when there was none.
+    pub fn message(&self, fallback: &str) -> String {
+        if self.stderr_tail.is_empty() {
+            fallback.to_string()
+        } else {
+            self.stderr_tail.join("\n")
+        }
+    }
+}
+
+/// Runs the external tools of a conversion. Stands between the encoders and
+/// the processes they start, so something else can play the tools' part,
+/// e.g. scripted results for exercising the pipeline without ffmpeg.
+pub trait CommandRunner: Send + Sync {
+    /// Runs `command` to completion, as [`run`] does. Dropping the future
+    /// must stop the command.
+    fn run<'a>(
+        &'a self,
+        command: &'a mut tokio::process::Command,
+        verbose: bool,
+    ) -> std::pin::Pin<Box<dyn Future<Output = std::io::Result<Finished>> + Send + 'a>>;
+}
+
+/// A runner borrowed for as long as the run, like a test's scripted fake.
+impl<T: CommandRunner + ?Sized> CommandRunner for &T {
+    fn run<'a>(
+        &'a self,
+        command: &'a mut tokio::process::Command,
+        verbose: bool,
+    ) -> std::pin::Pin<Box<dyn Future<Output = std::io::Result<Finished>> + Send + 'a>> {
+        (**self).run(command, verbose)
+    }
+}
+
+/// Runs commands as child processes, keeping them in `children` while they
+/// run. The lines the tools write with `verbose` go to `messages`.
+pub struct Spawn {
+    pub children: Children,
+    pub messages: crate::events::Messages,
+}
+
+impl Spawn {
+    /// For the commands that don't pause, with what they have to say going
+    /// to `messages`.
+    pub fn new(messages: &crate::events::Messages) -> Self {
+        Spawn {
+            children: Children::default(),
+            messages: messages.clone(),
+        }
+    }
+}
+
+/// Runs the commands with another runner, and fails the ones that take
+/// longer than the `--tool-timeout` with [`std::io::ErrorKind::TimedOut`].
+/// The command is stopped the way it is when a task is aborted.
+pub struct Timeout(
+    pub std::sync::Arc<dyn CommandRunner>,
+    pub std::time::Duration,
+);
+
+impl CommandRunner for Timeout {
+    fn run<'a>(
+        &'a self,
+        command: &'a mut tokio::process::Command,
+        verbose: bool,
+    ) -> std::pin::Pin<Box<dyn Future<Output = std::io::Result<Finished>> + Send + 'a>> {
+        Box::pin(async move {
+            let limit = self.1;
+            tokio::time::timeout(limit, self.0.run(command, verbose))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("timed out after {:?}", limit),
+                    ))
+                })
+        })
+    }
+}
+
+/// The ffprobe next to the ffmpeg at `ffmpeg`, with the same extension.
+pub fn ffprobe_next_to(ffmpeg: &std::path::Path) -> std::path::PathBuf {
+    let mut ffprobe = std::ffi::OsString::from("ffprobe");
+    if let Some(extension) = ffmpeg.extension() {
+        ffprobe.push(".");
+        ffprobe.push(extension);
+    }
+    ffmpeg.with_file_name(ffprobe)
+}
+
+/// Runs the commands with another runner, with the ffmpeg of `--ffmpeg-path`
+/// and the ffprobe next to it in place of those on the PATH.
+pub struct FfmpegPath {
+    runner: std::sync::Arc<dyn CommandRunner>,
+    ffmpeg: std::path::PathBuf,
+    ffprobe: std::path::PathBuf,
+}
+
+impl FfmpegPath {
+    pub fn new(runner: std::sync::Arc<dyn CommandRunner>, ffmpeg: &str) -> Self {
+        let ffmpeg = std::path::PathBuf::from(ffmpeg);
+        FfmpegPath {
+            runner,
+            ffprobe: ffprobe_next_to(&ffmpeg),
+            ffmpeg,
+        }
+    }
+}
+
+impl CommandRunner for FfmpegPath {
+    fn run<'a>(
+        &'a self,
+        command: &'a mut tokio::process::Command,
+        verbose: bool,
+    ) -> std::pin::Pin<Box<dyn Future<Output = std::io::Result<Finished>> + Send + 'a>> {
+        Box::pin(async move {
+            let given = command.as_std();
+            let program = match given.get_program().to_str() {
+                Some("ffmpeg") => &self.ffmpeg,
+                Some("ffprobe") => &self.ffprobe,
+                _ => return self.runner.run(command, verbose).await,
+            };
+            let mut located = tokio::process::Command::new(program);
+            located.kill_on_drop(true);
+            #[cfg(unix)]
+            located.process_group(0);
+            located.args(given.get_args());
+            for (key, value) in given.get_envs() {
+                match value {
+                    Some(value) => located.env(key, value),
+                    None => located.env_remove(key),
+                };
+            }
+            if let Some(dir) = given.get_current_dir() {
+                located.current_dir(dir);
+            }
+            self.runner.run(&mut located, verbose).await
+        })
+    }
+}
+
+impl CommandRunner for Spawn {
+    fn run<'a>(
+        &'a self,
+        command: &'a mut tokio::process::Command,
+        verbose: bool,
+    ) -> std::pin::Pin<Box<dyn Future<Output = std::io::Result<Finished>> + Send + 'a>> {
+        Box::pin(run(command, verbose, &self.children, &self.messages))
+    }
+}
+
+/// Runs a command to completion while reading its output as it comes, so the
+/// child never blocks on a full pipe. The last lines of stderr are kept for
+/// error messages, and with `verbose` every line goes to `messages` as well.
+/// The child is one of `children` until it exits.
+pub async fn run(
+    command: &mut tokio::process::Command,
+    verbose: bool,
+    #[cfg_attr(not(unix), allow(unused_variables))] children: &Children,
+    messages: &crate::events::Messages,
+) -> std::io::Result<Finished> {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+    let mut child = command
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    #[cfg(unix)]
+    let mut registered = Registered::new(children, child.id());
+    let mut stdout = child.stdout.take().unwrap();
+    let mut stderr = tokio::io::BufReader::new(child.stderr.take().unwrap());
+    let mut output = Vec::new();
+    let reading = stdout.read_to_end(&mut output);
+
+    let mut tail = std::collections::VecDeque::with_capacity(STDERR_TAIL_LINES);
+    let logging = async {
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if stderr.read_until(b'\n', &mut line).await? == 0 {
+                break;
+            }
+            let text = String::from_utf8_lossy(&line).trim_end().to_string();
+            if text.is_empty() {
+                continue;
+            }
+            if verbose {
+                say!(messages, "      | {}", text);
+            }
+            if tail.len() == STDERR_TAIL_LINES {
+                tail.pop_front();
+            }
+            tail.push_back(text);
+        }
+        std::io::Result::Ok(())
+    };
+    tokio::try_join!(reading, logging)?;
+    let status = child.wait().await?;
+    #[cfg(unix)]
+    {
+        registered.exited = true;
+    }
+
+    Ok(Finished {
+        status,
+        stdout: output,
+        stderr_tail: tail.into(),
+    })
+}
@@ -0,0 +1,161 @@
+use std::{
+    cell::Cell,
+    time::{Duration, Instant},
+};
+
+use crate::events::Messages;
+
+tokio::task_local! {
+    /// Time spent in each stage so far by the file the current task works on.
+    pub static TIMINGS: Cell<Timings>;
+}
+
+/// A step of working on a single file, timed for `--profile`.
+#[derive(Clone, Copy)]
+pub enum Stage {
+    /// Reading page counts, animation, orientation and pixel formats.
+    Probe,
+    /// Running the encoder and checking what it wrote.
+    Encode,
+    /// Decoding the output again for --verify.
+    Verify,
+    /// Copying a file that is not converted.
+    Copy,
+    /// Timestamps, extended attributes, owner and mode.
+    Metadata,
+    /// Moving a finished output to its final name.
+    Rename,
+    /// Flushing the output for --fsync.
+    Sync,
+}
+
+const STAGES: [Stage; 7] = [
+    Stage::Probe,
+    Stage::Encode,
+    Stage::Verify,
+    Stage::Copy,
+    Stage::Metadata,
+    Stage::Rename,
+    Stage::Sync,
+];
+
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Probe => "probe",
+            Stage::Encode => "encode",
+            Stage::Verify => "verify",
+            Stage::Copy => "copy",
+            Stage::Metadata => "metadata",
+            Stage::Rename => "rename",
+            Stage::Sync => "sync",
+        }
+    }
+}
+
+/// The time one file spent in each stage it went through.
+#[derive(Clone, Copy, Default)]
+pub struct Timings([Option<Duration>; STAGES.len()]);
+
+impl Timings {
+    /// The stages in the order they were listed, e.g.
+    /// `probe 3.10ms, encode 120.52ms, rename 80.00µs`, or `None` if the
+    /// file went through none of them.
+    pub fn note(&self) -> Option<String> {
+        let stages = STAGES
+            .iter()
+            .filter_map(|&stage| {
+                self.0[stage as usize].map(|time| format!("{} {:.2?}", stage.name(), time))
+            })
+            .collect::<Vec<_>>();
+        (!stages.is_empty()).then(|| stages.join(", "))
+    }
+}
+
+/// Adds `time` to `stage` of the current file. Does nothing outside a file's
+/// task, e.g. for the copies made by the pass that fills in hard links.
+pub fn add(stage: Stage, time: Duration) {
+    let _ = TIMINGS.try_with(|timings| {
+        let mut current = timings.get();
+        let slot = &mut current.0[stage as usize];
+        *slot = Some(slot.unwrap_or_default() + time);
+        timings.set(current);
+    });
+}
+
+/// Runs `work` and adds the time it took to `stage`.
+pub async fn time<T>(stage: Stage, work: impl Future<Output = T>) -> T {
+    let start = Instant::now();
+    let result = work.await;
+    add(stage, start.elapsed());
+    result
+}
+
+/// Adds the time until it is dropped to a stage, so steps with early
+/// returns are timed whichever way they end.
+pub struct Span {
+    stage: Stage,
+    start: Instant,
+}
+
+pub fn span(stage: Stage) -> Span {
+    Span {
+        stage,
+        start: Instant::now(),
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        add(self.stage, self.start.elapsed());
+    }
+}
+
+/// The stage timings of all files and the duration of the phases of the run.
+#[derive(Default)]
+pub struct Profile {
+    /// The total time of each stage and the number of files that went
+    /// through it.
+    stages: [(Duration, usize); STAGES.len()],
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Profile {
+    pub fn add(&mut self, timings: &Timings) {
+        for (total, time) in self.stages.iter_mut().zip(timings.0) {
+            if let Some(time) = time {
+                total.0 += time;
+                total.1 += 1;
+            }
+        }
+    }
+
+    /// Records the phase `name` of the whole run, such as collecting the
+    /// files, as lasting from `start` until now, and restarts `start` for the
+    /// next one.
+    pub fn lap(&mut self, name: &'static str, start: &mut Instant) {
+        self.phases.push((name, start.elapsed()));
+        *start = Instant::now();
+    }
+
+    pub fn print(&self, messages: &Messages) {
+        messages.say("Profile:".to_string());
+        for (name, time) in &self.phases {
+            messages.say(format!("  {:<22} {:.2?}", format!("{}:", name), time));
+        }
+        // Files run side by side, so the stages add up to more than the run
+        // took with several jobs.
+        messages.say("  Per file stages (summed over files, mean per file):".to_string());
+        for (stage, (total, files)) in STAGES.iter().zip(self.stages) {
+            if files > 0 {
+                messages.say(format!(
+                    "    {:<20} {:.2?} over {} files, {:.2?} each",
+                    format!("{}:", stage.name()),
+                    total,
+                    files,
+                    total / files as u32
+                ));
+            }
+        }
+    }
+}
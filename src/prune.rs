@@ -0,0 +1,246 @@
+use std::path::{Path, PathBuf};
+
+use crate::events::Messages;
+
+/// Removes the files in an output tree whose source is no longer in the
+/// input, e.g. after pictures were deleted or moved, and the temporary
+/// outputs crashed runs left behind.
+#[derive(clap::Args)]
+pub struct PruneArgs {
+    #[command(flatten)]
+    tree: crate::Tree,
+
+    #[command(flatten)]
+    filters: crate::Filters,
+
+    /// List what would be removed without removing anything
+    #[clap(long)]
+    dry_run: bool,
+}
+
+/// The source of an output, found by [`source_of`].
+pub(crate) struct Source {
+    pub path: PathBuf,
+    /// Whether the output holds all of the source, rather than one of its
+    /// pages or images, or a member of an archive.
+    pub whole: bool,
+}
+
+/// Finds the file in `input` an output at `relative` in the output tree was
+/// made from, if it's still there: the same path for copies, and for `.jxl`
+/// outputs and their `.xmp` sidecars a file with the same name but another
+/// extension, without the `~N` of renamed collisions or the `.pNNN` and
+/// `.SIZE` of pages and icon images. Outputs in a directory an archive was
+/// extracted to belong to the archive.
+pub(crate) fn source_of(input: &Path, relative: &Path) -> Option<Source> {
+    let same = input.join(relative);
+    if same.is_file() {
+        return Some(Source {
+            path: same,
+            whole: true,
+        });
+    }
+    let parent = relative.parent().unwrap_or(Path::new(""));
+    let name = relative.file_name()?.to_str()?;
+    let name = name.strip_suffix(".xmp").unwrap_or(name);
+    if let Some(stem) = name.strip_suffix(".jxl") {
+        // The longest stem first, so `a.b.jxl` goes with `a.b.png` before `a.png`.
+        let mut stems = vec![stem];
+        stems.extend(stem.match_indices('.').rev().map(|(at, _)| &stem[..at]));
+        for (index, stem) in stems.into_iter().enumerate() {
+            for stem in [stem, without_collision_suffix(stem)] {
+                if let Some(path) = with_stem(&input.join(parent), stem) {
+                    return Some(Source {
+                        path,
+                        whole: index == 0,
+                    });
+                }
+            }
+        }
+    }
+    // `extract` unpacks `name.zip` to the directory `name`.
+    for directory in relative.ancestors().skip(1) {
+        let Some(name) = directory.file_name() else {
+            break;
+        };
+        let parent = input.join(directory.parent().unwrap_or(Path::new("")));
+        if input.join(directory).is_dir() {
+            continue;
+        }
+        if let Some(path) = name.to_str().and_then(|name| with_stem(&parent, name)) {
+            return Some(Source { path, whole: false });
+        }
+    }
+    None
+}
+
+/// `stem` without the `~N` collisions are renamed with.
+fn without_collision_suffix(stem: &str) -> &str {
+    match stem.rsplit_once('~') {
+        Some((base, n)) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => base,
+        _ => stem,
+    }
+}
+
+/// A file in `dir` named `stem` with any extension, the first by name.
+fn with_stem(dir: &Path, stem: &str) -> Option<PathBuf> {
+    let mut found: Vec<_> = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.file_stem().is_some_and(|s| s == stem) && path.is_file())
+        .collect();
+    found.sort();
+    found.into_iter().next()
+}
+
+/// Whether the run itself keeps `name` in the output: its journal, lock and
+/// the like, and the files in progress.
+fn is_bookkeeping(name: &str) -> bool {
+    name.starts_with(".bulk-jxl")
+}
+
+pub fn run(args: PruneArgs, messages: &Messages) -> anyhow::Result<()> {
+    let input_path = PathBuf::from(&args.tree.input);
+    if !input_path.is_dir() {
+        return Err(anyhow::anyhow!("Input path is not a directory"));
+    }
+    let output_path = PathBuf::from(&args.tree.output);
+    if !output_path.is_dir() {
+        return Err(anyhow::anyhow!("Output path is not a directory"));
+    }
+    if output_path.join("index.jsonl").exists() {
+        return Err(anyhow::anyhow!(
+            "{} holds --content-addressed outputs, which are named after their contents and can't be matched to a source",
+            output_path.display()
+        ));
+    }
+
+    let mut orphans = Vec::new();
+    let mut kept = 0;
+    for entry in args.filters.files(&output_path, args.tree.recursive) {
+        let relative = entry.path().strip_prefix(&output_path)?;
+        if is_bookkeeping(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        match source_of(&input_path, relative) {
+            Some(_) => kept += 1,
+            None => orphans.push(entry.into_path()),
+        }
+    }
+
+    let mut removed = 0;
+    let mut reclaimed = 0;
+    for orphan in &orphans {
+        let size = std::fs::metadata(orphan).map_or(0, |metadata| metadata.len());
+        if args.dry_run {
+            say!(messages, "   Would remove {}", orphan.display());
+            continue;
+        }
+        match std::fs::remove_file(orphan) {
+            Ok(()) => {
+                say!(messages, "   Removed {}", orphan.display());
+                removed += 1;
+                reclaimed += size;
+                remove_empty_parents(orphan, &output_path);
+            }
+            Err(e) => say_error!(messages, "Error removing {}: {}", orphan.display(), e),
+        }
+    }
+    let (partials, partial_bytes) = if args.dry_run {
+        (0, 0)
+    } else {
+        crate::remove_stale_partials(&output_path)
+    };
+
+    say!(messages, "{}", "-".repeat(60));
+    say!(messages, "Prune Summary:");
+    say!(messages, "  Outputs with a source: {}", kept);
+    if args.dry_run {
+        say!(messages, "  Outputs to remove:     {}", orphans.len());
+    } else {
+        say!(messages, "  Outputs removed:       {}", removed);
+        say!(messages, "  Partial files removed: {}", partials);
+        say!(
+            messages,
+            "  Space reclaimed:       {}",
+            human_bytes::human_bytes((reclaimed + partial_bytes) as f64)
+        );
+    }
+    say!(messages, "{}", "-".repeat(60));
+    Ok(())
+}
+
+/// Removes the directories between `file` and `root` that removing it left
+/// empty.
+fn remove_empty_parents(file: &Path, root: &Path) {
+    for directory in file.ancestors().skip(1) {
+        if directory == root || std::fs::remove_dir(directory).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn touch(path: &Path) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, "").unwrap();
+    }
+
+    fn source(input: &Path, relative: &str) -> Option<(PathBuf, bool)> {
+        source_of(input, Path::new(relative)).map(|source| (source.path, source.whole))
+    }
+
+    #[test]
+    fn outputs_are_matched_to_their_sources() {
+        let temp = tempfile::tempdir().unwrap();
+        let input = temp.path();
+        for name in [
+            "a.png",
+            "a.b.gif",
+            "notes.txt",
+            "scan.tiff",
+            "sub/icon.ico",
+            "book.zip",
+        ] {
+            touch(&input.join(name));
+        }
+        let found = |path: &str| input.join(path);
+
+        assert_eq!(source(input, "a.jxl"), Some((found("a.png"), true)));
+        assert_eq!(source(input, "a.b.jxl"), Some((found("a.b.gif"), true)));
+        assert_eq!(source(input, "a~1.jxl"), Some((found("a.png"), true)));
+        assert_eq!(source(input, "a.jxl.xmp"), Some((found("a.png"), true)));
+        assert_eq!(source(input, "notes.txt"), Some((found("notes.txt"), true)));
+        assert_eq!(
+            source(input, "scan.p002.jxl"),
+            Some((found("scan.tiff"), false))
+        );
+        assert_eq!(
+            source(input, "sub/icon.32.8bpp.jxl"),
+            Some((found("sub/icon.ico"), false))
+        );
+        assert_eq!(
+            source(input, "book/page1.jxl"),
+            Some((found("book.zip"), false))
+        );
+    }
+
+    #[test]
+    fn outputs_without_a_source_are_orphans() {
+        let temp = tempfile::tempdir().unwrap();
+        let input = temp.path();
+        touch(&input.join("a.png"));
+        touch(&input.join("sub/b.png"));
+
+        assert!(source(input, "gone.jxl").is_none());
+        assert!(source(input, "a.txt").is_none());
+        assert!(source(input, "sub/a.jxl").is_none());
+        assert!(source(input, "other/b.jxl").is_none());
+        // `sub` is a directory of the input, not an extracted archive.
+        assert!(source(input, "sub/c.jxl").is_none());
+    }
+}
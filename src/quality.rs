@@ -0,0 +1,120 @@
+use std::path::Path;
+
+use crate::{encoder::InputSelection, process::CommandRunner};
+
+/// Minimum similarity a lossy conversion must keep to its source.
+#[derive(Clone, Copy, Debug)]
+pub enum QualityGate {
+    Ssim(f64),
+    Psnr(f64),
+}
+
+impl QualityGate {
+    /// Parses `ssim=0.98` or `psnr=45`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (metric, threshold) = value
+            .split_once('=')
+            .ok_or_else(|| "expected METRIC=THRESHOLD, e.g. ssim=0.98".to_string())?;
+        let threshold = threshold
+            .trim()
+            .parse::<f64>()
+            .map_err(|e| format!("invalid threshold: {}", e))?;
+        match metric.trim().to_lowercase().as_str() {
+            "ssim" => Ok(QualityGate::Ssim(threshold)),
+            "psnr" => Ok(QualityGate::Psnr(threshold)),
+            other => Err(format!("unknown metric {}, expected ssim or psnr", other)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            QualityGate::Ssim(_) => "ssim",
+            QualityGate::Psnr(_) => "psnr",
+        }
+    }
+
+    pub fn threshold(self) -> f64 {
+        match self {
+            QualityGate::Ssim(threshold) | QualityGate::Psnr(threshold) => threshold,
+        }
+    }
+
+    /// Decodes both files with ffmpeg and compares them with its ssim or psnr
+    /// filter, reading `selection` of the source as `source_format` the same
+    /// way the conversion did.
+    pub async fn measure(
+        self,
+        runner: &dyn CommandRunner,
+        source: &Path,
+        source_format: Option<&str>,
+        selection: InputSelection,
+        converted: &Path,
+    ) -> anyhow::Result<f64> {
+        let mut command = crate::process::command("ffmpeg");
+        command.arg("-hide_banner");
+        if let InputSelection::TiffPage(page) = selection {
+            command.arg("-page").arg(page.to_string());
+        }
+        if let Some(format) = crate::process::input_format(source, source_format).await {
+            command.arg("-f").arg(format);
+        }
+        command.arg("-i").arg(crate::process::file_arg(source));
+        if let Some(format) = crate::process::input_format(converted, None).await {
+            command.arg("-f").arg(format);
+        }
+        let stream = match selection {
+            InputSelection::Stream(index) => format!("0:{}", index),
+            InputSelection::Whole | InputSelection::TiffPage(_) => "0:v".to_string(),
+        };
+        command
+            .arg("-i")
+            .arg(crate::process::file_arg(converted))
+            .arg("-lavfi")
+            .arg(format!("[{}][1:v]{}", stream, self.name()))
+            .arg("-f")
+            .arg("null")
+            .arg("-");
+        let output = runner.run(&mut command, false).await?;
+
+        if !output.status.success() {
+            return Err(anyhow::anyhow!("Failed to compare images"));
+        }
+
+        // The filters log their totals as `All:0.987 (19.1)` and `average:45.2`.
+        let key = match self {
+            QualityGate::Ssim(_) => "All:",
+            QualityGate::Psnr(_) => "average:",
+        };
+        output
+            .stderr_tail
+            .iter()
+            .rev()
+            .find_map(|line| {
+                let value = &line[line.find(key)? + key.len()..];
+                value.split_whitespace().next()?.parse::<f64>().ok()
+            })
+            .ok_or_else(|| anyhow::anyhow!("No {} value in ffmpeg output", self.name()))
+    }
+}
+
+/// The error of a conversion that fell below the quality gate. Its output was
+/// removed without ever taking the place of the final one.
+#[derive(Debug)]
+pub struct BelowThreshold {
+    pub gate: QualityGate,
+    pub score: f64,
+}
+
+impl std::fmt::Display for BelowThreshold {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {:.4} below threshold {}",
+            self.gate.name(),
+            self.score,
+            self.gate.threshold()
+        )
+    }
+}
+
+impl std::error::Error for BelowThreshold {}
@@ -0,0 +1,182 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How much is copied between two looks at the limit.
+const CHUNK: usize = 256 << 10;
+
+/// How long the bytes are counted over for the throughput shown.
+const WINDOW: Duration = Duration::from_secs(2);
+
+/// A token bucket for `--rate-limit`, shared by all files of a run. Holds at
+/// most a second of the rate, so an idle moment doesn't allow a burst of
+/// more than that afterwards.
+pub struct RateLimit {
+    bytes_per_second: f64,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Bytes that may go right away, negative while the reservations made
+    /// are ahead of the rate.
+    available: f64,
+    updated: Instant,
+    /// Bytes that went through since `window_start`.
+    window_bytes: u64,
+    window_start: Instant,
+    /// The throughput over the last full window, `None` before the first.
+    throughput: Option<f64>,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_second: u64) -> Self {
+        let now = Instant::now();
+        RateLimit {
+            bytes_per_second: bytes_per_second as f64,
+            state: Mutex::new(State {
+                // Empty, so the start of a run isn't a burst either.
+                available: 0.0,
+                updated: now,
+                window_bytes: 0,
+                window_start: now,
+                throughput: None,
+            }),
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second as u64
+    }
+
+    /// Reserves `bytes` and returns how long to wait before moving them.
+    fn reserve(&self, bytes: u64) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(state.updated).as_secs_f64() * self.bytes_per_second;
+        state.available = (state.available + refill).min(self.bytes_per_second);
+        state.updated = now;
+        state.available -= bytes as f64;
+        if state.available >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.available / self.bytes_per_second)
+        }
+    }
+
+    /// Counts `bytes` that went through, once their wait is over.
+    fn record(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let window = now.duration_since(state.window_start);
+        if window >= WINDOW {
+            state.throughput = Some(state.window_bytes as f64 / window.as_secs_f64());
+            state.window_bytes = 0;
+            state.window_start = now;
+        }
+        state.window_bytes += bytes;
+    }
+
+    /// Waits until `bytes` fit in the rate.
+    pub async fn take(&self, bytes: u64) {
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        self.record(bytes);
+    }
+
+    /// Like `take`, for the blocking threads that copy.
+    pub fn take_blocking(&self, bytes: u64) {
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+        self.record(bytes);
+    }
+
+    /// Bytes per second that went through lately, over the current window
+    /// until the first one is full.
+    pub fn throughput(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        let throughput = state.throughput.unwrap_or_else(|| {
+            let elapsed = state.window_start.elapsed().as_secs_f64();
+            state.window_bytes as f64 / elapsed.max(1.0)
+        });
+        throughput as u64
+    }
+}
+
+/// Copies `from` to `to` like `std::fs::copy`, a chunk at a time within
+/// `limit`. Sparse files go through `sparse::copy` instead. Returns the size
+/// copied.
+pub fn copy(from: &Path, to: &Path, limit: &RateLimit) -> std::io::Result<u64> {
+    let mut source = File::open(from)?;
+    let mut target = File::create(to)?;
+    let mut buffer = vec![0u8; CHUNK];
+    let mut copied = 0;
+    loop {
+        let read = match source.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        limit.take_blocking(read as u64);
+        target.write_all(&buffer[..read])?;
+        copied += read as u64;
+    }
+    target.set_permissions(source.metadata()?.permissions())?;
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_run_starts_with_an_empty_bucket() {
+        let limit = RateLimit::new(1000);
+        let wait = limit.reserve(500);
+        assert!(wait > Duration::from_millis(490) && wait <= Duration::from_millis(500));
+        // The next reservation waits for the first one as well.
+        assert!(limit.reserve(500) > Duration::from_millis(990));
+    }
+
+    #[test]
+    fn an_idle_limit_holds_at_most_a_second_of_the_rate() {
+        let limit = RateLimit::new(1000);
+        limit.state.lock().unwrap().updated -= Duration::from_secs(10);
+        assert_eq!(limit.reserve(1000), Duration::ZERO);
+        assert!(limit.reserve(1000) > Duration::from_millis(990));
+    }
+
+    #[test]
+    fn throughput_counts_a_partial_window_as_a_second() {
+        let limit = RateLimit::new(1000);
+        assert_eq!(limit.throughput(), 0);
+        limit.record(700);
+        limit.record(300);
+        assert_eq!(limit.throughput(), 1000);
+    }
+
+    #[test]
+    fn copies_are_whole_and_keep_the_permissions() {
+        let temp = tempfile::tempdir().unwrap();
+        let (from, to) = (temp.path().join("from"), temp.path().join("to"));
+        // More than a chunk, and not a whole number of them.
+        let data = (0..CHUNK * 2 + 100).map(|i| i as u8).collect::<Vec<_>>();
+        std::fs::write(&from, &data).unwrap();
+        let mut permissions = std::fs::metadata(&from).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&from, permissions).unwrap();
+
+        let limit = RateLimit::new(100 << 20);
+        assert_eq!(copy(&from, &to, &limit).unwrap(), data.len() as u64);
+        assert_eq!(std::fs::read(&to).unwrap(), data);
+        assert!(std::fs::metadata(&to).unwrap().permissions().readonly());
+    }
+}
@@ -0,0 +1,53 @@
+use std::path::Path;
+
+/// Makes `to` a copy-on-write clone of `from`, sharing its data blocks until
+/// either is changed. Fails where the filesystem can't, e.g. across
+/// filesystems or on ext4, in which case `to` is left as it was or removed.
+#[cfg(target_os = "linux")]
+pub fn clone(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let source = std::fs::File::open(from)?;
+    let target = std::fs::File::create(to)?;
+    // SAFETY: FICLONE only reads the two descriptors, both ours.
+    let result = unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) };
+    let result = if result < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        target.set_permissions(source.metadata()?.permissions())
+    };
+    if result.is_err() {
+        drop(target);
+        let _ = std::fs::remove_file(to);
+    }
+    result
+}
+
+#[cfg(target_os = "macos")]
+pub fn clone(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+    let path = |path: &Path| {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+    };
+    let (source, target) = (path(from)?, path(to)?);
+    // clonefile won't replace an existing file.
+    match std::fs::remove_file(to) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    // SAFETY: both are valid NUL-terminated paths.
+    if unsafe { libc::clonefile(source.as_ptr(), target.as_ptr(), 0) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Windows clones on ReFS and Dev Drives by itself in `std::fs::copy` where it
+// can, so only a plain copy is on offer there.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn clone(_from: &Path, _to: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "reflinks are not supported on this platform",
+    ))
+}
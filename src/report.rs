@@ -0,0 +1,468 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{dimensions::Dimensions, events::DirectorySummary};
+
+/// Per-file report written as tab separated values, one line per processed
+/// file. The columns are named in the first line: `status`, `source`,
+/// `original_size` and `converted_size` (of conversions, 0 otherwise),
+/// `details`, `source_size` (as walked, empty where the walk couldn't read
+/// it) and `settings` (the encoder settings of the run), followed by the
+/// optional `timings` and `dimensions`, then `relative_source` (the source
+/// relative to the input directory) and `source_sha256` (of JPEGs
+/// transcoded losslessly, for `restore-jpeg`). Readers go by the names, see
+/// [`read`], so columns may be added but are never renamed or moved.
+///
+/// After the files, separated by an empty line, comes the table of the
+/// totals per directory from the summary, with its own header: `directory`,
+/// `files`, `converted`, `failed`, `original_size` and `converted_size`, see
+/// [`read_directories`].
+pub struct Report {
+    out: BufWriter<File>,
+    /// The input directory of the run, which `relative_source` is relative to.
+    input: PathBuf,
+    /// The encoder settings of the run, in every record.
+    settings: String,
+    /// Whether there is a `timings` column, for --profile.
+    timings: bool,
+    /// The stage timings of the file recorded next.
+    next_timings: Option<String>,
+    /// The size of the source recorded next, as the walk found it.
+    next_source_size: Option<u64>,
+    /// Whether the source recorded next is hashed, see [`Report::restorable`].
+    next_restorable: bool,
+    /// The dimensions of the sources, when they were read; written in a
+    /// `dimensions` column.
+    dimensions: Option<HashMap<PathBuf, Dimensions>>,
+}
+
+impl Report {
+    pub fn create(
+        path: &Path,
+        input: &Path,
+        timings: bool,
+        dimensions: Option<HashMap<PathBuf, Dimensions>>,
+        settings: String,
+    ) -> anyhow::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        write!(
+            out,
+            "status\tsource\toriginal_size\tconverted_size\tdetails\tsource_size\tsettings"
+        )?;
+        if timings {
+            write!(out, "\ttimings")?;
+        }
+        if dimensions.is_some() {
+            write!(out, "\tdimensions")?;
+        }
+        writeln!(out, "\trelative_source\tsource_sha256")?;
+        Ok(Self {
+            out,
+            input: input.to_owned(),
+            settings: escape(&settings),
+            timings,
+            next_timings: None,
+            next_source_size: None,
+            next_restorable: false,
+            dimensions,
+        })
+    }
+
+    /// Sets the stage timings written with the next record. Files left out
+    /// before they were worked on get none.
+    pub fn timings(&mut self, timings: &crate::profile::Timings) {
+        self.next_timings = timings.note();
+    }
+
+    /// Sets the size of the source recorded next, as the walk found it.
+    /// Files the walk couldn't read get none.
+    pub fn source_size(&mut self, size: Option<u64>) {
+        self.next_source_size = size;
+    }
+
+    /// Has the next record hash its source, a JPEG that `restore-jpeg` can
+    /// rebuild from its conversion. Only those are hashed, as it reads the
+    /// whole source again.
+    pub fn restorable(&mut self) {
+        self.next_restorable = true;
+    }
+
+    /// Writes the line of `source`. Hashes the source when it is
+    /// [`Report::restorable`], so runs call it off the runtime.
+    pub fn record(
+        &mut self,
+        status: &str,
+        source: &Path,
+        original_size: u64,
+        converted_size: u64,
+        details: &[String],
+    ) -> anyhow::Result<()> {
+        write!(
+            self.out,
+            "{}\t{}\t{}\t{}\t{}",
+            status,
+            escape_path(source),
+            original_size,
+            converted_size,
+            escape(&details.join("; "))
+        )?;
+        match self.next_source_size.take() {
+            Some(size) => write!(self.out, "\t{}", size)?,
+            None => write!(self.out, "\t")?,
+        }
+        write!(self.out, "\t{}", self.settings)?;
+        if self.timings {
+            write!(
+                self.out,
+                "\t{}",
+                escape(&self.next_timings.take().unwrap_or_default())
+            )?;
+        }
+        if let Some(dimensions) = &self.dimensions {
+            // Empty for files that weren't probed or couldn't be.
+            match dimensions.get(source) {
+                Some(size) => write!(self.out, "\t{}", size)?,
+                None => write!(self.out, "\t")?,
+            }
+        }
+        match source.strip_prefix(&self.input) {
+            Ok(relative) => write!(self.out, "\t{}", escape_path(relative))?,
+            Err(_) => write!(self.out, "\t")?,
+        }
+        let restorable = std::mem::take(&mut self.next_restorable) && status == "converted";
+        match restorable.then(|| crate::content::hash_file(source)) {
+            Some(Ok(hash)) => writeln!(self.out, "\t{}", hash)?,
+            _ => writeln!(self.out, "\t")?,
+        }
+        Ok(())
+    }
+
+    /// Writes the totals per directory after the files, when there are any,
+    /// and flushes the report.
+    pub fn finish(mut self, directories: &[DirectorySummary]) -> anyhow::Result<()> {
+        if !directories.is_empty() {
+            writeln!(self.out)?;
+            writeln!(
+                self.out,
+                "directory\tfiles\tconverted\tfailed\toriginal_size\tconverted_size"
+            )?;
+        }
+        for totals in directories {
+            writeln!(
+                self.out,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                escape_path(&totals.directory),
+                totals.files,
+                totals.converted,
+                totals.failed,
+                totals.original_size,
+                totals.converted_size
+            )?;
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// A line of a report, as read back by [`read`].
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub status: String,
+    pub source: PathBuf,
+    pub original_size: u64,
+    pub converted_size: u64,
+    pub details: String,
+    /// Not in reports from before the column was added.
+    pub source_size: Option<u64>,
+    pub settings: String,
+    /// Not in reports from before the column was added.
+    pub relative_source: Option<PathBuf>,
+    /// The SHA-256 of a converted JPEG source, in hex.
+    pub source_sha256: Option<String>,
+}
+
+/// Reads the records of a report written by this or an earlier version,
+/// finding the columns by their names. Lines without a source are left out.
+pub fn read(path: &Path) -> anyhow::Result<Vec<Record>> {
+    let text = std::fs::read_to_string(path)?;
+    let mut lines = text.lines();
+    let header = lines
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("{} is empty", path.display()))?
+        .split('\t')
+        .collect::<Vec<_>>();
+    let column = |name: &str| header.iter().position(|column| *column == name);
+    let (Some(status), Some(source)) = (column("status"), column("source")) else {
+        return Err(anyhow::anyhow!(
+            "{} is not a bulk-jxl report",
+            path.display()
+        ));
+    };
+    let [
+        original_size,
+        converted_size,
+        details,
+        source_size,
+        settings,
+        relative_source,
+        source_sha256,
+    ] = [
+        "original_size",
+        "converted_size",
+        "details",
+        "source_size",
+        "settings",
+        "relative_source",
+        "source_sha256",
+    ]
+    .map(column);
+    let mut records = Vec::new();
+    // The totals per directory follow an empty line.
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let fields = line.split('\t').collect::<Vec<_>>();
+        let field = |index: Option<usize>| index.and_then(|index| fields.get(index)).copied();
+        let Some(path) = field(Some(source)) else {
+            continue;
+        };
+        let size = |index| field(index).and_then(|size| size.parse().ok());
+        records.push(Record {
+            status: field(Some(status)).unwrap_or_default().to_string(),
+            source: unescape_path(path),
+            original_size: size(original_size).unwrap_or(0),
+            converted_size: size(converted_size).unwrap_or(0),
+            details: field(details).map(unescape).unwrap_or_default(),
+            source_size: size(source_size),
+            settings: field(settings).map(unescape).unwrap_or_default(),
+            relative_source: field(relative_source)
+                .filter(|path| !path.is_empty())
+                .map(unescape_path),
+            source_sha256: field(source_sha256)
+                .filter(|hash| !hash.is_empty())
+                .map(str::to_string),
+        });
+    }
+    Ok(records)
+}
+
+/// Reads the totals per directory back from a report, in the order they
+/// were written. Reports written without them have none.
+pub fn read_directories(path: &Path) -> anyhow::Result<Vec<DirectorySummary>> {
+    let text = std::fs::read_to_string(path)?;
+    let mut lines = text.lines().skip_while(|line| !line.is_empty()).skip(1);
+    let Some(header) = lines.next() else {
+        return Ok(Vec::new());
+    };
+    let header = header.split('\t').collect::<Vec<_>>();
+    let column = |name: &str| header.iter().position(|column| *column == name);
+    let [
+        directory,
+        files,
+        converted,
+        failed,
+        original_size,
+        converted_size,
+    ] = [
+        "directory",
+        "files",
+        "converted",
+        "failed",
+        "original_size",
+        "converted_size",
+    ]
+    .map(column);
+    let mut directories = Vec::new();
+    for line in lines {
+        let fields = line.split('\t').collect::<Vec<_>>();
+        let field = |index: Option<usize>| index.and_then(|index| fields.get(index)).copied();
+        let Some(path) = field(directory) else {
+            continue;
+        };
+        let number = |index| field(index).and_then(|number| number.parse().ok());
+        directories.push(DirectorySummary {
+            directory: unescape_path(path),
+            files: number(files).unwrap_or(0) as usize,
+            converted: number(converted).unwrap_or(0) as usize,
+            failed: number(failed).unwrap_or(0) as usize,
+            original_size: number(original_size).unwrap_or(0),
+            converted_size: number(converted_size).unwrap_or(0),
+        });
+    }
+    Ok(directories)
+}
+
+/// Reads the records of the converted files from a report written by an
+/// earlier run.
+pub fn converted_sources(path: &Path) -> anyhow::Result<Vec<Record>> {
+    Ok(read(path)?
+        .into_iter()
+        .filter(|record| record.status == "converted")
+        .collect())
+}
+
+pub fn unescape_path(field: &str) -> PathBuf {
+    let mut out = Vec::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        let c = if c != '\\' {
+            c
+        } else {
+            match chars.next() {
+                Some('t') => '\t',
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('x') => {
+                    let hex = chars.by_ref().take(2).collect::<String>();
+                    if let Ok(byte) = u8::from_str_radix(&hex, 16) {
+                        out.push(byte);
+                        continue;
+                    }
+                    out.extend_from_slice(b"\\x");
+                    out.extend_from_slice(hex.as_bytes());
+                    continue;
+                }
+                Some(other) => other,
+                None => '\\',
+            }
+        };
+        out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+    }
+    path_from_bytes(out)
+}
+
+#[cfg(unix)]
+pub(crate) fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// Writes the path so it reads back unchanged: bytes that aren't valid UTF-8,
+// as found in names from old Linux archives, become `\xNN`.
+pub fn escape_path(path: &Path) -> String {
+    let mut out = String::new();
+    for chunk in path.as_os_str().as_encoded_bytes().utf8_chunks() {
+        out.push_str(&escape(chunk.valid()));
+        for byte in chunk.invalid() {
+            out.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    out
+}
+
+// Keeps every record on a single line with a fixed number of columns.
+fn escape(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn unescape(field: &str) -> String {
+    unescape_path(field).to_string_lossy().into_owned()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::ffi::OsStrExt;
+
+    use super::*;
+
+    #[test]
+    fn paths_read_back_as_they_were_written() {
+        let names: [&[u8]; 8] = [
+            b"photos/a.png",
+            b"tab\tnew\nline\rreturn.png",
+            b"back\\slash\\.png",
+            // Looks like an escape already.
+            b"\\x41 and \\t.png",
+            b"trailing space .png ",
+            b"Stra\xdfe-latin1.png",
+            b"\xff\xfe\x80.png",
+            "ünïcödé/写真.png".as_bytes(),
+        ];
+        for name in names {
+            let path = Path::new(std::ffi::OsStr::from_bytes(name));
+            let field = escape_path(path);
+            assert!(!field.contains(['\t', '\n', '\r']), "{:?}", field);
+            assert_eq!(unescape_path(&field), path, "{:?}", field);
+        }
+    }
+
+    #[test]
+    fn restorable_jpegs_are_recorded_with_their_path_size_and_hash() {
+        let temp = tempfile::tempdir().unwrap();
+        let input = temp.path().join("in");
+        std::fs::create_dir_all(input.join("2019")).unwrap();
+        let (jpeg, png) = (input.join("2019/photo.jpg"), input.join("photo.png"));
+        std::fs::write(&jpeg, b"\xff\xd8\xff\xe0 a JPEG").unwrap();
+        std::fs::write(&png, b"\x89PNG\r\n\x1a\n a PNG").unwrap();
+        let path = temp.path().join("report.tsv");
+        let mut report = Report::create(&path, &input, false, None, String::new()).unwrap();
+        report.source_size(Some(15));
+        report.restorable();
+        report.record("converted", &jpeg, 15, 10, &[]).unwrap();
+        report.record("converted", &png, 15, 10, &[]).unwrap();
+        report.restorable();
+        report.record("skipped", &jpeg, 0, 0, &[]).unwrap();
+        report.finish(&[]).unwrap();
+
+        let records = read(&path).unwrap();
+
+        let relative = |record: &Record| record.relative_source.clone().unwrap();
+        assert_eq!(relative(&records[0]), Path::new("2019/photo.jpg"));
+        assert_eq!(relative(&records[1]), Path::new("photo.png"));
+        assert_eq!(
+            records[0].source_sha256,
+            Some(crate::content::hash_file(&jpeg).unwrap())
+        );
+        assert_eq!(records[1].source_sha256, None);
+        assert_eq!(records[2].source_sha256, None);
+        // Only the walk knows the size, the source isn't read again.
+        let sizes: Vec<_> = records.iter().map(|record| record.source_size).collect();
+        assert_eq!(sizes, [Some(15), None, None]);
+    }
+
+    #[test]
+    fn directory_totals_follow_the_files() {
+        let temp = tempfile::tempdir().unwrap();
+        let input = temp.path().join("in");
+        let path = temp.path().join("report.tsv");
+        let mut report = Report::create(&path, &input, false, None, String::new()).unwrap();
+        report
+            .record("converted", &input.join("2019/a.png"), 30, 10, &[])
+            .unwrap();
+        report
+            .record("error", &input.join("b.png"), 0, 0, &[])
+            .unwrap();
+        let totals =
+            |directory: &str, converted, failed, original_size, converted_size| DirectorySummary {
+                directory: PathBuf::from(directory),
+                files: 1,
+                converted,
+                failed,
+                original_size,
+                converted_size,
+            };
+        let directories = [totals(".", 0, 1, 0, 0), totals("2019", 1, 0, 30, 10)];
+        report.finish(&directories).unwrap();
+
+        // The files read back as before.
+        assert_eq!(read(&path).unwrap().len(), 2);
+        assert_eq!(read_directories(&path).unwrap(), directories);
+    }
+
+    #[test]
+    fn invalid_bytes_are_written_as_hex() {
+        let path = Path::new(std::ffi::OsStr::from_bytes(b"Stra\xdfe.png"));
+        assert_eq!(escape_path(path), "Stra\\xdfe.png");
+    }
+}
@@ -0,0 +1,304 @@
+use std::path::{Path, PathBuf};
+
+use filetime::FileTime;
+
+use crate::{
+    container,
+    events::Messages,
+    process::{CommandRunner, Spawn},
+    report,
+};
+
+/// Regenerates the original JPEG files from JPEG XL files that were
+/// transcoded losslessly, e.g. by `--policy` with `mode = "reconstruct"`.
+#[derive(clap::Args)]
+pub struct RestoreArgs {
+    #[command(flatten)]
+    tree: crate::Tree,
+
+    /// Report written by the conversion run, used to check the restored files
+    /// against the hashes of their sources and to give them their original
+    /// extension
+    #[clap(long)]
+    manifest: Option<String>,
+}
+
+pub async fn run(args: RestoreArgs, messages: &Messages) -> anyhow::Result<()> {
+    let input_path = PathBuf::from(&args.tree.input);
+    if !input_path.is_dir() {
+        return Err(anyhow::anyhow!("Input path is not a directory"));
+    }
+    let output_path = PathBuf::from(&args.tree.output);
+    std::fs::create_dir_all(&output_path)?;
+
+    let manifest = match &args.manifest {
+        Some(path) => report::converted_sources(Path::new(path))?,
+        None => Vec::new(),
+    };
+
+    let mut walkdir = walkdir::WalkDir::new(&input_path);
+    if !args.tree.recursive {
+        walkdir = walkdir.max_depth(1);
+    }
+    let files = walkdir
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter(|e| {
+            e.path()
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("jxl"))
+                && crate::partial_pid(&e.file_name().to_string_lossy()).is_none()
+        })
+        .map(|e| e.path().to_owned())
+        .collect::<Vec<_>>();
+
+    let mut restored = 0;
+    let mut skipped = 0;
+    let mut errors = 0;
+    let mut without_data = Vec::new();
+
+    for file in &files {
+        let relative_path = file.strip_prefix(&input_path)?;
+        match container::has_jpeg_reconstruction(file) {
+            Ok(true) => {}
+            Ok(false) => {
+                say!(
+                    messages,
+                    "   No JPEG reconstruction data: {}",
+                    file.display()
+                );
+                without_data.push(file.clone());
+                continue;
+            }
+            Err(e) => {
+                say_error!(messages, "Error reading {}: {}", file.display(), e);
+                errors += 1;
+                continue;
+            }
+        }
+
+        let original = original_of(&manifest, relative_path);
+        let extension = original
+            .and_then(|record| record.source.extension())
+            .unwrap_or(std::ffi::OsStr::new("jpg"));
+        let output_file_path = output_path.join(relative_path).with_extension(extension);
+
+        if output_file_path.exists() {
+            say!(
+                messages,
+                "   Skipping existing JPEG: {}",
+                output_file_path.display()
+            );
+            skipped += 1;
+            continue;
+        }
+
+        match restore(file, &output_file_path, original, messages).await {
+            Ok(size) => {
+                say!(
+                    messages,
+                    "   Restored {} -> {} ({} bytes)",
+                    file.display(),
+                    output_file_path.display(),
+                    size
+                );
+                restored += 1;
+            }
+            Err(e) => {
+                say_error!(messages, "Error restoring {}: {}", file.display(), e);
+                errors += 1;
+            }
+        }
+    }
+
+    say!(messages, "{}", "-".repeat(60));
+    say!(messages, "Restore Summary:");
+    say!(messages, "  JPEG XL files found:   {}", files.len());
+    say!(messages, "  JPEGs restored:        {}", restored);
+    say!(messages, "  Skipped (existing):    {}", skipped);
+    say!(
+        messages,
+        "  Without reconstruction data: {}",
+        without_data.len()
+    );
+    say!(messages, "  Files with errors:     {}", errors);
+    for file in &without_data {
+        say!(messages, "    {}", file.display());
+    }
+    say!(messages, "{}", "-".repeat(60));
+
+    Ok(())
+}
+
+/// The record of the source `relative_path` was converted from. The manifest
+/// knows it by its path in the input tree of the conversion, which this tree
+/// mirrors apart from the extension. Of a `photo.jpg` and a `photo.png`, the
+/// JPEG is the one with a hash.
+fn original_of<'a>(
+    manifest: &'a [report::Record],
+    relative_path: &Path,
+) -> Option<&'a report::Record> {
+    let stem = relative_path.with_extension("");
+    manifest
+        .iter()
+        .filter(|record| {
+            record
+                .relative_source
+                .as_ref()
+                .is_some_and(|source| source.with_extension("") == stem)
+        })
+        .max_by_key(|record| record.source_sha256.is_some())
+}
+
+/// Has djxl rebuild one JPEG and checks it against its record in the
+/// manifest, if any. Returns the size of the restored file.
+async fn restore(
+    file: &Path,
+    output_file_path: &Path,
+    original: Option<&report::Record>,
+    messages: &Messages,
+) -> anyhow::Result<u64> {
+    if let Some(parent) = output_file_path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let partial_path = output_file_path.with_extension("restore.jpg");
+    let checked = match reconstruct(&Spawn::new(messages), file, &partial_path).await {
+        // Reads the whole JPEG, off the runtime.
+        Ok(()) => {
+            let (path, original) = (partial_path.clone(), original.cloned());
+            tokio::task::spawn_blocking(move || check_jpeg(&path, original.as_ref())).await?
+        }
+        Err(e) => Err(e),
+    };
+    let size = match checked {
+        Ok(size) => size,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&partial_path).await;
+            return Err(e);
+        }
+    };
+
+    tokio::fs::rename(&partial_path, output_file_path).await?;
+    let modified = FileTime::from_last_modification_time(&tokio::fs::metadata(file).await?);
+    let output = output_file_path.to_owned();
+    tokio::task::spawn_blocking(move || filetime::set_file_mtime(&output, modified)).await??;
+    Ok(size)
+}
+
+/// Has djxl rebuild the JPEG that `file` was transcoded from as `jpeg`.
+/// djxl picks the output format by extension, a `.jpg` name makes it use the
+/// reconstruction data instead of encoding the pixels again.
+pub(crate) async fn reconstruct(
+    runner: &dyn CommandRunner,
+    file: &Path,
+    jpeg: &Path,
+) -> anyhow::Result<()> {
+    let finished = runner
+        .run(
+            crate::process::command("djxl")
+                .arg(crate::process::tool_arg(file))
+                .arg(crate::process::tool_arg(jpeg)),
+            false,
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to run djxl: {}", e))?;
+    if !finished.status.success() {
+        let _ = std::fs::remove_file(jpeg);
+        return Err(anyhow::anyhow!("{}", finished.message("djxl failed")));
+    }
+    Ok(())
+}
+
+fn check_jpeg(path: &Path, original: Option<&report::Record>) -> anyhow::Result<u64> {
+    let size = std::fs::metadata(path)?.len();
+    if crate::sniff::detect(path)? != Some(crate::sniff::ImageKind::Jpeg) {
+        return Err(anyhow::anyhow!("Restored file is not a JPEG"));
+    }
+    let Some(original) = original else {
+        return Ok(size);
+    };
+    if let Some(expected) = &original.source_sha256 {
+        let hash = crate::content::hash_file(path)?;
+        if &hash != expected {
+            return Err(anyhow::anyhow!(
+                "Restored file has SHA-256 {} but the manifest records {}",
+                hash,
+                expected
+            ));
+        }
+    } else if original.original_size != size {
+        // Reports from before the hashes were recorded.
+        return Err(anyhow::anyhow!(
+            "Restored {} bytes but the manifest records {}",
+            size,
+            original.original_size
+        ));
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(source: &str, sha256: Option<&str>) -> report::Record {
+        report::Record {
+            status: "converted".to_string(),
+            source: Path::new("/photos").join(source),
+            original_size: 4,
+            converted_size: 2,
+            details: String::new(),
+            source_size: Some(4),
+            settings: String::new(),
+            relative_source: Some(PathBuf::from(source)),
+            source_sha256: sha256.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn the_manifest_is_matched_on_the_whole_path() {
+        let manifest = [
+            record("2019/trip/photo.jpg", Some("ab")),
+            record("trip/photo.jpg", Some("cd")),
+            record("scan.png", None),
+            record("scan.jpeg", Some("ef")),
+        ];
+        let source = |relative: &str| {
+            original_of(&manifest, Path::new(relative)).map(|record| record.source.clone())
+        };
+
+        assert_eq!(
+            source("trip/photo.jxl"),
+            Some(PathBuf::from("/photos/trip/photo.jpg"))
+        );
+        assert_eq!(
+            source("2019/trip/photo.jxl"),
+            Some(PathBuf::from("/photos/2019/trip/photo.jpg"))
+        );
+        assert_eq!(source("photo.jxl"), None);
+        assert_eq!(source("scan.jxl"), Some(PathBuf::from("/photos/scan.jpeg")));
+    }
+
+    #[test]
+    fn restored_files_are_checked_against_the_recorded_hash() {
+        let temp = tempfile::tempdir().unwrap();
+        let restored = temp.path().join("photo.jpg");
+        std::fs::write(&restored, b"\xff\xd8\xff\xe0").unwrap();
+        let hash = crate::content::hash_file(&restored).unwrap();
+
+        assert_eq!(
+            check_jpeg(&restored, Some(&record("photo.jpg", Some(&hash)))).unwrap(),
+            4
+        );
+        // Of the same size, but other bytes.
+        let other = record("photo.jpg", Some(&"0".repeat(64)));
+        let error = check_jpeg(&restored, Some(&other)).unwrap_err();
+        assert!(error.to_string().contains("SHA-256"), "{error}");
+        assert_eq!(
+            check_jpeg(&restored, Some(&record("photo.jpg", None))).unwrap(),
+            4
+        );
+    }
+}
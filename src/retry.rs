@@ -0,0 +1,154 @@
+use std::{
+    cell::Cell,
+    hash::{BuildHasher, Hasher},
+    io,
+    time::Duration,
+};
+
+use crate::events::Messages;
+
+tokio::task_local! {
+    /// Retries needed so far for the file the current task works on.
+    pub static RETRIES: Cell<u32>;
+}
+
+// Doubled before every further retry.
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Whether an I/O error is of the kind network filesystems (SMB, NFS) report
+/// for a moment before the same operation succeeds, or another process
+/// holding the file briefly causes. Timeouts aren't: `--tool-timeout` ends
+/// with one after waiting as long as it was told to, and a retry would only
+/// wait that long again.
+pub fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock
+            | io::ErrorKind::ResourceBusy
+            | io::ErrorKind::ExecutableFileBusy
+            | io::ErrorKind::StaleNetworkFileHandle
+            | io::ErrorKind::Interrupted
+    ) || is_locked(error)
+}
+
+// Virus scanners and the search indexer open freshly written files without
+// sharing them, which fails renames and timestamp updates with a sharing or
+// lock violation, or with access denied while the scan holds the file.
+#[cfg(windows)]
+fn is_locked(error: &io::Error) -> bool {
+    const ERROR_ACCESS_DENIED: i32 = 5;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+    matches!(
+        error.raw_os_error(),
+        Some(ERROR_ACCESS_DENIED | ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION)
+    )
+}
+
+// Unix doesn't lock files against renames; busy files are `ResourceBusy`.
+#[cfg(not(windows))]
+fn is_locked(_error: &io::Error) -> bool {
+    false
+}
+
+/// `backoff` plus a random part of up to half of it, so parallel jobs that
+/// failed together don't all retry at the same moment.
+fn jittered(backoff: Duration) -> Duration {
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    backoff + backoff.mul_f64((random % 1000) as f64 / 2000.0)
+}
+
+/// Runs the I/O step `what` until it succeeds, fails with a permanent error or
+/// has been retried `retries` times, telling `messages` about every retry.
+pub async fn io<T, F: Future<Output = io::Result<T>>>(
+    what: &str,
+    retries: u32,
+    messages: &Messages,
+    mut step: impl FnMut() -> F,
+) -> io::Result<T> {
+    let mut backoff = FIRST_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        match step().await {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                attempt += 1;
+                let delay = jittered(backoff);
+                messages.say(format!(
+                    "      {} failed ({}), retrying in {:?}",
+                    what, e, delay
+                ));
+                let _ = RETRIES.try_with(|count| count.set(count.get() + 1));
+                tokio::time::sleep(delay).await;
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `io` with `retries` over a step failing with `errors` in turn,
+    /// then succeeding. Returns the result, the attempts made and the
+    /// retries counted for the file.
+    async fn attempt(retries: u32, errors: &[io::ErrorKind]) -> (io::Result<()>, usize, u32) {
+        let attempts = Cell::new(0);
+        RETRIES
+            .scope(Cell::new(0), async {
+                let result = io("Renaming", retries, &Messages::discard(), || {
+                    let error = errors.get(attempts.get()).copied();
+                    attempts.set(attempts.get() + 1);
+                    async move { error.map_or(Ok(()), |kind| Err(kind.into())) }
+                })
+                .await;
+                (result, attempts.get(), RETRIES.with(Cell::get))
+            })
+            .await
+    }
+
+    #[test]
+    fn timeouts_and_missing_files_are_not_transient() {
+        assert!(is_transient(&io::ErrorKind::ResourceBusy.into()));
+        assert!(is_transient(&io::ErrorKind::StaleNetworkFileHandle.into()));
+        assert!(!is_transient(&io::ErrorKind::TimedOut.into()));
+        assert!(!is_transient(&io::ErrorKind::NotFound.into()));
+        assert!(!is_transient(&io::ErrorKind::PermissionDenied.into()));
+    }
+
+    #[test]
+    fn jitter_adds_up_to_half_the_backoff() {
+        for _ in 0..100 {
+            let delay = jittered(FIRST_BACKOFF);
+            assert!(delay >= FIRST_BACKOFF && delay <= FIRST_BACKOFF * 3 / 2);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transient_errors_are_retried_until_the_step_succeeds() {
+        let busy = io::ErrorKind::ResourceBusy;
+        let (result, attempts, retries) = attempt(3, &[busy, busy]).await;
+        assert!(result.is_ok());
+        assert_eq!((attempts, retries), (3, 2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_last_error_is_returned_once_the_retries_are_used_up() {
+        let busy = io::ErrorKind::ResourceBusy;
+        let (result, attempts, retries) = attempt(2, &[busy, busy, busy]).await;
+        assert_eq!(result.unwrap_err().kind(), busy);
+        assert_eq!((attempts, retries), (3, 2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn permanent_errors_and_timeouts_are_returned_at_once() {
+        for kind in [io::ErrorKind::NotFound, io::ErrorKind::TimedOut] {
+            let (result, attempts, retries) = attempt(3, &[kind]).await;
+            assert_eq!(result.unwrap_err().kind(), kind);
+            assert_eq!((attempts, retries), (1, 0));
+        }
+    }
+}
@@ -0,0 +1,2068 @@
+use std::{
+    cell::Cell,
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use human_bytes::human_bytes;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::{JoinError, JoinHandle, JoinSet},
+};
+
+use crate::{
+    Action, ArchiveMode, ContentHash, Context, DirectoryConflict, Error, Event, ExistingJxl,
+    FileKind, OutputSeparator, PlannedFile, ProcessResult, RunPaths, SOURCE, Scan, SymlinkMode,
+    VerifyFailAction, adaptive, collect, content, control, convert_planned, emit_script, events,
+    failures, index_source, is_linked_output, journal, link_output, listing, lock, owner, perms,
+    prepare, print_errors, profile, remove_partial_outputs, remove_stale_partials, report,
+    report_settings, resolve_paths, retry, shutdown, source_metadata, space, stream, sync, xattrs,
+};
+
+/// Converts the tree the options of `context` describe, telling `events` how
+/// it goes.
+pub(crate) async fn run(mut context: Context, mut events: events::Events) -> anyhow::Result<()> {
+    if let Some(path) = context.args.emit_script.clone() {
+        return emit_script(context, &path).await;
+    }
+    // Before anything is printed, so all of it goes to the standard error,
+    // as the lines of `context` do already.
+    let printer = context.args.print_outputs.map(|separator| {
+        events.only_stderr();
+        listing::Printer::stdout(match separator {
+            OutputSeparator::Newline => b'\n',
+            OutputSeparator::Nul => b'\0',
+        })
+    });
+    prepare(&mut context).await?;
+    if let Some(dir) = &context.args.error_dir {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| anyhow::anyhow!("Creating {}: {}", dir.display(), e))?;
+        context.runner = Arc::new(failures::Capture(context.runner.clone()));
+    }
+    if let Some(path) = &context.args.status_file {
+        events.status_file(path);
+    }
+    let paths = resolve_paths(&context)?;
+    // Held until the run returns.
+    let _lock =
+        lock::Lock::acquire(&paths.output, context.args.wait_lock, &context.messages).await?;
+    // Before anything looks at which outputs exist.
+    recover_journal(&context, &paths).await?;
+    if !context.args.keep_partials {
+        remove_stale(&context, &paths).await?;
+    }
+
+    let Some(mut files) = Files::collect(&context, &paths, &mut events).await? else {
+        return Ok(());
+    };
+    // Only once the run goes ahead, so backing out keeps the last journal.
+    let journal = if context.args.no_journal {
+        None
+    } else {
+        Some(journal::Journal::open(&paths.output, &context.messages).await?)
+    };
+    let mut run = Run::new(context, paths, events, &mut files.scan, printer, journal)?;
+    run.execute(&mut files).await;
+    let collected = files.finish().await?;
+    run.profile.lap("Conversion", &mut run.phase_start);
+    run.finish_outputs(&collected.scan).await?;
+    run.record_left_out(&collected.scan).await?;
+    run.final_checks().await?;
+    run.end(&collected).await
+}
+
+/// Reads the journal of the output, telling which files an earlier run was
+/// still converting when it ended, and removes what they left.
+async fn recover_journal(context: &Context, paths: &RunPaths) -> anyhow::Result<()> {
+    let (args, messages) = (&context.args, &context.messages);
+    if args.no_journal {
+        return Ok(());
+    }
+    let (output, keep_partials) = (paths.output.clone(), args.keep_partials);
+    let unfinished =
+        tokio::task::spawn_blocking(move || journal::recover(&output, keep_partials)).await?;
+    if !unfinished.is_empty() {
+        say!(
+            messages,
+            "{} files were still being converted when an earlier run ended:",
+            unfinished.len()
+        );
+        for file in &unfinished {
+            say!(messages, "   {}", file.source.display());
+            for partial in &file.removed_partials {
+                say!(
+                    messages,
+                    "      Removed partial output {}",
+                    partial.display()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Removes the partial outputs earlier runs left behind.
+async fn remove_stale(context: &Context, paths: &RunPaths) -> anyhow::Result<()> {
+    let output = paths.output.clone();
+    let (removed, reclaimed) =
+        tokio::task::spawn_blocking(move || remove_stale_partials(&output)).await?;
+    if removed > 0 {
+        say!(
+            context.messages,
+            "Removed {} partial outputs of earlier runs ({})",
+            removed,
+            human_bytes(reclaimed as f64)
+        );
+    }
+    Ok(())
+}
+
+/// Where the run takes the next file to start from.
+enum PendingFiles {
+    /// Everything was collected and planned before the prompt.
+    Planned(std::vec::IntoIter<PlannedFile>),
+    /// Files come in while the walk of --stream is still running.
+    Streamed(tokio::sync::mpsc::Receiver<PlannedFile>),
+}
+
+impl PendingFiles {
+    async fn next(&mut self) -> Option<PlannedFile> {
+        match self {
+            PendingFiles::Planned(files) => files.next(),
+            PendingFiles::Streamed(receiver) => receiver.recv().await,
+        }
+    }
+}
+
+/// The files of a run, planned before it or coming from the walk of
+/// --stream while it goes on, and what the collection found out about them.
+struct Files {
+    pending: PendingFiles,
+    /// The walk of --stream.
+    walk: Option<JoinHandle<Scan>>,
+    /// Files sent by the walk of --stream so far.
+    found: Arc<AtomicUsize>,
+    /// Only known up front without --stream, otherwise once the walk is done.
+    total: Option<usize>,
+    /// The size of all sources, unknown with --stream.
+    total_input: Option<u64>,
+    /// The last scan progress sent for --stream.
+    shown: (usize, bool),
+    scan: Scan,
+}
+
+impl Files {
+    /// Collects and plans the files, or starts the walk of --stream. `None`
+    /// when the user backed out.
+    async fn collect(
+        context: &Context,
+        paths: &RunPaths,
+        events: &mut events::Events,
+    ) -> anyhow::Result<Option<Files>> {
+        let args = &context.args;
+        let found = Arc::new(AtomicUsize::new(0));
+        if args.stream {
+            say!(
+                context.messages,
+                "Converting {} to {} while the input is walked",
+                paths.input_display(args),
+                args.tree.output
+            );
+            // A few files ahead of the workers, so memory doesn't grow with the tree.
+            let (sender, receiver) = tokio::sync::mpsc::channel(args.parallel.jobs.max(1) * 2);
+            let walk = tokio::task::spawn_blocking({
+                let (context, paths, found) = (context.clone(), paths.clone(), found.clone());
+                move || stream::walk(&context, &paths, sender, &found)
+            });
+            return Ok(Some(Files {
+                pending: PendingFiles::Streamed(receiver),
+                walk: Some(walk),
+                found,
+                total: None,
+                total_input: None,
+                shown: (0, false),
+                scan: Scan::default(),
+            }));
+        }
+        let Some((planned, scan)) = collect::collect(context, paths).await? else {
+            return Ok(None);
+        };
+        let bytes = planned.iter().map(|(source, _, _)| source.size()).sum();
+        events
+            .send(Event::ScanProgress {
+                found: planned.len(),
+                bytes: Some(bytes),
+                complete: true,
+            })
+            .await;
+        Ok(Some(Files {
+            total: Some(planned.len()),
+            pending: PendingFiles::Planned(planned.into_iter()),
+            walk: None,
+            found,
+            total_input: Some(bytes),
+            shown: (0, false),
+            scan,
+        }))
+    }
+
+    /// Tells `events` how far the walk of --stream is, unless that was
+    /// already the last thing sent.
+    async fn send_progress(&mut self, events: &mut events::Events) {
+        if self.walk.is_none() {
+            return;
+        }
+        if self.total.is_none() && self.walk.as_ref().is_some_and(|walk| walk.is_finished()) {
+            self.total = Some(self.found.load(Ordering::Relaxed));
+        }
+        let progress = (self.found.load(Ordering::Relaxed), self.total.is_some());
+        if progress != self.shown {
+            self.shown = progress;
+            events
+                .send(Event::ScanProgress {
+                    found: progress.0,
+                    bytes: None,
+                    complete: progress.1,
+                })
+                .await;
+        }
+    }
+
+    /// Lets a walk that is still running stop, and returns what the
+    /// collection found.
+    async fn finish(self) -> anyhow::Result<Collected> {
+        drop(self.pending);
+        let scan = match self.walk {
+            Some(walk) => walk.await?,
+            None => self.scan,
+        };
+        Ok(Collected {
+            scan,
+            total: self
+                .total
+                .unwrap_or_else(|| self.found.load(Ordering::Relaxed)),
+            total_input: self.total_input,
+        })
+    }
+}
+
+/// What the collection found, once the run is done with the files.
+struct Collected {
+    scan: Scan,
+    /// The files the run had to do.
+    total: usize,
+    /// The size of all sources, unknown with --stream.
+    total_input: Option<u64>,
+}
+
+/// What the run counts as its files finish and in the steps after them, for
+/// the summary it prints at the end.
+#[derive(Default)]
+struct Tally {
+    completed: usize,
+    converted: usize,
+    copied: usize,
+    /// Images copied for being below --convert-min-size.
+    below_threshold: usize,
+    /// Symlinks recreated in the output.
+    linked: usize,
+    skipped: usize,
+    errors: usize,
+    /// Errors from files in the way of output directories.
+    dir_conflicts: usize,
+    /// The error of every failed file, to list them at the end.
+    failures: Vec<(PathBuf, String)>,
+    /// Multi-page sources encountered, and the JPEG XL files written for
+    /// them.
+    multipage: usize,
+    pages_emitted: usize,
+    /// Files that couldn't be brought under --target-size.
+    over_target: usize,
+    /// Originals kept after failing the quality gate.
+    kept_original: usize,
+    /// Conversions discarded after failing --verify, originals copied
+    /// through instead, and suspect conversions kept next to the original.
+    verify_errors: usize,
+    verify_copied: usize,
+    verify_kept_both: usize,
+    /// Existing JPEG XL files copied through, left out and re-encoded, and
+    /// the bytes the re-encoding saved.
+    jxl_copied: usize,
+    jxl_skipped: usize,
+    jxl_recompressed: usize,
+    jxl_reclaimed: u64,
+    /// Outputs missing some extended attributes.
+    xattr_partial: usize,
+    /// Outputs and directories whose owner or mode couldn't be set.
+    owner_failed: usize,
+    mode_failed: usize,
+    /// Archives converted, and the images converted inside them.
+    archives: usize,
+    archive_pages: usize,
+    /// The size of the converted sources, and of their conversions.
+    original_size: u64,
+    converted_size: u64,
+    /// Bytes written to the output, for --max-output-bytes.
+    output_written: u64,
+    /// Source bytes converted or copied, for --max-input-bytes.
+    input_done: u64,
+    /// Source bytes of the files started.
+    started_input: u64,
+    /// Sources whose content-addressed output was there already.
+    dedupe_hits: usize,
+    /// In-flight files aborted by a second stop or after the shutdown grace.
+    cancelled: usize,
+    /// Files that needed I/O retries, with their count.
+    retried: Vec<(PathBuf, u32)>,
+    /// The most conversions and copies running at once.
+    peak_conversions: usize,
+    peak_copies: usize,
+    /// Hard links to a finished file that got a link to its output instead
+    /// of a conversion, with that output.
+    hardlinked: Vec<(PathBuf, PathBuf)>,
+    /// Of those, the outputs copied where linking wasn't possible.
+    hardlink_copies: usize,
+    /// Sources failing --prevalidate copied to `_corrupt`.
+    quarantined: usize,
+    /// What --strict found wrong with the outputs.
+    discrepancies: Vec<String>,
+}
+
+impl Tally {
+    /// Counts a file that finished with `result`, and writes its line to
+    /// the report.
+    async fn add(
+        &mut self,
+        file: &Path,
+        result: anyhow::Result<ProcessResult>,
+        report: &mut Option<report::Report>,
+        messages: &events::Messages,
+    ) {
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => ProcessResult::Error(e),
+        };
+        match result {
+            ProcessResult::Converted {
+                original_size,
+                converted_size,
+                source_pages,
+                pages,
+                over_target,
+                restorable,
+                notes,
+                ..
+            } => {
+                self.converted += 1;
+                self.original_size += original_size;
+                self.converted_size += converted_size;
+                if source_pages > 1 {
+                    self.multipage += 1;
+                    self.pages_emitted += pages;
+                }
+                if over_target {
+                    self.over_target += 1;
+                }
+                if restorable && let Some(report) = report {
+                    report.restorable();
+                }
+                record(
+                    report,
+                    messages,
+                    "converted",
+                    file,
+                    original_size,
+                    converted_size,
+                    &notes,
+                )
+                .await;
+            }
+            ProcessResult::Archive {
+                original_size,
+                converted_size,
+                pages,
+                notes,
+            } => {
+                self.archives += 1;
+                self.archive_pages += pages;
+                self.original_size += original_size;
+                self.converted_size += converted_size;
+                record(
+                    report,
+                    messages,
+                    "converted",
+                    file,
+                    original_size,
+                    converted_size,
+                    &notes,
+                )
+                .await;
+            }
+            ProcessResult::Copied {
+                method,
+                below_threshold,
+            } => {
+                let mut notes = vec![method.to_string()];
+                if below_threshold {
+                    self.below_threshold += 1;
+                    notes.push("below conversion threshold".to_string());
+                } else {
+                    self.copied += 1;
+                }
+                record(report, messages, "copied", file, 0, 0, &notes).await;
+            }
+            ProcessResult::Linked => {
+                self.linked += 1;
+                record(report, messages, "linked", file, 0, 0, &[]).await;
+            }
+            ProcessResult::VerifyFailed {
+                action,
+                reason,
+                notes,
+            } => {
+                let status = match action {
+                    VerifyFailAction::Error => {
+                        say_error!(
+                            messages,
+                            "Error processing file {}: verification failed: {}",
+                            file.display(),
+                            reason
+                        );
+                        self.errors += 1;
+                        self.verify_errors += 1;
+                        self.failures
+                            .push((file.to_owned(), format!("verification failed: {}", reason)));
+                        "error"
+                    }
+                    VerifyFailAction::CopyOriginal => {
+                        self.verify_copied += 1;
+                        "kept-original"
+                    }
+                    VerifyFailAction::KeepBoth => {
+                        self.verify_kept_both += 1;
+                        "kept-both"
+                    }
+                };
+                record(report, messages, status, file, 0, 0, &notes).await;
+            }
+            ProcessResult::KeptOriginal { notes } => {
+                self.kept_original += 1;
+                record(report, messages, "kept-original", file, 0, 0, &notes).await;
+            }
+            ProcessResult::CopiedJxl { mut notes } => {
+                self.jxl_copied += 1;
+                notes.insert(0, "existing jxl".to_string());
+                record(report, messages, "copied", file, 0, 0, &notes).await;
+            }
+            ProcessResult::RecompressedJxl {
+                original_size,
+                converted_size,
+            } => {
+                self.jxl_recompressed += 1;
+                self.original_size += original_size;
+                self.converted_size += converted_size;
+                let saved = original_size - converted_size;
+                self.jxl_reclaimed += saved;
+                let notes = [
+                    "recompressed existing jxl".to_string(),
+                    format!(
+                        "saved {} bytes ({:.1}%)",
+                        saved,
+                        saved as f64 * 100.0 / original_size as f64
+                    ),
+                ];
+                record(
+                    report,
+                    messages,
+                    "converted",
+                    file,
+                    original_size,
+                    converted_size,
+                    &notes,
+                )
+                .await;
+            }
+            ProcessResult::SkippedJxl => {
+                self.jxl_skipped += 1;
+                let notes = ["existing jxl".to_string()];
+                record(report, messages, "skipped", file, 0, 0, &notes).await;
+            }
+            ProcessResult::Skipped => {
+                self.skipped += 1;
+                record(report, messages, "skipped", file, 0, 0, &[]).await;
+            }
+            ProcessResult::SkippedMultipage => {
+                self.skipped += 1;
+                self.multipage += 1;
+                let notes = ["multi-page".to_string()];
+                record(report, messages, "skipped", file, 0, 0, &notes).await;
+            }
+            ProcessResult::Error(e) => {
+                say_error!(messages, "Error processing file {}: {}", file.display(), e);
+                self.errors += 1;
+                if e.is::<DirectoryConflict>() {
+                    self.dir_conflicts += 1;
+                }
+                self.failures.push((file.to_owned(), e.to_string()));
+                record(report, messages, "error", file, 0, 0, &[e.to_string()]).await;
+            }
+        }
+    }
+
+    /// The outputs the summary counts as written.
+    fn outputs(&self) -> usize {
+        self.converted
+            + self.archives
+            + self.copied
+            + self.below_threshold
+            + self.jxl_copied
+            + self.jxl_recompressed
+            + self.hardlinked.len()
+    }
+}
+
+/// Writes the line of `source` to the report, off the runtime since that
+/// may hash the source. A report that can't be written mustn't stop the
+/// conversions, so the first failure is reported and the report dropped.
+async fn record(
+    report: &mut Option<report::Report>,
+    messages: &events::Messages,
+    status: &'static str,
+    source: &Path,
+    original_size: u64,
+    converted_size: u64,
+    details: &[String],
+) {
+    let Some(mut taken) = report.take() else {
+        return;
+    };
+    let source = source.to_owned();
+    let details = details.to_vec();
+    let written = tokio::task::spawn_blocking(move || {
+        taken
+            .record(status, &source, original_size, converted_size, &details)
+            .map(|()| taken)
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|written| written);
+    match written {
+        Ok(taken) => *report = Some(taken),
+        Err(e) => say!(
+            messages,
+            "   Warning: could not write the report, stopping it: {}",
+            e
+        ),
+    }
+}
+
+/// Writes the line of `file`, which the collection left out, to the
+/// report, with its size when the walk read it.
+async fn record_left_out(
+    report: &mut Option<report::Report>,
+    messages: &events::Messages,
+    status: &'static str,
+    file: &Path,
+    notes: &[String],
+    sizes: &HashMap<PathBuf, u64>,
+) {
+    if let Some(report) = report {
+        report.source_size(sizes.get(file).copied());
+    }
+    record(report, messages, status, file, 0, 0, notes).await;
+}
+
+/// Why the run stopped starting files, and what went wrong at the end.
+#[derive(Default)]
+struct Ending {
+    /// Stopped by Ctrl-C, a signal or the handle.
+    interrupted: bool,
+    /// The signal that stopped the run, unless it was Ctrl-C.
+    stopped_by: Option<shutdown::Signal>,
+    out_of_space: bool,
+    /// The option whose budget stopped new files, with its limit.
+    budget_reached: Option<(&'static str, u64)>,
+    /// Why the output filesystem couldn't be flushed with --fsync.
+    sync_failure: Option<std::io::Error>,
+}
+
+impl Ending {
+    /// Whether no more files are started.
+    fn stops_starting(&self) -> bool {
+        self.interrupted || self.out_of_space || self.budget_reached.is_some()
+    }
+}
+
+/// What a task hands back about its file once it is done.
+struct TaskOutcome {
+    file: PathBuf,
+    /// Where the result was planned to go.
+    output: Option<PathBuf>,
+    result: anyhow::Result<ProcessResult>,
+    /// Extended attributes that couldn't be copied to the output.
+    xattr_failures: Vec<String>,
+    /// Why the output didn't get the owner of its source or --chown.
+    owner_failure: Option<std::io::Error>,
+    /// Why the output didn't get --file-mode.
+    mode_failure: Option<std::io::Error>,
+    /// Transient I/O errors retried on the way.
+    retries: u32,
+    /// Time spent in each stage, for --profile.
+    timings: profile::Timings,
+    /// From the start of the task until the output was finished.
+    elapsed: Duration,
+    /// The size of the source as walked.
+    source_size: u64,
+    /// Where --content-addressed put the output.
+    addressed: Option<content::Addressed>,
+    /// The commands the file ran, with --error-dir.
+    commands: Vec<failures::Ran>,
+}
+
+/// The task of `planned`, which holds `slot` until it ends, aborted or not.
+fn file_task(
+    planned: PlannedFile,
+    slot: OwnedSemaphorePermit,
+    context: &Context,
+    output_dir: &Path,
+) -> impl Future<Output = TaskOutcome> + use<> {
+    let (source, kind, output) = planned;
+    let (file, source_size) = (source.path.clone(), source.size());
+    let task = convert_file(
+        file,
+        source_size,
+        kind,
+        output,
+        slot,
+        context.clone(),
+        output_dir.to_owned(),
+    );
+    let task = retry::RETRIES.scope(Cell::new(0), task);
+    let task = profile::TIMINGS.scope(Cell::default(), task);
+    let task = failures::COMMANDS.scope(Default::default(), task);
+    SOURCE.scope(source, task)
+}
+
+/// Converts or copies `file`, and gives the output the metadata the options
+/// ask for.
+async fn convert_file(
+    file: PathBuf,
+    source_size: u64,
+    kind: FileKind,
+    output: Option<PathBuf>,
+    slot: OwnedSemaphorePermit,
+    context: Context,
+    output_dir: PathBuf,
+) -> TaskOutcome {
+    let args = &context.args;
+    // Freed when the task ends, aborted or not.
+    let _slot = slot;
+    let started = Instant::now();
+    let piloted = context.piloted.lock().unwrap().remove(&file);
+    if let Some(limit) = &context.rate_limiter
+        && !kind.is_copy(args)
+        && piloted.is_none()
+    {
+        // The encoder reads its source as fast as it likes, so conversions
+        // only start as fast as their sources fit in the rate.
+        limit.take(source_size).await;
+    }
+    let (result, addressed) = match piloted {
+        Some(converted) => converted,
+        None => convert_planned(&file, kind, output.as_deref(), &output_dir, &context).await,
+    };
+    let output = match &addressed {
+        Some(addressed) => Some(addressed.output.clone()),
+        None => output,
+    };
+    // An output that was there already keeps the metadata it had.
+    let written = output
+        .as_deref()
+        .filter(|_| !addressed.as_ref().is_some_and(|a| a.deduplicated));
+    // A hard link shares its metadata with the source, which must stay as
+    // it is.
+    let linked = matches!(
+        &result,
+        Ok(ProcessResult::Copied {
+            method: "hardlink",
+            ..
+        })
+    );
+    let sets_metadata = args.preserve_xattrs
+        || args.preserve_owner
+        || args.chown.is_some()
+        || args.file_mode.is_some();
+    let metadata = (sets_metadata
+        && !linked
+        && matches!(&result, Ok(result) if !matches!(result, ProcessResult::Error(_))))
+    .then(|| profile::span(profile::Stage::Metadata));
+    let (xattr_failures, owner_failure, mode_failure) = match (&result, written) {
+        (Ok(result), Some(output)) if !linked => (
+            copy_xattrs(&file, result, output, &context).await,
+            set_owner(&file, result, output, &context).await,
+            // After the owner, since changing it may clear setuid and setgid.
+            set_mode(result, output, &context).await,
+        ),
+        _ => (Vec::new(), None, None),
+    };
+    drop(metadata);
+    let result = match (result, written) {
+        (Ok(result), Some(output)) if args.fsync => sync_output(result, output).await,
+        (result, _) => result,
+    };
+    TaskOutcome {
+        file,
+        output,
+        result,
+        xattr_failures,
+        owner_failure,
+        mode_failure,
+        retries: retry::RETRIES.with(Cell::get),
+        timings: profile::TIMINGS.with(Cell::get),
+        elapsed: started.elapsed(),
+        source_size,
+        addressed,
+        commands: failures::COMMANDS.with(std::cell::RefCell::take),
+    }
+}
+
+/// Copies the extended attributes of `file` to its output with
+/// --preserve-xattrs, returning those that couldn't be. Only outputs written
+/// at their planned path, not kept originals or the pages of multi-page
+/// sources.
+async fn copy_xattrs(
+    file: &Path,
+    result: &ProcessResult,
+    output: &Path,
+    context: &Context,
+) -> Vec<String> {
+    match result {
+        ProcessResult::Converted { .. }
+        | ProcessResult::Archive { .. }
+        | ProcessResult::Copied { .. }
+        | ProcessResult::CopiedJxl { .. }
+        | ProcessResult::RecompressedJxl { .. }
+            if context.args.preserve_xattrs && output.exists() =>
+        {
+            let (file, output) = (file.to_owned(), output.to_owned());
+            tokio::task::spawn_blocking(move || xattrs::copy(&file, &output))
+                .await
+                .unwrap_or_else(|e| vec![e.to_string()])
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Gives the output of `file` the owner of its source or --chown.
+async fn set_owner(
+    file: &Path,
+    result: &ProcessResult,
+    output: &Path,
+    context: &Context,
+) -> Option<std::io::Error> {
+    let args = &context.args;
+    // Not `exists`, recreated links may be dangling.
+    if !matches!(
+        result,
+        ProcessResult::Converted { .. }
+            | ProcessResult::Archive { .. }
+            | ProcessResult::Copied { .. }
+            | ProcessResult::Linked
+            | ProcessResult::CopiedJxl { .. }
+            | ProcessResult::RecompressedJxl { .. }
+    ) || !(args.preserve_owner || args.chown.is_some())
+        || output.symlink_metadata().is_err()
+    {
+        return None;
+    }
+    let owner = match args.chown {
+        Some(owner) => owner,
+        None => match source_metadata(file).await {
+            Ok(source) => perms::Owner::of(&source),
+            Err(e) => return Some(e),
+        },
+    };
+    retry::io("Setting owner", args.io_retries, &context.messages, || {
+        let output = output.to_owned();
+        async move { tokio::task::spawn_blocking(move || perms::set_owner(&output, owner)).await? }
+    })
+    .await
+    .err()
+}
+
+/// Gives the output --file-mode. Symlinks have no mode of their own.
+async fn set_mode(
+    result: &ProcessResult,
+    output: &Path,
+    context: &Context,
+) -> Option<std::io::Error> {
+    let args = &context.args;
+    let mode = args.file_mode?;
+    match result {
+        ProcessResult::Converted { .. }
+        | ProcessResult::Archive { .. }
+        | ProcessResult::Copied { .. }
+        | ProcessResult::CopiedJxl { .. }
+        | ProcessResult::RecompressedJxl { .. }
+            if output.exists() =>
+        {
+            retry::io("Setting mode", args.io_retries, &context.messages, || {
+                let output = output.to_owned();
+                async move {
+                    tokio::task::spawn_blocking(move || perms::set_mode(&output, mode)).await?
+                }
+            })
+            .await
+            .err()
+        }
+        _ => None,
+    }
+}
+
+/// Flushes the output of `result` and its directory with --fsync. A symlink
+/// has nothing of its own to flush.
+async fn sync_output(result: ProcessResult, output: &Path) -> anyhow::Result<ProcessResult> {
+    if !matches!(
+        result,
+        ProcessResult::Converted { .. }
+            | ProcessResult::Archive { .. }
+            | ProcessResult::Copied { .. }
+            | ProcessResult::CopiedJxl { .. }
+            | ProcessResult::RecompressedJxl { .. }
+    ) || !output.exists()
+    {
+        return Ok(result);
+    }
+    let output = output.to_owned();
+    let syncing = tokio::task::spawn_blocking(move || sync::file_and_dir(&output));
+    match profile::time(profile::Stage::Sync, syncing).await {
+        Ok(Ok(())) => Ok(result),
+        Ok(Err(e)) => Ok(ProcessResult::Error(anyhow::anyhow!(
+            "Syncing the output failed: {}",
+            e
+        ))),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// An output the run counts as written, for `--strict`.
+struct Produced {
+    source: PathBuf,
+    output: PathBuf,
+    /// Whether it was written at its planned path rather than split into
+    /// pages, which aren't checked.
+    whole: bool,
+}
+
+/// Checks that every output in `produced` is on disk, isn't empty and has
+/// its source's modification time within `tolerance`. Returns a description
+/// of everything that isn't so.
+fn check_outputs(produced: &[Produced], tolerance: Duration) -> Vec<String> {
+    let mut discrepancies = Vec::new();
+    for item in produced.iter().filter(|item| item.whole) {
+        let output = match std::fs::metadata(&item.output) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                discrepancies.push(format!("{} is missing: {}", item.output.display(), e));
+                continue;
+            }
+        };
+        if output.len() == 0 {
+            discrepancies.push(format!("{} is empty", item.output.display()));
+        }
+        let (Ok(wanted), Ok(got)) = (
+            std::fs::metadata(&item.source).and_then(|source| source.modified()),
+            output.modified(),
+        ) else {
+            continue;
+        };
+        let off = wanted
+            .duration_since(got)
+            .or_else(|_| got.duration_since(wanted))
+            .unwrap_or_default();
+        if off > tolerance {
+            discrepancies.push(format!(
+                "{} has a modification time {:?} off from {}",
+                item.output.display(),
+                off,
+                item.source.display()
+            ));
+        }
+    }
+    discrepancies
+}
+
+/// A run under way: where it reports what happens, and what it has counted.
+struct Run {
+    context: Context,
+    paths: RunPaths,
+    events: events::Events,
+    tally: Tally,
+    ending: Ending,
+    /// Stop and pause requests, taken from when the files start.
+    requests: control::Listener,
+    report: Option<report::Report>,
+    journal: Option<journal::Journal>,
+    printer: Option<listing::Printer>,
+    content_index: Option<content::Index>,
+    breakdown: events::Breakdown,
+    profile: profile::Profile,
+    phase_start: Instant,
+    /// The outputs --strict checks after the run.
+    produced: Vec<Produced>,
+    /// Where finished files went, for hard links to them.
+    outputs: HashMap<PathBuf, PathBuf>,
+    /// Content-addressed outputs, for duplicate paths of their sources.
+    addressed: HashMap<PathBuf, content::Addressed>,
+}
+
+impl Run {
+    /// Sets up what the run writes along the way, with what the collection
+    /// found out for the conversions to use.
+    fn new(
+        mut context: Context,
+        paths: RunPaths,
+        events: events::Events,
+        scan: &mut Scan,
+        printer: Option<listing::Printer>,
+        journal: Option<journal::Journal>,
+    ) -> anyhow::Result<Self> {
+        let dimensions = std::mem::take(&mut scan.dimensions);
+        context.probed = Arc::new(std::mem::take(&mut scan.probed));
+        context.piloted = Arc::new(std::sync::Mutex::new(std::mem::take(&mut scan.piloted)));
+        let args = &context.args;
+        let report = args
+            .report
+            .as_ref()
+            .map(|path| {
+                report::Report::create(
+                    Path::new(path),
+                    &paths.input,
+                    args.profile,
+                    (args.probe || args.dimension_limits().is_set()).then_some(dimensions),
+                    report_settings(&context),
+                )
+            })
+            .transpose()?;
+        let content_index = args
+            .content_addressed
+            .map(|_| content::Index::open(&paths.output))
+            .transpose()?;
+        Ok(Run {
+            breakdown: events::Breakdown::new(&paths.input, args.summary_depth),
+            // Only now, so the command line still ends the process right
+            // away when stopped before the run.
+            requests: context.control.listen(),
+            profile: std::mem::take(&mut scan.profile),
+            phase_start: Instant::now(),
+            context,
+            paths,
+            events,
+            tally: Tally::default(),
+            ending: Ending::default(),
+            report,
+            journal,
+            printer,
+            content_index,
+            produced: Vec::new(),
+            outputs: HashMap::new(),
+            addressed: HashMap::new(),
+        })
+    }
+
+    /// Starts the files as slots of their kind come free and takes in their
+    /// outcomes, until every file is done or the run is stopped.
+    async fn execute(&mut self, files: &mut Files) {
+        let context = self.context.clone();
+        let (args, messages) = (&context.args, &context.messages);
+        let mut set: JoinSet<TaskOutcome> = JoinSet::new();
+        let mut paused = false;
+        let mut abort_at = None;
+        let mut all_started = false;
+        let mut journal_flush = tokio::time::interval(journal::FLUSH_INTERVAL);
+        let mut status_write = tokio::time::interval(events::STATUS_INTERVAL);
+        // Conversions and copies have slots of their own, so big copies don't
+        // leave the CPU idle.
+        let conversion_limit = args
+            .adaptive_jobs
+            .map_or(args.parallel.jobs.max(1), |range| range.max);
+        let conversion_slots = Arc::new(Semaphore::new(conversion_limit));
+        let mut adaptive = args.adaptive_jobs.map(adaptive::Adaptive::new);
+        if let Some(adaptive) = &adaptive {
+            self.events
+                .send(Event::Jobs {
+                    jobs: adaptive.jobs(),
+                })
+                .await;
+        }
+        let mut load_sample = tokio::time::interval_at(
+            tokio::time::Instant::now() + adaptive::SAMPLE_INTERVAL,
+            adaptive::SAMPLE_INTERVAL,
+        );
+        let copy_slots = Arc::new(Semaphore::new(args.copy_jobs.max(1)));
+        let mut held = VecDeque::new(); // Files taken ahead that wait for a slot of their kind
+        loop {
+            if self.ending.stops_starting() {
+                held.clear();
+            }
+            let parked = match &mut adaptive {
+                Some(adaptive) => {
+                    adaptive.settle(&conversion_slots);
+                    adaptive.parked()
+                }
+                None => 0,
+            };
+            // Files are only spawned once a slot of their kind is free, so
+            // an interrupt can stop new work from being started.
+            let mut index = 0;
+            while !paused && index < held.len() {
+                let (_, kind, _): &PlannedFile = &held[index];
+                let slots = if kind.is_copy(args) {
+                    &copy_slots
+                } else {
+                    &conversion_slots
+                };
+                match slots.clone().try_acquire_owned() {
+                    Ok(slot) => {
+                        if let Some(planned) = held.remove(index) {
+                            self.start(&mut set, planned, slot).await;
+                        }
+                    }
+                    Err(_) => index += 1,
+                }
+            }
+            let tally = &mut self.tally;
+            tally.peak_conversions = tally
+                .peak_conversions
+                .max(conversion_limit - conversion_slots.available_permits() - parked);
+            tally.peak_copies = tally
+                .peak_copies
+                .max(args.copy_jobs.max(1) - copy_slots.available_permits());
+
+            let done_starting = self.ending.stops_starting() || all_started;
+            let starting = !done_starting && !paused;
+            if done_starting && set.is_empty() {
+                break;
+            }
+            let task_result = tokio::select! {
+                planned = files.pending.next(), if starting && held.len() < conversion_limit + args.copy_jobs.max(1) => {
+                    match planned {
+                        Some(planned) => held.push_back(planned),
+                        None => {
+                            all_started = true;
+                            files.total.get_or_insert(files.found.load(Ordering::Relaxed));
+                            files.send_progress(&mut self.events).await;
+                        }
+                    }
+                    continue;
+                }
+                Some(task_result) = set.join_next() => task_result,
+                _ = load_sample.tick(), if adaptive.is_some() => {
+                    if let Some(adaptive) = &mut adaptive
+                        && let Some(jobs) = adaptive.sample()
+                    {
+                        say!(messages, "   Load changed, running {} conversions at a time", jobs);
+                        self.events.send(Event::Jobs { jobs }).await;
+                    }
+                    continue;
+                }
+                _ = journal_flush.tick(), if self.journal.is_some() => {
+                    if let Some(journal) = &mut self.journal {
+                        journal.flush().await;
+                    }
+                    continue;
+                }
+                _ = status_write.tick(), if args.status_file.is_some() => {
+                    self.events.write_status();
+                    continue;
+                }
+                request = self.requests.recv() => {
+                    match request {
+                        control::Request::Pause if !paused && !self.ending.interrupted => {
+                            say!(
+                                messages,
+                                "Paused until SIGUSR2: not starting new files, {} running files {}",
+                                set.len(),
+                                if args.pause_encoders { "stopped" } else { "still finishing" }
+                            );
+                            if args.pause_encoders {
+                                self.context.children.stop(true);
+                            }
+                            paused = true;
+                            self.events.send(Event::Paused { running: set.len() }).await;
+                        }
+                        control::Request::Resume if paused => {
+                            say!(messages, "Resuming");
+                            self.resume(&mut paused).await;
+                        }
+                        control::Request::Stop(signal) => {
+                            // Stopped encoders couldn't finish in the grace period.
+                            self.resume(&mut paused).await;
+                            self.stop(signal, &mut set, &mut abort_at);
+                        }
+                        _ => {}
+                    }
+                    continue;
+                }
+                _ = async {
+                    match abort_at {
+                        Some(deadline) => tokio::time::sleep_until(deadline).await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    say!(messages, "Shutdown grace period over, aborting {} running files...", set.len());
+                    set.abort_all();
+                    abort_at = None;
+                    continue;
+                }
+            };
+
+            if let Err(e) = &task_result
+                && e.is_cancelled()
+            {
+                self.tally.cancelled += 1;
+                continue;
+            }
+            self.tally.completed += 1;
+            if let Ok(outcome) = &task_result {
+                self.check_limits(outcome).await;
+            }
+            files.send_progress(&mut self.events).await;
+            self.finish_file(task_result).await;
+        }
+    }
+
+    /// Starts the task of `planned` in `set`, holding `slot` until it ends.
+    async fn start(
+        &mut self,
+        set: &mut JoinSet<TaskOutcome>,
+        planned: PlannedFile,
+        slot: OwnedSemaphorePermit,
+    ) {
+        let path = planned.0.path.clone();
+        self.tally.started_input += planned.0.size();
+        if let Some(journal) = &mut self.journal {
+            journal.started(&path, planned.2.as_deref()).await;
+        }
+        set.spawn(file_task(planned, slot, &self.context, &self.paths.output));
+        self.events.send(Event::FileStarted { path }).await;
+    }
+
+    /// Ends a pause, also when the run is stopped while paused, so stopped
+    /// encoders can finish or be aborted.
+    async fn resume(&mut self, paused: &mut bool) {
+        if !*paused {
+            return;
+        }
+        if self.context.args.pause_encoders {
+            self.context.children.stop(false);
+        }
+        *paused = false;
+        self.events.send(Event::Resumed).await;
+    }
+
+    /// Stops starting files on the first request, and aborts the running
+    /// ones on the next, or after the shutdown grace for signals other than
+    /// Ctrl-C.
+    fn stop(
+        &mut self,
+        signal: shutdown::Signal,
+        set: &mut JoinSet<TaskOutcome>,
+        abort_at: &mut Option<tokio::time::Instant>,
+    ) {
+        let (args, messages) = (&self.context.args, &self.context.messages);
+        let by_terminal = matches!(signal, shutdown::Signal::Interrupt);
+        if self.ending.interrupted && by_terminal {
+            say!(
+                messages,
+                "Interrupted again, aborting {} running files...",
+                set.len()
+            );
+            set.abort_all();
+        } else if self.ending.interrupted {
+            say!(
+                messages,
+                "{} received, aborting {} running files...",
+                signal.name(),
+                set.len()
+            );
+            set.abort_all();
+        } else if by_terminal {
+            say!(
+                messages,
+                "Interrupted, waiting for {} running files to finish (Ctrl-C again to abort)...",
+                set.len()
+            );
+            self.ending.interrupted = true;
+        } else {
+            say!(
+                messages,
+                "{} received, waiting up to {}s for {} running files to finish...",
+                signal.name(),
+                args.shutdown_grace,
+                set.len()
+            );
+            self.ending.interrupted = true;
+            self.ending.stopped_by = Some(signal);
+            *abort_at =
+                Some(tokio::time::Instant::now() + Duration::from_secs(args.shutdown_grace));
+        }
+    }
+
+    /// Stops starting files once the output filesystem is full, or once what
+    /// the finished files wrote or read reaches its budget.
+    async fn check_limits(&mut self, outcome: &TaskOutcome) {
+        let (args, messages) = (&self.context.args, &self.context.messages);
+        // Every further file would fail the same way on a full filesystem.
+        if !self.ending.out_of_space
+            && let Err(e) | Ok(ProcessResult::Error(e)) = &outcome.result
+            && space::is_out_of_space(e)
+        {
+            say!(
+                messages,
+                "Output filesystem is full, not starting any more files"
+            );
+            self.ending.out_of_space = true;
+        }
+
+        let (Ok(result), Some(output)) = (&outcome.result, &outcome.output) else {
+            return;
+        };
+        let written = match result {
+            ProcessResult::Converted { converted_size, .. }
+            | ProcessResult::Archive { converted_size, .. }
+            | ProcessResult::RecompressedJxl { converted_size, .. } => Some(*converted_size),
+            ProcessResult::Copied { .. } | ProcessResult::CopiedJxl { .. } => Some(
+                tokio::fs::metadata(output)
+                    .await
+                    .map_or(0, |metadata| metadata.len()),
+            ),
+            _ => None,
+        };
+        let tally = &mut self.tally;
+        if let Some(written) = written {
+            tally.output_written += written;
+            tally.input_done += outcome.source_size;
+        }
+        if self.ending.budget_reached.is_none() {
+            self.ending.budget_reached = match (args.max_output_bytes, args.max_input_bytes) {
+                (Some(max), _) if tally.output_written >= max => Some(("--max-output-bytes", max)),
+                (_, Some(max)) if tally.input_done >= max => Some(("--max-input-bytes", max)),
+                _ => None,
+            };
+            if let Some((option, max)) = self.ending.budget_reached {
+                say!(
+                    messages,
+                    "Reached {} {}, not starting any more files",
+                    option,
+                    human_bytes(max as f64)
+                );
+            }
+        }
+    }
+
+    /// Takes in the outcome of a finished task: counts it, writes it to the
+    /// report, the journal and the other records of the run, and sends it to
+    /// the events once its warnings and errors are printed.
+    async fn finish_file(&mut self, task_result: Result<TaskOutcome, JoinError>) {
+        let context = self.context.clone();
+        let (args, messages) = (&context.args, &context.messages);
+        let outcome = match task_result {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                // The task panicked.
+                say_error!(messages, "Task join error: {}", e);
+                self.tally.errors += 1;
+                return;
+            }
+        };
+        let TaskOutcome {
+            file,
+            output,
+            mut result,
+            xattr_failures,
+            owner_failure,
+            mode_failure,
+            retries,
+            timings,
+            elapsed,
+            source_size,
+            addressed,
+            commands,
+        } = outcome;
+        self.profile.add(&timings);
+        // An output that was there already wasn't created by this run.
+        let deduplicated = addressed.as_ref().is_some_and(|a| a.deduplicated);
+        if let Some(addressed) = addressed {
+            if addressed.deduplicated {
+                self.tally.dedupe_hits += 1;
+            }
+            // Without its line a later run can't tell which source the
+            // output belongs to.
+            if let Some(index) = &mut self.content_index
+                && let Err(e) = index_source(index, &self.paths.input, &file, &addressed)
+            {
+                result = Err(e);
+            }
+            self.addressed.insert(file.clone(), addressed);
+        }
+        let finished = match &result {
+            Ok(result) => result.file_result(&file, output.as_deref(), elapsed),
+            Err(e) => ProcessResult::Error(anyhow::anyhow!("{}", e)).file_result(
+                &file,
+                output.as_deref(),
+                elapsed,
+            ),
+        };
+        if let Some(dir) = &args.error_dir
+            && finished.action == Action::Failed
+        {
+            let (dir, failed, sample_bytes) =
+                (dir.clone(), finished.clone(), args.error_sample_bytes);
+            let relative = file
+                .strip_prefix(&self.paths.input)
+                .unwrap_or(&file)
+                .to_owned();
+            // Samples the source, off the runtime.
+            let written = tokio::task::spawn_blocking(move || {
+                failures::write(&dir, &relative, &failed, &commands, sample_bytes)
+            })
+            .await
+            .map_err(std::io::Error::other)
+            .and_then(|written| written);
+            match written {
+                Ok(path) => detail!(messages, "   Failure details in {}", path.display()),
+                Err(e) => say!(
+                    messages,
+                    "   Warning: writing the failure details of {}: {}",
+                    file.display(),
+                    e
+                ),
+            }
+        }
+        if let Some(report) = &mut self.report {
+            report.source_size(Some(source_size));
+            if args.profile {
+                report.timings(&timings);
+            }
+        }
+        if let (Some(output), Ok(result)) = (&output, &result) {
+            self.note_output(&file, output, result, deduplicated);
+        }
+        if let Some(journal) = &mut self.journal {
+            let outcome = match &result {
+                Err(_) | Ok(ProcessResult::Error(_)) => "error",
+                Ok(_) => "done",
+            };
+            journal.finished(&file, outcome).await;
+        }
+        if retries > 0 {
+            self.tally.retried.push((file.clone(), retries));
+        }
+        if !xattr_failures.is_empty() {
+            for failure in &xattr_failures {
+                say!(
+                    messages,
+                    "   Warning: could not copy extended attribute of {}: {}",
+                    file.display(),
+                    failure
+                );
+            }
+            self.tally.xattr_partial += 1;
+        }
+        if let Some(e) = owner_failure {
+            say!(
+                messages,
+                "   Warning: could not set owner of {}: {}",
+                file.display(),
+                e
+            );
+            self.tally.owner_failed += 1;
+        }
+        if let Some(e) = mode_failure {
+            say!(
+                messages,
+                "   Warning: could not set mode of {}: {}",
+                file.display(),
+                e
+            );
+            self.tally.mode_failed += 1;
+        }
+        self.tally
+            .add(&file, result, &mut self.report, messages)
+            .await;
+
+        self.breakdown.add(&finished);
+        if let Some(limit) = &context.rate_limiter {
+            self.events
+                .send(Event::Throughput {
+                    bytes_per_second: limit.throughput(),
+                    limit: limit.bytes_per_second(),
+                })
+                .await;
+        }
+        self.events.send(Event::FileFinished(finished)).await;
+    }
+
+    /// Keeps the output `file` finished with for what comes after the files:
+    /// --strict, --print-outputs and hard links to it.
+    fn note_output(
+        &mut self,
+        file: &Path,
+        output: &Path,
+        result: &ProcessResult,
+        deduplicated: bool,
+    ) {
+        let (args, messages) = (&self.context.args, &self.context.messages);
+        if args.strict {
+            let whole = match result {
+                ProcessResult::Converted { pages, .. } => Some(*pages <= 1),
+                ProcessResult::Archive { .. }
+                | ProcessResult::Copied { .. }
+                | ProcessResult::CopiedJxl { .. }
+                | ProcessResult::RecompressedJxl { .. } => Some(true),
+                _ => None,
+            };
+            if let Some(whole) = whole {
+                self.produced.push(Produced {
+                    source: file.to_owned(),
+                    output: output.to_owned(),
+                    whole,
+                });
+            }
+        }
+        if let Some(listed) = &mut self.printer
+            && !deduplicated
+        {
+            for created in listing::created(result, output) {
+                if let Err(e) = listed.print(created) {
+                    say_error!(messages, "Error printing outputs: {}", e);
+                    break;
+                }
+            }
+        }
+        if !args.no_preserve_hardlinks
+            && matches!(
+                result,
+                ProcessResult::Converted { .. }
+                    | ProcessResult::Copied { .. }
+                    | ProcessResult::CopiedJxl { .. }
+                    | ProcessResult::RecompressedJxl { .. }
+                    | ProcessResult::Skipped
+            )
+        {
+            self.outputs.insert(file.to_owned(), output.to_owned());
+        }
+    }
+
+    /// Tidies up the output once the files are done: removes what aborted
+    /// files left, gives hard links to converted files their outputs and
+    /// sets the owner and mode of the directories.
+    async fn finish_outputs(&mut self, scan: &Scan) -> anyhow::Result<()> {
+        let (args, messages) = (&self.context.args, &self.context.messages);
+        let (input_path, output_path) = (&self.paths.input, &self.paths.output);
+        if self.tally.cancelled > 0 && !args.keep_partials {
+            // Aborted conversions can't clean up after themselves.
+            remove_partial_outputs(output_path, messages).await;
+        }
+
+        if args.content_addressed == Some(ContentHash::Output) {
+            // Only what couldn't be moved into place is left.
+            let staging = output_path.join(content::STAGING_DIR);
+            for dir in walkdir::WalkDir::new(&staging)
+                .contents_first(true)
+                .into_iter()
+                .filter_map(Result::ok)
+                .filter(|entry| entry.file_type().is_dir())
+            {
+                let _ = std::fs::remove_dir(dir.path());
+            }
+        }
+
+        // Paths that are hard links to a converted file get their output as
+        // a hard link to its output rather than a conversion of their own.
+        for (file, original) in &scan.duplicates {
+            // The same content needs no output of its own, only its line in
+            // the index.
+            if let Some(addressed) = self.addressed.get(original) {
+                self.tally.dedupe_hits += 1;
+                if let Some(index) = &mut self.content_index
+                    && let Err(e) = index_source(index, input_path, file, addressed)
+                {
+                    say_error!(messages, "Error processing file {}: {}", file.display(), e);
+                    self.tally.errors += 1;
+                    self.tally.failures.push((file.clone(), e.to_string()));
+                }
+                continue;
+            }
+            let Some(original_output) = self.outputs.get(original) else {
+                continue;
+            };
+            // Only hard links have an output placed for them.
+            let Some(output) = scan.link_outputs.get(file) else {
+                continue;
+            };
+            if original.is_symlink() || !original_output.is_file() {
+                continue;
+            }
+            if is_linked_output(original_output, output) {
+                continue;
+            }
+            if output.symlink_metadata().is_ok() {
+                say!(
+                    messages,
+                    "   Warning: not linking {} to the output of {}, {} is already there",
+                    file.display(),
+                    original.display(),
+                    output.display()
+                );
+                continue;
+            }
+            let output = output.clone();
+            match link_output(original_output, &output, &self.context) {
+                Ok(linked) => {
+                    if !linked {
+                        self.tally.hardlink_copies += 1;
+                    }
+                    if let Some(listed) = &mut self.printer
+                        && let Err(e) = listed.print(&output)
+                    {
+                        say_error!(messages, "Error printing outputs: {}", e);
+                    }
+                    self.tally.hardlinked.push((file.clone(), output));
+                }
+                Err(e) => say_error!(messages, "Error linking output {}: {}", output.display(), e),
+            }
+        }
+
+        let created_output = self.paths.created_output;
+        if args.preserve_owner {
+            // Directories are created along the way by whichever file needs
+            // them first, so they get their owners once everything is
+            // written.
+            let (input, output) = (input_path.clone(), output_path.clone());
+            let failures = tokio::task::spawn_blocking(move || {
+                owner::copy_dirs(&input, &output, created_output)
+            })
+            .await?;
+            for failure in &failures {
+                say!(
+                    messages,
+                    "   Warning: could not set owner of directory {}",
+                    failure
+                );
+            }
+            self.tally.owner_failed += failures.len();
+        }
+        if args.dir_mode.is_some() || args.chown.is_some() {
+            let dirs = self.context.created_dirs.below(output_path);
+            let (mode, owner) = (args.dir_mode, args.chown);
+            let (failures, owner_failures) =
+                tokio::task::spawn_blocking(move || perms::apply_dirs(&dirs, mode, owner)).await?;
+            for failure in &failures {
+                say!(messages, "   Warning: could not set {}", failure);
+            }
+            self.tally.owner_failed += owner_failures;
+            self.tally.mode_failed += failures.len() - owner_failures;
+        }
+        Ok(())
+    }
+
+    /// Quarantines the sources that failed --prevalidate, and writes the
+    /// files the collection left out to the report.
+    async fn record_left_out(&mut self, scan: &Scan) -> anyhow::Result<()> {
+        let (args, messages) = (&self.context.args, &self.context.messages);
+        let (report, sizes) = (&mut self.report, &scan.left_out_sizes);
+        for (file, reason) in &scan.failed_validation {
+            if args.quarantine_corrupt {
+                let relative_path = file.strip_prefix(&self.paths.input)?;
+                let quarantine_path = self.paths.output.join("_corrupt").join(relative_path);
+                say!(
+                    messages,
+                    "   Quarantining {} -> {}",
+                    file.display(),
+                    quarantine_path.display()
+                );
+                let created_dirs = self.context.created_dirs.clone();
+                let source = file.clone();
+                let copied = tokio::task::spawn_blocking(move || {
+                    match quarantine_path.parent() {
+                        Some(parent) => created_dirs.create(parent),
+                        None => Ok(()),
+                    }
+                    .and_then(|_| std::fs::copy(&source, &quarantine_path))
+                })
+                .await?;
+                match copied {
+                    Ok(_) => self.tally.quarantined += 1,
+                    Err(e) => say_error!(
+                        messages,
+                        "Error quarantining file {}: {}",
+                        file.display(),
+                        e
+                    ),
+                }
+            }
+            let notes = std::slice::from_ref(reason);
+            record_left_out(report, messages, "invalid", file, notes, sizes).await;
+        }
+
+        for (file, reason) in &scan.inaccessible {
+            let notes = std::slice::from_ref(reason);
+            record_left_out(report, messages, "inaccessible", file, notes, sizes).await;
+        }
+        for (file, target) in &scan.dangling {
+            let reason = format!("dangling symlink to {}", target.display());
+            record_left_out(report, messages, "inaccessible", file, &[reason], sizes).await;
+        }
+        for file in &scan.skipped_symlinks {
+            let notes = ["symlink".to_string()];
+            record_left_out(report, messages, "skipped", file, &notes, sizes).await;
+        }
+        for file in &scan.empty_sources {
+            let notes = ["empty file".to_string()];
+            record_left_out(report, messages, "invalid", file, &notes, sizes).await;
+        }
+        for file in &scan.unstable {
+            let notes = ["unstable".to_string()];
+            record_left_out(report, messages, "skipped", file, &notes, sizes).await;
+        }
+        for (file, reason) in &scan.outside_dimensions {
+            let notes = std::slice::from_ref(reason);
+            record_left_out(report, messages, "skipped", file, notes, sizes).await;
+        }
+        for (file, reason) in &scan.collided {
+            let notes = std::slice::from_ref(reason);
+            record_left_out(report, messages, "error", file, notes, sizes).await;
+        }
+        for (file, original) in &scan.duplicates {
+            let mut notes = vec![format!("duplicate of {}", original.display())];
+            let linked = self
+                .tally
+                .hardlinked
+                .iter()
+                .find(|(linked, _)| linked == file);
+            let status = match linked {
+                Some((_, output)) => {
+                    notes.push(format!("output linked as {}", output.display()));
+                    "hardlinked"
+                }
+                None => "duplicate",
+            };
+            record_left_out(report, messages, status, file, &notes, sizes).await;
+        }
+        Ok(())
+    }
+
+    /// Checks the outputs with --strict, and flushes the output filesystem
+    /// with --fsync.
+    async fn final_checks(&mut self) -> anyhow::Result<()> {
+        let (args, messages) = (&self.context.args, &self.context.messages);
+        if args.strict {
+            let mut produced = std::mem::take(&mut self.produced);
+            produced.extend(self.tally.hardlinked.iter().map(|(file, output)| Produced {
+                source: file.clone(),
+                output: output.clone(),
+                whole: true,
+            }));
+            let counted = self.tally.outputs();
+            if counted != produced.len() {
+                self.tally.discrepancies.push(format!(
+                    "the summary counts {} outputs, but the run recorded {}",
+                    counted,
+                    produced.len()
+                ));
+            }
+            let tolerance = Duration::from_secs_f64(args.mtime_tolerance);
+            self.tally.discrepancies.extend(
+                tokio::task::spawn_blocking(move || check_outputs(&produced, tolerance)).await?,
+            );
+            for discrepancy in &self.tally.discrepancies {
+                say_error!(messages, "Strict check: {}", discrepancy);
+            }
+        }
+
+        if args.fsync {
+            say!(messages, "Syncing the output filesystem...");
+            let output = self.paths.output.clone();
+            if let Err(e) = tokio::task::spawn_blocking(move || sync::filesystem(&output)).await? {
+                say_error!(messages, "Error syncing the output filesystem: {}", e);
+                self.ending.sync_failure = Some(e);
+            }
+        }
+        if args.strict || args.fsync {
+            self.profile.lap("Final checks", &mut self.phase_start);
+        }
+        Ok(())
+    }
+
+    /// Ends the run: sends the summary, prints it, finishes the report and
+    /// the journal and adds the run to the history. Fails the way the run
+    /// ended.
+    async fn end(mut self, collected: &Collected) -> anyhow::Result<()> {
+        // Nothing is started anymore, so a stop request during the final
+        // steps only sets how the run ends.
+        if !self.ending.interrupted
+            && let Some(signal) = self.requests.pending()
+        {
+            say!(
+                self.context.messages,
+                "{} received while finishing up",
+                signal.name()
+            );
+            self.ending.interrupted = true;
+            if !matches!(signal, shutdown::Signal::Interrupt) {
+                self.ending.stopped_by = Some(signal);
+            }
+        }
+
+        // Only now, so nothing happens to the output after the run said it's
+        // finished.
+        let directories = self.breakdown.finish();
+        let summary = self
+            .events
+            .finish(self.tally.cancelled, directories.clone())
+            .await;
+        let (context, tally, ending) = (&self.context, &self.tally, &self.ending);
+        let messages = &context.messages;
+        print_summary(context, collected, tally, ending);
+        // Nothing to break down when every file is directly in the input.
+        if directories
+            .iter()
+            .any(|totals| totals.directory != Path::new("."))
+        {
+            events::print_directories(&directories, messages);
+            say!(messages, "{}", "-".repeat(60));
+        }
+        if !tally.failures.is_empty() {
+            print_errors(&tally.failures, context.args.report.is_some(), messages);
+            say!(messages, "{}", "-".repeat(60));
+        }
+        if context.args.profile {
+            self.profile.print(messages);
+            say!(messages, "{}", "-".repeat(60));
+        }
+
+        if let Some(report) = self.report
+            && let Err(e) = report.finish(&directories)
+        {
+            say!(messages, "   Warning: could not write the report: {}", e);
+        }
+        if let Some(journal) = &mut self.journal {
+            journal.flush().await;
+        }
+        // Last, so the summary doesn't wait for other runs writing the history.
+        if context.history {
+            // Waits for other runs writing the history, off the runtime.
+            let (input, output) = (self.paths.input.clone(), self.paths.output.clone());
+            let settings = report_settings(context);
+            let _ = tokio::task::spawn_blocking(move || {
+                crate::history::record(&input, &output, settings, &summary)
+            })
+            .await;
+        }
+
+        let ending = self.ending;
+        if let Some(signal) = ending.stopped_by {
+            return Err(Error::Stopped(signal.exit_code()).into());
+        }
+        if ending.interrupted {
+            // The conventional exit code for a process stopped by SIGINT.
+            return Err(Error::Stopped(130).into());
+        }
+        if ending.out_of_space {
+            return Err(anyhow::anyhow!("The output filesystem ran out of space"));
+        }
+        if !self.tally.discrepancies.is_empty() {
+            return Err(anyhow::anyhow!(
+                "The strict check found {} discrepancies",
+                self.tally.discrepancies.len()
+            ));
+        }
+        if let Some(e) = ending.sync_failure {
+            return Err(anyhow::anyhow!(
+                "The output filesystem could not be synced: {}",
+                e
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Prints how the run ended and what it did.
+fn print_summary(context: &Context, collected: &Collected, tally: &Tally, ending: &Ending) {
+    let (args, messages) = (&context.args, &context.messages);
+    let Collected {
+        scan, total_input, ..
+    } = collected;
+    let not_started = collected.total - tally.completed - tally.cancelled;
+    say!(messages, "{}", "-".repeat(60));
+    if let Some(signal) = ending.stopped_by {
+        say!(
+            messages,
+            "Run stopped by {}: {} files not started, {} aborted",
+            signal.name(),
+            not_started,
+            tally.cancelled
+        );
+    } else if ending.interrupted {
+        say!(
+            messages,
+            "Run interrupted: {} files not started, {} aborted",
+            not_started,
+            tally.cancelled
+        );
+    }
+    if ending.out_of_space {
+        say!(
+            messages,
+            "Run stopped, the output filesystem is full: {} files not started",
+            not_started
+        );
+    }
+    if let Some((option, max)) = ending.budget_reached {
+        say!(
+            messages,
+            "Run stopped at {} {}: {} files not started{}",
+            option,
+            human_bytes(max as f64),
+            not_started,
+            match total_input {
+                Some(total) => format!(
+                    " ({} of input left)",
+                    human_bytes(total.saturating_sub(tally.started_input) as f64)
+                ),
+                None => String::new(),
+            }
+        );
+    }
+    say!(messages, "Processing Summary:");
+    say!(messages, "  Total files processed: {}", tally.completed);
+    say!(messages, "  Files converted:       {}", tally.converted);
+    say!(messages, "  Files copied:          {}", tally.copied);
+    if args.convert_min_size > 0 {
+        say!(
+            messages,
+            "  Copied (below conversion threshold): {}",
+            tally.below_threshold
+        );
+    }
+    if args.symlinks == SymlinkMode::Preserve {
+        say!(messages, "  Symlinks recreated:    {}", tally.linked);
+    }
+    say!(messages, "  Files skipped:         {}", tally.skipped);
+    say!(messages, "  Files with errors:     {}", tally.errors);
+    if tally.dir_conflicts > 0 {
+        say!(messages, "  Blocked by a file:     {}", tally.dir_conflicts);
+    }
+    if !scan.empty_sources.is_empty() {
+        say!(
+            messages,
+            "  Empty sources:         {}",
+            scan.empty_sources.len()
+        );
+    }
+    if args.stable_seconds.is_some() {
+        say!(messages, "  Unstable, skipped:     {}", scan.unstable.len());
+    }
+    if scan.empty_outputs > 0 {
+        say!(
+            messages,
+            "  Empty outputs replaced: {}",
+            scan.empty_outputs_replaced
+        );
+    }
+    if args.revalidate_existing.is_some() {
+        say!(messages, "  Outputs revalidated:   {}", scan.revalidated);
+        say!(messages, "  Outputs repaired:      {}", scan.repaired.len());
+        for (output, reason) in &scan.repaired {
+            say!(messages, "    {}: {}", output.display(), reason);
+        }
+    }
+    if !scan.collided.is_empty() {
+        say!(messages, "  Collisions, left out:  {}", scan.collided.len());
+    }
+    if !scan.duplicates.is_empty() {
+        say!(
+            messages,
+            "  Duplicate paths:       {}",
+            scan.duplicates.len()
+        );
+    }
+    if args.content_addressed.is_some() {
+        say!(messages, "  Dedupe hits:           {}", tally.dedupe_hits);
+    }
+    if !tally.hardlinked.is_empty() {
+        say!(
+            messages,
+            "  Conversions avoided:   {} (hard links, {} copied instead)",
+            tally.hardlinked.len(),
+            tally.hardlink_copies
+        );
+    }
+    if scan.symlinks > 0 {
+        say!(messages, "  Symlinks found:        {}", scan.symlinks);
+        if !scan.skipped_symlinks.is_empty() {
+            say!(
+                messages,
+                "  Symlinks skipped:      {}",
+                scan.skipped_symlinks.len()
+            );
+        }
+    }
+    if !scan.dangling.is_empty() {
+        say!(messages, "  Dangling symlinks:     {}", scan.dangling.len());
+        for (file, target) in &scan.dangling {
+            say!(
+                messages,
+                "    Warning: {} -> {}",
+                file.display(),
+                target.display()
+            );
+        }
+    }
+    let pruned = &scan.pruned;
+    if pruned.dirs > 0 {
+        say!(messages, "  Directories pruned:    {}", pruned.dirs);
+    }
+    if args.filters.skip_hidden {
+        say!(
+            messages,
+            "  Hidden skipped:        {} files, {} directories",
+            pruned.hidden_files,
+            pruned.hidden_dirs
+        );
+    }
+    if args.respect_gitignore {
+        say!(
+            messages,
+            "  Gitignored:            {} files, {} directories",
+            pruned.ignored_files,
+            pruned.ignored_dirs
+        );
+    }
+    if !scan.inaccessible.is_empty() {
+        say!(
+            messages,
+            "  Inaccessible files:    {}",
+            scan.inaccessible.len()
+        );
+        for (file, reason) in &scan.inaccessible {
+            say!(messages, "    {}: {}", file.display(), reason);
+        }
+    }
+    if args.quality_gate.is_some() {
+        say!(messages, "  Kept original (gate):  {}", tally.kept_original);
+    }
+    if args.verify {
+        say!(messages, "  Verify failed, error:  {}", tally.verify_errors);
+        say!(
+            messages,
+            "  Verify failed, copied original: {}",
+            tally.verify_copied
+        );
+        say!(
+            messages,
+            "  Verify failed, kept both: {}",
+            tally.verify_kept_both
+        );
+    }
+    if args.preserve_xattrs {
+        say!(messages, "  Xattrs partly copied:  {}", tally.xattr_partial);
+    }
+    if args.preserve_owner || args.chown.is_some() {
+        say!(messages, "  Owner not set:         {}", tally.owner_failed);
+    }
+    if args.dir_mode.is_some() || args.file_mode.is_some() {
+        say!(messages, "  Mode not set:          {}", tally.mode_failed);
+    }
+    if args.strict {
+        say!(
+            messages,
+            "  Strict check failures: {}",
+            tally.discrepancies.len()
+        );
+    }
+    say!(
+        messages,
+        "  Peak conversions:      {}",
+        tally.peak_conversions
+    );
+    say!(messages, "  Peak copies:           {}", tally.peak_copies);
+    if !tally.retried.is_empty() {
+        say!(messages, "  Needed I/O retries:    {}", tally.retried.len());
+        for (file, retries) in &tally.retried {
+            say!(messages, "    {}: {} retries", file.display(), retries);
+        }
+    }
+    if args.target_size.is_some() {
+        say!(messages, "  Over target size:      {}", tally.over_target);
+    }
+    if args.prevalidate {
+        say!(
+            messages,
+            "  Failed validation:     {}",
+            scan.failed_validation.len()
+        );
+        say!(messages, "  Quarantined:           {}", tally.quarantined);
+    }
+    if args.dimension_limits().is_set() {
+        say!(
+            messages,
+            "  Outside dimensions:    {}",
+            scan.outside_dimensions.len()
+        );
+        say!(
+            messages,
+            "  Dimensions unknown:    {}",
+            scan.unknown_dimensions
+        );
+    }
+    say!(messages, "  Multi-page files:      {}", tally.multipage);
+    say!(messages, "  Pages emitted:         {}", tally.pages_emitted);
+    if args.archives != ArchiveMode::Skip {
+        say!(messages, "  Archives processed:    {}", tally.archives);
+        say!(
+            messages,
+            "  Archive pages converted: {}",
+            tally.archive_pages
+        );
+    }
+    say!(messages, "  Existing JXL copied:   {}", tally.jxl_copied);
+    say!(messages, "  Existing JXL skipped:  {}", tally.jxl_skipped);
+    say!(
+        messages,
+        "  Existing JXL recompressed: {}",
+        tally.jxl_recompressed
+    );
+    if args.existing_jxl == ExistingJxl::Recompress {
+        say!(
+            messages,
+            "  Reclaimed by recompression: {}",
+            human_bytes(tally.jxl_reclaimed as f64)
+        );
+    }
+    say!(
+        messages,
+        "  Total original size (converted files): {}",
+        human_bytes(tally.original_size as f64)
+    );
+    say!(
+        messages,
+        "  Total converted size (converted files): {}",
+        human_bytes(tally.converted_size as f64)
+    );
+    let saved = tally.original_size.saturating_sub(tally.converted_size);
+    say!(
+        messages,
+        "  Total storage saved (converted files): {}",
+        human_bytes(saved as f64)
+    );
+    say!(messages, "{}", "-".repeat(60));
+}
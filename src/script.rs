@@ -0,0 +1,336 @@
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use clap::ValueEnum;
+
+use crate::{
+    CopyMode,
+    process::{CommandRunner, Finished},
+};
+
+/// The shell `--emit-script` writes for.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// A POSIX shell script
+    Sh,
+    /// A Windows batch file for cmd.exe
+    Cmd,
+}
+
+/// Records the commands the encoders run instead of running them, as if
+/// each succeeded, so the script gets exactly the command lines of a run.
+#[derive(Default)]
+pub struct Recorder(Mutex<Vec<Vec<OsString>>>);
+
+impl Recorder {
+    /// The commands recorded since the last call, each program and arguments.
+    pub fn take(&self) -> Vec<Vec<OsString>> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+}
+
+impl CommandRunner for Recorder {
+    fn run<'a>(
+        &'a self,
+        command: &'a mut tokio::process::Command,
+        _verbose: bool,
+    ) -> std::pin::Pin<Box<dyn Future<Output = std::io::Result<Finished>> + Send + 'a>> {
+        let command = command.as_std();
+        let recorded = std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(OsStr::to_os_string)
+            .collect();
+        self.0.lock().unwrap().push(recorded);
+        Box::pin(async {
+            Ok(Finished {
+                status: std::process::ExitStatus::default(),
+                stdout: Vec::new(),
+                stderr_tail: Vec::new(),
+            })
+        })
+    }
+}
+
+/// One step of a line of the script. A line stops at the first step that
+/// fails, except that creating a directory that is there already is fine.
+pub enum Step {
+    /// Creates a directory with its parents.
+    Mkdir(PathBuf),
+    /// Runs a program with its arguments.
+    Run(Vec<OsString>),
+    /// Renames a finished output into place.
+    Move {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    Copy {
+        from: PathBuf,
+        to: PathBuf,
+        mode: CopyMode,
+    },
+    /// Gives `output` the modification time of `source`. Only in shell
+    /// scripts, cmd has nothing for it.
+    Touch {
+        source: PathBuf,
+        output: PathBuf,
+    },
+    /// Gives `path` the `--file-mode`. Only in shell scripts.
+    Chmod {
+        mode: u32,
+        path: PathBuf,
+    },
+    Symlink {
+        target: PathBuf,
+        link: PathBuf,
+    },
+}
+
+/// A script of a line per output, each standing alone so they can be run
+/// in any order or side by side.
+pub struct Script {
+    format: Format,
+    body: Vec<u8>,
+}
+
+impl Script {
+    pub fn new(format: Format) -> Self {
+        Script {
+            format,
+            body: Vec::new(),
+        }
+    }
+
+    pub fn comment(&mut self, text: &str) {
+        let text = text.replace('\n', "\\n").replace('\r', "\\r");
+        match self.format {
+            Format::Sh => self
+                .body
+                .extend_from_slice(format!("# {}\n", text).as_bytes()),
+            // Batch files expand variables even in comments.
+            Format::Cmd => self
+                .body
+                .extend_from_slice(format!("REM {}\n", text.replace('%', "%%")).as_bytes()),
+        }
+    }
+
+    /// Adds a line doing `steps` one after the other.
+    pub fn line(&mut self, steps: &[Step]) -> anyhow::Result<()> {
+        let mut line = Vec::new();
+        let mut separator = "";
+        for step in steps {
+            let words = match self.format {
+                Format::Sh => sh_step(step),
+                Format::Cmd => cmd_step(step),
+            };
+            let Some(words) = words else {
+                continue;
+            };
+            line.extend_from_slice(separator.as_bytes());
+            for (index, word) in words.iter().enumerate() {
+                if index > 0 {
+                    line.push(b' ');
+                }
+                match word {
+                    Word::Bare(word) => line.extend_from_slice(word.as_bytes()),
+                    Word::Mode(mode) => line.extend_from_slice(format!("{:o}", mode).as_bytes()),
+                    Word::Quoted(word) => match self.format {
+                        Format::Sh => line.extend_from_slice(&quote_sh(word)),
+                        Format::Cmd => line.extend_from_slice(quote_cmd(word)?.as_bytes()),
+                    },
+                }
+            }
+            // cmd's mkdir fails for a directory that is there already, so
+            // the line goes on whatever it says.
+            separator = match (self.format, step) {
+                (Format::Cmd, Step::Mkdir(_)) => " & ",
+                _ => " && ",
+            };
+        }
+        line.push(b'\n');
+        self.body.extend_from_slice(&line);
+        Ok(())
+    }
+
+    /// Writes the script to `path` after a header with `summary`, and makes
+    /// a shell script executable.
+    pub fn write(&self, path: &Path, summary: &str) -> std::io::Result<()> {
+        let header = match self.format {
+            Format::Sh => format!(
+                "#!/bin/sh\n\
+                 # Written by bulk-jxl --emit-script: {}.\n\
+                 # Every line stands alone, so they can be run side by side, e.g. with\n\
+                 # `grep -v '^#' {} | parallel`.\n",
+                summary,
+                path.file_name().unwrap_or_default().to_string_lossy()
+            ),
+            Format::Cmd => format!(
+                "@echo off\r\n\
+                 setlocal DisableDelayedExpansion\r\n\
+                 chcp 65001 >nul\r\n\
+                 REM Written by bulk-jxl --emit-script: {}.\r\n\
+                 REM Outputs don't get the modification time of their sources.\r\n",
+                summary
+            ),
+        };
+        let mut text = header.into_bytes();
+        match self.format {
+            Format::Sh => text.extend_from_slice(&self.body),
+            Format::Cmd => {
+                for line in self.body.split_inclusive(|&byte| byte == b'\n') {
+                    text.extend_from_slice(&line[..line.len() - 1]);
+                    text.extend_from_slice(b"\r\n");
+                }
+            }
+        }
+        std::fs::write(path, text)?;
+        #[cfg(unix)]
+        if self.format == Format::Sh {
+            use std::os::unix::fs::PermissionsExt;
+            let mut permissions = std::fs::metadata(path)?.permissions();
+            permissions.set_mode(permissions.mode() | 0o111);
+            std::fs::set_permissions(path, permissions)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Word<'a> {
+    /// Written as it is.
+    Bare(&'static str),
+    /// A file mode, in octal.
+    Mode(u32),
+    /// Quoted as the shell needs.
+    Quoted(&'a OsStr),
+}
+
+fn sh_step(step: &Step) -> Option<Vec<Word<'_>>> {
+    use Word::{Bare, Quoted};
+    Some(match step {
+        Step::Mkdir(dir) => vec![Bare("mkdir -p --"), Quoted(dir.as_os_str())],
+        Step::Run(command) => command.iter().map(|word| Quoted(word)).collect(),
+        Step::Move { from, to } => vec![
+            Bare("mv -f --"),
+            Quoted(from.as_os_str()),
+            Quoted(to.as_os_str()),
+        ],
+        Step::Copy { from, to, mode } => {
+            let (from, to) = (Quoted(from.as_os_str()), Quoted(to.as_os_str()));
+            match mode {
+                CopyMode::Auto | CopyMode::Content => vec![Bare("cp --"), from, to],
+                CopyMode::Reflink => vec![Bare("cp --reflink=always --"), from, to],
+                // Copied where it can't be linked, as a run does.
+                CopyMode::Hardlink => vec![
+                    Bare("{ ln --"),
+                    from,
+                    to,
+                    Bare("2>/dev/null || cp --"),
+                    from,
+                    to,
+                    Bare("; }"),
+                ],
+            }
+        }
+        Step::Touch { source, output } => vec![
+            Bare("touch -r"),
+            Quoted(source.as_os_str()),
+            Bare("--"),
+            Quoted(output.as_os_str()),
+        ],
+        Step::Chmod { mode, path } => vec![
+            Bare("chmod"),
+            Word::Mode(*mode),
+            Bare("--"),
+            Quoted(path.as_os_str()),
+        ],
+        Step::Symlink { target, link } => vec![
+            Bare("ln -s --"),
+            Quoted(target.as_os_str()),
+            Quoted(link.as_os_str()),
+        ],
+    })
+}
+
+fn cmd_step(step: &Step) -> Option<Vec<Word<'_>>> {
+    use Word::{Bare, Quoted};
+    Some(match step {
+        Step::Mkdir(dir) => vec![Bare("mkdir"), Quoted(dir.as_os_str()), Bare("2>nul")],
+        Step::Run(command) => command.iter().map(|word| Quoted(word)).collect(),
+        Step::Move { from, to } => vec![
+            Bare("move /Y"),
+            Quoted(from.as_os_str()),
+            Quoted(to.as_os_str()),
+            Bare(">nul"),
+        ],
+        Step::Copy { from, to, mode } => {
+            let (from, to) = (Quoted(from.as_os_str()), Quoted(to.as_os_str()));
+            match mode {
+                CopyMode::Hardlink => vec![
+                    Bare("(mklink /H"),
+                    to,
+                    from,
+                    Bare(">nul 2>nul || copy /Y"),
+                    from,
+                    to,
+                    Bare(">nul)"),
+                ],
+                _ => vec![Bare("copy /Y"), from, to, Bare(">nul")],
+            }
+        }
+        Step::Touch { .. } | Step::Chmod { .. } => return None,
+        Step::Symlink { target, link } => vec![
+            Bare("mklink"),
+            Quoted(link.as_os_str()),
+            Quoted(target.as_os_str()),
+            Bare(">nul"),
+        ],
+    })
+}
+
+/// `word` for a POSIX shell: as it is when nothing in it is special, and in
+/// single quotes otherwise, which keep everything but a single quote.
+pub fn quote_sh(word: &OsStr) -> Vec<u8> {
+    let bytes = word.as_encoded_bytes();
+    if !bytes.is_empty()
+        && bytes
+            .iter()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"%+,-./:@_".contains(byte))
+    {
+        return bytes.to_vec();
+    }
+    let mut quoted = vec![b'\''];
+    for &byte in bytes {
+        if byte == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(byte);
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+/// `word` in double quotes for cmd, which keep `&`, `|`, `<`, `>` and `^`
+/// from being special. Percent signs are doubled as batch files expand
+/// variables even in quotes, and trailing backslashes so they don't escape
+/// the closing quote for the program reading its arguments.
+fn quote_cmd(word: &OsStr) -> anyhow::Result<String> {
+    let word = word
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("{} isn't valid Unicode", word.display()))?;
+    if word.contains(['"', '\n', '\r']) {
+        return Err(anyhow::anyhow!(
+            "{:?} can't be written in a batch file",
+            word
+        ));
+    }
+    let trailing = word.len() - word.trim_end_matches('\\').len();
+    Ok(format!(
+        "\"{}{}\"",
+        word.replace('%', "%%"),
+        "\\".repeat(trailing)
+    ))
+}
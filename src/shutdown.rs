@@ -0,0 +1,99 @@
+/// A request from outside to stop, from the terminal or as service managers
+/// and container runtimes send it.
+#[derive(Clone, Copy)]
+pub enum Signal {
+    /// Ctrl-C in the terminal, SIGINT on Unix.
+    Interrupt,
+    #[cfg(unix)]
+    Terminate,
+    #[cfg(unix)]
+    Hangup,
+    /// The console window closing or the system shutting down on Windows.
+    #[cfg(windows)]
+    ConsoleClose,
+}
+
+impl Signal {
+    pub fn name(self) -> &'static str {
+        match self {
+            Signal::Interrupt => "Ctrl-C",
+            #[cfg(unix)]
+            Signal::Terminate => "SIGTERM",
+            #[cfg(unix)]
+            Signal::Hangup => "SIGHUP",
+            #[cfg(windows)]
+            Signal::ConsoleClose => "console close",
+        }
+    }
+
+    /// 128 plus the signal number, as shells report a process the signal
+    /// stopped, and that of SIGTERM for Windows console events.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Signal::Interrupt => 130,
+            #[cfg(unix)]
+            Signal::Terminate => 143,
+            #[cfg(unix)]
+            Signal::Hangup => 129,
+            #[cfg(windows)]
+            Signal::ConsoleClose => 143,
+        }
+    }
+}
+
+/// Listens for stop requests, for the command line to pass on to the run.
+/// Signals that come in while nothing is awaiting `recv` are kept.
+#[cfg(unix)]
+pub struct Listener {
+    interrupt: tokio::signal::unix::Signal,
+    terminate: tokio::signal::unix::Signal,
+    hangup: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Listener {
+    pub fn new() -> std::io::Result<Self> {
+        use tokio::signal::unix::{SignalKind, signal};
+        Ok(Listener {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+            hangup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    pub async fn recv(&mut self) -> Signal {
+        tokio::select! {
+            _ = self.interrupt.recv() => Signal::Interrupt,
+            _ = self.terminate.recv() => Signal::Terminate,
+            _ = self.hangup.recv() => Signal::Hangup,
+        }
+    }
+}
+
+// Windows only gives a few seconds after these before ending the process,
+// whatever the grace period.
+#[cfg(windows)]
+pub struct Listener {
+    interrupt: tokio::signal::windows::CtrlC,
+    close: tokio::signal::windows::CtrlClose,
+    shutdown: tokio::signal::windows::CtrlShutdown,
+}
+
+#[cfg(windows)]
+impl Listener {
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Listener {
+            interrupt: tokio::signal::windows::ctrl_c()?,
+            close: tokio::signal::windows::ctrl_close()?,
+            shutdown: tokio::signal::windows::ctrl_shutdown()?,
+        })
+    }
+
+    pub async fn recv(&mut self) -> Signal {
+        tokio::select! {
+            _ = self.interrupt.recv() => Signal::Interrupt,
+            _ = self.close.recv() => Signal::ConsoleClose,
+            _ = self.shutdown.recv() => Signal::ConsoleClose,
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::path::{Path, PathBuf};
+
+use crate::process::CommandRunner;
+
+/// Where the sidecar of an output file goes, e.g. `photo.jxl.xmp`. A sidecar
+/// belongs to its image and should be moved or removed along with it.
+pub fn path_for(output_file_path: &Path) -> PathBuf {
+    let mut path = output_file_path.as_os_str().to_owned();
+    path.push(".xmp");
+    PathBuf::from(path)
+}
+
+/// The metadata a source holds, as far as its format is read here.
+#[derive(Default, Debug, PartialEq)]
+pub struct Held {
+    pub exif: bool,
+    pub xmp: bool,
+    /// IPTC, PNG text chunks and the like, which a JPEG XL file has no box
+    /// for.
+    pub other: bool,
+}
+
+const XMP_NAMESPACE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const EXTENDED_XMP_NAMESPACE: &[u8] = b"http://ns.adobe.com/xmp/extension/\0";
+
+/// What metadata `source` holds, read from the segments of a JPEG and the
+/// chunks of a PNG or WebP file. `None` for other formats, which may hold
+/// anything.
+pub fn held(source: &Path) -> std::io::Result<Option<Held>> {
+    let data = std::fs::read(source)?;
+    let mut held = Held::default();
+    if data.starts_with(b"\xff\xd8") {
+        let mut position = 2;
+        // Metadata comes before the image data.
+        while let Some(&[0xff, marker, high, low]) = data.get(position..position + 4) {
+            if marker == 0xda {
+                break;
+            }
+            let end = position + 2 + u16::from_be_bytes([high, low]) as usize;
+            let segment = data.get(position + 4..end.min(data.len())).unwrap_or(&[]);
+            match marker {
+                0xe1 if segment.starts_with(b"Exif\0\0") => held.exif = true,
+                0xe1 if segment.starts_with(XMP_NAMESPACE)
+                    || segment.starts_with(EXTENDED_XMP_NAMESPACE) =>
+                {
+                    held.xmp = true
+                }
+                // ICC profiles (APP2) and JFIF or Adobe markers are carried
+                // by the pixels' encoding; comments, IPTC and the rest aren't.
+                0xe2 | 0xe0 | 0xee => {}
+                0xe1 | 0xe3..=0xef | 0xfe => held.other = true,
+                _ => {}
+            }
+            position = end;
+        }
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        let mut position = 8;
+        while let Some(header) = data.get(position..position + 8) {
+            let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+            let content = data
+                .get(position + 8..(position + 8).saturating_add(length).min(data.len()))
+                .unwrap_or(&[]);
+            match &header[4..8] {
+                b"eXIf" => held.exif = true,
+                b"iTXt" if content.starts_with(b"XML:com.adobe.xmp\0") => held.xmp = true,
+                b"tEXt" | b"zTXt" | b"iTXt" => held.other = true,
+                b"IEND" => break,
+                _ => {}
+            }
+            position = position.saturating_add(12 + length);
+        }
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        let mut position = 12;
+        while let Some(header) = data.get(position..position + 8) {
+            let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+            match &header[0..4] {
+                b"EXIF" => held.exif = true,
+                b"XMP " => held.xmp = true,
+                _ => {}
+            }
+            // Chunks are padded to an even length.
+            position = position.saturating_add(8 + length + length % 2);
+        }
+    } else {
+        return Ok(None);
+    }
+    Ok(Some(held))
+}
+
+/// Has exiftool write all metadata of `source` as XMP to `sidecar_path`.
+/// Returns `false` when the source has no metadata to write.
+pub async fn write(
+    runner: &dyn CommandRunner,
+    source: &Path,
+    sidecar_path: &Path,
+) -> anyhow::Result<bool> {
+    // exiftool refuses to overwrite, a leftover from an earlier run would fail.
+    if sidecar_path.exists() {
+        std::fs::remove_file(sidecar_path)?;
+    }
+    let output = runner
+        .run(
+            crate::process::command("exiftool")
+                .arg("-q")
+                .arg("-tagsfromfile")
+                .arg(crate::process::tool_arg(source))
+                .arg("-all:all")
+                .arg("-o")
+                .arg(crate::process::tool_arg(sidecar_path)),
+            false,
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to run exiftool: {}", e))?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "exiftool: {}",
+            output
+                .stderr_tail
+                .first()
+                .map_or("failed", |line| line.trim())
+        ));
+    }
+    Ok(sidecar_path.exists())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held_in(data: &[u8]) -> Option<Held> {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("source");
+        std::fs::write(&path, data).unwrap();
+        held(&path).unwrap()
+    }
+
+    /// A JPEG with `segments` of the given marker and content before its
+    /// image data.
+    fn jpeg(segments: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut data = b"\xff\xd8".to_vec();
+        for (marker, content) in segments {
+            data.extend_from_slice(&[0xff, *marker]);
+            data.extend_from_slice(&(content.len() as u16 + 2).to_be_bytes());
+            data.extend_from_slice(content);
+        }
+        // Looks like an Exif segment, but is image data.
+        data.extend_from_slice(b"\xff\xda\0\x08Exif\0\0\xff\xd9");
+        data
+    }
+
+    #[test]
+    fn jpeg_segments_tell_the_metadata() {
+        let exif = (0xe1, &b"Exif\0\0MM\0*"[..]);
+        let xmp = (0xe1, &b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta/>"[..]);
+        let icc = (0xe2, &b"ICC_PROFILE\0"[..]);
+        let iptc = (0xed, &b"Photoshop 3.0\0"[..]);
+
+        let only = |exif, xmp, other| Some(Held { exif, xmp, other });
+        assert_eq!(
+            held_in(&jpeg(&[(0xe0, b"JFIF\0")])),
+            only(false, false, false)
+        );
+        assert_eq!(held_in(&jpeg(&[exif, icc])), only(true, false, false));
+        assert_eq!(held_in(&jpeg(&[exif, xmp])), only(true, true, false));
+        assert_eq!(held_in(&jpeg(&[iptc])), only(false, false, true));
+        assert_eq!(
+            held_in(&jpeg(&[(0xfe, b"a comment")])),
+            only(false, false, true)
+        );
+    }
+
+    #[test]
+    fn png_and_webp_chunks_tell_the_metadata() {
+        let chunk = |kind: &[u8], content: &[u8]| {
+            let mut chunk = (content.len() as u32).to_be_bytes().to_vec();
+            chunk.extend_from_slice(kind);
+            chunk.extend_from_slice(content);
+            chunk.extend_from_slice(&[0; 4]);
+            chunk
+        };
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend(chunk(b"IHDR", &[0; 13]));
+        png.extend(chunk(b"eXIf", b"MM\0*"));
+        png.extend(chunk(b"iTXt", b"XML:com.adobe.xmp\0\0\0\0\0<x:xmpmeta/>"));
+        png.extend(chunk(b"IEND", b""));
+        let held = held_in(&png).unwrap();
+        assert!(held.exif && held.xmp && !held.other);
+
+        let mut webp = b"RIFF\0\0\0\0WEBP".to_vec();
+        for (kind, content) in [(b"VP8X", &[0u8; 10][..]), (b"EXIF", b"MM\0*\0")] {
+            webp.extend_from_slice(kind);
+            webp.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            webp.extend_from_slice(content);
+            webp.resize(webp.len() + content.len() % 2, 0);
+        }
+        let held = held_in(&webp).unwrap();
+        assert!(held.exif && !held.xmp && !held.other);
+
+        assert_eq!(held_in(b"II*\0 a TIFF"), None);
+    }
+}
@@ -0,0 +1,193 @@
+use std::{io::Read, path::Path};
+
+use crate::sniff::ImageKind;
+
+/// What `--smart` looks at to choose the settings for one source.
+pub struct SourceInfo<'a> {
+    /// Canonical extension of the source format.
+    pub format_extension: &'a str,
+    pub pix_fmt: &'a str,
+    pub width: u32,
+    pub height: u32,
+    /// Bits per sample, 0 when ffprobe doesn't know.
+    pub bit_depth: u32,
+    pub file_size: u64,
+    /// Estimated quality (1-100) of a JPEG source.
+    pub jpeg_quality: Option<u32>,
+}
+
+/// Encoder settings picked by `--smart`.
+pub struct Choice {
+    pub distance: Option<f32>,
+    pub jpeg_reconstruct: bool,
+    pub reason: &'static str,
+}
+
+// Screenshots and line art compress far better than photos in lossless formats.
+const SCREENSHOT_BYTES_PER_MEGAPIXEL: f64 = 1024.0 * 1024.0;
+
+/// Picks per-file settings from the source characteristics. This only looks
+/// at its argument so it can be reasoned about without any files.
+pub fn choose(info: &SourceInfo) -> Choice {
+    let lossless = |reason| Choice {
+        distance: Some(0.0),
+        jpeg_reconstruct: false,
+        reason,
+    };
+
+    if ImageKind::Jpeg.matches_extension(info.format_extension) {
+        return Choice {
+            distance: None,
+            jpeg_reconstruct: true,
+            reason: match info.jpeg_quality {
+                // Re-encoding an already heavily compressed JPEG would only add artifacts.
+                Some(quality) if quality < 50 => "low quality jpeg, reconstruction",
+                _ => "jpeg reconstruction",
+            },
+        };
+    }
+    if info.bit_depth > 8 || info.pix_fmt.contains("16") || info.pix_fmt.contains("f32") {
+        return lossless("high bit depth");
+    }
+    if matches!(info.pix_fmt, "pal8" | "monow" | "monob")
+        || ImageKind::Gif.matches_extension(info.format_extension)
+    {
+        return lossless("palette");
+    }
+
+    let megapixels = info.width as f64 * info.height as f64 / 1_000_000.0;
+    let is_lossless_source = !ImageKind::Webp.matches_extension(info.format_extension);
+    if is_lossless_source
+        && megapixels > 0.0
+        && (info.file_size as f64 / megapixels) < SCREENSHOT_BYTES_PER_MEGAPIXEL
+    {
+        return lossless("screenshot-like");
+    }
+
+    Choice {
+        distance: Some(1.0),
+        jpeg_reconstruct: false,
+        reason: "photographic",
+    }
+}
+
+// Luminance quantization table from Annex K of the JPEG standard, which
+// libjpeg scales by the quality setting.
+const STANDARD_LUMINANCE: [u32; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113,
+    92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+/// Estimates the quality a JPEG was saved at from its luminance quantization
+/// table, assuming the usual libjpeg scaling. `None` when there is no table.
+pub fn jpeg_quality(path: &Path) -> std::io::Result<Option<u32>> {
+    let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut marker = [0u8; 2];
+    file.read_exact(&mut marker)?;
+    if marker != [0xFF, 0xD8] {
+        return Ok(None);
+    }
+
+    loop {
+        file.read_exact(&mut marker)?;
+        if marker[0] != 0xFF {
+            return Ok(None);
+        }
+        // Start of scan, the tables come before it.
+        if marker[1] == 0xDA || marker[1] == 0xD9 {
+            return Ok(None);
+        }
+        let mut length = [0u8; 2];
+        file.read_exact(&mut length)?;
+        let length = u16::from_be_bytes(length).saturating_sub(2) as usize;
+        let mut segment = vec![0u8; length];
+        file.read_exact(&mut segment)?;
+
+        if marker[1] != 0xDB {
+            continue;
+        }
+        let mut position = 0;
+        while position < segment.len() {
+            let precision = segment[position] >> 4;
+            let table_id = segment[position] & 0x0F;
+            let entry_size = if precision == 0 { 1 } else { 2 };
+            let table = match segment.get(position + 1..position + 1 + 64 * entry_size) {
+                Some(table) => table,
+                None => return Ok(None),
+            };
+            if table_id == 0 {
+                let sum: u32 = table
+                    .chunks(entry_size)
+                    .map(|v| v.iter().fold(0u32, |acc, b| acc << 8 | *b as u32))
+                    .sum();
+                let scale = sum as f64 * 100.0 / STANDARD_LUMINANCE.iter().sum::<u32>() as f64;
+                let quality = if scale <= 100.0 {
+                    (200.0 - scale) / 2.0
+                } else {
+                    5000.0 / scale
+                };
+                return Ok(Some(quality.round().clamp(1.0, 100.0) as u32));
+            }
+            position += 1 + 64 * entry_size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(format_extension: &str) -> SourceInfo<'_> {
+        SourceInfo {
+            format_extension,
+            pix_fmt: "rgb24",
+            width: 4000,
+            height: 3000,
+            bit_depth: 8,
+            file_size: 30 << 20,
+            jpeg_quality: None,
+        }
+    }
+
+    #[test]
+    fn every_jpeg_extension_is_reconstructed() {
+        for extension in ["jpg", "jpeg", "jpe", "jfif"] {
+            let choice = choose(&info(extension));
+            assert!(choice.jpeg_reconstruct, "{}", extension);
+            assert_eq!(choice.reason, "jpeg reconstruction");
+        }
+        let choice = choose(&SourceInfo {
+            jpeg_quality: Some(30),
+            ..info("jpeg")
+        });
+        assert_eq!(choice.reason, "low quality jpeg, reconstruction");
+    }
+
+    #[test]
+    fn other_sources_are_chosen_by_their_pixels() {
+        assert_eq!(choose(&info("png")).reason, "photographic");
+        assert_eq!(choose(&info("webp")).reason, "photographic");
+        assert_eq!(choose(&info("gif")).reason, "palette");
+        let deep = SourceInfo {
+            pix_fmt: "rgb48le",
+            bit_depth: 16,
+            ..info("png")
+        };
+        assert_eq!(choose(&deep).reason, "high bit depth");
+        // Few bytes for its size, like a screenshot, but not from a lossy format.
+        let flat = SourceInfo {
+            file_size: 1 << 20,
+            ..info("png")
+        };
+        assert_eq!(choose(&flat).distance, Some(0.0));
+        assert_eq!(
+            choose(&SourceInfo {
+                file_size: 1 << 20,
+                ..info("webp")
+            })
+            .reason,
+            "photographic"
+        );
+    }
+}
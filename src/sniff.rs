@@ -0,0 +1,357 @@
+use std::{io::Read, path::Path};
+
+/// Extensions of the formats below whose files often don't start with the
+/// format's signature: DIBs without the file header of a BMP, JPIP streams
+/// and PGX components of JPEG 2000, and `.pic`, which Softimage and PICT
+/// files use as well as Radiance. A missing signature says nothing there.
+const UNSIGNED_EXTENSIONS: &[&str] = &["dib", "jpt", "pgx", "pic"];
+
+/// Image formats we can recognise from their leading bytes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ImageKind {
+    Jpeg,
+    Jxl,
+    Png,
+    Gif,
+    Webp,
+    Bmp,
+    Tiff,
+    Ico,
+    Psd,
+    Qoi,
+    Exr,
+    Jpeg2000,
+    Dds,
+    Hdr,
+    Pbm,
+    Pgm,
+    Ppm,
+    Pam,
+    Fits,
+    Sgi,
+    SunRaster,
+}
+
+impl ImageKind {
+    const ALL: &'static [ImageKind] = &[
+        ImageKind::Jpeg,
+        ImageKind::Jxl,
+        ImageKind::Png,
+        ImageKind::Gif,
+        ImageKind::Webp,
+        ImageKind::Bmp,
+        ImageKind::Tiff,
+        ImageKind::Ico,
+        ImageKind::Psd,
+        ImageKind::Qoi,
+        ImageKind::Exr,
+        ImageKind::Jpeg2000,
+        ImageKind::Dds,
+        ImageKind::Hdr,
+        ImageKind::Pbm,
+        ImageKind::Pgm,
+        ImageKind::Ppm,
+        ImageKind::Pam,
+        ImageKind::Fits,
+        ImageKind::Sgi,
+        ImageKind::SunRaster,
+    ];
+
+    /// The format a file extension normally stands for, if it has a signature we can check.
+    pub fn from_extension(extension: &str) -> Option<ImageKind> {
+        if UNSIGNED_EXTENSIONS.contains(&extension) {
+            return None;
+        }
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.matches_extension(extension))
+    }
+
+    /// Extensions commonly used for this format, the first is the canonical one.
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            ImageKind::Jpeg => &["jpg", "jpeg", "jpe", "jif", "jfif", "jfi"],
+            ImageKind::Jxl => &["jxl"],
+            ImageKind::Png => &["png"],
+            ImageKind::Gif => &["gif"],
+            ImageKind::Webp => &["webp"],
+            ImageKind::Bmp => &["bmp", "dib"],
+            ImageKind::Tiff => &["tif", "tiff"],
+            ImageKind::Ico => &["ico"],
+            ImageKind::Psd => &["psd"],
+            ImageKind::Qoi => &["qoi"],
+            ImageKind::Exr => &["exr"],
+            ImageKind::Jpeg2000 => &["jp2", "j2k", "jpt", "pgx"],
+            ImageKind::Dds => &["dds"],
+            ImageKind::Hdr => &["hdr", "pic"],
+            ImageKind::Pbm => &["pbm"],
+            ImageKind::Pgm => &["pgm", "pgmyuv"],
+            ImageKind::Ppm => &["ppm"],
+            ImageKind::Pam => &["pam"],
+            ImageKind::Fits => &["fits"],
+            ImageKind::Sgi => &["sgi"],
+            ImageKind::SunRaster => &["ras"],
+        }
+    }
+
+    /// The ffmpeg demuxer that reads this format regardless of the file name.
+    pub fn ffmpeg_format(self) -> &'static str {
+        match self {
+            ImageKind::Jpeg => "jpeg_pipe",
+            ImageKind::Jxl => "jpegxl_pipe",
+            ImageKind::Png => "png_pipe",
+            ImageKind::Gif => "gif",
+            ImageKind::Webp => "webp_pipe",
+            ImageKind::Bmp => "bmp_pipe",
+            ImageKind::Tiff => "tiff_pipe",
+            ImageKind::Ico => "ico",
+            ImageKind::Psd => "psd_pipe",
+            ImageKind::Qoi => "qoi_pipe",
+            ImageKind::Exr => "exr_pipe",
+            ImageKind::Jpeg2000 => "j2k_pipe",
+            ImageKind::Dds => "dds_pipe",
+            ImageKind::Hdr => "hdr_pipe",
+            ImageKind::Pbm => "pbm_pipe",
+            ImageKind::Pgm => "pgm_pipe",
+            ImageKind::Ppm => "ppm_pipe",
+            ImageKind::Pam => "pam_pipe",
+            ImageKind::Fits => "fits",
+            ImageKind::Sgi => "sgi_pipe",
+            ImageKind::SunRaster => "sunrast_pipe",
+        }
+    }
+
+    pub fn matches_extension(self, extension: &str) -> bool {
+        self.extensions().contains(&extension)
+    }
+}
+
+/// Identifies the image format of a file from its first bytes. Returns `None`
+/// for files that don't look like any image format we know.
+pub fn detect(path: &Path) -> std::io::Result<Option<ImageKind>> {
+    detect_in(&mut std::fs::File::open(path)?)
+}
+
+/// Like [`detect`], for a file that is already open, from where it is read.
+pub fn detect_in(file: &mut impl Read) -> std::io::Result<Option<ImageKind>> {
+    let mut header = [0u8; 16];
+    let mut len = 0;
+    while len < header.len() {
+        match file.read(&mut header[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(detect_bytes(&header[..len]))
+}
+
+/// Identifies the image format of `header`, the first 16 bytes of a file or
+/// fewer for a shorter one, with the matchers of `infer` and those of the
+/// formats it doesn't know. Formats whose magic is short or plain text (BMP,
+/// GIF, ICO, WebP, PNM) also need the fields after it to be plausible, so
+/// text files and other RIFF containers aren't taken for images.
+fn detect_bytes(header: &[u8]) -> Option<ImageKind> {
+    let kind = match MATCHERS.get(header)?.extension() {
+        "jpg" => ImageKind::Jpeg,
+        // Bare codestream or ISO BMFF container.
+        "jxl" => ImageKind::Jxl,
+        "png" => ImageKind::Png,
+        "gif" => ImageKind::Gif,
+        "webp" => ImageKind::Webp,
+        "tif" => ImageKind::Tiff,
+        "ico" => ImageKind::Ico,
+        "psd" => ImageKind::Psd,
+        "qoi" => ImageKind::Qoi,
+        "exr" => ImageKind::Exr,
+        "jp2" | "j2k" => ImageKind::Jpeg2000,
+        "dds" => ImageKind::Dds,
+        "hdr" => ImageKind::Hdr,
+        "fits" => ImageKind::Fits,
+        "sgi" => ImageKind::Sgi,
+        "ras" => ImageKind::SunRaster,
+        "bmp" => ImageKind::Bmp,
+        "pbm" => ImageKind::Pbm,
+        "pgm" => ImageKind::Pgm,
+        "ppm" => ImageKind::Ppm,
+        "pam" => ImageKind::Pam,
+        // Camera raw files, formats ffmpeg doesn't read and anything else.
+        _ => return None,
+    };
+    let plausible = match kind {
+        ImageKind::Gif => matches!(header.get(3..6), Some(b"87a" | b"89a")),
+        ImageKind::Webp => header.starts_with(b"RIFF"),
+        ImageKind::Ico => header.len() >= 6 && header[4..6] != [0, 0],
+        // "BM" alone is too common in text, the reserved header fields must be zero.
+        ImageKind::Bmp => header.len() >= 14 && header[6..10] == [0, 0, 0, 0],
+        _ => true,
+    };
+    plausible.then_some(kind)
+}
+
+/// The matchers of `infer`, after those of the formats it doesn't know,
+/// which it tries first.
+static MATCHERS: std::sync::LazyLock<infer::Infer> = std::sync::LazyLock::new(|| {
+    let mut matchers = infer::Infer::new();
+    let custom: [(&str, &str, infer::Matcher); 13] = [
+        // infer only knows the JP2 container.
+        ("image/x-jp2-codestream", "j2k", |header| {
+            header.starts_with(b"\xFF\x4F\xFF\x51")
+        }),
+        // And only classic TIFF, not BigTIFF.
+        ("image/tiff", "tif", |header| {
+            header.starts_with(b"II+\0") || header.starts_with(b"MM\0+")
+        }),
+        ("image/qoi", "qoi", |header| header.starts_with(b"qoif")),
+        ("image/x-exr", "exr", |header| {
+            header.starts_with(b"\x76\x2F\x31\x01")
+        }),
+        ("image/vnd.ms-dds", "dds", |header| {
+            header.starts_with(b"DDS ")
+        }),
+        ("image/vnd.radiance", "hdr", |header| {
+            header.starts_with(b"#?RADIANCE") || header.starts_with(b"#?RGBE")
+        }),
+        ("image/fits", "fits", |header| {
+            header.starts_with(b"SIMPLE  =")
+        }),
+        ("image/x-sgi", "sgi", |header| {
+            header.starts_with(b"\x01\xDA")
+        }),
+        ("image/x-cmu-raster", "ras", |header| {
+            header.starts_with(b"\x59\xA6\x6A\x95")
+        }),
+        ("image/x-portable-bitmap", "pbm", |header| {
+            is_pnm(header, b"14", u8::is_ascii_digit)
+        }),
+        ("image/x-portable-graymap", "pgm", |header| {
+            is_pnm(header, b"25", u8::is_ascii_digit)
+        }),
+        ("image/x-portable-pixmap", "ppm", |header| {
+            is_pnm(header, b"36", u8::is_ascii_digit)
+        }),
+        ("image/x-portable-arbitrarymap", "pam", |header| {
+            is_pnm(header, b"7", u8::is_ascii_uppercase)
+        }),
+    ];
+    for (mime_type, extension, matcher) in custom {
+        matchers.add(mime_type, extension, matcher);
+    }
+    matchers
+});
+
+/// Whether `header` starts like a Netpbm file with one of `numbers` after
+/// the `P`, followed by whitespace and then a comment or a first field that
+/// `field` accepts: the width, or a named field for PAM.
+fn is_pnm(header: &[u8], numbers: &[u8], field: fn(&u8) -> bool) -> bool {
+    if header.len() < 3
+        || header[0] != b'P'
+        || !numbers.contains(&header[1])
+        || !header[2].is_ascii_whitespace()
+    {
+        return false;
+    }
+    let next = header[2..].iter().find(|b| !b.is_ascii_whitespace());
+    next.is_none_or(|b| *b == b'#' || field(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A header of every signature `detect_bytes` knows, with its format.
+    const SIGNATURES: &[(&[u8], ImageKind)] = &[
+        (b"\xFF\xD8\xFF\xE0\0\x10JFIF\0", ImageKind::Jpeg),
+        (b"\xFF\x0A\xFA\x7F", ImageKind::Jxl),
+        (b"\0\0\0\x0CJXL \r\n\x87\n\0\0", ImageKind::Jxl),
+        (b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", ImageKind::Png),
+        (b"GIF89a\x10\0\x0C\0", ImageKind::Gif),
+        (b"GIF87a\x10\0\x0C\0", ImageKind::Gif),
+        (b"RIFF\x24\0\0\0WEBPVP8 ", ImageKind::Webp),
+        (b"BM\x36\x03\0\0\0\0\0\0\x36\0\0\0", ImageKind::Bmp),
+        (b"II*\0\x08\0\0\0\x0C\0", ImageKind::Tiff),
+        (b"MM\0*\0\0\0\x08\0\x0C", ImageKind::Tiff),
+        (b"II+\0\x08\0\0\0", ImageKind::Tiff),
+        (b"MM\0+\0\x08\0\0", ImageKind::Tiff),
+        (b"\0\0\x01\0\x03\0\x10\x10", ImageKind::Ico),
+        (b"8BPS\0\x01", ImageKind::Psd),
+        (b"qoif\0\0\0\x10", ImageKind::Qoi),
+        (b"\x76\x2F\x31\x01\x02\0", ImageKind::Exr),
+        (b"\0\0\0\x0CjP  \r\n\x87\n\0\0\0\x14", ImageKind::Jpeg2000),
+        (b"\xFF\x4F\xFF\x51\0\x2F", ImageKind::Jpeg2000),
+        (b"DDS \x7C\0\0\0", ImageKind::Dds),
+        (b"#?RADIANCE\n", ImageKind::Hdr),
+        (b"#?RGBE\n", ImageKind::Hdr),
+        (b"P1\n16 12\n", ImageKind::Pbm),
+        (b"P4 16 12\n", ImageKind::Pbm),
+        (b"P2\n16 12\n255\n", ImageKind::Pgm),
+        (b"P5\n# GIMP\n16 12\n", ImageKind::Pgm),
+        (b"P3\n16 12\n255\n", ImageKind::Ppm),
+        (b"P6\n16 12\n255\n", ImageKind::Ppm),
+        (b"P7\nWIDTH 16\n", ImageKind::Pam),
+        (b"SIMPLE  =                    T", ImageKind::Fits),
+        (b"\x01\xDA\x01\x01\0\x03", ImageKind::Sgi),
+        (b"\x59\xA6\x6A\x95\0\0\0\x10", ImageKind::SunRaster),
+    ];
+
+    #[test]
+    fn every_format_is_recognised_by_its_signature() {
+        for (header, kind) in SIGNATURES {
+            assert_eq!(detect_bytes(header), Some(*kind), "{:?}", header);
+        }
+        // A format added to the list needs its signature above as well.
+        for kind in ImageKind::ALL {
+            assert!(
+                SIGNATURES.iter().any(|(_, signed)| signed == kind),
+                "{:?}",
+                kind
+            );
+        }
+    }
+
+    #[test]
+    fn signatures_are_found_in_files() {
+        let temp = tempfile::tempdir().unwrap();
+        for (index, (header, kind)) in SIGNATURES.iter().enumerate() {
+            // Named without an extension, the contents alone tell.
+            let path = temp.path().join(index.to_string());
+            std::fs::write(&path, header).unwrap();
+            assert_eq!(detect(&path).unwrap(), Some(*kind), "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn extensions_without_a_reliable_signature_are_not_checked() {
+        assert_eq!(ImageKind::from_extension("bmp"), Some(ImageKind::Bmp));
+        assert_eq!(ImageKind::from_extension("j2k"), Some(ImageKind::Jpeg2000));
+        for extension in ["dib", "jpt", "pgx", "pic"] {
+            assert_eq!(ImageKind::from_extension(extension), None, "{}", extension);
+        }
+        // They still name their format.
+        assert!(ImageKind::Bmp.matches_extension("dib"));
+        assert!(ImageKind::Jpeg2000.matches_extension("pgx"));
+        assert!(ImageKind::Hdr.matches_extension("pic"));
+    }
+
+    #[test]
+    fn lookalikes_are_not_images() {
+        let headers: &[&[u8]] = &[
+            b"",
+            b"\xFF\xD8",
+            // Text starting like a BMP or a PNM file.
+            b"BMW service history, 2019",
+            b"P6 is the next step",
+            b"P7 ships next week",
+            b"PS: see attached",
+            // A RIFF container holding audio.
+            b"RIFF\x24\0\0\0WAVEfmt ",
+            // An ICO header that claims no images.
+            b"\0\0\x01\0\0\0",
+            b"GIF90a",
+            b"plain text",
+        ];
+        for header in headers {
+            assert_eq!(detect_bytes(header), None, "{:?}", header);
+        }
+    }
+}
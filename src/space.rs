@@ -0,0 +1,37 @@
+use std::path::Path;
+
+/// Bytes available to us on the filesystem holding `path`, `None` on
+/// platforms where this isn't queried.
+#[cfg(unix)]
+pub fn available(path: &Path) -> std::io::Result<Option<u64>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is NUL-terminated and `stat` is only read once statvfs
+    // has filled it in.
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    // The field types differ between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+pub fn available(_path: &Path) -> std::io::Result<Option<u64>> {
+    Ok(None)
+}
+
+/// Whether a file failed because the output filesystem is full. External
+/// tools only say so in their messages.
+pub fn is_out_of_space(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::StorageFull)
+    }) || format!("{:#}", error).contains("No space left on device")
+}
@@ -0,0 +1,134 @@
+use std::path::Path;
+
+use crate::ratelimit::{self, RateLimit};
+
+/// Copies `from` to `to` like `std::fs::copy`, but leaves the holes of sparse
+/// files unallocated in the copy on Linux, where disk images and databases
+/// would otherwise take their full logical size. Only the data is copied
+/// within `limit`, holes cost nothing. Returns the logical size.
+pub fn copy(from: &Path, to: &Path, limit: Option<&RateLimit>) -> std::io::Result<u64> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = std::fs::metadata(from)?;
+        // Blocks are counted in 512-byte units whatever the filesystem's.
+        if metadata.is_file() && metadata.blocks() * 512 < metadata.len() {
+            return linux::copy_sparse(from, to, &metadata, limit);
+        }
+    }
+    match limit {
+        Some(limit) => ratelimit::copy(from, to, limit),
+        None => std::fs::copy(from, to),
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        fs::{File, Metadata},
+        io::{Read, Seek, SeekFrom, Write},
+        os::fd::AsRawFd,
+        path::Path,
+    };
+
+    use crate::ratelimit::RateLimit;
+
+    /// Copies only the data regions of `from`, found with SEEK_DATA and
+    /// SEEK_HOLE, into a file of the same length.
+    pub fn copy_sparse(
+        from: &Path,
+        to: &Path,
+        metadata: &Metadata,
+        limit: Option<&RateLimit>,
+    ) -> std::io::Result<u64> {
+        let mut source = File::open(from)?;
+        let mut target = File::create(to)?;
+        let len = metadata.len();
+        target.set_len(len)?;
+
+        let mut buffer = vec![0u8; 1 << 20];
+        let mut position = 0;
+        while position < len {
+            let Some(data) = seek(&source, position, libc::SEEK_DATA)? else {
+                break;
+            };
+            let hole = seek(&source, data, libc::SEEK_HOLE)?.unwrap_or(len);
+            source.seek(SeekFrom::Start(data))?;
+            target.seek(SeekFrom::Start(data))?;
+            let mut remaining = hole - data;
+            while remaining > 0 {
+                let chunk = remaining.min(buffer.len() as u64) as usize;
+                if let Some(limit) = limit {
+                    limit.take_blocking(chunk as u64);
+                }
+                source.read_exact(&mut buffer[..chunk])?;
+                target.write_all(&buffer[..chunk])?;
+                remaining -= chunk as u64;
+            }
+            position = hole;
+        }
+        target.set_permissions(metadata.permissions())?;
+        Ok(len)
+    }
+
+    /// Where the next data or hole at or after `offset` starts, `None` when
+    /// there is no further data.
+    fn seek(file: &File, offset: u64, whence: libc::c_int) -> std::io::Result<Option<u64>> {
+        // SAFETY: lseek only moves the offset of a descriptor we own.
+        let result = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+        if result < 0 {
+            let error = std::io::Error::last_os_error();
+            return match error.raw_os_error() {
+                Some(libc::ENXIO) => Ok(None),
+                _ => Err(error),
+            };
+        }
+        Ok(Some(result as u64))
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::{
+        io::{Seek, SeekFrom, Write},
+        os::unix::fs::MetadataExt,
+    };
+
+    use super::*;
+
+    /// A 64 MiB file holding a few bytes at its start, middle and end.
+    fn write_sparse(path: &Path) {
+        let mut file = std::fs::File::create(path).unwrap();
+        for offset in [0, 32 << 20, (64 << 20) - 5] {
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.write_all(b"data!").unwrap();
+        }
+    }
+
+    #[test]
+    fn holes_stay_holes_with_and_without_a_limit() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("disk.img");
+        write_sparse(&source);
+        let allocated = |path: &Path| std::fs::metadata(path).unwrap().blocks() * 512;
+        // Not every filesystem keeps holes, there's nothing to check there.
+        if allocated(&source) >= 1 << 20 {
+            return;
+        }
+        let limit = RateLimit::new(1 << 30);
+        for (name, limit) in [("plain.img", None), ("limited.img", Some(&limit))] {
+            let target = temp.path().join(name);
+            assert_eq!(copy(&source, &target, limit).unwrap(), 64 << 20);
+            assert_eq!(
+                std::fs::read(&target).unwrap(),
+                std::fs::read(&source).unwrap()
+            );
+            // Blocks for the data and maybe a few of metadata, not 64 MiB.
+            assert!(
+                allocated(&target) <= allocated(&source) + (1 << 20),
+                "{}",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    collections::HashSet,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use tokio::sync::mpsc::Sender;
+
+use crate::{
+    CollisionMode, Context, FileKind, PlannedFile, Pruned, RunPaths, Scan, Seen, SourceFile,
+    collision,
+};
+
+/// Walks the input for `--stream` and sends the files of every directory to
+/// `sender` as soon as they are planned, so conversions start while the walk
+/// goes on. Counts the files sent in `found`, and stops early once the
+/// receiver is gone.
+pub fn walk(
+    context: &Context,
+    paths: &RunPaths,
+    sender: Sender<PlannedFile>,
+    found: &AtomicUsize,
+) -> Scan {
+    let (args, messages) = (&context.args, &context.messages);
+    let mut scan = Scan::default();
+    let mut walkdir = walkdir::WalkDir::new(&paths.input)
+        .follow_links(args.follow_symlinks)
+        // Files before subdirectories, so those of a directory come in one go,
+        // and sorted, so duplicates resolve to the same path on every run.
+        .sort_by(|a, b| {
+            (a.file_type().is_dir().cmp(&b.file_type().is_dir()))
+                .then_with(|| a.file_name().cmp(b.file_name()))
+        });
+    if !args.tree.recursive {
+        walkdir = walkdir.max_depth(1);
+    }
+
+    let mut seen = Seen::new(paths);
+    let mut directory: Vec<SourceFile> = Vec::new();
+    let mut pruned = Pruned::default();
+    let mut gitignore = args
+        .respect_gitignore
+        .then(|| crate::gitignore::Stack::new(messages));
+    let entries = walkdir
+        .into_iter()
+        .filter_entry(|e| !pruned.prunes(e, context, gitignore.as_mut()) && paths.is_walked(e));
+    for entry in entries {
+        if sender.is_closed() {
+            return scan;
+        }
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                messages.say(format!("   Warning: {}", e));
+                scan.walk_warnings += 1;
+                continue;
+            }
+        };
+        if !crate::is_candidate(&entry, context)
+            || (entry.path_is_symlink() && !scan.symlink(&entry, context))
+        {
+            continue;
+        }
+        let mut source = SourceFile::walked(&entry, args);
+        let size = crate::readable_size(&mut source, context);
+        if scan.is_duplicate(&source, &mut seen, context) {
+            continue;
+        }
+        if scan.readable(&source, size, context).is_none() {
+            continue;
+        }
+        if directory
+            .last()
+            .is_some_and(|last| last.path.parent() != source.path.parent())
+            && !send_directory(
+                std::mem::take(&mut directory),
+                context,
+                paths,
+                &sender,
+                found,
+                &mut scan,
+            )
+        {
+            return scan;
+        }
+        directory.push(source);
+    }
+    send_directory(directory, context, paths, &sender, found, &mut scan);
+    scan.pruned = pruned;
+    scan
+}
+
+/// Plans the files of one input directory and sends them. Collisions can only
+/// be found among these and the files already in their output directory, so
+/// outputs of directories whose names differ only in case aren't checked
+/// against each other. Returns false once the receiver is gone.
+fn send_directory(
+    sources: Vec<SourceFile>,
+    context: &Context,
+    paths: &RunPaths,
+    sender: &Sender<PlannedFile>,
+    found: &AtomicUsize,
+    scan: &mut Scan,
+) -> bool {
+    let (args, messages) = (&context.args, &context.messages);
+    let mut planned = sources
+        .into_iter()
+        .filter_map(|source| {
+            let kind = FileKind::of(&source, context);
+            let relative = source.path.strip_prefix(&paths.input).ok()?;
+            let output = kind.output_path(relative, &paths.output, args);
+            Some((source, kind, output))
+        })
+        .collect::<Vec<_>>();
+
+    // Hard links among the files of the directory come last, so they give
+    // way to the planned files.
+    let mut links = scan.take_links(context, paths);
+    // The outputs of one input directory all go to the same output directory.
+    let existing = planned
+        .iter()
+        .filter_map(|(_, _, output)| output.as_deref())
+        .chain(links.iter().map(|(_, output)| output.as_path()))
+        .find_map(|output| output.parent())
+        .and_then(|dir| std::fs::read_dir(dir).ok())
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let collisions = collision::resolve(
+        planned
+            .iter_mut()
+            .filter_map(|(source, _, output)| Some((source.path.as_path(), output.as_mut()?)))
+            .chain(
+                links
+                    .iter_mut()
+                    .map(|(link, output)| (link.as_path(), output)),
+            ),
+        &existing,
+        args.on_collision == CollisionMode::Suffix,
+    );
+    for collision in &collisions {
+        crate::print_collision(collision, messages);
+    }
+    // Conversions are already running, so colliding files are left out
+    // instead of stopping the run.
+    if args.on_collision == CollisionMode::Error {
+        let collided = collisions
+            .iter()
+            .map(|collision| collision.source.as_path())
+            .collect::<HashSet<_>>();
+        for (source, _, _) in &planned {
+            if collided.contains(source.path.as_path()) {
+                scan.left_out(source);
+            }
+        }
+        planned.retain(|(source, _, _)| !collided.contains(source.path.as_path()));
+        // A hard link whose output is taken stays a duplicate without one.
+        let is_link = |source: &Path| links.iter().any(|(link, _)| link == source);
+        scan.collided.extend(
+            collisions
+                .iter()
+                .filter(|collision| !is_link(&collision.source))
+                .map(|collision| {
+                    (
+                        collision.source.clone(),
+                        format!(
+                            "output {} is taken by {}",
+                            collision.output.display(),
+                            collision.holder.display()
+                        ),
+                    )
+                }),
+        );
+        links.retain(|(link, _)| !collided.contains(link.as_path()));
+    }
+    scan.link_outputs.extend(links);
+
+    for (source, _, output) in &planned {
+        let Some(output) = output else {
+            continue;
+        };
+        if crate::is_empty_output(source, output) {
+            scan.replace_empty_output(output, messages);
+        }
+        if let Some(reason) = scan.revalidate(source, output, args) {
+            scan.replace_invalid_output(output, reason, messages);
+        }
+        if crate::is_long_output(output) {
+            messages.say(format!(
+                "   Warning: output path is {} characters or longer: {}",
+                crate::MAX_PATH,
+                output.display()
+            ));
+        }
+    }
+
+    for planned in planned {
+        found.fetch_add(1, Ordering::Relaxed);
+        if sender.blocking_send(planned).is_err() {
+            return false;
+        }
+    }
+    true
+}
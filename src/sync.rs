@@ -0,0 +1,57 @@
+use std::{fs::File, path::Path};
+
+/// Flushes `path` to the disk, then the directory entry that names it.
+pub fn file_and_dir(path: &Path) -> std::io::Result<()> {
+    open(path)?.sync_all()?;
+    // Windows can't open directories as files, and writes their entries out
+    // along with the file.
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        let parent = if parent.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            parent
+        };
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Flushes everything still buffered for the filesystem `path` is on.
+#[cfg(target_os = "linux")]
+pub fn filesystem(path: &Path) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let dir = File::open(path)?;
+    // SAFETY: syncfs only reads the descriptor, which is ours.
+    if unsafe { libc::syncfs(dir.as_raw_fd()) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Other Unixes only offer syncing every filesystem.
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn filesystem(_path: &Path) -> std::io::Result<()> {
+    // SAFETY: sync has no preconditions and can't fail.
+    unsafe { libc::sync() };
+    Ok(())
+}
+
+// Each output was already flushed on its own, which is all Windows offers
+// without administrator rights.
+#[cfg(not(unix))]
+pub fn filesystem(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+// Flushing a file takes write access on Windows, while Unix syncs read-only
+// descriptors and outputs may have kept a read-only mode from their source.
+#[cfg(unix)]
+fn open(path: &Path) -> std::io::Result<File> {
+    File::open(path)
+}
+
+#[cfg(not(unix))]
+fn open(path: &Path) -> std::io::Result<File> {
+    std::fs::OpenOptions::new().write(true).open(path)
+}
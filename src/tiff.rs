@@ -0,0 +1,253 @@
+use std::{
+    collections::HashSet,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+// Guards against IFD chains that loop back on themselves or never end in corrupt files.
+const MAX_PAGES: usize = 65_536;
+
+// NewSubfileType, whose lowest bit marks a reduced-resolution copy of another
+// image, and the older SubfileType, where 2 means the same.
+const NEW_SUBFILE_TYPE: u16 = 254;
+const SUBFILE_TYPE: u16 = 255;
+
+/// Finds the pages of a TIFF or BigTIFF file by walking the IFD chain in the
+/// header, without decoding any image data. Returns the number of the image
+/// file directory of each page, counted from 1 as ffmpeg selects them;
+/// thumbnails and the other reduced-resolution copies cameras and scanners
+/// store next to a page aren't pages of their own.
+pub fn pages(path: &Path) -> anyhow::Result<Vec<usize>> {
+    pages_in(std::io::BufReader::new(std::fs::File::open(path)?))
+}
+
+fn pages_in(mut file: impl Read + Seek) -> anyhow::Result<Vec<usize>> {
+    let mut header = [0u8; 4];
+    file.read_exact(&mut header)?;
+    let little_endian = match &header[..2] {
+        b"II" => true,
+        b"MM" => false,
+        _ => return Err(anyhow::anyhow!("Not a TIFF file")),
+    };
+    let magic = u16_from([header[2], header[3]], little_endian);
+
+    // Classic TIFF uses 32-bit offsets and 12-byte entries, BigTIFF 64-bit offsets and 20-byte entries.
+    let big_tiff = match magic {
+        42 => false,
+        43 => {
+            let mut rest = [0u8; 4];
+            file.read_exact(&mut rest)?;
+            true
+        }
+        _ => return Err(anyhow::anyhow!("Unknown TIFF version {}", magic)),
+    };
+
+    let mut offset = read_offset(&mut file, little_endian, big_tiff)?;
+    let mut visited = HashSet::new();
+    let mut pages = Vec::new();
+
+    while offset != 0 {
+        if !visited.insert(offset) || visited.len() > MAX_PAGES {
+            return Err(anyhow::anyhow!("Corrupt TIFF directory chain"));
+        }
+
+        file.seek(SeekFrom::Start(offset))?;
+        let (entry_count, entry_size) = if big_tiff {
+            (read_offset(&mut file, little_endian, true)?, 20)
+        } else {
+            let mut buf = [0u8; 2];
+            file.read_exact(&mut buf)?;
+            (u16_from(buf, little_endian) as u64, 12)
+        };
+        if entry_count > u16::MAX as u64 {
+            return Err(anyhow::anyhow!("Corrupt TIFF directory"));
+        }
+        let mut entries = vec![0u8; entry_count as usize * entry_size];
+        file.read_exact(&mut entries)?;
+        let reduced = entries
+            .chunks_exact(entry_size)
+            .any(|entry| is_reduced_resolution(entry, little_endian, big_tiff));
+        if !reduced {
+            pages.push(visited.len());
+        }
+        offset = read_offset(&mut file, little_endian, big_tiff)?;
+    }
+
+    Ok(pages)
+}
+
+/// Whether the IFD `entry` says its image is a reduced-resolution copy.
+fn is_reduced_resolution(entry: &[u8], little_endian: bool, big_tiff: bool) -> bool {
+    let tag = u16_from([entry[0], entry[1]], little_endian);
+    let field_type = u16_from([entry[2], entry[3]], little_endian);
+    // Small enough to be stored in the entry itself, after the count.
+    let value = &entry[if big_tiff { 12 } else { 8 }..];
+    let value = match field_type {
+        // SHORT
+        3 => u16_from([value[0], value[1]], little_endian) as u64,
+        // LONG
+        4 => read_offset(&mut &value[..4], little_endian, false).unwrap_or(0),
+        // LONG8, which only BigTIFF has room for.
+        16 if big_tiff => read_offset(&mut &value[..8], little_endian, true).unwrap_or(0),
+        _ => return false,
+    };
+    match tag {
+        NEW_SUBFILE_TYPE => value & 1 != 0,
+        SUBFILE_TYPE => value == 2,
+        _ => false,
+    }
+}
+
+fn u16_from(bytes: [u8; 2], little_endian: bool) -> u16 {
+    if little_endian {
+        u16::from_le_bytes(bytes)
+    } else {
+        u16::from_be_bytes(bytes)
+    }
+}
+
+fn read_offset<R: Read>(
+    reader: &mut R,
+    little_endian: bool,
+    big_tiff: bool,
+) -> anyhow::Result<u64> {
+    if big_tiff {
+        let mut buf = [0u8; 8];
+        reader.read_exact(&mut buf)?;
+        Ok(if little_endian {
+            u64::from_le_bytes(buf)
+        } else {
+            u64::from_be_bytes(buf)
+        })
+    } else {
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf)?;
+        Ok(if little_endian {
+            u32::from_le_bytes(buf) as u64
+        } else {
+            u32::from_be_bytes(buf) as u64
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A TIFF of directories laid out one after the other, each holding only
+    /// a NewSubfileType entry where one is given, and pointing at the next
+    /// or, for the last, at `last_next`.
+    fn tiff(
+        little_endian: bool,
+        big_tiff: bool,
+        subfile_types: &[Option<u32>],
+        last_next: u64,
+    ) -> Vec<u8> {
+        let u16_bytes = |value: u16| {
+            if little_endian {
+                value.to_le_bytes().to_vec()
+            } else {
+                value.to_be_bytes().to_vec()
+            }
+        };
+        let offset_bytes = |value: u64| match (big_tiff, little_endian) {
+            (true, true) => value.to_le_bytes().to_vec(),
+            (true, false) => value.to_be_bytes().to_vec(),
+            (false, true) => (value as u32).to_le_bytes().to_vec(),
+            (false, false) => (value as u32).to_be_bytes().to_vec(),
+        };
+
+        let mut data = if little_endian {
+            b"II".to_vec()
+        } else {
+            b"MM".to_vec()
+        };
+        if big_tiff {
+            data.extend(u16_bytes(43));
+            data.extend(u16_bytes(8));
+            data.extend(u16_bytes(0));
+        } else {
+            data.extend(u16_bytes(42));
+        }
+        let first = data.len() as u64 + if big_tiff { 8 } else { 4 };
+        data.extend(offset_bytes(first));
+        for (index, subfile_type) in subfile_types.iter().enumerate() {
+            let count = subfile_type.is_some() as u64;
+            if big_tiff {
+                data.extend(offset_bytes(count));
+            } else {
+                data.extend(u16_bytes(count as u16));
+            }
+            if let Some(value) = subfile_type {
+                data.extend(u16_bytes(NEW_SUBFILE_TYPE));
+                data.extend(u16_bytes(4));
+                data.extend(offset_bytes(1));
+                let value = if little_endian {
+                    value.to_le_bytes()
+                } else {
+                    value.to_be_bytes()
+                };
+                data.extend(value);
+                if big_tiff {
+                    data.extend([0; 4]);
+                }
+            }
+            let next = if index + 1 == subfile_types.len() {
+                last_next
+            } else {
+                data.len() as u64 + if big_tiff { 8 } else { 4 }
+            };
+            data.extend(offset_bytes(next));
+        }
+        data
+    }
+
+    fn pages_of(data: Vec<u8>) -> anyhow::Result<Vec<usize>> {
+        pages_in(std::io::Cursor::new(data))
+    }
+
+    #[test]
+    fn every_directory_of_either_byte_order_is_a_page() {
+        for little_endian in [true, false] {
+            for big_tiff in [false, true] {
+                let data = tiff(little_endian, big_tiff, &[None, Some(0), None], 0);
+                assert_eq!(pages_of(data).unwrap(), [1, 2, 3]);
+            }
+        }
+    }
+
+    #[test]
+    fn reduced_resolution_copies_are_not_pages() {
+        for little_endian in [true, false] {
+            for big_tiff in [false, true] {
+                // A page, its thumbnail, a second page and its preview, which
+                // is also marked as part of a multi-page image.
+                let data = tiff(
+                    little_endian,
+                    big_tiff,
+                    &[Some(0), Some(1), None, Some(3)],
+                    0,
+                );
+                assert_eq!(pages_of(data).unwrap(), [1, 3]);
+            }
+        }
+    }
+
+    #[test]
+    fn chains_that_loop_are_corrupt() {
+        // The last directory points back at the first.
+        let data = tiff(true, false, &[None, None], 8);
+        assert!(pages_of(data).is_err());
+        let data = tiff(false, true, &[None, None], 16);
+        assert!(pages_of(data).is_err());
+    }
+
+    #[test]
+    fn truncated_files_are_corrupt() {
+        let mut data = tiff(true, false, &[None, Some(0)], 0);
+        data.truncate(data.len() - 6);
+        assert!(pages_of(data).is_err());
+        assert!(pages_of(b"II*\0\x08\0".to_vec()).is_err());
+        assert!(pages_of(b"GIF89a".to_vec()).is_err());
+    }
+}
@@ -0,0 +1,67 @@
+use std::{fs::Metadata, path::Path, time::Duration};
+
+use clap::ValueEnum;
+use filetime::FileTime;
+
+/// Which timestamps of the source an output gets.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PreserveTimes {
+    /// The modification time
+    Modified,
+    /// The modification time and, on Windows and macOS, the creation time
+    All,
+}
+
+/// Gives `output` the timestamps of the source with `source` metadata.
+/// Filesystems that store modification times coarsely round them; within
+/// `tolerance` that returns how far off the time reads back, beyond it that
+/// fails.
+pub fn preserve(
+    source: &Metadata,
+    output: &Path,
+    which: PreserveTimes,
+    tolerance: Duration,
+) -> std::io::Result<Duration> {
+    filetime::set_file_mtime(output, FileTime::from_last_modification_time(source))?;
+    if which == PreserveTimes::All && !output.is_dir() {
+        set_created(source, output)?;
+    }
+
+    let (Ok(wanted), Ok(got)) = (source.modified(), std::fs::metadata(output)?.modified()) else {
+        return Ok(Duration::ZERO);
+    };
+    let off = wanted
+        .duration_since(got)
+        .or_else(|_| got.duration_since(wanted))
+        .unwrap_or_default();
+    if off > tolerance {
+        return Err(std::io::Error::other(format!(
+            "modification time reads back {:?} off from the one set",
+            off
+        )));
+    }
+    Ok(off)
+}
+
+#[cfg(any(windows, target_os = "macos"))]
+fn set_created(source: &Metadata, output: &Path) -> std::io::Result<()> {
+    #[cfg(target_os = "macos")]
+    use std::os::macos::fs::FileTimesExt;
+    #[cfg(windows)]
+    use std::os::windows::fs::FileTimesExt;
+
+    // Not every filesystem records one.
+    let Ok(created) = source.created() else {
+        return Ok(());
+    };
+    std::fs::File::options()
+        .write(true)
+        .open(output)?
+        .set_times(std::fs::FileTimes::new().set_created(created))
+}
+
+// Other Unix systems have no way to set the birth time.
+#[cfg(not(any(windows, target_os = "macos")))]
+fn set_created(_source: &Metadata, _output: &Path) -> std::io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use std::path::Path;
+
+use crate::{encoder::InputSelection, probe, process::CommandRunner, sniff::ImageKind};
+
+/// Quickly checks whether an image looks intact, without decoding it: the
+/// leading bytes, which the walk found to be `detected` in a file of `size`,
+/// must match the format its extension claims, and ffprobe must be able to
+/// read the headers without complaint. Returns the reason the file was
+/// rejected.
+pub async fn validate(
+    runner: &dyn CommandRunner,
+    path: &Path,
+    extension: &str,
+    size: u64,
+    detected: Option<ImageKind>,
+) -> Result<(), String> {
+    if size == 0 {
+        return Err("File is empty".to_string());
+    }
+
+    if detected.is_none() && ImageKind::from_extension(extension).is_some() {
+        return Err(format!("Header does not look like {}", extension));
+    }
+
+    let mut command = crate::process::command("ffprobe");
+    command.arg("-v").arg("error");
+    if let Some(kind) = detected {
+        command.arg("-f").arg(kind.ffmpeg_format());
+    }
+    command.arg("-i").arg(crate::process::file_arg(path));
+    let output = runner
+        .run(&mut command, false)
+        .await
+        .map_err(|e| format!("Failed to run ffprobe: {}", e))?;
+
+    if !output.status.success() || !output.stderr_tail.is_empty() {
+        let reason = output
+            .stderr_tail
+            .first()
+            .map_or("ffprobe failed", |line| line.trim());
+        return Err(format!("ffprobe: {}", reason));
+    }
+
+    Ok(())
+}
+
+/// Fully decodes a converted file and checks that it holds as many pixels as
+/// the image of its source that `selection` picks. Comparing the area keeps
+/// rotated outputs from failing. Returns the reason the output was rejected.
+pub async fn verify_output(
+    runner: &dyn CommandRunner,
+    source: &Path,
+    source_format: Option<&str>,
+    selection: InputSelection,
+    output: &Path,
+) -> Result<(), String> {
+    decodes(runner, output).await?;
+
+    let source_info = probe::stream_info(runner, source, source_format, selection)
+        .await
+        .map_err(|e| format!("cannot probe source: {}", e))?;
+    let output_info = probe::stream_info(runner, output, None, InputSelection::Whole)
+        .await
+        .map_err(|e| format!("cannot probe output: {}", e))?;
+    let area = |info: &probe::StreamInfo| info.width as u64 * info.height as u64;
+    if area(&source_info) != area(&output_info) {
+        return Err(format!(
+            "output is {}x{} but the source is {}x{}",
+            output_info.width, output_info.height, source_info.width, source_info.height
+        ));
+    }
+    Ok(())
+}
+
+/// Fully decodes `output` with ffmpeg. Returns why it doesn't decode.
+pub async fn decodes(runner: &dyn CommandRunner, output: &Path) -> Result<(), String> {
+    let mut command = crate::process::command("ffmpeg");
+    command.arg("-v").arg("error");
+    if let Some(format) = crate::process::input_format(output, None).await {
+        command.arg("-f").arg(format);
+    }
+    command
+        .arg("-i")
+        .arg(crate::process::file_arg(output))
+        .arg("-f")
+        .arg("null")
+        .arg("-");
+    let decoded = runner
+        .run(&mut command, false)
+        .await
+        .map_err(|e| format!("Failed to run ffmpeg: {}", e))?;
+    if !decoded.status.success() || !decoded.stderr_tail.is_empty() {
+        let reason = decoded
+            .stderr_tail
+            .first()
+            .map_or("decode failed", |line| line.trim());
+        return Err(format!("output does not decode: {}", reason));
+    }
+    Ok(())
+}
+
+/// Decodes both files to 16-bit RGBA and checks that they hold the same
+/// pixels in every frame. Returns the reason they were found to differ.
+pub async fn same_pixels(
+    runner: &dyn CommandRunner,
+    first: &Path,
+    second: &Path,
+    format: Option<&str>,
+) -> Result<(), String> {
+    let first_hash = pixel_hash(runner, first, format).await?;
+    if first_hash != pixel_hash(runner, second, format).await? {
+        return Err("the pixels differ".to_string());
+    }
+    Ok(())
+}
+
+async fn pixel_hash(
+    runner: &dyn CommandRunner,
+    path: &Path,
+    format: Option<&str>,
+) -> Result<Vec<u8>, String> {
+    let mut command = crate::process::command("ffmpeg");
+    command.arg("-v").arg("error");
+    if let Some(format) = crate::process::input_format(path, format).await {
+        command.arg("-f").arg(format);
+    }
+    command
+        .arg("-i")
+        .arg(crate::process::file_arg(path))
+        .arg("-map")
+        .arg("0:v")
+        .arg("-pix_fmt")
+        .arg("rgba64le")
+        .arg("-f")
+        .arg("hash")
+        .arg("-hash")
+        .arg("sha256")
+        .arg("-");
+    let decoded = runner
+        .run(&mut command, false)
+        .await
+        .map_err(|e| format!("Failed to run ffmpeg: {}", e))?;
+    if !decoded.status.success() || decoded.stdout.is_empty() {
+        return Err(format!(
+            "{} does not decode: {}",
+            path.display(),
+            decoded.message("decode failed")
+        ));
+    }
+    Ok(decoded.stdout)
+}
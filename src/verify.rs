@@ -0,0 +1,115 @@
+use std::path::PathBuf;
+
+use tokio::task::JoinSet;
+
+use crate::{encoder::InputSelection, events::Messages, process::Spawn, prune, validate};
+
+/// Checks the JPEG XL files in an output tree, e.g. after moving them to
+/// other storage: every one must decode fully, and one made from a whole
+/// source must hold as many pixels as that source.
+#[derive(clap::Args)]
+pub struct VerifyArgs {
+    #[command(flatten)]
+    tree: crate::Tree,
+
+    #[command(flatten)]
+    parallel: crate::Jobs,
+
+    #[command(flatten)]
+    filters: crate::Filters,
+}
+
+pub async fn run(args: VerifyArgs, messages: &Messages) -> anyhow::Result<()> {
+    let input_path = PathBuf::from(&args.tree.input);
+    if !input_path.is_dir() {
+        return Err(anyhow::anyhow!("Input path is not a directory"));
+    }
+    let output_path = PathBuf::from(&args.tree.output);
+    if !output_path.is_dir() {
+        return Err(anyhow::anyhow!("Output path is not a directory"));
+    }
+
+    let outputs = args
+        .filters
+        .files(&output_path, args.tree.recursive)
+        .map(|entry| entry.into_path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("jxl"))
+        })
+        .collect::<Vec<_>>();
+
+    let mut pending = outputs.iter().cloned();
+    let mut set = JoinSet::new();
+    let mut verified = 0;
+    let mut without_source = Vec::new();
+    let mut failed = Vec::new();
+    loop {
+        while set.len() < args.parallel.jobs.max(1)
+            && let Some(output) = pending.next()
+        {
+            let source = output
+                .strip_prefix(&output_path)
+                .ok()
+                .and_then(|relative| prune::source_of(&input_path, relative));
+            let spawn = Spawn::new(messages);
+            set.spawn(async move {
+                let checked = match &source {
+                    Some(source) if source.whole => {
+                        validate::verify_output(
+                            &spawn,
+                            &source.path,
+                            None,
+                            InputSelection::Whole,
+                            &output,
+                        )
+                        .await
+                    }
+                    _ => validate::decodes(&spawn, &output).await,
+                };
+                (output, source.is_some(), checked)
+            });
+        }
+        let Some(task_result) = set.join_next().await else {
+            break;
+        };
+        match task_result {
+            Ok((output, has_source, Ok(()))) => {
+                verified += 1;
+                if !has_source {
+                    without_source.push(output);
+                }
+            }
+            Ok((output, _, Err(reason))) => {
+                say_error!(messages, "Error verifying {}: {}", output.display(), reason);
+                failed.push(output);
+            }
+            Err(e) => say_error!(messages, "Task join error: {}", e),
+        }
+    }
+
+    say!(messages, "{}", "-".repeat(60));
+    say!(messages, "Verify Summary:");
+    say!(messages, "  JPEG XL files found:   {}", outputs.len());
+    say!(messages, "  Verified:              {}", verified);
+    say!(
+        messages,
+        "  Without a source:      {}",
+        without_source.len()
+    );
+    say!(messages, "  Files with errors:     {}", failed.len());
+    failed.sort();
+    for file in &failed {
+        say!(messages, "    {}", file.display());
+    }
+    say!(messages, "{}", "-".repeat(60));
+
+    if !failed.is_empty() {
+        return Err(anyhow::anyhow!(
+            "{} of {} outputs failed verification",
+            failed.len(),
+            outputs.len()
+        ));
+    }
+    Ok(())
+}
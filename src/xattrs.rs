@@ -0,0 +1,168 @@
+use std::{
+    ffi::{CStr, CString},
+    path::Path,
+};
+
+// Attributes the system manages itself, which can't be set on another file.
+const RESERVED_PREFIXES: &[&str] = &[
+    "com.apple.provenance",
+    "com.apple.rootless",
+    "com.apple.system.",
+    "security.",
+    "system.",
+];
+
+/// Copies the extended attributes of `source` to `output`, including the
+/// Finder tags and labels on macOS. Returns a description of every attribute
+/// that couldn't be copied.
+pub fn copy(source: &Path, output: &Path) -> Vec<String> {
+    let names = match sys::list(source) {
+        Ok(names) => names,
+        Err(e) => return vec![format!("listing attributes: {}", e)],
+    };
+    let mut failures = Vec::new();
+    for name in names {
+        let text = name.to_string_lossy();
+        if RESERVED_PREFIXES
+            .iter()
+            .any(|prefix| text.starts_with(prefix))
+        {
+            continue;
+        }
+        if let Err(e) = sys::get(source, &name).and_then(|value| sys::set(output, &name, &value)) {
+            failures.push(format!("{}: {}", text, e));
+        }
+    }
+    failures
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn c_path(path: &Path) -> std::io::Result<CString> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+/// Splits the NUL-separated name list the list calls return.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn split_names(buffer: &[u8]) -> Vec<CString> {
+    buffer
+        .split(|&b| b == 0)
+        .filter(|name| !name.is_empty())
+        .filter_map(|name| CString::new(name).ok())
+        .collect()
+}
+
+/// Calls `fill` once to learn the size and again with a buffer of that size.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn read_sized(mut fill: impl FnMut(*mut u8, usize) -> isize) -> std::io::Result<Vec<u8>> {
+    let size = fill(std::ptr::null_mut(), 0);
+    if size < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let mut buffer = vec![0u8; size as usize];
+    let size = fill(buffer.as_mut_ptr(), buffer.len());
+    if size < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    buffer.truncate(size as usize);
+    Ok(buffer)
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::*;
+
+    pub fn list(path: &Path) -> std::io::Result<Vec<CString>> {
+        let path = c_path(path)?;
+        // SAFETY: the buffer pointer and length come from a live Vec.
+        let buffer = read_sized(|buffer, size| unsafe {
+            libc::listxattr(path.as_ptr(), buffer.cast(), size)
+        })?;
+        Ok(split_names(&buffer))
+    }
+
+    pub fn get(path: &Path, name: &CStr) -> std::io::Result<Vec<u8>> {
+        let path = c_path(path)?;
+        // SAFETY: as in `list`.
+        read_sized(|buffer, size| unsafe {
+            libc::getxattr(path.as_ptr(), name.as_ptr(), buffer.cast(), size)
+        })
+    }
+
+    pub fn set(path: &Path, name: &CStr, value: &[u8]) -> std::io::Result<()> {
+        let path = c_path(path)?;
+        // SAFETY: all pointers are valid for the duration of the call.
+        let result = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod sys {
+    use super::*;
+
+    pub fn list(path: &Path) -> std::io::Result<Vec<CString>> {
+        let path = c_path(path)?;
+        // SAFETY: the buffer pointer and length come from a live Vec.
+        let buffer = read_sized(|buffer, size| unsafe {
+            libc::listxattr(path.as_ptr(), buffer.cast(), size, 0)
+        })?;
+        Ok(split_names(&buffer))
+    }
+
+    pub fn get(path: &Path, name: &CStr) -> std::io::Result<Vec<u8>> {
+        let path = c_path(path)?;
+        // SAFETY: as in `list`.
+        read_sized(|buffer, size| unsafe {
+            libc::getxattr(path.as_ptr(), name.as_ptr(), buffer.cast(), size, 0, 0)
+        })
+    }
+
+    pub fn set(path: &Path, name: &CStr, value: &[u8]) -> std::io::Result<()> {
+        let path = c_path(path)?;
+        // SAFETY: all pointers are valid for the duration of the call.
+        let result = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+                0,
+            )
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+// Windows alternate data streams aren't copied.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod sys {
+    use super::*;
+
+    pub fn list(_path: &Path) -> std::io::Result<Vec<CString>> {
+        Ok(Vec::new())
+    }
+
+    pub fn get(_path: &Path, _name: &CStr) -> std::io::Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+
+    pub fn set(_path: &Path, _name: &CStr, _value: &[u8]) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+//! Fixtures and helpers shared by the end-to-end tests. The fixtures are
+//! generated while the tests run, so the repository holds no images.
+
+use std::path::Path;
+
+use bulk_jxl::{Converter, Error, Event, FileResult};
+
+/// Writes a small gradient image to `path`, in the format its extension
+/// names, making the directories it needs.
+pub fn write_image(path: &Path) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let image = image::RgbImage::from_fn(16, 12, |x, y| {
+        image::Rgb([(x * 16) as u8, (y * 20) as u8, 128])
+    });
+    image.save(path).unwrap();
+}
+
+/// Writes a small grayscale gradient image to `path`, like a black-and-white
+/// scan, in the format its extension names.
+pub fn write_gray_image(path: &Path) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let image = image::GrayImage::from_fn(16, 12, |x, y| image::Luma([(x * 8 + y * 10) as u8]));
+    image.save(path).unwrap();
+}
+
+/// Writes a 16x12 JPEG whose EXIF asks for `orientation`, e.g. 6 for a
+/// camera held upright, which is shown turned a quarter clockwise as 12x16.
+/// The 4 leftmost columns are white and the rest black, so the white ends up
+/// at the top when shown upright.
+pub fn write_oriented_jpeg(path: &Path, orientation: u16) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let image = image::RgbImage::from_fn(16, 12, |x, _| {
+        image::Rgb(if x < 4 { [255; 3] } else { [0; 3] })
+    });
+    let mut jpeg = Vec::new();
+    image
+        .write_to(
+            &mut std::io::Cursor::new(&mut jpeg),
+            image::ImageFormat::Jpeg,
+        )
+        .unwrap();
+    // A big-endian TIFF with one IFD holding only the orientation.
+    let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+    exif.extend_from_slice(&orientation.to_be_bytes());
+    exif.extend_from_slice(&[0; 6]);
+    let mut app1 = vec![0xff, 0xe1];
+    app1.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+    app1.extend_from_slice(&exif);
+    // Right after the start of image marker.
+    jpeg.splice(2..2, app1);
+    std::fs::write(path, jpeg).unwrap();
+}
+
+/// Writes an animated PNG of `frames` 16x12 frames of a tenth of a second
+/// each, which plays `plays` times (0 for forever).
+pub fn write_apng(path: &Path, frames: u32, plays: u32) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let file = std::io::BufWriter::new(std::fs::File::create(path).unwrap());
+    let mut encoder = png::Encoder::new(file, 16, 12);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_animated(frames, plays).unwrap();
+    encoder.set_frame_delay(1, 10).unwrap();
+    let mut writer = encoder.write_header().unwrap();
+    for frame in 0..frames {
+        let pixels: Vec<u8> = (0..16 * 12)
+            .flat_map(|i| [(i * 3) as u8, (frame * 80) as u8, 128])
+            .collect();
+        writer.write_image_data(&pixels).unwrap();
+    }
+    writer.finish().unwrap();
+}
+
+/// Writes an ICO file holding a square image of each of `sizes`, the first as
+/// a 32-bit BMP like old icons have, the others PNG-compressed.
+pub fn write_ico(path: &Path, sizes: &[u32]) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let images: Vec<Vec<u8>> = sizes
+        .iter()
+        .enumerate()
+        .map(|(index, &size)| {
+            let image = image::RgbaImage::from_fn(size, size, |x, y| {
+                image::Rgba([(x * 255 / size) as u8, (y * 255 / size) as u8, 128, 255])
+            });
+            if index > 0 {
+                let mut png = Vec::new();
+                image
+                    .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+                    .unwrap();
+                return png;
+            }
+            // A BITMAPINFOHEADER with twice the height for the AND mask,
+            // bottom-up BGRA rows and then the mask, rows padded to 4 bytes.
+            let mut bmp = Vec::new();
+            for value in [40, size, size * 2] {
+                bmp.extend_from_slice(&value.to_le_bytes());
+            }
+            bmp.extend_from_slice(&1u16.to_le_bytes());
+            bmp.extend_from_slice(&32u16.to_le_bytes());
+            bmp.extend_from_slice(&[0; 24]);
+            for row in image.rows().rev() {
+                for pixel in row {
+                    let [r, g, b, a] = pixel.0;
+                    bmp.extend_from_slice(&[b, g, r, a]);
+                }
+            }
+            bmp.resize(bmp.len() + (size.div_ceil(32) * 4 * size) as usize, 0);
+            bmp
+        })
+        .collect();
+    let mut ico = vec![0, 0, 1, 0];
+    ico.extend_from_slice(&(sizes.len() as u16).to_le_bytes());
+    let mut offset = 6 + 16 * sizes.len();
+    for (&size, image) in sizes.iter().zip(&images) {
+        // 256 is written as 0.
+        let side = (size % 256) as u8;
+        ico.extend_from_slice(&[side, side, 0, 0]);
+        ico.extend_from_slice(&1u16.to_le_bytes());
+        ico.extend_from_slice(&32u16.to_le_bytes());
+        ico.extend_from_slice(&(image.len() as u32).to_le_bytes());
+        ico.extend_from_slice(&(offset as u32).to_le_bytes());
+        offset += image.len();
+    }
+    for image in images {
+        ico.extend_from_slice(&image);
+    }
+    std::fs::write(path, ico).unwrap();
+}
+
+/// Sets the modification time of `path` to a whole second long ago, so its
+/// copy in the output can be compared exactly.
+pub fn set_old_mtime(path: &Path) {
+    let time = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+    filetime::set_file_mtime(path, time).unwrap();
+}
+
+pub fn mtime(path: &Path) -> filetime::FileTime {
+    filetime::FileTime::from_last_modification_time(&std::fs::metadata(path).unwrap())
+}
+
+/// Runs `converter` and returns how the run ended with the result of every
+/// file, sorted by source.
+pub async fn run(converter: &Converter) -> (Result<(), Error>, Vec<FileResult>) {
+    let (events, mut received) = tokio::sync::mpsc::channel(16);
+    let (outcome, mut results) = tokio::join!(converter.run(events), async {
+        let mut results = Vec::new();
+        while let Some(event) = received.recv().await {
+            if let Event::FileFinished(result) = event {
+                results.push(result);
+            }
+        }
+        results
+    });
+    results.sort_by(|a, b| a.source.cmp(&b.source));
+    (outcome, results)
+}
@@ -0,0 +1,294 @@
+//! Stand-ins for ffmpeg, ffprobe, cjxl, djxl and ImageMagick that play their part
+//! through the library's `CommandRunner`, so a whole run works without any
+//! of them installed. A script picks how each command goes.
+
+use std::{
+    ffi::{OsStr, OsString},
+    os::unix::{ffi::OsStrExt, process::ExitStatusExt},
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use bulk_jxl::{CommandRunner, Finished};
+
+/// How a command goes.
+pub enum Reply {
+    /// Does what the tool would: encoders write a small JPEG XL file, ffprobe
+    /// describes the size and pixel format of the source, or a 16x12 RGB
+    /// image when it can't be read, ImageMagick writes a PNG, djxl always
+    /// rebuilds the same JPEG and every decode hashes to the same pixels.
+    Succeed,
+    /// Exits with `status` after printing `stderr`, writing nothing.
+    Fail { status: i32, stderr: &'static str },
+    /// Prints `stdout` and exits, writing nothing.
+    Print(&'static str),
+    /// Logs `stderr` and exits successfully, writing nothing.
+    Log(&'static str),
+    /// Never finishes, like an encoder stuck on a file.
+    Hang,
+    /// Writes the start of its output and then dies, like an encoder that
+    /// crashed half way.
+    Partial,
+    /// Can't be started, as the tool isn't installed.
+    Missing,
+}
+
+/// A command the fake was asked to run.
+#[derive(Clone, Debug)]
+pub struct Call {
+    /// The file name of the program, e.g. `ffmpeg`.
+    pub program: String,
+    pub args: Vec<OsString>,
+}
+
+impl Call {
+    /// Whether this is ffmpeg or cjxl writing a JPEG XL file.
+    pub fn is_encode(&self) -> bool {
+        match self.program.as_str() {
+            "ffmpeg" => self.has("-c:v"),
+            "cjxl" => !self.has("-version"),
+            _ => false,
+        }
+    }
+
+    /// Whether any argument contains `text`.
+    pub fn has(&self, text: &str) -> bool {
+        self.args
+            .iter()
+            .any(|arg| arg.to_string_lossy().contains(text))
+    }
+
+    /// The value after the option `name`.
+    pub fn value(&self, name: &str) -> Option<String> {
+        let index = self.args.iter().position(|arg| arg == name)?;
+        Some(self.args.get(index + 1)?.to_string_lossy().into_owned())
+    }
+
+    /// The file ffprobe is asked about.
+    fn probed_file(&self) -> Option<PathBuf> {
+        let path = self.args.last()?.as_encoded_bytes();
+        Some(PathBuf::from(OsStr::from_bytes(
+            path.strip_prefix(b"file:").unwrap_or(path),
+        )))
+    }
+
+    /// The file the command writes, if it writes one.
+    fn output(&self) -> Option<PathBuf> {
+        if self.has("-version") {
+            return None;
+        }
+        let path = match self.program.as_str() {
+            "ffmpeg" if self.is_encode() => self.args.last()?,
+            "cjxl" | "djxl" => self.args.get(1)?,
+            "magick" | "convert" => self.args.last()?,
+            _ => return None,
+        };
+        // Without a lossy conversion, for names that aren't UTF-8.
+        let path = path.as_encoded_bytes();
+        let path = path
+            .strip_prefix(b"file:")
+            .or_else(|| path.strip_prefix(b"png:"))
+            .unwrap_or(path);
+        Some(PathBuf::from(OsStr::from_bytes(path)))
+    }
+}
+
+type Script = dyn Fn(&Call) -> Reply + Send + Sync;
+
+pub struct Fake {
+    script: Box<Script>,
+    calls: Mutex<Vec<Call>>,
+    /// The commands running now and the most that ran at once.
+    running: AtomicUsize,
+    most_running: AtomicUsize,
+}
+
+impl Fake {
+    /// A fake that replies to every command as `script` says. Lives for the
+    /// rest of the tests, so its calls can be looked at after the run.
+    pub fn new(script: impl Fn(&Call) -> Reply + Send + Sync + 'static) -> &'static Fake {
+        Box::leak(Box::new(Fake {
+            script: Box::new(script),
+            calls: Mutex::new(Vec::new()),
+            running: AtomicUsize::new(0),
+            most_running: AtomicUsize::new(0),
+        }))
+    }
+
+    /// A fake where every command succeeds.
+    pub fn succeeding() -> &'static Fake {
+        Fake::new(|_| Reply::Succeed)
+    }
+
+    /// The commands run so far.
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// The most commands that ran at the same time so far.
+    pub fn most_running(&self) -> usize {
+        self.most_running.load(Ordering::SeqCst)
+    }
+
+    /// How many encodes were started so far.
+    pub fn encodes(&self) -> usize {
+        self.calls().iter().filter(|call| call.is_encode()).count()
+    }
+}
+
+/// The smallest thing that passes for a JPEG XL file: a codestream
+/// signature and some bytes.
+const JXL: &[u8] = b"\xff\x0a fake codestream, long enough for the size check";
+
+fn exited(code: i32) -> ExitStatus {
+    ExitStatus::from_raw(code << 8)
+}
+
+/// What ffprobe prints for the entries `call` asks for, about a PNG without
+/// tags of the size and pixel format of the file, or a 16x12 RGB one.
+fn probed(call: &Call) -> Vec<u8> {
+    let Some(entries) = call.value("-show_entries") else {
+        return Vec::new();
+    };
+    let image = call
+        .probed_file()
+        .and_then(|path| image::ImageReader::open(path).ok()?.decode().ok());
+    let (width, height) = image
+        .as_ref()
+        .map_or((16, 12), |image| (image.width(), image.height()));
+    let pix_fmt = match image.as_ref().map(|image| image.color()) {
+        Some(image::ColorType::L8) => "gray",
+        Some(image::ColorType::La8) => "ya8",
+        Some(image::ColorType::L16) => "gray16be",
+        Some(image::ColorType::Rgba8) => "rgba",
+        _ => "rgb24",
+    };
+    let (width, height) = (width.to_string(), height.to_string());
+    let keys = entries.split_once('=').map_or("", |(_, keys)| keys);
+    let bare = call
+        .value("-of")
+        .is_some_and(|format| format.contains("nokey=1"));
+    let mut text = String::new();
+    for key in keys.split(',') {
+        let value = match key {
+            "codec_name" => "png",
+            "pix_fmt" => pix_fmt,
+            "width" => &width,
+            "height" => &height,
+            "bits_per_raw_sample" => "8",
+            "nb_read_packets" => "1",
+            "color_primaries" | "color_transfer" | "color_space" => "unknown",
+            // No orientation tag.
+            _ => continue,
+        };
+        if bare {
+            text.push_str(&format!("{}\n", value));
+        } else {
+            text.push_str(&format!("{}={}\n", key, value));
+        }
+    }
+    text.into_bytes()
+}
+
+fn write(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, data)
+}
+
+impl CommandRunner for Fake {
+    fn run<'a>(
+        &'a self,
+        command: &'a mut tokio::process::Command,
+        _verbose: bool,
+    ) -> std::pin::Pin<Box<dyn Future<Output = std::io::Result<Finished>> + Send + 'a>> {
+        let command = command.as_std();
+        let call = Call {
+            program: Path::new(command.get_program())
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            args: command.get_args().map(|arg| arg.to_os_string()).collect(),
+        };
+        self.calls.lock().unwrap().push(call.clone());
+        // The version checks at startup find the tools the script has.
+        let reply = match (self.script)(&call) {
+            Reply::Missing => Reply::Missing,
+            _ if call.has("-version") => Reply::Succeed,
+            reply => reply,
+        };
+        Box::pin(async move {
+            let _running = Running::start(self);
+            // Gives the other files a turn, as a real tool would, so the
+            // commands of files in flight together overlap.
+            tokio::task::yield_now().await;
+            let mut finished = Finished {
+                status: exited(0),
+                stdout: Vec::new(),
+                stderr_tail: Vec::new(),
+            };
+            match reply {
+                Reply::Succeed => {
+                    match call.output() {
+                        Some(output) if call.program == "magick" || call.program == "convert" => {
+                            write(&output, b"\x89PNG\r\n\x1a\nfake")?
+                        }
+                        Some(output) if call.program == "djxl" => {
+                            write(&output, b"\xff\xd8\xff fake jpeg")?
+                        }
+                        Some(output) => write(&output, JXL)?,
+                        None => {}
+                    }
+                    if call.program == "ffprobe" {
+                        finished.stdout = probed(&call);
+                    }
+                    if call.program == "ffmpeg" && call.has("hash") {
+                        finished.stdout = b"SHA256=fake pixels\n".to_vec();
+                    }
+                    if call.has("ssim") {
+                        finished.stderr_tail = vec!["SSIM All:1.000000 (inf)".to_string()];
+                    }
+                }
+                Reply::Fail { status, stderr } => {
+                    finished.status = exited(status);
+                    finished.stderr_tail = stderr.lines().map(str::to_string).collect();
+                }
+                Reply::Print(stdout) => finished.stdout = stdout.as_bytes().to_vec(),
+                Reply::Log(stderr) => {
+                    finished.stderr_tail = stderr.lines().map(str::to_string).collect()
+                }
+                Reply::Hang => std::future::pending::<()>().await,
+                Reply::Missing => return Err(std::io::ErrorKind::NotFound.into()),
+                Reply::Partial => {
+                    if let Some(output) = call.output() {
+                        write(&output, &JXL[..6])?;
+                    }
+                    // The wait status of a process SIGKILL ended.
+                    finished.status = ExitStatus::from_raw(9);
+                    finished.stderr_tail = vec!["Killed".to_string()];
+                }
+            }
+            Ok(finished)
+        })
+    }
+}
+
+/// Counts a command as running until it finishes or is dropped.
+struct Running<'a>(&'a Fake);
+
+impl<'a> Running<'a> {
+    fn start(fake: &'a Fake) -> Self {
+        let running = fake.running.fetch_add(1, Ordering::SeqCst) + 1;
+        fake.most_running.fetch_max(running, Ordering::SeqCst);
+        Running(fake)
+    }
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.running.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,233 @@
+//! End-to-end tests that convert images with the ffmpeg on the PATH. Each
+//! one passes without doing anything when there is no ffmpeg with libjxl, so
+//! the rest of the suite still runs on machines without it.
+
+mod common;
+
+use bulk_jxl::{Action, Converter, IcoMode};
+
+/// Whether the ffmpeg on the PATH can encode JPEG XL. Prints why a test is
+/// skipped when it can't.
+fn have_libjxl() -> bool {
+    let found = std::process::Command::new("ffmpeg")
+        .args(["-hide_banner", "-encoders"])
+        .output()
+        .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).contains("libjxl"));
+    if !found {
+        eprintln!("skipped: no ffmpeg with libjxl on the PATH");
+    }
+    found
+}
+
+/// The start of a bare JPEG XL codestream or of the container format.
+fn is_jxl(data: &[u8]) -> bool {
+    data.starts_with(&[0xff, 0x0a]) || data.starts_with(b"\0\0\0\x0cJXL \r\n\x87\n")
+}
+
+#[tokio::test]
+async fn converts_a_tree_and_skips_it_the_second_time() {
+    if !have_libjxl() {
+        return;
+    }
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    let sources = ["a.png", "sub/b.gif", "sub/deeper/c.bmp"];
+    for source in sources {
+        common::write_image(&input.join(source));
+        common::set_old_mtime(&input.join(source));
+    }
+
+    let converter = Converter::new(&input, &output).recursive();
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+
+    assert_eq!(results.len(), sources.len());
+    for (source, result) in sources.iter().zip(&results) {
+        assert_eq!(result.action, Action::Converted, "{}", source);
+        let converted = output.join(source).with_extension("jxl");
+        assert_eq!(result.output.as_ref(), Some(&converted));
+        assert!(is_jxl(&std::fs::read(&converted).unwrap()), "{}", source);
+        assert_eq!(
+            common::mtime(&converted),
+            common::mtime(&input.join(source))
+        );
+    }
+
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+    assert!(
+        results
+            .iter()
+            .all(|result| result.action == Action::Skipped)
+    );
+}
+
+#[tokio::test]
+async fn corrupt_image_fails_without_an_output() {
+    if !have_libjxl() {
+        return;
+    }
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("good.png"));
+    // A PNG signature with nothing valid after it.
+    std::fs::write(input.join("bad.png"), b"\x89PNG\r\n\x1a\nnot really").unwrap();
+
+    let (outcome, results) = common::run(&Converter::new(&input, &output)).await;
+    // One bad file doesn't fail the run.
+    outcome.unwrap();
+
+    assert_eq!(results[0].action, Action::Failed);
+    assert!(!results[0].notes.is_empty());
+    assert!(!output.join("bad.jxl").exists());
+    assert_eq!(results[1].action, Action::Converted);
+}
+
+/// The number of frames ffprobe decodes from `path`.
+fn frame_count(path: &std::path::Path) -> u32 {
+    let output = std::process::Command::new("ffprobe")
+        .args(["-v", "error", "-count_frames", "-select_streams", "v:0"])
+        .args(["-show_entries", "stream=nb_read_frames", "-of", "csv=p=0"])
+        .arg(path)
+        .output()
+        .unwrap();
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .unwrap()
+}
+
+/// What ffprobe says about the `entries` of the first video stream of
+/// `path`, e.g. `16,12` for `width,height`.
+fn probed(path: &std::path::Path, entries: &str) -> String {
+    let output = std::process::Command::new("ffprobe")
+        .args(["-v", "error", "-select_streams", "v:0"])
+        .args(["-show_entries", &format!("stream={}", entries)])
+        .args(["-of", "csv=p=0"])
+        .arg(path)
+        .output()
+        .unwrap();
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+#[tokio::test]
+async fn animated_png_becomes_an_animated_jxl() {
+    if !have_libjxl() {
+        return;
+    }
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_apng(&input.join("anim.png"), 3, 2);
+
+    let (outcome, results) = common::run(&Converter::new(&input, &output)).await;
+    outcome.unwrap();
+
+    assert_eq!(results[0].action, Action::Converted);
+    let converted = output.join("anim.jxl");
+    assert!(is_jxl(&std::fs::read(&converted).unwrap()));
+    assert_eq!(frame_count(&converted), 3);
+}
+
+#[tokio::test]
+async fn names_ffmpeg_could_misread_are_converted() {
+    if !have_libjxl() {
+        return;
+    }
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    // An option, an image sequence pattern and shell syntax.
+    let sources = ["-foo.png", "file%03d.png", "weird;name$.png"];
+    for source in sources {
+        common::write_image(&input.join(source));
+    }
+
+    let (outcome, results) = common::run(&Converter::new(&input, &output)).await;
+    outcome.unwrap();
+
+    assert_eq!(results.len(), sources.len());
+    for result in &results {
+        assert_eq!(result.action, Action::Converted, "{:?}", result.notes);
+        let converted = result.output.as_ref().unwrap();
+        assert!(is_jxl(&std::fs::read(converted).unwrap()));
+    }
+    assert!(output.join("-foo.jxl").exists());
+    assert!(output.join("file%03d.jxl").exists());
+    assert!(output.join("weird;name$.jxl").exists());
+    // Nothing was written under a name ffmpeg made from the pattern.
+    assert!(!output.join("file001.jxl").exists());
+}
+
+#[tokio::test]
+async fn every_image_of_an_icon_is_converted() {
+    if !have_libjxl() {
+        return;
+    }
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_ico(&input.join("favicon.ico"), &[16, 48, 32]);
+
+    let converter = Converter::new(&input, &output).ico(IcoMode::All);
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+
+    assert_eq!(results[0].action, Action::Converted);
+    for size in [16, 48, 32] {
+        let converted = output.join(format!("favicon.{}.jxl", size));
+        assert!(is_jxl(&std::fs::read(&converted).unwrap()), "{}", size);
+        assert_eq!(
+            probed(&converted, "width,height"),
+            format!("{},{}", size, size)
+        );
+    }
+}
+
+#[tokio::test]
+async fn grayscale_png_becomes_a_single_channel_jxl() {
+    if !have_libjxl() {
+        return;
+    }
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_gray_image(&input.join("scan.png"));
+
+    let (outcome, results) = common::run(&Converter::new(&input, &output)).await;
+    outcome.unwrap();
+
+    assert_eq!(results[0].action, Action::Converted);
+    // The pixel format the JXL decodes to follows the color channels its
+    // header declares.
+    let pix_fmt = probed(&output.join("scan.jxl"), "pix_fmt");
+    assert!(pix_fmt.starts_with("gray"), "{}", pix_fmt);
+}
+
+#[tokio::test]
+async fn baked_orientation_decodes_upright() {
+    if !have_libjxl() {
+        return;
+    }
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_oriented_jpeg(&input.join("portrait.jpg"), 6);
+
+    let converter = Converter::new(&input, &output).bake_orientation();
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+    assert_eq!(results[0].action, Action::Converted);
+
+    // Decoded the way a viewer would, applying any orientation the output
+    // still asks for: a second rotation would turn it sideways again.
+    let decoded = temp.path().join("decoded.png");
+    let status = std::process::Command::new("ffmpeg")
+        .args(["-v", "error", "-i"])
+        .arg(output.join("portrait.jxl"))
+        .args(["-frames:v", "1", "-update", "1"])
+        .arg(&decoded)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let image = image::open(&decoded).unwrap().into_luma8();
+    assert_eq!(image.dimensions(), (12, 16));
+    // The white columns on the left of the stored image are now on top.
+    assert!(image.get_pixel(6, 1)[0] > 200);
+    assert!(image.get_pixel(6, 14)[0] < 50);
+}
@@ -0,0 +1,1640 @@
+//! End-to-end tests that run without an ffmpeg on the machine. The tools
+//! are played by the scripted fake in `fake`, through the runner the
+//! library takes for them.
+#![cfg(unix)]
+
+mod common;
+mod fake;
+
+use std::{io::Write, time::Duration};
+
+use bulk_jxl::{
+    Action, CollisionMode, Converter, CopyMode, Error, ExistingJxl, GateAction, IcoMode,
+    MultipageMode, Normalization, PlanAction, QualityGate, ScriptFormat,
+};
+use fake::{Fake, Reply};
+
+/// The names of the files in `dir`, sorted, without the journal every run
+/// keeps there.
+fn files_in(dir: &std::path::Path) -> Vec<std::ffi::OsString> {
+    let mut names: Vec<_> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .filter(|name| name != ".bulk-jxl.journal")
+        .collect();
+    names.sort();
+    names
+}
+
+#[tokio::test]
+async fn plan_names_outputs_after_their_sources() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("a.png"));
+    common::write_image(&input.join("sub/b.gif"));
+    common::write_image(&input.join("sub/c.bmp"));
+    std::fs::write(input.join("notes.txt"), "not an image").unwrap();
+    // Left by an earlier run.
+    std::fs::create_dir_all(output.join("sub")).unwrap();
+    std::fs::write(output.join("sub/c.jxl"), "converted before").unwrap();
+
+    let plan = Converter::new(&input, &output)
+        .recursive()
+        .runner(Fake::succeeding())
+        .plan()
+        .await
+        .unwrap();
+
+    let mut actions = plan.actions;
+    actions.sort_by(|a, b| a.source.cmp(&b.source));
+    // Without --copy-all the text file isn't part of the run.
+    let destinations: Vec<_> = actions.iter().map(|a| a.destination.clone()).collect();
+    assert_eq!(
+        destinations,
+        [
+            Some(output.join("a.jxl")),
+            Some(output.join("sub/b.jxl")),
+            Some(output.join("sub/c.jxl")),
+        ]
+    );
+    assert!(matches!(actions[0].action, PlanAction::Convert { .. }));
+    assert!(matches!(actions[1].action, PlanAction::Convert { .. }));
+    assert!(matches!(actions[2].action, PlanAction::Skip { .. }));
+    // Planning writes nothing.
+    assert!(!output.join("a.jxl").exists());
+    assert_eq!(
+        std::fs::read_to_string(output.join("sub/c.jxl")).unwrap(),
+        "converted before"
+    );
+}
+
+#[tokio::test]
+async fn planning_needs_no_tools_and_makes_no_output() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out/sub"));
+    common::write_image(&input.join("a.png"));
+    let without_tools = Fake::new(|_| Reply::Missing);
+
+    let plan = Converter::new(&input, &output)
+        .runner(without_tools)
+        .plan()
+        .await
+        .unwrap();
+
+    assert_eq!(plan.actions[0].destination, Some(output.join("a.jxl")));
+    assert!(matches!(plan.actions[0].action, PlanAction::Convert { .. }));
+    assert!(!temp.path().join("out").exists());
+    assert!(without_tools.calls().is_empty());
+}
+
+#[tokio::test]
+async fn jpeg_xl_under_another_extension_is_not_converted_again() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("a.png"));
+    // A bare codestream, as a tool that kept the old name writes it.
+    std::fs::write(input.join("b.png"), b"\xFF\x0A\xFA\x7F\x01\x90\x08\x06").unwrap();
+
+    let plan = Converter::new(&input, &output)
+        .runner(Fake::succeeding())
+        .plan()
+        .await
+        .unwrap();
+
+    let mut actions = plan.actions;
+    actions.sort_by(|a, b| a.source.cmp(&b.source));
+    assert!(matches!(actions[0].action, PlanAction::Convert { .. }));
+    assert!(matches!(actions[1].action, PlanAction::Copy));
+    assert_eq!(actions[1].destination, Some(output.join("b.jxl")));
+}
+
+#[tokio::test]
+async fn plan_lists_every_output_of_multi_image_sources() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_ico(&input.join("favicon.ico"), &[16, 48, 32]);
+    // A TIFF of two empty pages: the header, then two directories without
+    // entries, the first pointing at the second.
+    let mut tiff = b"II*\0".to_vec();
+    tiff.extend_from_slice(&8u32.to_le_bytes());
+    tiff.extend_from_slice(&0u16.to_le_bytes());
+    tiff.extend_from_slice(&14u32.to_le_bytes());
+    tiff.extend_from_slice(&0u16.to_le_bytes());
+    tiff.extend_from_slice(&0u32.to_le_bytes());
+    std::fs::write(input.join("scan.tif"), tiff).unwrap();
+    // Only some of the pages are there, all of the icons.
+    std::fs::create_dir_all(&output).unwrap();
+    for name in [
+        "scan.p001.jxl",
+        "favicon.16.jxl",
+        "favicon.32.jxl",
+        "favicon.48.jxl",
+    ] {
+        std::fs::write(output.join(name), "converted before").unwrap();
+    }
+
+    let plan = Converter::new(&input, &output)
+        .multipage(MultipageMode::All)
+        .ico(IcoMode::All)
+        .runner(Fake::succeeding())
+        .plan()
+        .await
+        .unwrap();
+
+    let mut actions = plan.actions;
+    actions.sort_by(|a, b| a.source.cmp(&b.source));
+    assert_eq!(
+        actions[0].outputs,
+        [
+            output.join("favicon.16.jxl"),
+            output.join("favicon.48.jxl"),
+            output.join("favicon.32.jxl"),
+        ]
+    );
+    assert!(matches!(actions[0].action, PlanAction::Skip { .. }));
+    assert_eq!(
+        actions[1].outputs,
+        [output.join("scan.p001.jxl"), output.join("scan.p002.jxl")]
+    );
+    assert!(matches!(actions[1].action, PlanAction::Convert { .. }));
+}
+
+#[tokio::test]
+async fn excluded_directories_are_left_out() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("a.png"));
+    common::write_image(&input.join("node_modules/pkg/b.png"));
+    common::write_image(&input.join("sub/drafts/c.png"));
+    common::write_image(&input.join("sub/d.png"));
+
+    let plan = Converter::new(&input, &output)
+        .recursive()
+        .exclude_dir("drafts")
+        .runner(Fake::succeeding())
+        .plan()
+        .await
+        .unwrap();
+
+    let mut sources: Vec<_> = plan.actions.into_iter().map(|a| a.source).collect();
+    sources.sort();
+    assert_eq!(sources, [input.join("a.png"), input.join("sub/d.png")]);
+}
+
+#[tokio::test]
+async fn null_separated_list_keeps_names_with_newlines() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("two\nlines.png"));
+    common::write_image(&input.join("sub/b.png"));
+    common::write_image(&input.join("unlisted.png"));
+    let list = temp.path().join("list");
+    let mut listed = Vec::new();
+    for name in ["two\nlines.png", "sub/b.png"] {
+        listed.extend_from_slice(input.join(name).as_os_str().as_encoded_bytes());
+        // Empty records, as a doubled separator gives, are left out.
+        listed.extend_from_slice(b"\0\0");
+    }
+    std::fs::write(&list, listed).unwrap();
+
+    let plan = Converter::new(&input, &output)
+        .files_from_nul(&list)
+        .runner(Fake::succeeding())
+        .plan()
+        .await
+        .unwrap();
+
+    let mut sources: Vec<_> = plan.actions.into_iter().map(|a| a.source).collect();
+    sources.sort();
+    assert_eq!(
+        sources,
+        [input.join("sub/b.png"), input.join("two\nlines.png")]
+    );
+}
+
+#[tokio::test]
+async fn emitted_script_quotes_names_and_converts_nothing() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("it's -a.png"));
+    std::fs::create_dir_all(&input).unwrap();
+    std::fs::write(input.join("$notes.txt"), "copied as it is").unwrap();
+    let script = temp.path().join("convert.sh");
+
+    let converter = Converter::new(&input, &output)
+        .copy_all()
+        .emit_script(&script, ScriptFormat::Sh)
+        .runner(Fake::succeeding());
+    let (outcome, _) = common::run(&converter).await;
+    outcome.unwrap();
+
+    let text = std::fs::read_to_string(&script).unwrap();
+    let lines: Vec<_> = text.lines().filter(|l| !l.starts_with('#')).collect();
+    assert_eq!(lines.len(), 2);
+    let input = std::path::absolute(&input).unwrap();
+    assert!(text.contains(&format!("'file:{}/it'\\''s -a.png'", input.display())));
+    assert!(text.contains("$notes.txt' && touch -r"));
+    let checked = std::process::Command::new("sh")
+        .arg("-n")
+        .arg(&script)
+        .status()
+        .unwrap();
+    assert!(checked.success());
+    assert!(!output.join("it's -a.jxl").exists());
+    assert!(!output.join("$notes.txt").exists());
+}
+
+#[tokio::test]
+async fn emitted_script_encodes_like_a_run() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_gray_image(&input.join("scan.png"));
+    common::write_oriented_jpeg(&input.join("portrait.jpg"), 6);
+    let script = temp.path().join("convert.sh");
+    let fake = Fake::new(|call| match call.program.as_str() {
+        "ffprobe" if call.has("Orientation") => Reply::Print("6\n"),
+        _ => Reply::Succeed,
+    });
+
+    let converter = Converter::new(&input, &output)
+        .bake_orientation()
+        .emit_script(&script, ScriptFormat::Sh)
+        .runner(fake);
+    let (outcome, _) = common::run(&converter).await;
+    outcome.unwrap();
+
+    let text = std::fs::read_to_string(&script).unwrap();
+    let lines: Vec<_> = text.lines().filter(|l| !l.starts_with('#')).collect();
+    assert_eq!(lines.len(), 2, "{}", text);
+    let line = |name: &str| *lines.iter().find(|line| line.contains(name)).unwrap();
+    assert!(line("scan.png").contains(" -pix_fmt gray "), "{}", text);
+    assert!(
+        line("portrait.jpg")
+            .contains(" -vf 'transpose=1,sidedata=mode=delete:type=DISPLAYMATRIX' "),
+        "{}",
+        text
+    );
+    // The probes ran, but aren't part of the script.
+    assert!(fake.calls().iter().any(|call| call.program == "ffprobe"));
+    assert!(!text.contains("ffprobe"), "{}", text);
+    assert_eq!(fake.encodes(), 0);
+}
+
+#[tokio::test]
+async fn copies_keep_the_modification_time() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    std::fs::create_dir_all(&input).unwrap();
+    let source = input.join("notes.txt");
+    std::fs::write(&source, "copied as it is").unwrap();
+    common::set_old_mtime(&source);
+
+    let converter = Converter::new(&input, &output)
+        .copy_all()
+        .runner(Fake::succeeding());
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].action, Action::Copied);
+    let copy = output.join("notes.txt");
+    assert_eq!(std::fs::read_to_string(&copy).unwrap(), "copied as it is");
+    assert_eq!(common::mtime(&copy), common::mtime(&source));
+
+    // A second run finds the copy in place.
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+    assert_eq!(results[0].action, Action::Skipped);
+}
+
+#[tokio::test]
+async fn failed_files_leave_their_details_in_the_error_dir() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("sub/a.png"));
+    let errors = temp.path().join("errors");
+
+    let fake = Fake::new(|call| {
+        if call.is_encode() {
+            Reply::Fail {
+                status: 1,
+                stderr: "Error while decoding stream",
+            }
+        } else {
+            Reply::Succeed
+        }
+    });
+    let converter = Converter::new(&input, &output)
+        .recursive()
+        .error_dir(&errors)
+        .error_sample_bytes(4)
+        .runner(fake);
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+
+    assert_eq!(results[0].action, Action::Failed);
+    let details = std::fs::read_to_string(errors.join("sub__a.png.txt")).unwrap();
+    assert!(details.contains(&format!(
+        "Source:      {}",
+        input.join("sub/a.png").display()
+    )));
+    assert!(details.contains("\n$ "));
+    assert!(details.contains(" -c:v libjxl "));
+    assert!(details.contains("| Error while decoding stream"));
+    let sample = std::fs::read(errors.join("sub__a.png.sample")).unwrap();
+    assert_eq!(sample, b"\x89PNG");
+}
+
+#[tokio::test]
+async fn existing_outputs_are_skipped_on_the_next_run() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("a.png"));
+    common::write_image(&input.join("b.png"));
+    let fake = Fake::succeeding();
+
+    let converter = Converter::new(&input, &output).runner(fake);
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+    assert!(results.iter().all(|r| r.action == Action::Converted));
+    assert_eq!(fake.encodes(), 2);
+    assert!(output.join("a.jxl").exists());
+
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+    assert!(results.iter().all(|r| r.action == Action::Skipped));
+    assert_eq!(fake.encodes(), 2);
+}
+
+#[tokio::test]
+async fn failed_encodes_are_counted_and_leave_nothing() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("bad.png"));
+    common::write_image(&input.join("crash.png"));
+    common::write_image(&input.join("good.png"));
+    let fake = Fake::new(|call| match call.is_encode() {
+        true if call.has("bad.png") => Reply::Fail {
+            status: 187,
+            stderr: "Invalid argument",
+        },
+        true if call.has("crash.png") => Reply::Partial,
+        _ => Reply::Succeed,
+    });
+
+    let converter = Converter::new(&input, &output).runner(fake);
+    let (outcome, results) = common::run(&converter).await;
+    // Failed files don't fail the run.
+    outcome.unwrap();
+
+    let actions: Vec<_> = results.iter().map(|r| r.action).collect();
+    assert_eq!(actions, [Action::Failed, Action::Failed, Action::Converted]);
+    assert!(
+        results[0]
+            .notes
+            .iter()
+            .any(|n| n.contains("Invalid argument"))
+    );
+    assert!(results[1].notes.iter().any(|n| n.contains("Killed")));
+    // Neither the crash nor the failure leaves a file, partial or not.
+    assert_eq!(files_in(&output), ["good.jxl"]);
+}
+
+#[tokio::test]
+async fn hung_tools_are_stopped_by_the_timeout() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("a.png"));
+    let fake = Fake::new(|call| match call.is_encode() {
+        true => Reply::Hang,
+        false => Reply::Succeed,
+    });
+
+    let converter = Converter::new(&input, &output)
+        .tool_timeout(Duration::from_secs(1))
+        .runner(fake);
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+
+    assert_eq!(results[0].action, Action::Failed);
+    assert!(results[0].notes.iter().any(|n| n.contains("timed out")));
+    assert!(!output.join("a.jxl").exists());
+}
+
+#[tokio::test]
+async fn the_handle_stops_a_run_and_a_second_stop_aborts_it() {
+    use bulk_jxl::Event;
+
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("a.png"));
+    common::write_image(&input.join("b.png"));
+    let fake = Fake::new(|call| match call.is_encode() {
+        true => Reply::Hang,
+        false => Reply::Succeed,
+    });
+
+    let converter = Converter::new(&input, &output).jobs(1).runner(fake);
+    let handle = converter.handle();
+    let (events, mut received) = tokio::sync::mpsc::channel(16);
+    let (outcome, (started, summary)) = tokio::join!(converter.run(events), async {
+        let (mut started, mut summary) = (0, None);
+        while let Some(event) = received.recv().await {
+            match event {
+                // The first lets the hung file finish, which it never does.
+                Event::FileStarted { .. } => {
+                    started += 1;
+                    handle.stop();
+                    handle.stop();
+                }
+                Event::RunFinished { summary: finished } => summary = Some(finished),
+                _ => {}
+            }
+        }
+        (started, summary.unwrap())
+    });
+
+    assert!(matches!(outcome, Err(Error::Stopped(130))));
+    assert_eq!(started, 1);
+    assert_eq!(summary.cancelled, 1);
+    assert_eq!(files_in(&output), Vec::<std::ffi::OsString>::new());
+}
+
+#[tokio::test]
+async fn a_killed_encode_is_repaired_on_the_next_run() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("a.png"));
+    // What a run killed half way through encoding leaves: the start of the
+    // output under the name of its partial file, and no output.
+    let mut killed = std::process::Command::new("true").spawn().unwrap();
+    killed.wait().unwrap();
+    let partial = output.join(format!(".bulk-jxl-partial-{}.a.jxl", killed.id()));
+    std::fs::create_dir_all(&output).unwrap();
+    std::fs::write(&partial, b"\xff\x0a fake").unwrap();
+    let fake = Fake::succeeding();
+
+    let converter = Converter::new(&input, &output).runner(fake);
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+
+    assert_eq!(results[0].action, Action::Converted);
+    assert_eq!(fake.encodes(), 1);
+    assert_eq!(files_in(&output), ["a.jxl"]);
+}
+
+#[tokio::test]
+async fn dangling_symlinks_are_reported_without_failing_the_run() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("a.png"));
+    std::os::unix::fs::symlink(input.join("gone.png"), input.join("b.png")).unwrap();
+    let report = temp.path().join("report.tsv");
+
+    let converter = Converter::new(&input, &output)
+        .report(&report)
+        .runner(Fake::succeeding());
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+
+    let converted: Vec<_> = results.iter().map(|r| r.source.clone()).collect();
+    assert_eq!(converted, [input.join("a.png")]);
+    assert_eq!(results[0].action, Action::Converted);
+    assert_eq!(files_in(&output), ["a.jxl"]);
+    let report = std::fs::read_to_string(report).unwrap();
+    assert!(
+        report
+            .lines()
+            .any(|line| line.starts_with("inaccessible\t") && line.contains("b.png")),
+        "{}",
+        report
+    );
+}
+
+#[tokio::test]
+async fn a_file_reached_through_a_symlink_converts_once() {
+    let temp = tempfile::tempdir().unwrap();
+    let input = temp.path().join("in");
+    // One link sorts before its target and one after.
+    common::write_image(&input.join("b.png"));
+    common::write_image(&input.join("m.png"));
+    std::os::unix::fs::symlink(input.join("b.png"), input.join("a.png")).unwrap();
+    std::os::unix::fs::symlink(input.join("m.png"), input.join("z.png")).unwrap();
+
+    for streamed in [false, true] {
+        let output = temp.path().join(format!("out-{}", streamed));
+        let mut converter = Converter::new(&input, &output).runner(Fake::succeeding());
+        if streamed {
+            converter = converter.stream();
+        }
+        let (outcome, results) = common::run(&converter).await;
+        outcome.unwrap();
+
+        let mut converted: Vec<_> = results.iter().map(|r| r.source.clone()).collect();
+        converted.sort();
+        assert_eq!(converted, [input.join("a.png"), input.join("m.png")]);
+        assert_eq!(files_in(&output), ["a.jxl", "m.jxl"]);
+    }
+}
+
+#[tokio::test]
+async fn names_that_are_not_utf8_keep_their_bytes() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    let name = |bytes: &[u8]| std::ffi::OsStr::from_bytes(bytes).to_owned();
+    // Latin-1, from an old archive, in a directory with such a name too.
+    common::write_image(&input.join(name(b"Fr\xfchling/Stra\xdfe.png")));
+    common::write_image(&input.join(name(b"new\nline .png")));
+    let report = temp.path().join("report.tsv");
+
+    let converter = Converter::new(&input, &output)
+        .recursive()
+        .report(&report)
+        .runner(Fake::succeeding());
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+
+    assert!(results.iter().all(|r| r.action == Action::Converted));
+    assert_eq!(
+        files_in(&output),
+        [name(b"Fr\xfchling"), name(b"new\nline .jxl")]
+    );
+    assert_eq!(
+        files_in(&output.join(name(b"Fr\xfchling"))),
+        [name(b"Stra\xdfe.jxl")]
+    );
+    let report = std::fs::read_to_string(report).unwrap();
+    assert!(report.contains("Fr\\xfchling/Stra\\xdfe.png"), "{}", report);
+    assert!(report.contains("new\\nline .png"), "{}", report);
+}
+
+#[tokio::test]
+async fn failed_files_are_retried_on_the_next_run() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("a.png"));
+    static BROKEN: AtomicBool = AtomicBool::new(true);
+    let fake = Fake::new(|call| match call.is_encode() {
+        true if BROKEN.load(Ordering::SeqCst) => Reply::Partial,
+        _ => Reply::Succeed,
+    });
+
+    let converter = Converter::new(&input, &output).runner(fake);
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+    assert_eq!(results[0].action, Action::Failed);
+
+    BROKEN.store(false, Ordering::SeqCst);
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+    assert_eq!(results[0].action, Action::Converted);
+    assert_eq!(fake.encodes(), 2);
+}
+
+#[tokio::test]
+async fn undecodable_sources_go_through_imagemagick() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("a.png"));
+    // ffmpeg can't read the source, only the PNG ImageMagick makes of it.
+    let fake = Fake::new(|call| match call.is_encode() {
+        true if call.has("a.png") => Reply::Fail {
+            status: 1,
+            stderr: "a.png: Invalid data found when processing input",
+        },
+        _ => Reply::Succeed,
+    });
+
+    let converter = Converter::new(&input, &output)
+        .magick_fallback()
+        .runner(fake);
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+
+    assert_eq!(results[0].action, Action::Converted);
+    assert!(fake.calls().iter().any(|call| call.program == "magick"));
+    assert!(output.join("a.jxl").exists());
+}
+
+#[tokio::test]
+async fn target_size_attempts_are_temporary_outputs() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("a.png"));
+    let fake = Fake::succeeding();
+
+    // Nothing fits, so every attempt is made and the smallest kept.
+    let converter = Converter::new(&input, &output).target_size(8).runner(fake);
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+
+    assert_eq!(results[0].action, Action::Converted);
+    let partial = format!(".bulk-jxl-partial-{}.", std::process::id());
+    let encodes: Vec<_> = fake.calls().into_iter().filter(|c| c.is_encode()).collect();
+    assert!(encodes.len() > 1);
+    assert!(encodes.iter().all(|c| c.has(".attempt") && c.has(&partial)));
+    assert_eq!(files_in(&output), ["a.jxl"]);
+}
+
+#[tokio::test]
+async fn target_size_conversions_pass_the_quality_gate() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("good.png"));
+    common::write_image(&input.join("poor.png"));
+    let fake = Fake::new(|call| match call.has("ssim") {
+        true if call.has("poor.") => Reply::Log(POOR_SSIM),
+        _ => Reply::Succeed,
+    });
+
+    let converter = Converter::new(&input, &output)
+        .target_size(1 << 20)
+        .quality_gate(QualityGate::Ssim(0.98), GateAction::Error)
+        .runner(fake);
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+
+    let actions: Vec<_> = results.iter().map(|r| r.action).collect();
+    assert_eq!(actions, [Action::Converted, Action::Failed]);
+    assert!(results[0].notes.contains(&"ssim=1.0000".to_string()));
+    assert!(
+        results[1]
+            .notes
+            .iter()
+            .any(|n| n.contains("below threshold"))
+    );
+    // Only the attempt the search chose is measured, before it takes the
+    // final name.
+    let measured: Vec<_> = fake.calls().into_iter().filter(|c| c.has("ssim")).collect();
+    assert_eq!(measured.len(), 2);
+    assert!(measured.iter().all(|c| c.has(".attempt")));
+    assert_eq!(files_in(&output), ["good.jxl"]);
+}
+
+#[tokio::test]
+async fn recompressed_jxl_is_kept_only_when_it_decodes_the_same() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    std::fs::create_dir_all(&input).unwrap();
+    // Bigger than what the fake encoder writes.
+    let mut original = b"\xff\x0a".to_vec();
+    original.resize(4096, 0);
+    std::fs::write(input.join("same.jxl"), &original).unwrap();
+    std::fs::write(input.join("changed.jxl"), &original).unwrap();
+    let fake = Fake::new(|call| {
+        if call.has("hash") && call.has("changed.recompress") {
+            Reply::Print("SHA256=other pixels\n")
+        } else {
+            Reply::Succeed
+        }
+    });
+
+    let converter = Converter::new(&input, &output)
+        .existing_jxl(ExistingJxl::Recompress)
+        .runner(fake);
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+
+    // Losslessly, whatever the distance.
+    let encodes: Vec<_> = fake.calls().into_iter().filter(|c| c.is_encode()).collect();
+    assert!(
+        encodes
+            .iter()
+            .all(|c| c.has("-distance") && c.args.contains(&"0".into()))
+    );
+    assert_eq!(results[0].source, input.join("changed.jxl"));
+    assert_eq!(results[0].action, Action::Copied);
+    assert!(
+        results[0]
+            .notes
+            .iter()
+            .any(|n| n.contains("changed the image"))
+    );
+    assert_eq!(std::fs::read(output.join("changed.jxl")).unwrap(), original);
+    assert_eq!(results[1].action, Action::Converted);
+    assert!(std::fs::metadata(output.join("same.jxl")).unwrap().len() < 4096);
+    assert_eq!(files_in(&output), ["changed.jxl", "same.jxl"]);
+}
+
+#[tokio::test]
+async fn recompressed_jpeg_transcodes_still_restore_the_jpeg() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    std::fs::create_dir_all(&input).unwrap();
+    // A container with the JPEG reconstruction box, bigger than what the fake
+    // encoder writes.
+    let mut original = b"\0\0\0\x0cJXL \r\n\x87\n\0\0\0\x14ftypjxl \0\0\0\0jxl ".to_vec();
+    original.extend_from_slice(b"\0\0\0\x10jbrd12345678");
+    // The codestream box runs to the end of the file.
+    original.extend_from_slice(b"\0\0\0\0jxlc\xff\x0a");
+    original.resize(4096, 0);
+    std::fs::write(input.join("photo.jxl"), &original).unwrap();
+    let fake = Fake::succeeding();
+
+    let converter = Converter::new(&input, &output)
+        .existing_jxl(ExistingJxl::Recompress)
+        .runner(fake);
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+
+    assert_eq!(results[0].action, Action::Converted);
+    let calls = fake.calls();
+    let programs: Vec<_> = calls
+        .iter()
+        .filter(|c| c.program != "ffmpeg" && c.program != "ffprobe" && !c.has("-version"))
+        .map(|c| c.program.as_str())
+        .collect();
+    // The JPEG is rebuilt, transcoded again, and rebuilt from the result to
+    // compare.
+    assert_eq!(programs, ["djxl", "cjxl", "djxl"]);
+    let cjxl = calls
+        .iter()
+        .find(|c| c.is_encode() && c.program == "cjxl")
+        .unwrap();
+    assert!(cjxl.args[0].to_string_lossy().ends_with(".jpg"));
+    assert_eq!(files_in(&output), ["photo.jxl"]);
+}
+
+#[tokio::test]
+async fn jpegs_cjxl_cannot_take_as_they_are_go_through_ffmpeg() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_oriented_jpeg(&input.join("portrait.jpg"), 6);
+    common::write_oriented_jpeg(&input.join("landscape.jpg"), 1);
+    common::write_gray_image(&input.join("scan.jpg"));
+    let policy = temp.path().join("policy.toml");
+    std::fs::write(&policy, "[jpg]\nmode = \"reconstruct\"\n").unwrap();
+    let report = temp.path().join("report.tsv");
+    let fake = Fake::new(|call| match call.program.as_str() {
+        "ffprobe" if call.has("Orientation") && call.has("portrait.jpg") => Reply::Print("6\n"),
+        _ => Reply::Succeed,
+    });
+
+    let converter = Converter::new(&input, &output)
+        .policy(&policy)
+        .bake_orientation()
+        .report(&report)
+        .runner(fake);
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+
+    assert!(results.iter().all(|r| r.action == Action::Converted));
+    let calls = fake.calls();
+    let encoder = |name: &str| {
+        let call = calls.iter().find(|c| c.is_encode() && c.has(name));
+        call.unwrap().clone()
+    };
+    // Rotating needs ffmpeg, which encodes it losslessly instead.
+    let portrait = encoder("portrait.jpg");
+    assert_eq!(portrait.program, "ffmpeg");
+    assert_eq!(portrait.value("-distance").as_deref(), Some("0"));
+    assert!(portrait.has("transpose=1"));
+    assert_eq!(encoder("landscape.jpg").program, "cjxl");
+    // cjxl keeps a grayscale JPEG single-channel by itself.
+    assert_eq!(encoder("scan.jpg").program, "cjxl");
+    let report = std::fs::read_to_string(&report).unwrap();
+    let line = |name: &str| report.lines().find(|line| line.contains(name)).unwrap();
+    assert!(line("portrait.jpg").contains("lossless instead of reconstruct (rotated)"));
+    assert!(!line("landscape.jpg").contains("instead of reconstruct"));
+    assert!(line("scan.jpg").contains("channels 1 -> 1"));
+}
+
+#[tokio::test]
+async fn options_that_go_against_each_other_are_rejected() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("a.png"));
+
+    for (converter, message) in [
+        (
+            Converter::new(&input, &output).stream().probe(),
+            "--stream can't be used with --probe",
+        ),
+        (
+            Converter::new(&input, &output)
+                .emit_script(temp.path().join("run.sh"), ScriptFormat::Sh)
+                .verify(),
+            "--emit-script can't be used with --verify",
+        ),
+        (
+            Converter::new(&input, &output).error_sample_bytes(64),
+            "--error-sample-bytes needs --error-dir",
+        ),
+        (
+            Converter::new(&input, &output).threads(0),
+            "--total-threads must be 1 or more",
+        ),
+    ] {
+        let fake = Fake::succeeding();
+        let (outcome, results) = common::run(&converter.runner(fake)).await;
+        assert!(
+            matches!(&outcome, Err(Error::Options(e)) if e == message),
+            "{:?}",
+            outcome.err()
+        );
+        assert!(results.is_empty() && fake.calls().is_empty());
+    }
+    assert!(!output.exists());
+}
+
+#[tokio::test]
+async fn policies_are_checked_before_the_run() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("a.png"));
+    let policy = temp.path().join("policy.toml");
+
+    std::fs::write(&policy, "[png]\neffort = 12\n").unwrap();
+    let converter = Converter::new(&input, &output)
+        .policy(&policy)
+        .runner(Fake::succeeding());
+    let (outcome, _) = common::run(&converter).await;
+    assert!(
+        matches!(&outcome, Err(Error::Options(e)) if e.contains("effort 12 of [png]")),
+        "{:?}",
+        outcome.err()
+    );
+
+    std::fs::write(&policy, "[jpg]\nmode = \"reconstruct\"\n").unwrap();
+    let without_cjxl = Fake::new(|call| match call.program.as_str() {
+        "cjxl" => Reply::Missing,
+        _ => Reply::Succeed,
+    });
+    let converter = Converter::new(&input, &output)
+        .policy(&policy)
+        .runner(without_cjxl);
+    let (outcome, results) = common::run(&converter).await;
+    assert!(matches!(outcome, Err(Error::MissingTool { tool, .. }) if tool == "cjxl"));
+    assert!(results.is_empty());
+    assert_eq!(without_cjxl.encodes(), 0);
+}
+
+/// What ffmpeg's ssim filter logs for a poor conversion.
+const POOR_SSIM: &str = "[Parsed_ssim_0 @ 0x1] SSIM Y:0.5 U:0.5 V:0.5 All:0.500000 (3.010300)";
+
+#[tokio::test]
+async fn quality_gate_keeps_failing_outputs_out_of_place() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("good.png"));
+    common::write_image(&input.join("poor.png"));
+    common::write_image(&input.join("unmeasurable.png"));
+    let fake = Fake::new(|call| match call.has("ssim") {
+        true if call.has("poor.png") => Reply::Log(POOR_SSIM),
+        true if call.has("unmeasurable.png") => Reply::Fail {
+            status: 1,
+            stderr: "Error reinitializing filters!",
+        },
+        _ => Reply::Succeed,
+    });
+
+    let converter = Converter::new(&input, &output)
+        .quality_gate(QualityGate::Ssim(0.98), GateAction::Error)
+        .runner(fake);
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+
+    let actions: Vec<_> = results.iter().map(|r| r.action).collect();
+    assert_eq!(actions, [Action::Converted, Action::Failed, Action::Failed]);
+    assert!(
+        results[1]
+            .notes
+            .iter()
+            .any(|n| n.contains("below threshold"))
+    );
+    // The temporary output is measured, before it takes the final name.
+    let measured: Vec<_> = fake.calls().into_iter().filter(|c| c.has("ssim")).collect();
+    assert_eq!(measured.len(), 3);
+    let partial = format!(".bulk-jxl-partial-{}.", std::process::id());
+    assert!(measured.iter().all(|c| c.has(&partial)));
+    assert_eq!(files_in(&output), ["good.jxl"]);
+}
+
+#[tokio::test]
+async fn quality_gate_actions_re_encode_or_keep_the_original() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("a.png"));
+    // The first two measurements fall short, the third passes.
+    static MEASURED: AtomicUsize = AtomicUsize::new(0);
+    let fake = Fake::new(|call| {
+        if call.has("ssim") && MEASURED.fetch_add(1, Ordering::SeqCst) < 2 {
+            Reply::Log(POOR_SSIM)
+        } else {
+            Reply::Succeed
+        }
+    });
+
+    let converter = Converter::new(&input, &output)
+        .quality_gate(QualityGate::Ssim(0.98), GateAction::ReEncode)
+        .runner(fake);
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+    assert_eq!(results[0].action, Action::Converted);
+    assert!(results[0].notes.contains(&"distance=0.25".to_string()));
+    assert_eq!(files_in(&output), ["a.jxl"]);
+
+    let output = temp.path().join("kept");
+    let fake = Fake::new(|call| match call.has("ssim") {
+        true => Reply::Log(POOR_SSIM),
+        false => Reply::Succeed,
+    });
+    let converter = Converter::new(&input, &output)
+        .quality_gate(QualityGate::Ssim(0.98), GateAction::KeepOriginal)
+        .runner(fake);
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+    assert_eq!(results[0].action, Action::KeptOriginal);
+    assert_eq!(files_in(&output), ["a.png"]);
+}
+
+#[tokio::test]
+async fn typed_options_drive_a_conversion() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    let report = temp.path().join("report.tsv");
+    common::write_image(&input.join("a.png"));
+    common::write_image(&input.join("sub/b.png"));
+    common::write_image(&input.join("sub/c.gif"));
+    common::write_image(&input.join("drafts/d.png"));
+
+    let converter = Converter::new(&input, &output)
+        .recursive()
+        .jobs(2)
+        .copy_jobs(1)
+        .threads(2)
+        .extensions(["png"])
+        .exclude_dir("drafts")
+        .verify()
+        .report(&report)
+        .tool_timeout(Duration::from_millis(2500))
+        .runner(Fake::succeeding());
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+
+    let sources: Vec<_> = results.iter().map(|r| r.source.clone()).collect();
+    assert_eq!(sources, [input.join("a.png"), input.join("sub/b.png")]);
+    assert!(results.iter().all(|r| r.action == Action::Converted));
+    assert_eq!(files_in(&output.join("sub")), ["b.jxl"]);
+    assert!(!output.join("drafts").exists());
+    let report = std::fs::read_to_string(report).unwrap();
+    assert!(report.contains("a.png"));
+}
+
+#[tokio::test]
+async fn runs_that_cannot_start_end_with_typed_errors() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("a.png"));
+
+    let missing = temp.path().join("missing");
+    let (outcome, _) =
+        common::run(&Converter::new(&missing, &output).runner(Fake::succeeding())).await;
+    assert!(matches!(outcome, Err(Error::Input { path, .. }) if path == missing));
+
+    let file = temp.path().join("file");
+    std::fs::write(&file, "not a directory").unwrap();
+    let (outcome, _) = common::run(&Converter::new(&input, &file).runner(Fake::succeeding())).await;
+    assert!(matches!(outcome, Err(Error::Output { path, .. }) if path == file));
+
+    let without_ffprobe = Fake::new(|call| match call.program.as_str() {
+        "ffprobe" => Reply::Missing,
+        _ => Reply::Succeed,
+    });
+    let (outcome, results) =
+        common::run(&Converter::new(&input, &output).runner(without_ffprobe)).await;
+    assert!(matches!(outcome, Err(Error::MissingTool { tool, .. }) if tool == "ffprobe"));
+    assert!(results.is_empty());
+
+    // A live run, this one, holds the output.
+    std::fs::create_dir_all(&output).unwrap();
+    let started = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let mut lock = std::fs::File::create(output.join(".bulk-jxl.lock")).unwrap();
+    lock.try_lock().unwrap();
+    writeln!(lock, "{}\n{}", std::process::id(), started).unwrap();
+    let (outcome, results) =
+        common::run(&Converter::new(&input, &output).runner(Fake::succeeding())).await;
+    assert!(matches!(outcome, Err(Error::Locked { pid, .. }) if pid == std::process::id()));
+    assert!(results.is_empty());
+    assert!(!output.join("a.jxl").exists());
+}
+
+#[tokio::test]
+async fn files_in_the_way_of_output_directories_are_named() {
+    let temp = tempfile::tempdir().unwrap();
+    let input = temp.path().join("in");
+    common::write_image(&input.join("a.png"));
+    common::write_image(&input.join("sub/b.png"));
+    common::write_image(&input.join("other/c.png"));
+
+    // The output itself, or a directory above it, is a file.
+    let file = temp.path().join("file");
+    std::fs::write(&file, "not a directory").unwrap();
+    for output in [file.clone(), file.join("out")] {
+        let converter = Converter::new(&input, &output)
+            .recursive()
+            .runner(Fake::succeeding());
+        let (outcome, results) = common::run(&converter).await;
+        let blocked = format!("{} is a file", file.display());
+        assert!(
+            matches!(&outcome, Err(Error::Output { path, message }) if path == &output && message.contains(&blocked)),
+            "{}: {:?}",
+            output.display(),
+            outcome.err()
+        );
+        assert!(results.is_empty());
+    }
+
+    // A file where a subdirectory of the output goes fails only the files
+    // that belong in it.
+    let output = temp.path().join("out");
+    std::fs::create_dir_all(&output).unwrap();
+    std::fs::write(output.join("sub"), "left by an earlier run").unwrap();
+    let converter = Converter::new(&input, &output)
+        .recursive()
+        .runner(Fake::succeeding());
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+    let actions: Vec<_> = results.iter().map(|r| r.action).collect();
+    assert_eq!(
+        actions,
+        [Action::Converted, Action::Converted, Action::Failed]
+    );
+    let blocked = format!("{} is a file", output.join("sub").display());
+    assert!(
+        results[2].notes.iter().any(|note| note.contains(&blocked)),
+        "{:?}",
+        results[2].notes
+    );
+    assert!(output.join("other/c.jxl").exists());
+    assert_eq!(
+        std::fs::read(output.join("sub")).unwrap(),
+        b"left by an earlier run"
+    );
+}
+
+#[tokio::test]
+async fn outputs_that_lead_back_to_the_input_are_refused() {
+    let temp = tempfile::tempdir().unwrap();
+    let input = temp.path().join("in");
+    common::write_image(&input.join("sub/a.png"));
+    std::os::unix::fs::symlink(&input, temp.path().join("link")).unwrap();
+
+    for output in [
+        input.clone(),
+        input.join("."),
+        input.join("sub/.."),
+        temp.path().join("link"),
+        temp.path().join("link/sub/../."),
+    ] {
+        let converter = Converter::new(&input, &output)
+            .recursive()
+            .runner(Fake::succeeding());
+        let (outcome, results) = common::run(&converter).await;
+        assert!(
+            matches!(&outcome, Err(Error::Output { message, .. }) if message.contains("same directory")),
+            "{}: {:?}",
+            output.display(),
+            outcome.err()
+        );
+        assert!(results.is_empty());
+    }
+    // Nor does reaching the input through a symlink fool the check.
+    let (outcome, _) = common::run(
+        &Converter::new(temp.path().join("link"), input.join("sub/..")).runner(Fake::succeeding()),
+    )
+    .await;
+    assert!(matches!(outcome, Err(Error::Output { .. })));
+    assert_eq!(files_in(&input.join("sub")), ["a.png"]);
+}
+
+#[tokio::test]
+async fn inputs_inside_the_output_are_refused() {
+    let temp = tempfile::tempdir().unwrap();
+    let output = temp.path().join("out");
+    let input = output.join("photos");
+    common::write_image(&input.join("a.png"));
+    std::os::unix::fs::symlink(&output, temp.path().join("link")).unwrap();
+
+    for (input, output) in [
+        (input.clone(), output.clone()),
+        (input.clone(), input.join("..")),
+        (input.clone(), temp.path().join("link")),
+        (temp.path().join("link/photos"), output.clone()),
+        (input.clone(), temp.path().to_owned()),
+    ] {
+        let converter = Converter::new(&input, &output).runner(Fake::succeeding());
+        let (outcome, results) = common::run(&converter).await;
+        assert!(
+            matches!(&outcome, Err(Error::Output { message, .. }) if message.contains("inside the output")),
+            "{} in {}: {:?}",
+            input.display(),
+            output.display(),
+            outcome.err()
+        );
+        assert!(results.is_empty());
+    }
+    assert_eq!(files_in(&input), ["a.png"]);
+}
+
+#[tokio::test]
+async fn outputs_inside_the_input_are_not_collected_again() {
+    let temp = tempfile::tempdir().unwrap();
+    let input = temp.path().join("in");
+    common::write_image(&input.join("sub/a.png"));
+    std::os::unix::fs::symlink(&input, temp.path().join("link")).unwrap();
+    // The output inside the input, named through the symlink and `..`.
+    let output = temp.path().join("link/sub/../out");
+    let fake = Fake::succeeding();
+
+    let converter = Converter::new(&input, &output).recursive().runner(fake);
+    for _ in 0..2 {
+        let (outcome, _) = common::run(&converter).await;
+        outcome.unwrap();
+    }
+
+    assert_eq!(fake.encodes(), 1);
+    assert_eq!(files_in(&input.join("out")), ["sub"]);
+    assert_eq!(files_in(&input.join("out/sub")), ["a.jxl"]);
+}
+
+#[tokio::test]
+async fn composed_and_decomposed_names_give_the_same_output() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    let (composed, decomposed) = ("caf\u{e9}", "cafe\u{301}");
+    // Written on macOS.
+    common::write_image(&input.join(format!("{}.png", decomposed)));
+    let fake = Fake::succeeding();
+
+    let converter = Converter::new(&input, &output).runner(fake);
+    let (outcome, _) = common::run(&converter).await;
+    outcome.unwrap();
+    assert_eq!(files_in(&output), [format!("{}.jxl", composed).as_str()]);
+
+    // The same file copied over from Linux.
+    std::fs::rename(
+        input.join(format!("{}.png", decomposed)),
+        input.join(format!("{}.png", composed)),
+    )
+    .unwrap();
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+    assert_eq!(results[0].action, Action::Skipped);
+    assert_eq!(fake.encodes(), 1);
+
+    // Both at once would be written to the same name.
+    common::write_image(&input.join(format!("{}.png", decomposed)));
+    let (outcome, results) = common::run(&converter).await;
+    assert!(outcome.is_err_and(|e| e.to_string().contains("collide")));
+    assert!(results.is_empty());
+
+    // Keeping the source's bytes, the earlier output only differs in its
+    // normalization, which counts as a collision as well.
+    std::fs::remove_file(input.join(format!("{}.png", composed))).unwrap();
+    let converter = Converter::new(&input, &output)
+        .normalize_names(Normalization::None)
+        .runner(fake);
+    let (outcome, _) = common::run(&converter).await;
+    assert!(outcome.is_err_and(|e| e.to_string().contains("collide")));
+    assert_eq!(files_in(&output), [format!("{}.jxl", composed).as_str()]);
+    assert_eq!(fake.encodes(), 1);
+}
+
+#[tokio::test]
+async fn an_input_given_as_a_symlink_converts_like_its_target() {
+    let temp = tempfile::tempdir().unwrap();
+    let target = temp.path().join("2024");
+    common::write_image(&target.join("a.png"));
+    common::write_image(&target.join("sub/b.gif"));
+    let link = temp.path().join("current");
+    std::os::unix::fs::symlink(&target, &link).unwrap();
+
+    let tree = |root: &std::path::Path| -> Vec<_> {
+        let mut files: Vec<_> = walkdir::WalkDir::new(root)
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.path().strip_prefix(root).unwrap().to_owned())
+            .filter(|path| path != std::path::Path::new(".bulk-jxl.journal"))
+            .collect();
+        files.sort();
+        files
+    };
+    let mut outputs = Vec::new();
+    for (input, output) in [(&target, "direct"), (&link, "linked")] {
+        let output = temp.path().join(output);
+        let converter = Converter::new(input, &output)
+            .recursive()
+            .runner(Fake::succeeding());
+        let (outcome, results) = common::run(&converter).await;
+        outcome.unwrap();
+        assert!(results.iter().all(|r| r.action == Action::Converted));
+        outputs.push(tree(&output));
+    }
+
+    assert_eq!(
+        outputs[0],
+        ["a.jxl", "sub/b.jxl"].map(std::path::PathBuf::from)
+    );
+    assert_eq!(outputs[0], outputs[1]);
+}
+
+#[tokio::test]
+async fn huge_trees_keep_only_a_few_files_in_flight() {
+    use bulk_jxl::Event;
+
+    const FILES: usize = 3000;
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("0.png"));
+    let image = std::fs::read(input.join("0.png")).unwrap();
+    for i in 1..FILES {
+        std::fs::write(input.join(format!("{}.png", i)), &image).unwrap();
+    }
+
+    for streamed in [false, true] {
+        let fake = Fake::succeeding();
+        let mut converter = Converter::new(&input, &output)
+            .jobs(2)
+            .copy_jobs(1)
+            .runner(fake);
+        if streamed {
+            converter = converter.stream();
+        }
+        let (events, mut received) = tokio::sync::mpsc::channel(16);
+        let (outcome, (finished, most_ahead)) = tokio::join!(converter.run(events), async {
+            let (mut finished, mut most_ahead) = (0, 0);
+            while let Some(event) = received.recv().await {
+                match event {
+                    Event::FileFinished(_) => finished += 1,
+                    // How far the walk of --stream got past the finished files.
+                    Event::ScanProgress {
+                        found,
+                        complete: false,
+                        ..
+                    } => most_ahead = usize::max(most_ahead, found - finished),
+                    _ => {}
+                }
+            }
+            (finished, most_ahead)
+        });
+        outcome.unwrap();
+
+        assert_eq!(finished, FILES);
+        // Every file is a conversion and runs one tool at a time, so the
+        // tools running at once are the conversion slots held.
+        assert_eq!(fake.most_running(), 2);
+        if streamed {
+            // The files in flight, the ones waiting for a slot and the few
+            // queued by the walk.
+            assert!(most_ahead <= 16, "{} found ahead", most_ahead);
+        }
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn icons_convert_their_largest_image_or_every_one() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_ico(&input.join("favicon.ico"), &[16, 48, 32]);
+    let report = temp.path().join("report.tsv");
+
+    let fake = Fake::succeeding();
+    let converter = Converter::new(&input, &output).report(&report).runner(fake);
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+    assert_eq!(results[0].action, Action::Converted);
+    assert_eq!(files_in(&output), ["favicon.jxl"]);
+    let maps: Vec<_> = fake
+        .calls()
+        .iter()
+        .filter(|call| call.is_encode())
+        .map(|call| call.value("-map").unwrap())
+        .collect();
+    assert_eq!(maps, ["0:1"]);
+    // The picked image is probed like any other, not the first one.
+    let probed: Vec<_> = fake
+        .calls()
+        .iter()
+        .filter(|call| call.program == "ffprobe")
+        .filter_map(|call| call.value("-select_streams"))
+        .collect();
+    assert!(!probed.is_empty());
+    assert!(probed.iter().all(|stream| stream == "1"), "{:?}", probed);
+    let report = std::fs::read_to_string(&report).unwrap();
+    assert!(
+        report.contains("ico with 3 images: 16x16 32bpp, 48x48 png, 32x32 png; converted largest"),
+        "{}",
+        report
+    );
+
+    let output = temp.path().join("all");
+    let fake = Fake::succeeding();
+    let converter = Converter::new(&input, &output)
+        .ico(IcoMode::All)
+        .runner(fake);
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+    assert_eq!(results[0].action, Action::Converted);
+    assert_eq!(
+        files_in(&output),
+        ["favicon.16.jxl", "favicon.32.jxl", "favicon.48.jxl"]
+    );
+    // Each image is picked by its stream, in the order of the directory.
+    let mut encodes: Vec<_> = fake
+        .calls()
+        .iter()
+        .filter(|call| call.is_encode())
+        .map(|call| {
+            let written = call.args.last().unwrap().to_string_lossy().into_owned();
+            let name = written.rsplit('/').next().unwrap();
+            // Past the `.bulk-jxl-partial-PID.` of the temporary output.
+            let name = name
+                .strip_prefix(".bulk-jxl-partial-")
+                .and_then(|name| name.split_once('.'));
+            (call.value("-map").unwrap(), name.unwrap().1.to_string())
+        })
+        .collect();
+    encodes.sort();
+    assert_eq!(
+        encodes,
+        [
+            ("0:0".to_string(), "favicon.16.jxl".to_string()),
+            ("0:1".to_string(), "favicon.48.jxl".to_string()),
+            ("0:2".to_string(), "favicon.32.jxl".to_string()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn grayscale_sources_stay_single_channel() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_gray_image(&input.join("scan.png"));
+    common::write_image(&input.join("photo.png"));
+
+    for (force_rgb, scan_format, scan_channels) in [
+        (false, Some("gray"), "channels 1 -> 1"),
+        (true, None, "channels 1 -> 3"),
+    ] {
+        let report = temp.path().join("report.tsv");
+        let fake = Fake::succeeding();
+        let mut converter = Converter::new(&input, &output).report(&report).runner(fake);
+        if force_rgb {
+            converter = converter.force_rgb();
+        }
+        let (outcome, _) = common::run(&converter).await;
+        outcome.unwrap();
+
+        let pixel_format = |name: &str| {
+            let calls = fake.calls();
+            let encode = calls.iter().find(|call| call.is_encode() && call.has(name));
+            encode.unwrap().value("-pix_fmt")
+        };
+        assert_eq!(pixel_format("scan.png").as_deref(), scan_format);
+        assert_eq!(pixel_format("photo.png"), None);
+        let report = std::fs::read_to_string(&report).unwrap();
+        let line = |name: &str| report.lines().find(|line| line.contains(name)).unwrap();
+        assert!(line("scan.png").contains(scan_channels), "{}", report);
+        assert!(line("photo.png").contains("channels 3 -> 3"), "{}", report);
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn bake_orientation_rotates_only_oriented_files() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_oriented_jpeg(&input.join("portrait.jpg"), 6);
+    common::write_oriented_jpeg(&input.join("landscape.jpg"), 1);
+    let report = temp.path().join("report.tsv");
+    let fake = Fake::new(|call| match call.program.as_str() {
+        "ffprobe" if call.has("Orientation") && call.has("portrait.jpg") => Reply::Print("6\n"),
+        "ffprobe" if call.has("Orientation") => Reply::Print("1\n"),
+        _ => Reply::Succeed,
+    });
+
+    let converter = Converter::new(&input, &output)
+        .bake_orientation()
+        .report(&report)
+        .runner(fake);
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+
+    assert!(results.iter().all(|r| r.action == Action::Converted));
+    let calls = fake.calls();
+    let encode = |name: &str| {
+        calls
+            .iter()
+            .find(|call| call.is_encode() && call.has(name))
+            .unwrap()
+    };
+    let portrait = encode("portrait.jpg");
+    assert!(portrait.has("-noautorotate"));
+    assert_eq!(
+        portrait.value("-vf").as_deref(),
+        Some("transpose=1,sidedata=mode=delete:type=DISPLAYMATRIX")
+    );
+    assert!(!portrait.has("-metadata"));
+    let landscape = encode("landscape.jpg");
+    assert!(!landscape.has("-vf") && !landscape.has("-noautorotate"));
+    let report = std::fs::read_to_string(&report).unwrap();
+    let line = |name: &str| report.lines().find(|line| line.contains(name)).unwrap();
+    assert!(line("portrait.jpg").contains("rotated (orientation 6)"));
+    assert!(!line("landscape.jpg").contains("rotated"));
+}
+
+#[tokio::test]
+async fn animated_pngs_keep_their_frames_and_loop_count() {
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_apng(&input.join("forever.png"), 3, 0);
+    common::write_apng(&input.join("twice.png"), 3, 2);
+    let fake = Fake::succeeding();
+
+    let (outcome, results) = common::run(&Converter::new(&input, &output).runner(fake)).await;
+    outcome.unwrap();
+
+    assert!(results.iter().all(|r| r.action == Action::Converted));
+    assert!(
+        results[1]
+            .notes
+            .iter()
+            .any(|note| note.contains("animated, 3 frames, plays 2 times"))
+    );
+    let encode = |name: &str| {
+        fake.calls()
+            .into_iter()
+            .find(|call| call.is_encode() && call.has(name))
+            .unwrap()
+    };
+    let twice = encode("twice.png");
+    assert_eq!(twice.value("-f").as_deref(), Some("apng"));
+    assert_eq!(twice.value("-c:v").as_deref(), Some("libjxl_anim"));
+    assert_eq!(twice.value("-loop").as_deref(), Some("2"));
+    // Forever is the default of the encoder.
+    assert_eq!(encode("forever.png").value("-loop"), None);
+}
+
+/// The permission bits of `path`.
+fn mode_of(path: &std::path::Path) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path).unwrap().permissions().mode() & 0o7777
+}
+
+#[tokio::test]
+async fn file_mode_replaces_the_mode_copies_keep() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = tempfile::tempdir().unwrap();
+    let input = temp.path().join("in");
+    common::write_image(&input.join("a.png"));
+    std::fs::write(input.join("notes.txt"), b"notes").unwrap();
+    let private = std::fs::Permissions::from_mode(0o600);
+    std::fs::set_permissions(input.join("notes.txt"), private).unwrap();
+
+    let output = temp.path().join("kept");
+    let converter = Converter::new(&input, &output)
+        .copy_all()
+        .runner(Fake::succeeding());
+    common::run(&converter).await.0.unwrap();
+    assert_eq!(mode_of(&output.join("notes.txt")), 0o600);
+
+    let output = temp.path().join("given");
+    let converter = Converter::new(&input, &output)
+        .copy_all()
+        .file_mode(0o640)
+        .runner(Fake::succeeding());
+    common::run(&converter).await.0.unwrap();
+    assert_eq!(mode_of(&output.join("notes.txt")), 0o640);
+    assert_eq!(mode_of(&output.join("a.jxl")), 0o640);
+}
+
+#[tokio::test]
+async fn dir_mode_leaves_directories_that_were_there_alone() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("old/a.png"));
+    common::write_image(&input.join("new/deeper/b.png"));
+    std::fs::create_dir_all(output.join("old")).unwrap();
+    for dir in [&output, &output.join("old")] {
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    let converter = Converter::new(&input, &output)
+        .recursive()
+        .dir_mode(0o700)
+        .runner(Fake::succeeding());
+    common::run(&converter).await.0.unwrap();
+    assert!(output.join("old/a.jxl").exists());
+    assert!(output.join("new/deeper/b.jxl").exists());
+    assert_eq!(mode_of(&output), 0o755);
+    assert_eq!(mode_of(&output.join("old")), 0o755);
+    assert_eq!(mode_of(&output.join("new")), 0o700);
+    assert_eq!(mode_of(&output.join("new/deeper")), 0o700);
+}
+
+#[tokio::test]
+async fn chown_takes_the_place_of_preserve_owner() {
+    use std::os::unix::fs::MetadataExt;
+
+    let temp = tempfile::tempdir().unwrap();
+    // Changing owners takes root, elsewhere both options do nothing.
+    if std::fs::metadata(temp.path()).unwrap().uid() != 0 {
+        return;
+    }
+    let input = temp.path().join("in");
+    common::write_image(&input.join("a.png"));
+    std::os::unix::fs::lchown(input.join("a.png"), Some(4321), Some(4321)).unwrap();
+    let owner = |path: &std::path::Path| {
+        let metadata = std::fs::metadata(path).unwrap();
+        (metadata.uid(), metadata.gid())
+    };
+
+    let output = temp.path().join("preserved");
+    let converter = Converter::new(&input, &output)
+        .preserve_owner()
+        .runner(Fake::succeeding());
+    common::run(&converter).await.0.unwrap();
+    assert_eq!(owner(&output.join("a.jxl")), (4321, 4321));
+
+    let output = temp.path().join("given");
+    let converter = Converter::new(&input, &output)
+        .preserve_owner()
+        .chown(Some(1234), Some(1234))
+        .runner(Fake::succeeding());
+    common::run(&converter).await.0.unwrap();
+    assert_eq!(owner(&output.join("a.jxl")), (1234, 1234));
+}
+
+#[tokio::test]
+async fn hard_linked_copies_leave_the_source_metadata_alone() {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    std::fs::create_dir_all(&input).unwrap();
+    std::fs::write(input.join("notes.txt"), b"notes").unwrap();
+    let shared = std::fs::Permissions::from_mode(0o644);
+    std::fs::set_permissions(input.join("notes.txt"), shared).unwrap();
+
+    let converter = Converter::new(&input, &output)
+        .copy_all()
+        .copy_mode(CopyMode::Hardlink)
+        .file_mode(0o600)
+        .runner(Fake::succeeding());
+    let (outcome, results) = common::run(&converter).await;
+    outcome.unwrap();
+    assert_eq!(results[0].action, Action::Copied);
+    let (source, copy) = (input.join("notes.txt"), output.join("notes.txt"));
+    let inode = |path: &std::path::Path| std::fs::metadata(path).unwrap().ino();
+    assert_eq!(inode(&source), inode(&copy));
+    assert_eq!(mode_of(&source), 0o644);
+}
+
+#[tokio::test]
+async fn hard_links_get_linked_outputs_unless_told_not_to() {
+    use std::os::unix::fs::MetadataExt;
+
+    let temp = tempfile::tempdir().unwrap();
+    let (input, output) = (temp.path().join("in"), temp.path().join("out"));
+    common::write_image(&input.join("a.png"));
+    std::fs::hard_link(input.join("a.png"), input.join("b.png")).unwrap();
+    // Takes the name the link's output would have.
+    common::write_image(&input.join("b.bmp"));
+    let inode = |path: &str| std::fs::metadata(output.join(path)).unwrap().ino();
+
+    for preserved in [true, false] {
+        let fake = Fake::succeeding();
+        let mut converter = Converter::new(&input, &output)
+            .on_collision(CollisionMode::Suffix)
+            .runner(fake);
+        if !preserved {
+            converter = converter.no_preserve_hardlinks();
+        }
+        let (outcome, _) = common::run(&converter).await;
+        outcome.unwrap();
+        assert_eq!(files_in(&output), ["a.jxl", "b.jxl", "b~2.jxl"]);
+        if preserved {
+            assert_eq!(fake.encodes(), 2);
+            assert_eq!(inode("b~2.jxl"), inode("a.jxl"));
+        } else {
+            assert_eq!(fake.encodes(), 3);
+            assert_ne!(inode("b~2.jxl"), inode("a.jxl"));
+        }
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+}